@@ -0,0 +1,107 @@
+// fuzz/fuzz_targets/protocol_parse.rs
+
+//! Feeds arbitrary bytes through the text protocol's line parsers the way a
+//! session reads them: line by line, except where a header announces a raw
+//! payload (chunk data, pushed manifests) or a run of DHT or file entry lines.
+//! Any panic is a bug; so is a parsed line that doesn't format back to itself.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use peerchunks::file_manager::manifest::FileManifest;
+use peerchunks::json;
+use peerchunks::peer::protocol::{
+    decode_message, parse_chunk_request, parse_chunk_response_header, parse_dht_entry, parse_dht_response_header,
+    parse_file_entry, parse_list_files_header, ChunkRequestLine, MAX_MESSAGE_LEN,
+};
+use peerchunks::telemetry::TraceContext;
+
+/// What the session expects next.
+enum State {
+    Line,
+    DhtEntries(usize),
+    FileEntries(usize),
+    Payload(usize),
+    Manifest(usize),
+}
+
+/// Splits off the next `\n`-terminated line, or everything left at end of input.
+fn next_line<'a>(input: &mut &'a [u8]) -> &'a [u8] {
+    let end = input.iter().position(|&b| b == b'\n').map_or(input.len(), |i| i + 1);
+    let (line, rest) = input.split_at(end);
+    *input = rest;
+    line.strip_suffix(b"\n").unwrap_or(line)
+}
+
+fn handle_line(line: &str) -> State {
+    if let Some(count) = parse_dht_response_header(line) {
+        return State::DhtEntries(count);
+    }
+    if let Some(count) = parse_list_files_header(line) {
+        return State::FileEntries(count);
+    }
+    if let Some(header) = parse_chunk_response_header(line) {
+        assert!(header.chunk_size <= MAX_MESSAGE_LEN);
+        return State::Payload(header.chunk_size);
+    }
+    match parse_chunk_request(line) {
+        ChunkRequestLine::Valid { file_id, chunk_index, trace_parent } => {
+            let reformatted = match trace_parent {
+                Some(trace_parent) => format!("CHUNK_REQUEST:{}:{}:{}", file_id, chunk_index, trace_parent),
+                None => format!("CHUNK_REQUEST:{}:{}", file_id, chunk_index),
+            };
+            assert_eq!(parse_chunk_request(&reformatted), ChunkRequestLine::Valid { file_id, chunk_index, trace_parent });
+        }
+        ChunkRequestLine::Malformed { file_id, chunk_index } => {
+            assert!(!file_id.contains(':') && !chunk_index.contains(':'));
+        }
+        ChunkRequestLine::Invalid => {}
+    }
+    if let Some((_, len)) = line.strip_prefix("MANIFEST_PUSH:").and_then(|h| h.split_once(':')) {
+        if let Ok(len) = len.parse::<usize>() {
+            if len <= MAX_MESSAGE_LEN {
+                return State::Manifest(len);
+            }
+        }
+    }
+    let _ = TraceContext::from_header(line);
+    State::Line
+}
+
+fuzz_target!(|data: &[u8]| {
+    // The same bytes as the body of a JSON frame.
+    let _ = decode_message(data);
+
+    let mut input = data;
+    let mut state = State::Line;
+    while !input.is_empty() {
+        state = match state {
+            State::Line => handle_line(&String::from_utf8_lossy(next_line(&mut input))),
+            State::DhtEntries(0) => State::Line,
+            State::DhtEntries(remaining) => {
+                if let Some((_, address)) = parse_dht_entry(&String::from_utf8_lossy(next_line(&mut input))) {
+                    assert!(!address.is_empty());
+                }
+                State::DhtEntries(remaining - 1)
+            }
+            State::FileEntries(0) => State::Line,
+            State::FileEntries(remaining) => {
+                if let Some(entry) = parse_file_entry(&String::from_utf8_lossy(next_line(&mut input))) {
+                    let line = format!("{}:{}:{}", entry.file_id, entry.file_name, entry.size_bytes);
+                    assert_eq!(parse_file_entry(&line), Some(entry));
+                }
+                State::FileEntries(remaining - 1)
+            }
+            State::Payload(len) => {
+                input = &input[len.min(input.len())..];
+                State::Line
+            }
+            State::Manifest(len) => {
+                let (payload, rest) = input.split_at(len.min(input.len()));
+                input = rest;
+                let _ = json::from_str::<FileManifest>(&String::from_utf8_lossy(payload));
+                State::Line
+            }
+        };
+    }
+});
@@ -0,0 +1,32 @@
+// fuzz/fuzz_targets/split_file_into_chunks.rs
+
+//! Chunks arbitrary file content. The first two input bytes pick the chunk
+//! size (1..=65536); the rest is the file.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use peerchunks::file_manager::chunker::split_file_into_chunks;
+use std::io::Write;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((size, content)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let chunk_size = u16::from_le_bytes(*size) as usize + 1;
+
+    let path = std::env::temp_dir().join(format!("peerchunks-fuzz-{}", std::process::id()));
+    std::fs::File::create(&path).unwrap().write_all(content).unwrap();
+    let (file_id, chunks) = split_file_into_chunks(&path, chunk_size).unwrap();
+
+    assert_eq!(chunks.len(), content.len().div_ceil(chunk_size));
+    let mut offset = 0;
+    for (i, (metadata, chunk)) in chunks.iter().enumerate() {
+        assert_eq!((metadata.file_id, metadata.chunk_index), (file_id, i));
+        assert_eq!(metadata.total_chunks, chunks.len());
+        assert_eq!(metadata.actual_size, chunk.len());
+        assert_eq!(&content[offset..offset + chunk.len()], &chunk[..]);
+        offset += chunk.len();
+    }
+    assert_eq!(offset, content.len());
+});
@@ -1,21 +1,1194 @@
 // src/config.rs
 
-use serde::Deserialize;
+use crate::file_manager::manifest::{load_manifest, save_manifest, FileManifest, MANIFEST_FILENAME};
+use crate::file_manager::replication::ReplicationPolicy;
+use crate::file_manager::storage::list_all_files;
+use crate::peer::discovery::{
+    default_heartbeat_interval_secs, default_heartbeat_timeout_secs, default_max_connections_per_ip,
+    default_lan_beacon_addr, default_max_incoming_connections, PeerDiscoveryConfig,
+};
+use crate::peer::encryption::{decrypt, encrypt, NonceTracker};
+use crate::peer::url::PeerUrl;
+use crate::json;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
 use std::fs;
+use std::net::SocketAddr;
 use std::error::Error;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use uuid::Uuid;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Config {
-    pub peer_port: u16,
-    pub bootstrap_peers: Vec<String>,
+    /// Read from the same flat keys as before, e.g. `peer_port:` rather than
+    /// `discovery: { peer_port: }`.
+    #[serde(flatten)]
+    pub discovery: PeerDiscoveryConfig,
+    #[serde(flatten)]
+    pub storage: StorageConfig,
+    #[serde(flatten)]
+    pub transfer: TransferConfig,
+    pub encryption_key: SecretField<String>,
+    /// SOCKS5 proxy for outbound peer connections, as `[user:password@]host:port`.
+    #[serde(default)]
+    pub socks5_proxy: Option<String>,
+    /// OTLP/HTTP collector that spans are exported to, e.g. `http://localhost:4318`.
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    /// Upper bound on DHT entries sent in one gossip round.
+    #[serde(default = "default_max_gossip_entries")]
+    pub max_gossip_entries: usize,
+    /// Random peers each gossip round is sent to.
+    #[serde(default = "default_gossip_fanout")]
+    pub gossip_fanout: usize,
+    /// Port of the HTTP server for `/health` and `/ready`; the server is off when unset.
+    #[serde(default)]
+    pub http_port: Option<u16>,
+    /// Peer connect/disconnect events buffered for each `watch-peers` subscriber;
+    /// a subscriber that falls further behind skips the oldest ones.
+    #[serde(default = "default_peer_event_buffer")]
+    pub peer_event_buffer: usize,
+    /// How often, in seconds, every stored file is announced to the known peers
+    /// again, so peers that restarted or joined later learn where it is.
+    #[serde(default = "default_re_announce_interval_secs")]
+    pub re_announce_interval_secs: u64,
+    /// This node's ID in hex, sent to the peers it downloads from so they can
+    /// apply file ACLs; see `share`.
+    #[serde(default)]
+    pub node_id: Option<String>,
+    /// 64 hex characters proving `node_id`: the ID is only believed by peers
+    /// that were given this key with `share --key`. Keep it secret.
+    #[serde(default)]
+    pub node_key: Option<SecretField<String>>,
+}
+
+/// Where files are kept and how many copies of them the network should hold.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct StorageConfig {
     pub storage_path: String,
-    pub encryption_key: String,
+    /// Storage the node may use; `/health` reports `degraded` above 90% of it.
+    #[serde(default)]
+    pub storage_quota_bytes: Option<u64>,
+    /// Stored files held by fewer peers than this, the local node included,
+    /// are replicated again by the repair task and `repair`.
+    #[serde(default = "default_replication_target_factor")]
+    pub replication_target_factor: usize,
+    /// How often, in seconds, stored files are checked for missing replicas.
+    #[serde(default = "default_repair_interval_secs")]
+    pub repair_interval_secs: u64,
+}
+
+/// How chunks are sized, sent and fetched during uploads and downloads.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct TransferConfig {
+    /// Chunk size used by `upload-dir`. `upload` without `--chunk-size` picks
+    /// one from the file's size and the number of connected peers instead.
+    #[serde(default = "default_chunk_size")]
+    pub default_chunk_size: usize,
+    #[serde(default = "default_download_write_buffer_bytes")]
+    pub download_write_buffer_bytes: usize,
+    /// Workers sending replicated chunks during uploads, each to one peer at a time.
+    #[serde(default = "default_max_concurrent_uploads")]
+    pub max_concurrent_uploads: usize,
+    /// How peers are chosen for each replicated chunk.
+    #[serde(default)]
+    pub replication_policy: ReplicationPolicy,
+    /// `CHUNK_REQUEST`s sent ahead on one connection before waiting for replies.
+    #[serde(default = "default_max_pipeline_depth")]
+    pub max_pipeline_depth: usize,
+    /// Chunks after the one just downloaded that are fetched ahead of time.
+    #[serde(default = "default_prefetch_lookahead")]
+    pub prefetch_lookahead: usize,
+}
+
+/// A value kept out of logs: `Debug` and `Display` both print `[REDACTED]`.
+/// The value itself is reached through `Deref`, so `&config.encryption_key`
+/// still works where a `&str` is expected. Beware `to_string()`, which goes
+/// through `Display`; use `as_str().to_string()` for an owned copy.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SecretField<T: Clone>(T);
+
+impl<T: Clone> SecretField<T> {
+    pub fn new(value: T) -> Self {
+        SecretField(value)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: Clone> Deref for SecretField<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Clone> From<T> for SecretField<T> {
+    fn from(value: T) -> Self {
+        SecretField(value)
+    }
+}
+
+impl<T: Clone> fmt::Debug for SecretField<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl<T: Clone> fmt::Display for SecretField<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretField<String> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(SecretField)
+    }
+}
+
+fn default_download_write_buffer_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_chunk_size() -> usize {
+    64 * 1024
+}
+
+fn default_max_gossip_entries() -> usize {
+    50
+}
+
+fn default_gossip_fanout() -> usize {
+    3
+}
+
+fn default_max_pipeline_depth() -> usize {
+    16
+}
+
+fn default_prefetch_lookahead() -> usize {
+    4
+}
+
+fn default_max_concurrent_uploads() -> usize {
+    4
+}
+
+fn default_peer_event_buffer() -> usize {
+    256
+}
+
+fn default_re_announce_interval_secs() -> u64 {
+    300
+}
+
+fn default_replication_target_factor() -> usize {
+    2
+}
+
+fn default_repair_interval_secs() -> u64 {
+    600
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Missing required config field: {0}")]
+    MissingField(&'static str),
+
+    #[error("Encryption key must be 64 hex characters (32 bytes)")]
+    InvalidEncryptionKey,
+
+    #[error("Key rotation failed: {0}")]
+    Rotation(String),
+
+    #[error("Failed to update config file: {0}")]
+    Update(String),
+
+    #[error("Failed to load {}: {}", .0.display(), .1)]
+    Load(PathBuf, String),
+
+    #[error("Invalid value for {0}: {1}")]
+    InvalidEnvVar(&'static str, String),
+
+    #[error("Invalid configuration:{}", .0.iter().map(|e| format!("\n  {}", e)).collect::<String>())]
+    Invalid(Vec<ValidationError>),
+}
+
+/// One problem found by [`Config::validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub field: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Port 8080, no bootstrap peers, storage under `/tmp/sharesphere`, an
+/// all-zero encryption key and the `config.yaml` defaults for everything else.
+/// Meant for tests and examples; the key is not secret.
+impl Default for Config {
+    fn default() -> Self {
+        Config::builder()
+            .peer_port(8080)
+            .storage_path("/tmp/sharesphere")
+            .encryption_key("0".repeat(64))
+            .build()
+            .expect("default config is valid")
+    }
 }
 
 impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// [`Config::default`] with `storage_path` replaced.
+    pub fn with_storage_path(path: impl AsRef<Path>) -> Self {
+        let mut config = Config::default();
+        config.storage.storage_path = path.as_ref().to_string_lossy().into_owned();
+        config
+    }
+
+    /// [`Config::default`] with `peer_port` replaced.
+    pub fn with_port(port: u16) -> Self {
+        let mut config = Config::default();
+        config.discovery.peer_port = port;
+        config
+    }
+
+    /// Layers `other` on top of `self`. Scalar fields are taken from `other`;
+    /// optional fields only when `other` sets them. `bootstrap_peers` are
+    /// appended to ours, skipping peers we already list.
+    pub fn merge(&self, other: &Config) -> Config {
+        let mut bootstrap_peers = self.discovery.bootstrap_peers.clone();
+        for peer in &other.discovery.bootstrap_peers {
+            if !bootstrap_peers.contains(peer) {
+                bootstrap_peers.push(peer.clone());
+            }
+        }
+        Config {
+            discovery: PeerDiscoveryConfig { bootstrap_peers, ..other.discovery.clone() },
+            storage: StorageConfig {
+                storage_quota_bytes: other.storage.storage_quota_bytes.or(self.storage.storage_quota_bytes),
+                ..other.storage.clone()
+            },
+            transfer: other.transfer.clone(),
+            encryption_key: other.encryption_key.clone(),
+            socks5_proxy: other.socks5_proxy.clone().or_else(|| self.socks5_proxy.clone()),
+            otlp_endpoint: other.otlp_endpoint.clone().or_else(|| self.otlp_endpoint.clone()),
+            max_gossip_entries: other.max_gossip_entries,
+            gossip_fanout: other.gossip_fanout,
+            http_port: other.http_port.or(self.http_port),
+            peer_event_buffer: other.peer_event_buffer,
+            re_announce_interval_secs: other.re_announce_interval_secs,
+            node_id: other.node_id.clone().or_else(|| self.node_id.clone()),
+            node_key: other.node_key.clone().or_else(|| self.node_key.clone()),
+        }
+    }
+
+    /// Merges `layers` from first to last, e.g. a system-wide config followed
+    /// by a user's, so the last layer wins for scalar fields.
+    ///
+    /// # Panics
+    ///
+    /// If `layers` is empty.
+    pub fn from_layers(layers: &[Config]) -> Config {
+        let (first, rest) = layers.split_first().expect("at least one config layer is required");
+        rest.iter().fold(first.clone(), |merged, layer| merged.merge(layer))
+    }
+
+    /// Reads and validates a YAML config file. Every problem [`Config::validate`]
+    /// finds is reported in the error, not just the first.
     pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self, Box<dyn Error>> {
         let contents = fs::read_to_string(path)?;
         let config: Config = serde_yaml::from_str(&contents)?;
+        config.validate().map_err(ConfigError::Invalid)?;
+        Ok(config)
+    }
+
+    /// Replaces fields with the `SHARESPHERE_PEER_PORT`, `SHARESPHERE_STORAGE_PATH`,
+    /// `SHARESPHERE_ENCRYPTION_KEY` and `SHARESPHERE_BOOTSTRAP_PEERS` (comma
+    /// separated) environment variables that are set.
+    pub fn apply_env_overrides(&mut self) -> Result<(), ConfigError> {
+        self.apply_overrides(|name| std::env::var(name).ok())
+    }
+
+    fn apply_overrides(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        if let Some(port) = var("SHARESPHERE_PEER_PORT") {
+            self.discovery.peer_port = port.parse().map_err(|_| ConfigError::InvalidEnvVar("SHARESPHERE_PEER_PORT", port))?;
+        }
+        if let Some(path) = var("SHARESPHERE_STORAGE_PATH") {
+            self.storage.storage_path = path;
+        }
+        if let Some(key) = var("SHARESPHERE_ENCRYPTION_KEY") {
+            self.encryption_key = key.into();
+        }
+        if let Some(peers) = var("SHARESPHERE_BOOTSTRAP_PEERS") {
+            self.discovery.bootstrap_peers = peers
+                .split(',')
+                .map(str::trim)
+                .filter(|peer| !peer.is_empty())
+                .map(|peer| peer.parse().map_err(|_| ConfigError::InvalidEnvVar("SHARESPHERE_BOOTSTRAP_PEERS", peer.to_string())))
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(())
+    }
+
+    /// Checks for misconfigurations that would otherwise surface later as
+    /// confusing I/O or connection errors. Creates `storage_path` if it is
+    /// missing, as the node would at startup, to check that it is writable.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+        let mut invalid = |field: &str, message: String| {
+            errors.push(ValidationError { field: field.to_string(), message })
+        };
+
+        if self.discovery.peer_port < 1024 {
+            invalid("peer_port", format!("{} is a privileged port; use 1024 or above", self.discovery.peer_port));
+        }
+        if validate_encryption_key(&self.encryption_key).is_err() {
+            invalid("encryption_key", "must be 64 hex characters (32 bytes)".to_string());
+        }
+        if let Err(e) = check_writable(Path::new(&self.storage.storage_path)) {
+            invalid("storage_path", format!("{} is not writable: {}", self.storage.storage_path, e));
+        }
+        for peer in &self.discovery.bootstrap_peers {
+            let is_ip = peer.address().parse::<std::net::SocketAddr>().is_ok();
+            if !is_ip && !is_valid_hostname(&peer.host) {
+                invalid("bootstrap_peers", format!("{} is neither an IP address nor a host name", peer));
+            }
+        }
+        if let Some(node_id) = &self.node_id {
+            if node_id.is_empty() || hex::decode(node_id).is_err() {
+                invalid("node_id", format!("{} is not a hex node ID", node_id));
+            }
+        }
+        if self.node_key.as_ref().is_some_and(|key| validate_encryption_key(key).is_err()) {
+            invalid("node_key", "must be 64 hex characters (32 bytes)".to_string());
+        }
+        if self.transfer.default_chunk_size == 0 {
+            invalid("default_chunk_size", "must be greater than 0".to_string());
+        }
+        if self.re_announce_interval_secs == 0 {
+            invalid("re_announce_interval_secs", "must be greater than 0".to_string());
+        }
+        if self.discovery.heartbeat_interval_secs == 0 {
+            invalid("heartbeat_interval_secs", "must be greater than 0".to_string());
+        }
+        if self.discovery.heartbeat_timeout_secs <= self.discovery.heartbeat_interval_secs {
+            invalid(
+                "heartbeat_timeout_secs",
+                format!("must be longer than heartbeat_interval_secs ({})", self.discovery.heartbeat_interval_secs),
+            );
+        }
+        if let Some(quota) = self.storage.storage_quota_bytes {
+            if quota <= self.transfer.default_chunk_size as u64 {
+                invalid(
+                    "storage_quota_bytes",
+                    format!("{} bytes does not hold even one {} byte chunk", quota, self.transfer.default_chunk_size),
+                );
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Config files layered from lowest to highest priority: system-wide, then
+/// the user's, then any added with [`MultiConfig::with_path`]. Each file is a
+/// complete config; see [`Config::merge`] for how layers combine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiConfig {
+    layers: Vec<PathBuf>,
+}
+
+impl Default for MultiConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MultiConfig {
+    /// `/etc/sharesphere/config.yaml`, `$HOME/.config/sharesphere/config.yaml`
+    /// and `$XDG_CONFIG_HOME/sharesphere/config.yaml`, in that order.
+    pub fn new() -> Self {
+        let mut layers = vec![PathBuf::from("/etc/sharesphere/config.yaml")];
+        if let Some(home) = std::env::var_os("HOME") {
+            layers.push(Path::new(&home).join(".config/sharesphere/config.yaml"));
+        }
+        if let Some(xdg) = std::env::var_os("XDG_CONFIG_HOME") {
+            let path = Path::new(&xdg).join("sharesphere/config.yaml");
+            if !layers.contains(&path) {
+                layers.push(path);
+            }
+        }
+        MultiConfig { layers }
+    }
+
+    /// Adds `path` on top of the layers so far, e.g. the `--config` flag.
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.layers.push(path.into());
+        self
+    }
+
+    pub fn layers(&self) -> &[PathBuf] {
+        &self.layers
+    }
+
+    /// The last layer that exists, which changes made at runtime are saved
+    /// to; `None` when the config comes from the defaults and the environment.
+    pub fn writable_layer(&self) -> Option<&Path> {
+        self.layers.iter().rev().find(|path| path.is_file()).map(PathBuf::as_path)
+    }
+
+    /// The file `encryption_key` is read from, for `rotate-key` to write the
+    /// new key to. Every layer sets the key, so the last one that exists wins.
+    /// Fails when `SHARESPHERE_ENCRYPTION_KEY` overrides it, as only whoever
+    /// sets the variable can change the key, or when no layer exists.
+    pub fn encryption_key_layer(&self) -> Result<&Path, ConfigError> {
+        self.encryption_key_layer_with(|name| std::env::var(name).ok())
+    }
+
+    fn encryption_key_layer_with(&self, var: impl Fn(&str) -> Option<String>) -> Result<&Path, ConfigError> {
+        if var("SHARESPHERE_ENCRYPTION_KEY").is_some() {
+            return Err(ConfigError::Rotation(
+                "the key is set by SHARESPHERE_ENCRYPTION_KEY; unset it or change it there".to_string(),
+            ));
+        }
+        self.writable_layer()
+            .ok_or_else(|| ConfigError::Rotation("no config file sets encryption_key; the default key is in use".to_string()))
+    }
+
+    /// Merges every layer that exists, or starts from [`Config::default`] if
+    /// none does, then applies [`Config::apply_env_overrides`] and validates
+    /// the result.
+    pub fn load(&self) -> Result<Config, ConfigError> {
+        let config = self.merge_layers()?;
+        let mut config = config.unwrap_or_default();
+        config.apply_env_overrides()?;
+        config.validate().map_err(ConfigError::Invalid)?;
         Ok(config)
     }
+
+    fn merge_layers(&self) -> Result<Option<Config>, ConfigError> {
+        let mut merged: Option<Config> = None;
+        for path in self.layers.iter().filter(|path| path.is_file()) {
+            let load_error = |e: String| ConfigError::Load(path.clone(), e);
+            let contents = fs::read_to_string(path).map_err(|e| load_error(e.to_string()))?;
+            let layer: Config = serde_yaml::from_str(&contents).map_err(|e| load_error(e.to_string()))?;
+            merged = Some(match merged {
+                Some(merged) => merged.merge(&layer),
+                None => layer,
+            });
+        }
+        Ok(merged)
+    }
+}
+
+/// Builds a [`Config`] in code, applying the same defaults as `config.yaml`.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    peer_port: Option<u16>,
+    bootstrap_peers: Vec<PeerUrl>,
+    storage_path: Option<String>,
+    encryption_key: Option<String>,
+    download_write_buffer_bytes: Option<usize>,
+    default_chunk_size: Option<usize>,
+    socks5_proxy: Option<String>,
+    max_concurrent_uploads: Option<usize>,
+    otlp_endpoint: Option<String>,
+    replication_policy: Option<ReplicationPolicy>,
+    max_incoming_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    max_gossip_entries: Option<usize>,
+    gossip_fanout: Option<usize>,
+    max_pipeline_depth: Option<usize>,
+    prefetch_lookahead: Option<usize>,
+    http_port: Option<u16>,
+    storage_quota_bytes: Option<u64>,
+    peer_event_buffer: Option<usize>,
+    re_announce_interval_secs: Option<u64>,
+    node_id: Option<String>,
+    node_key: Option<String>,
+    heartbeat_interval_secs: Option<u64>,
+    heartbeat_timeout_secs: Option<u64>,
+    lan_beacon_addr: Option<Option<SocketAddr>>,
+    replication_target_factor: Option<usize>,
+    repair_interval_secs: Option<u64>,
+}
+
+impl ConfigBuilder {
+    pub fn peer_port(mut self, peer_port: u16) -> ConfigBuilder {
+        self.peer_port = Some(peer_port);
+        self
+    }
+
+    pub fn bootstrap_peers(mut self, bootstrap_peers: Vec<PeerUrl>) -> ConfigBuilder {
+        self.bootstrap_peers = bootstrap_peers;
+        self
+    }
+
+    pub fn storage_path(mut self, storage_path: impl Into<String>) -> ConfigBuilder {
+        self.storage_path = Some(storage_path.into());
+        self
+    }
+
+    pub fn encryption_key(mut self, encryption_key: impl Into<String>) -> ConfigBuilder {
+        self.encryption_key = Some(encryption_key.into());
+        self
+    }
+
+    pub fn download_write_buffer_bytes(mut self, bytes: usize) -> ConfigBuilder {
+        self.download_write_buffer_bytes = Some(bytes);
+        self
+    }
+
+    pub fn default_chunk_size(mut self, chunk_size: usize) -> ConfigBuilder {
+        self.default_chunk_size = Some(chunk_size);
+        self
+    }
+
+    pub fn socks5_proxy(mut self, proxy: impl Into<String>) -> ConfigBuilder {
+        self.socks5_proxy = Some(proxy.into());
+        self
+    }
+
+    pub fn max_concurrent_uploads(mut self, max_concurrent_uploads: usize) -> ConfigBuilder {
+        self.max_concurrent_uploads = Some(max_concurrent_uploads);
+        self
+    }
+
+    pub fn otlp_endpoint(mut self, endpoint: impl Into<String>) -> ConfigBuilder {
+        self.otlp_endpoint = Some(endpoint.into());
+        self
+    }
+
+    pub fn replication_policy(mut self, policy: ReplicationPolicy) -> ConfigBuilder {
+        self.replication_policy = Some(policy);
+        self
+    }
+
+    pub fn max_incoming_connections(mut self, max_incoming_connections: usize) -> ConfigBuilder {
+        self.max_incoming_connections = Some(max_incoming_connections);
+        self
+    }
+
+    pub fn max_connections_per_ip(mut self, max_connections_per_ip: usize) -> ConfigBuilder {
+        self.max_connections_per_ip = Some(max_connections_per_ip);
+        self
+    }
+
+    pub fn max_gossip_entries(mut self, max_gossip_entries: usize) -> ConfigBuilder {
+        self.max_gossip_entries = Some(max_gossip_entries);
+        self
+    }
+
+    pub fn gossip_fanout(mut self, gossip_fanout: usize) -> ConfigBuilder {
+        self.gossip_fanout = Some(gossip_fanout);
+        self
+    }
+
+    pub fn max_pipeline_depth(mut self, max_pipeline_depth: usize) -> ConfigBuilder {
+        self.max_pipeline_depth = Some(max_pipeline_depth);
+        self
+    }
+
+    pub fn prefetch_lookahead(mut self, prefetch_lookahead: usize) -> ConfigBuilder {
+        self.prefetch_lookahead = Some(prefetch_lookahead);
+        self
+    }
+
+    pub fn http_port(mut self, http_port: u16) -> ConfigBuilder {
+        self.http_port = Some(http_port);
+        self
+    }
+
+    pub fn storage_quota_bytes(mut self, bytes: u64) -> ConfigBuilder {
+        self.storage_quota_bytes = Some(bytes);
+        self
+    }
+
+    pub fn peer_event_buffer(mut self, peer_event_buffer: usize) -> ConfigBuilder {
+        self.peer_event_buffer = Some(peer_event_buffer);
+        self
+    }
+
+    pub fn re_announce_interval_secs(mut self, re_announce_interval_secs: u64) -> ConfigBuilder {
+        self.re_announce_interval_secs = Some(re_announce_interval_secs);
+        self
+    }
+
+    pub fn node_id(mut self, node_id: String) -> ConfigBuilder {
+        self.node_id = Some(node_id);
+        self
+    }
+
+    pub fn node_key(mut self, node_key: impl Into<String>) -> ConfigBuilder {
+        self.node_key = Some(node_key.into());
+        self
+    }
+
+    pub fn heartbeat_interval_secs(mut self, heartbeat_interval_secs: u64) -> ConfigBuilder {
+        self.heartbeat_interval_secs = Some(heartbeat_interval_secs);
+        self
+    }
+
+    pub fn heartbeat_timeout_secs(mut self, heartbeat_timeout_secs: u64) -> ConfigBuilder {
+        self.heartbeat_timeout_secs = Some(heartbeat_timeout_secs);
+        self
+    }
+
+    /// `None` turns LAN discovery off.
+    pub fn lan_beacon_addr(mut self, lan_beacon_addr: Option<SocketAddr>) -> ConfigBuilder {
+        self.lan_beacon_addr = Some(lan_beacon_addr);
+        self
+    }
+
+    pub fn replication_target_factor(mut self, replication_target_factor: usize) -> ConfigBuilder {
+        self.replication_target_factor = Some(replication_target_factor);
+        self
+    }
+
+    pub fn repair_interval_secs(mut self, repair_interval_secs: u64) -> ConfigBuilder {
+        self.repair_interval_secs = Some(repair_interval_secs);
+        self
+    }
+
+    pub fn build(self) -> Result<Config, ConfigError> {
+        let encryption_key = self.encryption_key.ok_or(ConfigError::MissingField("encryption_key"))?;
+        validate_encryption_key(&encryption_key)?;
+        Ok(Config {
+            discovery: PeerDiscoveryConfig {
+                peer_port: self.peer_port.ok_or(ConfigError::MissingField("peer_port"))?,
+                bootstrap_peers: self.bootstrap_peers,
+                max_incoming_connections: self
+                    .max_incoming_connections
+                    .unwrap_or_else(default_max_incoming_connections),
+                max_connections_per_ip: self
+                    .max_connections_per_ip
+                    .unwrap_or_else(default_max_connections_per_ip),
+                heartbeat_interval_secs: self.heartbeat_interval_secs.unwrap_or_else(default_heartbeat_interval_secs),
+                heartbeat_timeout_secs: self.heartbeat_timeout_secs.unwrap_or_else(default_heartbeat_timeout_secs),
+                lan_beacon_addr: self.lan_beacon_addr.unwrap_or_else(default_lan_beacon_addr),
+            },
+            storage: StorageConfig {
+                storage_path: self.storage_path.ok_or(ConfigError::MissingField("storage_path"))?,
+                storage_quota_bytes: self.storage_quota_bytes,
+                replication_target_factor: self.replication_target_factor.unwrap_or_else(default_replication_target_factor),
+                repair_interval_secs: self.repair_interval_secs.unwrap_or_else(default_repair_interval_secs),
+            },
+            transfer: TransferConfig {
+                default_chunk_size: self.default_chunk_size.unwrap_or_else(default_chunk_size),
+                download_write_buffer_bytes: self
+                    .download_write_buffer_bytes
+                    .unwrap_or_else(default_download_write_buffer_bytes),
+                max_concurrent_uploads: self
+                    .max_concurrent_uploads
+                    .unwrap_or_else(default_max_concurrent_uploads),
+                replication_policy: self.replication_policy.unwrap_or_default(),
+                max_pipeline_depth: self.max_pipeline_depth.unwrap_or_else(default_max_pipeline_depth),
+                prefetch_lookahead: self.prefetch_lookahead.unwrap_or_else(default_prefetch_lookahead),
+            },
+            encryption_key: SecretField::new(encryption_key),
+            socks5_proxy: self.socks5_proxy,
+            otlp_endpoint: self.otlp_endpoint,
+            max_gossip_entries: self.max_gossip_entries.unwrap_or_else(default_max_gossip_entries),
+            gossip_fanout: self.gossip_fanout.unwrap_or_else(default_gossip_fanout),
+            http_port: self.http_port,
+            peer_event_buffer: self.peer_event_buffer.unwrap_or_else(default_peer_event_buffer),
+            re_announce_interval_secs: self.re_announce_interval_secs.unwrap_or_else(default_re_announce_interval_secs),
+            node_id: self.node_id,
+            node_key: self.node_key.map(SecretField::new),
+        })
+    }
+}
+
+fn validate_encryption_key(key: &str) -> Result<(), ConfigError> {
+    if key.len() != 64 || hex::decode(key).is_err() {
+        return Err(ConfigError::InvalidEncryptionKey);
+    }
+    Ok(())
+}
+
+/// Creates `dir` if needed and writes and removes a probe file in it.
+fn check_writable(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".write_probe_{}", std::process::id()));
+    fs::write(&probe, b"")?;
+    fs::remove_file(probe)
+}
+
+/// DNS host names: dot-separated labels of ASCII letters, digits and inner hyphens.
+fn is_valid_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Outcome of [`rotate_encryption_key`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RotationReport {
+    pub rotated: Vec<Uuid>,
+    /// Files whose key could not be unwrapped with the old key, with the reason.
+    pub failed: Vec<(Uuid, String)>,
+    /// Files without a manifest or without a key of their own.
+    pub skipped: Vec<Uuid>,
+}
+
+/// Re-wraps every stored file's key with `new_key` and writes `new_key` into
+/// the config file at `config_path`.
+///
+/// Nothing is changed unless every key can be unwrapped with the current
+/// `config.encryption_key`: if any cannot, the report lists them as failed and
+/// `rotated` is empty. A failure while writing restores the manifests already
+/// rewritten and returns an error.
+pub async fn rotate_encryption_key(
+    config: &Config,
+    new_key: &str,
+    storage_root: &Path,
+    config_path: &Path,
+) -> Result<RotationReport, ConfigError> {
+    validate_encryption_key(new_key)?;
+    let rotation_error = |e: &dyn std::fmt::Display| ConfigError::Rotation(e.to_string());
+    let nonces = NonceTracker::new();
+
+    let mut report = RotationReport::default();
+    let mut rewrapped: Vec<FileManifest> = Vec::new();
+    for file_id in list_all_files(storage_root).map_err(|e| rotation_error(&e))? {
+        let storage_dir = storage_root.join(file_id.to_string());
+        let Ok(mut manifest) = load_manifest(&storage_dir) else {
+            report.skipped.push(file_id);
+            continue;
+        };
+        let Some(wrapped) = &manifest.wrapped_key else {
+            report.skipped.push(file_id);
+            continue;
+        };
+        let Some((nonce, ciphertext)) = wrapped.split_once(':') else {
+            report.failed.push((file_id, "malformed wrapped key".to_string()));
+            continue;
+        };
+        let file_key = match decrypt(nonce, ciphertext, &config.encryption_key) {
+            Ok(file_key) => file_key,
+            Err(e) => {
+                report.failed.push((file_id, e.to_string()));
+                continue;
+            }
+        };
+        let (nonce, ciphertext) = encrypt(&file_key, new_key, &nonces).map_err(|e| rotation_error(&e))?;
+        manifest.wrapped_key = Some(format!("{}:{}", nonce, ciphertext));
+        rewrapped.push(manifest);
+    }
+    if !report.failed.is_empty() {
+        return Ok(report);
+    }
+
+    let original_config = fs::read_to_string(config_path).map_err(|e| rotation_error(&e))?;
+    let updated_config = replace_encryption_key(&original_config, new_key)
+        .ok_or_else(|| ConfigError::Rotation(format!("no encryption_key line in {}", config_path.display())))?;
+
+    // Original manifest contents, for rolling back.
+    let mut written: Vec<(std::path::PathBuf, String)> = Vec::new();
+    let result = (|| -> Result<(), ConfigError> {
+        for manifest in &rewrapped {
+            let storage_dir = storage_root.join(manifest.file_id.to_string());
+            let original = fs::read_to_string(storage_dir.join(MANIFEST_FILENAME)).map_err(|e| rotation_error(&e))?;
+            save_manifest(&storage_dir, manifest).map_err(|e| rotation_error(&e))?;
+            written.push((storage_dir.join(MANIFEST_FILENAME), original));
+        }
+        json::replace_file(config_path, &updated_config).map_err(|e| rotation_error(&e))
+    })();
+    if let Err(e) = result {
+        for (path, original) in written.iter().rev() {
+            if let Err(restore) = fs::write(path, original) {
+                log::error!("Failed to restore {} after key rotation failed: {}", path.display(), restore);
+            }
+        }
+        return Err(e);
+    }
+
+    report.rotated = rewrapped.iter().map(|m| m.file_id).collect();
+    Ok(report)
+}
+
+/// The `bootstrap_peers` of the config file at `config_path` alone, without
+/// those of other layers or `SHARESPHERE_BOOTSTRAP_PEERS`.
+pub fn load_bootstrap_peers(config_path: &Path) -> Result<Vec<PeerUrl>, ConfigError> {
+    #[derive(Deserialize)]
+    struct Layer {
+        #[serde(default)]
+        bootstrap_peers: Vec<PeerUrl>,
+    }
+    let load_error = |e: String| ConfigError::Load(config_path.to_path_buf(), e);
+    let contents = fs::read_to_string(config_path).map_err(|e| load_error(e.to_string()))?;
+    let layer: Layer = serde_yaml::from_str(&contents).map_err(|e| load_error(e.to_string()))?;
+    Ok(layer.bootstrap_peers)
+}
+
+/// Applies `update` to the `bootstrap_peers` of the config file at
+/// `config_path` alone and saves them if they changed, so peers from other
+/// layers or the environment are not copied into it. Returns whether they changed.
+pub fn update_bootstrap_peers(config_path: &Path, update: impl FnOnce(&mut Vec<PeerUrl>)) -> Result<bool, ConfigError> {
+    let mut peers = load_bootstrap_peers(config_path)?;
+    let original = peers.clone();
+    update(&mut peers);
+    if peers == original {
+        return Ok(false);
+    }
+    save_bootstrap_peers(config_path, &peers)?;
+    Ok(true)
+}
+
+/// Rewrites the `bootstrap_peers` list of the config file at `config_path`,
+/// leaving every other line untouched.
+pub fn save_bootstrap_peers(config_path: &Path, peers: &[PeerUrl]) -> Result<(), ConfigError> {
+    let update_error = |e: &dyn fmt::Display| ConfigError::Update(format!("{}: {}", config_path.display(), e));
+    let original = fs::read_to_string(config_path).map_err(|e| update_error(&e))?;
+    json::replace_file(config_path, &replace_bootstrap_peers(&original, peers)).map_err(|e| update_error(&e))
+}
+
+/// Replaces the top-level `bootstrap_peers:` key and the list items below
+/// it, or appends the key when the file has none.
+fn replace_bootstrap_peers(yaml: &str, peers: &[PeerUrl]) -> String {
+    let block: Vec<String> = if peers.is_empty() {
+        vec!["bootstrap_peers: []".to_string()]
+    } else {
+        std::iter::once("bootstrap_peers:".to_string())
+            .chain(peers.iter().map(|peer| format!("  - \"{}\"", peer)))
+            .collect()
+    };
+    let mut lines = Vec::new();
+    let mut replaced = false;
+    let mut in_list = false;
+    for line in yaml.lines() {
+        if in_list && line.starts_with([' ', '\t', '-']) {
+            continue;
+        }
+        in_list = false;
+        if !replaced && line.starts_with("bootstrap_peers:") {
+            lines.extend(block.iter().cloned());
+            replaced = true;
+            in_list = true;
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+    if !replaced {
+        lines.extend(block);
+    }
+    let mut updated = lines.join("\n");
+    if yaml.ends_with('\n') || !replaced {
+        updated.push('\n');
+    }
+    updated
+}
+
+/// Swaps the value of the top-level `encryption_key:` line, leaving the rest
+/// of the file, comments included, untouched.
+fn replace_encryption_key(yaml: &str, new_key: &str) -> Option<String> {
+    let mut found = false;
+    let lines: Vec<String> = yaml
+        .lines()
+        .map(|line| {
+            if !found && line.starts_with("encryption_key:") {
+                found = true;
+                format!("encryption_key: \"{}\"", new_key)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    let mut updated = lines.join("\n");
+    if yaml.ends_with('\n') {
+        updated.push('\n');
+    }
+    found.then_some(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_builder_requires_fields_and_applies_defaults() {
+        let key = "ab".repeat(32);
+        let config = Config::builder()
+            .peer_port(9000)
+            .storage_path("/tmp/store")
+            .encryption_key(key.clone())
+            .build()
+            .unwrap();
+        assert_eq!(*config.encryption_key, key);
+        // The key never shows up in debug output.
+        assert!(!format!("{:?}", config).contains(&key));
+        assert!(format!("{:?}", config).contains("encryption_key: [REDACTED]"));
+        assert_eq!(config.encryption_key.to_string(), "[REDACTED]");
+        assert!(config.discovery.bootstrap_peers.is_empty());
+        assert_eq!(config.transfer.download_write_buffer_bytes, default_download_write_buffer_bytes());
+        assert_eq!(config.transfer.default_chunk_size, default_chunk_size());
+        assert_eq!(config.socks5_proxy, None);
+        assert_eq!(config.transfer.max_concurrent_uploads, default_max_concurrent_uploads());
+        assert_eq!(config.otlp_endpoint, None);
+        assert_eq!(config.transfer.replication_policy, ReplicationPolicy::LatencyBased);
+        assert_eq!(config.discovery.max_incoming_connections, default_max_incoming_connections());
+        assert_eq!(config.discovery.max_connections_per_ip, default_max_connections_per_ip());
+        assert_eq!(config.max_gossip_entries, default_max_gossip_entries());
+        assert_eq!(config.gossip_fanout, default_gossip_fanout());
+        assert_eq!(config.transfer.max_pipeline_depth, default_max_pipeline_depth());
+        assert_eq!(config.transfer.prefetch_lookahead, default_prefetch_lookahead());
+        assert_eq!(config.http_port, None);
+        assert_eq!(config.storage.storage_quota_bytes, None);
+        assert_eq!(config.peer_event_buffer, default_peer_event_buffer());
+        assert_eq!(config.re_announce_interval_secs, default_re_announce_interval_secs());
+        assert_eq!(config.node_id, None);
+        assert_eq!(config.discovery.heartbeat_interval_secs, default_heartbeat_interval_secs());
+        assert_eq!(config.discovery.heartbeat_timeout_secs, default_heartbeat_timeout_secs());
+        assert_eq!(config.discovery.lan_beacon_addr, default_lan_beacon_addr());
+        assert_eq!(config.storage.replication_target_factor, default_replication_target_factor());
+        assert_eq!(config.storage.repair_interval_secs, default_repair_interval_secs());
+
+        assert!(matches!(
+            Config::builder().storage_path("/tmp/store").encryption_key(key).build(),
+            Err(ConfigError::MissingField("peer_port"))
+        ));
+        assert!(matches!(
+            Config::builder().peer_port(9000).storage_path("/tmp/store").encryption_key("xyz").build(),
+            Err(ConfigError::InvalidEncryptionKey)
+        ));
+    }
+
+    #[test]
+    fn test_default_and_single_field_helpers() {
+        let config = Config::default();
+        assert_eq!((config.discovery.peer_port, config.storage.storage_path.as_str()), (8080, "/tmp/sharesphere"));
+        assert_eq!(validate_encryption_key(&config.encryption_key).ok(), Some(()));
+        assert_eq!(config.transfer.default_chunk_size, default_chunk_size());
+
+        let moved = Config::with_storage_path(Path::new("/srv/store"));
+        let storage = StorageConfig { storage_path: "/srv/store".to_string(), ..Config::default().storage };
+        assert_eq!(moved, Config { storage, ..Config::default() });
+        let discovery = PeerDiscoveryConfig { peer_port: 9000, ..Config::default().discovery };
+        assert_eq!(Config::with_port(9000), Config { discovery, ..Config::default() });
+    }
+
+    #[test]
+    fn test_merge_and_from_layers() {
+        let base = Config::builder()
+            .peer_port(9000)
+            .storage_path("/srv/store")
+            .encryption_key("ab".repeat(32))
+            .bootstrap_peers(vec!["10.0.0.1:8080".parse().unwrap(), "10.0.0.2:8080".parse().unwrap()])
+            .otlp_endpoint("http://collector:4318")
+            .build()
+            .unwrap();
+        let user = Config::builder()
+            .peer_port(9100)
+            .storage_path("/home/me/store")
+            .encryption_key("cd".repeat(32))
+            .bootstrap_peers(vec!["10.0.0.2:8080".parse().unwrap(), "10.0.0.3:8080".parse().unwrap()])
+            .http_port(8088)
+            .build()
+            .unwrap();
+
+        let merged = base.merge(&user);
+        assert_eq!(merged.discovery.peer_port, 9100);
+        assert_eq!(merged.storage.storage_path, "/home/me/store");
+        assert_eq!(*merged.encryption_key, "cd".repeat(32));
+        let peers: Vec<String> = merged.discovery.bootstrap_peers.iter().map(|p| p.address()).collect();
+        assert_eq!(peers, vec!["10.0.0.1:8080", "10.0.0.2:8080", "10.0.0.3:8080"]);
+        assert_eq!(merged.otlp_endpoint.as_deref(), Some("http://collector:4318"));
+        assert_eq!(merged.http_port, Some(8088));
+
+        let layered = Config::from_layers(&[base.clone(), user, base]);
+        assert_eq!(layered.discovery.peer_port, 9000);
+        assert_eq!(layered.discovery.bootstrap_peers.len(), 3);
+        assert_eq!(layered.http_port, Some(8088));
+    }
+
+    #[test]
+    fn test_multi_config_layers_and_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = dir.path().to_str().unwrap();
+        let system = dir.path().join("system.yaml");
+        let local = dir.path().join("local.yaml");
+        let key = "ab".repeat(32);
+        fs::write(
+            &system,
+            format!("peer_port: 9000\nbootstrap_peers: [\"10.0.0.1:8080\"]\nstorage_path: \"{storage}\"\nencryption_key: \"{key}\"\nhttp_port: 8088\n"),
+        )
+        .unwrap();
+        fs::write(
+            &local,
+            format!("peer_port: 9100\nbootstrap_peers: [\"10.0.0.2:8080\"]\nstorage_path: \"{storage}\"\nencryption_key: \"{key}\"\nmax_pipeline_depth: 8\nheartbeat_timeout_secs: 120\n"),
+        )
+        .unwrap();
+
+        let layers = MultiConfig { layers: vec![system, dir.path().join("missing.yaml"), local] };
+        let mut config = layers.merge_layers().unwrap().unwrap();
+        assert_eq!(config.discovery.peer_port, 9100);
+        assert_eq!(config.discovery.bootstrap_peers.len(), 2);
+        assert_eq!(config.http_port, Some(8088));
+        // The flat keys land in their sections.
+        assert_eq!((config.transfer.max_pipeline_depth, config.discovery.heartbeat_timeout_secs), (8, 120));
+        assert_eq!(config.storage.storage_path, storage);
+
+        let vars = HashMap::from([("SHARESPHERE_PEER_PORT", "9200"), ("SHARESPHERE_BOOTSTRAP_PEERS", "10.0.0.3:8080, 10.0.0.4:8080")]);
+        config.apply_overrides(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(config.discovery.peer_port, 9200);
+        let peers: Vec<String> = config.discovery.bootstrap_peers.iter().map(|p| p.address()).collect();
+        assert_eq!(peers, vec!["10.0.0.3:8080", "10.0.0.4:8080"]);
+        assert!(matches!(
+            config.apply_overrides(|name| (name == "SHARESPHERE_PEER_PORT").then(|| "port".to_string())),
+            Err(ConfigError::InvalidEnvVar("SHARESPHERE_PEER_PORT", _))
+        ));
+
+        let none = MultiConfig { layers: vec![dir.path().join("missing.yaml")] };
+        assert_eq!(none.merge_layers().unwrap(), None);
+        assert!(MultiConfig::new().with_path("config.yaml").layers().ends_with(&[PathBuf::from("config.yaml")]));
+    }
+
+
+    #[test]
+    fn test_runtime_changes_go_to_the_top_layer() {
+        let dir = tempfile::tempdir().unwrap();
+        let (system, local) = (dir.path().join("system.yaml"), dir.path().join("local.yaml"));
+        fs::write(&system, "peer_port: 9000\nbootstrap_peers: [\"10.0.0.1:8080\"]\nencryption_key: \"00\"\n").unwrap();
+        let layers = MultiConfig { layers: vec![system.clone(), local.clone()] };
+        assert_eq!(layers.writable_layer(), Some(system.as_path()));
+        assert_eq!(MultiConfig { layers: vec![local.clone()] }.writable_layer(), None);
+
+        fs::write(&local, "peer_port: 9100\nencryption_key: \"00\"\n").unwrap();
+        assert_eq!(layers.writable_layer(), Some(local.as_path()));
+        assert_eq!(layers.encryption_key_layer_with(|_| None).unwrap(), local.as_path());
+        let env_key = |name: &str| (name == "SHARESPHERE_ENCRYPTION_KEY").then(|| "ab".repeat(32));
+        assert!(matches!(layers.encryption_key_layer_with(env_key), Err(ConfigError::Rotation(_))));
+
+        let added: PeerUrl = "10.0.0.2:8080".parse().unwrap();
+        assert!(update_bootstrap_peers(&local, |peers| peers.push(added.clone())).unwrap());
+        assert_eq!(load_bootstrap_peers(&local).unwrap(), vec![added.clone()]);
+        // The system layer's peer is not in this file, so removing it changes nothing here.
+        assert!(!update_bootstrap_peers(&local, |peers| peers.retain(|p| p.address() != "10.0.0.1:8080")).unwrap());
+        assert_eq!(load_bootstrap_peers(&local).unwrap(), vec![added]);
+        assert_eq!(load_bootstrap_peers(&system).unwrap().len(), 1);
+        assert!(load_bootstrap_peers(&dir.path().join("missing.yaml")).is_err());
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage = temp_dir.path().join("storage");
+        let valid = Config::builder()
+            .peer_port(8080)
+            .storage_path(storage.to_str().unwrap())
+            .encryption_key("ab".repeat(32))
+            .bootstrap_peers(vec!["sharesphere://node-1.example:8081".parse().unwrap(), "[::1]:8082".parse().unwrap()])
+            .build()
+            .unwrap();
+        assert_eq!(valid.validate(), Ok(()));
+        assert!(storage.is_dir());
+
+        let blocker = temp_dir.path().join("file");
+        fs::write(&blocker, b"").unwrap();
+        let mut config = valid.clone();
+        config.discovery.peer_port = 80;
+        config.encryption_key = "xyz".to_string().into();
+        config.storage.storage_path = blocker.join("storage").to_str().unwrap().to_string();
+        config.discovery.bootstrap_peers.push("bad_host!:8080".parse().unwrap());
+        config.storage.storage_quota_bytes = Some(1024);
+        config.transfer.default_chunk_size = 4096;
+
+        let fields: Vec<String> = config.validate().unwrap_err().into_iter().map(|e| e.field).collect();
+        assert_eq!(fields, ["peer_port", "encryption_key", "storage_path", "bootstrap_peers", "storage_quota_bytes"]);
+
+        let path = temp_dir.path().join("config.yaml");
+        fs::write(&path, format!("peer_port: 80\nbootstrap_peers: []\nstorage_path: \"{}\"\nencryption_key: \"xyz\"\n", storage.display())).unwrap();
+        let message = Config::load(&path).unwrap_err().to_string();
+        assert!(message.contains("\n  peer_port: ") && message.contains("\n  encryption_key: "), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_rotate_encryption_key_rewraps_file_keys() {
+        let old_key = "ab".repeat(32);
+        let new_key = "cd".repeat(32);
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage_root = temp_dir.path().join("storage");
+        let config_path = temp_dir.path().join("config.yaml");
+        fs::write(
+            &config_path,
+            format!("peer_port: 8080\nbootstrap_peers: []\nstorage_path: \"{}\"\n# master key\nencryption_key: \"{}\"\n", storage_root.display(), old_key),
+        )
+        .unwrap();
+        let config = Config::load(&config_path).unwrap();
+
+        let store = |wrapped_key: Option<String>| {
+            let file_id = Uuid::new_v4();
+            let storage_dir = crate::file_manager::storage::initialize_storage(&storage_root, file_id).unwrap();
+            let mut manifest = FileManifest::new(file_id, "f".to_string(), 1, 1, 1);
+            manifest.wrapped_key = wrapped_key;
+            save_manifest(&storage_dir, &manifest).unwrap();
+            file_id
+        };
+        let wrap = |key: &str| {
+            let (nonce, ciphertext) = encrypt(b"file key", key, &NonceTracker::new()).unwrap();
+            format!("{}:{}", nonce, ciphertext)
+        };
+        let keyed = store(Some(wrap(&old_key)));
+        let unkeyed = store(None);
+
+        // A key wrapped with some other key blocks the whole rotation.
+        let foreign = store(Some(wrap(&"ef".repeat(32))));
+        let report = rotate_encryption_key(&config, &new_key, &storage_root, &config_path).await.unwrap();
+        assert!(report.rotated.is_empty());
+        assert_eq!(report.failed.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![foreign]);
+        assert_eq!(*Config::load(&config_path).unwrap().encryption_key, old_key);
+        fs::remove_dir_all(storage_root.join(foreign.to_string())).unwrap();
+
+        let report = rotate_encryption_key(&config, &new_key, &storage_root, &config_path).await.unwrap();
+        assert_eq!(report.rotated, vec![keyed]);
+        assert_eq!(report.skipped, vec![unkeyed]);
+        let contents = fs::read_to_string(&config_path).unwrap();
+        assert!(contents.contains("# master key\n"));
+        assert_eq!(*Config::load(&config_path).unwrap().encryption_key, new_key);
+
+        let manifest = load_manifest(storage_root.join(keyed.to_string())).unwrap();
+        let (nonce, ciphertext) = manifest.wrapped_key.as_deref().unwrap().split_once(':').unwrap();
+        assert_eq!(decrypt(nonce, ciphertext, &new_key).unwrap(), b"file key");
+        assert!(matches!(
+            rotate_encryption_key(&config, "short", &storage_root, &config_path).await,
+            Err(ConfigError::InvalidEncryptionKey)
+        ));
+    }
+
+    #[test]
+    fn test_replace_bootstrap_peers_keeps_other_lines() {
+        let yaml = "peer_port: 8080\nbootstrap_peers:\n  - \"10.0.0.1:8080\"\n  - \"10.0.0.2:8080\"\n# storage\nstorage_path: \"/tmp\"\n";
+        let peers: Vec<PeerUrl> = vec!["10.0.0.3:8080".parse().unwrap()];
+        assert_eq!(
+            replace_bootstrap_peers(yaml, &peers),
+            "peer_port: 8080\nbootstrap_peers:\n  - \"sharesphere://10.0.0.3:8080\"\n# storage\nstorage_path: \"/tmp\"\n"
+        );
+        assert_eq!(replace_bootstrap_peers("bootstrap_peers: []\nx: 1", &[]), "bootstrap_peers: []\nx: 1");
+        assert_eq!(replace_bootstrap_peers("x: 1\n", &peers), "x: 1\nbootstrap_peers:\n  - \"sharesphere://10.0.0.3:8080\"\n");
+    }
 }
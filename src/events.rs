@@ -0,0 +1,39 @@
+// src/events.rs
+
+//! Progress of uploads and downloads as [`TelemetryEvent`]s on a broadcast
+//! channel, so a front end can show transfers without being wired into the
+//! code that runs them. Events are dropped when nobody subscribes, and a
+//! subscriber that falls behind misses the oldest ones.
+
+use std::time::Duration;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// Events buffered for each subscriber before the oldest are dropped.
+pub const TELEMETRY_EVENT_BUFFER: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TelemetryEvent {
+    UploadStarted { file_id: Uuid, path: String, total_chunks: usize },
+    /// A chunk is stored locally and was handed to its replicas.
+    ChunkUploaded { file_id: Uuid, chunk_index: usize },
+    UploadFinished { file_id: Uuid, bytes: u64, duration: Duration },
+    DownloadStarted { file_id: Uuid },
+    /// An interrupted download continues with `fetched` of its chunks already stored.
+    DownloadResumed { file_id: Uuid, fetched: usize, total_chunks: usize },
+    ChunkDownloaded { file_id: Uuid, chunk_index: usize, from_peer: String },
+    DownloadFinished { file_id: Uuid, bytes: u64, duration: Duration },
+    /// An operation failed; `context` says which, e.g. `upload notes.txt`.
+    Error { context: String, message: String },
+}
+
+pub type TelemetrySender = broadcast::Sender<TelemetryEvent>;
+
+pub fn channel() -> TelemetrySender {
+    broadcast::channel(TELEMETRY_EVENT_BUFFER).0
+}
+
+/// Sends `event` to every current subscriber, if any.
+pub fn emit(events: &TelemetrySender, event: TelemetryEvent) {
+    let _ = events.send(event);
+}
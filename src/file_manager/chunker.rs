@@ -1,64 +1,261 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+#[cfg(not(feature = "wasm"))]
+use crate::file_manager::manifest::FileManifest;
+use crate::json::{self, JsonError};
+#[cfg(not(feature = "wasm"))]
+use crate::file_manager::storage::{get_chunk, SparseWriter, StorageError};
+#[cfg(not(feature = "wasm"))]
 use std::fs::File;
 use std::io::{self, Read};
+#[cfg(not(feature = "wasm"))]
 use std::path::Path;
 use uuid::Uuid;
 
-#[derive(Debug, Clone)]
+/// Smallest size [`optimal_chunk_size`] picks; smaller chunks cost more in
+/// per-chunk overhead than they gain in parallelism.
+pub const MIN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest chunk size, which is also the largest `--chunk-size` accepted.
+pub const MAX_CHUNK_SIZE: usize = 128 * 1024 * 1024;
+
+/// Chunks per peer aimed for by [`optimal_chunk_size_for_peers`].
+const CHUNKS_PER_PEER: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ChunkMetadata {
     pub file_id: Uuid,        // Unique identifier for the file
     pub chunk_index: usize,   // Index of the chunk within the file
-    pub chunk_size: usize,    // Size of the chunk in bytes
+    /// Size of this chunk in bytes; only the last chunk may be shorter than the base size.
+    #[serde(rename = "chunk_size")]
+    pub actual_size: usize,
+    /// Size of every chunk of the file except possibly the last; 0 when not known.
+    #[serde(default)]
+    pub base_chunk_size: usize,
     pub total_chunks: usize,  // Total number of chunks for the file
 }
 
 impl ChunkMetadata {
-    pub fn new(file_id: Uuid, chunk_index: usize, chunk_size: usize, total_chunks: usize) -> Self {
+    /// Metadata whose base size is taken to be `actual_size`; see
+    /// [`ChunkMetadata::with_base_chunk_size`] for the last chunk of a file.
+    pub fn new(file_id: Uuid, chunk_index: usize, actual_size: usize, total_chunks: usize) -> Self {
         Self {
             file_id,
             chunk_index,
-            chunk_size,
+            actual_size,
+            base_chunk_size: actual_size,
             total_chunks,
         }
     }
+
+    pub fn with_base_chunk_size(mut self, base_chunk_size: usize) -> Self {
+        self.base_chunk_size = base_chunk_size;
+        self
+    }
+
+    /// Byte position of this chunk in the original file.
+    pub fn file_offset(&self, base_chunk_size: usize) -> u64 {
+        (self.chunk_index as u64) * (base_chunk_size as u64)
+    }
+
+    /// The inverse of `json::Value::from(metadata)`, for metadata kept in a
+    /// manifest.
+    pub fn from_json(value: json::Value) -> Result<Self, JsonError> {
+        json::from_value(value)
+    }
 }
 
+impl From<ChunkMetadata> for json::Value {
+    fn from(metadata: ChunkMetadata) -> Self {
+        json::to_value(&metadata).expect("chunk metadata holds only strings and integers")
+    }
+}
+
+/// `file=<uuid> chunk=<index>/<total> size=<bytes>`, for logs.
+impl fmt::Display for ChunkMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "file={} chunk={}/{} size={}",
+            self.file_id, self.chunk_index, self.total_chunks, self.actual_size
+        )
+    }
+}
+
+pub type Chunk = (ChunkMetadata, Vec<u8>);
+
+/// Reads a file's chunks one at a time, so only one chunk is held in memory.
+///
+/// `total_chunks` in the produced metadata is `total` when it was given up
+/// front (see [`ChunkReader::with_total`]) and 0 otherwise; callers that need
+/// it must fill it in once iteration has finished.
+pub struct ChunkReader<R: Read> {
+    reader: R,
+    chunk_size: usize,
+    file_id: Uuid,
+    index: usize,
+    total: Option<usize>,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R, file_id: Uuid, chunk_size: usize) -> Self {
+        ChunkReader {
+            reader,
+            chunk_size,
+            file_id,
+            index: 0,
+            total: None,
+        }
+    }
+
+    /// Records a known chunk count, e.g. derived from the file's length, in every chunk's metadata.
+    pub fn with_total(mut self, total: usize) -> Self {
+        self.total = Some(total);
+        self
+    }
+
+    /// Fills `buffer` from the reader, returning fewer bytes only at end of input.
+    fn fill(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match self.reader.read(&mut buffer[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+}
+
+impl<R: Read> Iterator for ChunkReader<R> {
+    type Item = io::Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buffer = vec![0u8; self.chunk_size];
+        let bytes_read = match self.fill(&mut buffer) {
+            Ok(0) => return None,
+            Ok(n) => n,
+            Err(e) => return Some(Err(e)),
+        };
+        buffer.truncate(bytes_read);
+
+        let metadata = ChunkMetadata::new(self.file_id, self.index, bytes_read, self.total.unwrap_or(0))
+            .with_base_chunk_size(self.chunk_size);
+        self.index += 1;
+        Some(Ok((metadata, buffer)))
+    }
+}
+
+/// Reads a whole file into chunks under a freshly generated file ID.
+/// Prefer [`ChunkReader`] for large files.
+#[cfg(not(feature = "wasm"))]
 pub fn split_file_into_chunks<P: AsRef<Path>>(
     file_path: P,
     chunk_size: usize,
-) -> io::Result<(Uuid, Vec<(ChunkMetadata, Vec<u8>)>)> {
-    let mut file = File::open(&file_path)?;
+) -> io::Result<(Uuid, Vec<Chunk>)> {
+    let file = File::open(&file_path)?;
     let file_id = Uuid::new_v4();
-    let mut chunks = Vec::new();
-    let mut buffer = vec![0u8; chunk_size];
-    let mut chunk_index = 0;
-
-    loop {
-        let bytes_read = file.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
-        }
-
-        let data = buffer[..bytes_read].to_vec();
-        let metadata = ChunkMetadata::new(
-            file_id,
-            chunk_index,
-            bytes_read,
-            (bytes_read as f64 / chunk_size as f64).ceil() as usize,
-        );
+    let mut chunks = ChunkReader::new(file, file_id, chunk_size).collect::<io::Result<Vec<Chunk>>>()?;
 
-        chunks.push((metadata, data));
-        chunk_index += 1;
+    let total_chunks = chunks.len();
+    for (metadata, _) in &mut chunks {
+        metadata.total_chunks = total_chunks;
     }
 
     Ok((file_id, chunks))
 }
 
-#[cfg(test)]
+/// Splits an in-memory buffer into chunks under a freshly generated file ID.
+pub fn split_bytes_into_chunks(data: &[u8], chunk_size: usize) -> (Uuid, Vec<Chunk>) {
+    let file_id = Uuid::new_v4();
+    let total_chunks = data.len().div_ceil(chunk_size);
+    let chunks = data
+        .chunks(chunk_size)
+        .enumerate()
+        .map(|(chunk_index, data)| {
+            let metadata = ChunkMetadata::new(file_id, chunk_index, data.len(), total_chunks).with_base_chunk_size(chunk_size);
+            (metadata, data.to_vec())
+        })
+        .collect();
+    (file_id, chunks)
+}
+
+/// The power of two between [`MIN_CHUNK_SIZE`] and [`MAX_CHUNK_SIZE`] closest
+/// above the size that splits `file_size` bytes into `target_chunks` chunks,
+/// so the file ends up in about that many chunks or fewer.
+pub fn optimal_chunk_size(file_size: u64, target_chunks: usize) -> usize {
+    let exact = file_size.div_ceil(target_chunks.max(1) as u64);
+    let rounded = exact.checked_next_power_of_two().unwrap_or(u64::MAX);
+    rounded.clamp(MIN_CHUNK_SIZE as u64, MAX_CHUNK_SIZE as u64) as usize
+}
+
+/// [`optimal_chunk_size`] for about [`CHUNKS_PER_PEER`] chunks per peer, so
+/// replication and downloads can spread over every peer.
+pub fn optimal_chunk_size_for_peers(file_size: u64, peer_count: usize) -> usize {
+    optimal_chunk_size(file_size, peer_count.max(1) * CHUNKS_PER_PEER)
+}
+
+/// Reassembles the chunks of one file, in any order, into its bytes: the
+/// inverse of [`split_bytes_into_chunks`]. Fails with `InvalidData` unless the
+/// indices are exactly `0..chunks.len()` and each chunk is as long as its
+/// metadata says.
+pub fn merge_chunks(chunks: &[Chunk]) -> io::Result<Vec<u8>> {
+    let mut sorted: Vec<&Chunk> = chunks.iter().collect();
+    sorted.sort_by_key(|(metadata, _)| metadata.chunk_index);
+    let mut merged = Vec::with_capacity(chunks.iter().map(|(_, data)| data.len()).sum());
+    for (expected_index, (metadata, data)) in sorted.into_iter().enumerate() {
+        if metadata.chunk_index != expected_index {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected chunk {}, found chunk {}", expected_index, metadata.chunk_index),
+            ));
+        }
+        if data.len() != metadata.actual_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("chunk {} is {} bytes, expected {}", metadata.chunk_index, data.len(), metadata.actual_size),
+            ));
+        }
+        merged.extend_from_slice(data);
+    }
+    Ok(merged)
+}
+
+/// Writes every chunk of `manifest` stored in `storage_dir` to `dest` at its
+/// offset, through a [`SparseWriter`] pre-allocated to the file's size, so
+/// the file is never held in memory.
+#[cfg(not(feature = "wasm"))]
+pub async fn merge_chunks_to_file(storage_dir: &Path, manifest: &FileManifest, dest: &Path) -> Result<(), StorageError> {
+    let mut output = SparseWriter::create(dest, manifest.base_chunk_size, manifest.file_size).await?;
+    for i in 0..manifest.total_chunks {
+        output.write_chunk(i, &get_chunk(storage_dir, i)?).await?;
+    }
+    if !output.is_complete(manifest.total_chunks) {
+        return Err(StorageError::IncompleteFile(manifest.file_id));
+    }
+    output.finish().await
+}
+
+#[cfg(all(test, not(feature = "wasm")))]
 mod tests {
     use super::*;
     use std::io::Write;
     use tempfile::NamedTempFile;
 
+    #[test]
+    fn test_chunk_metadata_json_round_trip() {
+        let metadata = ChunkMetadata::new(Uuid::new_v4(), 3, 1024, 10);
+        let encoded = crate::json::to_string(&metadata).unwrap();
+        assert_eq!(crate::json::from_str::<ChunkMetadata>(&encoded).unwrap(), metadata);
+
+        let value = crate::json::Value::from(metadata.clone());
+        assert_eq!(value["chunk_size"].as_u64(), Some(1024));
+        assert_eq!(value["file_id"].as_str(), Some(metadata.file_id.to_string().as_str()));
+        assert_eq!(ChunkMetadata::from_json(value).unwrap(), metadata);
+    }
+
     #[test]
     fn test_split_file_into_chunks() {
         let mut temp_file = NamedTempFile::new().unwrap();
@@ -69,18 +266,162 @@ mod tests {
 
         let (file_id, chunks) = split_file_into_chunks(temp_file.path(), chunk_size).unwrap();
 
-        assert_eq!(chunks.len(), 5);
+        assert_eq!(chunks.len(), 6);
 
         for (i, (metadata, data)) in chunks.iter().enumerate() {
             assert_eq!(metadata.file_id, file_id);
             assert_eq!(metadata.chunk_index, i);
-            if i < 3 {
-                assert_eq!(metadata.chunk_size, chunk_size);
+            assert_eq!(metadata.total_chunks, 6);
+            assert_eq!(metadata.base_chunk_size, chunk_size);
+            assert_eq!(metadata.file_offset(metadata.base_chunk_size), (i * chunk_size) as u64);
+            if i < 5 {
+                assert_eq!(metadata.actual_size, chunk_size);
                 assert_eq!(data.len(), chunk_size);
             } else {
-                assert_eq!(metadata.chunk_size, 7);
-                assert_eq!(data.len(), 7);
+                assert_eq!(metadata.actual_size, 3);
+                assert_eq!(data.len(), 3);
             }
         }
     }
+
+    /// Properties that must hold for any content and chunk size: the chunks
+    /// reassemble to the input, each chunk's metadata describes it, and the
+    /// chunks are numbered `0..n` under one file ID.
+    fn check_chunk_properties(content: &[u8], chunk_size: usize, seed: u64) {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(content).unwrap();
+        let (file_id, chunks) = split_file_into_chunks(temp_file.path(), chunk_size).unwrap();
+        let case = format!("seed {}, {} bytes, chunk size {}", seed, content.len(), chunk_size);
+
+        let mut shuffled = chunks.clone();
+        shuffled.reverse();
+        assert!(merge_chunks(&shuffled).unwrap() == content, "content differs after reassembly ({})", case);
+        assert_eq!(chunks.len(), content.len().div_ceil(chunk_size), "{}", case);
+        let mut offset = 0;
+        for (i, (metadata, data)) in chunks.iter().enumerate() {
+            assert_eq!(metadata.actual_size, data.len(), "{}", case);
+            assert_eq!(metadata.file_offset(metadata.base_chunk_size), offset, "{}", case);
+            offset += data.len() as u64;
+            assert!(!data.is_empty() && data.len() <= chunk_size, "{}", case);
+            assert_eq!(metadata.total_chunks, chunks.len(), "{}", case);
+            assert_eq!(metadata.chunk_index, i, "{}", case);
+            assert_eq!(metadata.file_id, file_id, "{}", case);
+        }
+    }
+
+    #[test]
+    fn test_split_file_into_chunks_properties() {
+        use rand::{Rng, RngCore, SeedableRng};
+
+        for seed in 0..64 {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            let mut content = vec![0u8; rng.gen_range(0..=64 * 1024)];
+            rng.fill_bytes(&mut content);
+            // Half the cases use chunk sizes near the content length, where
+            // off-by-one errors show up; the rest span the full 1 byte..1 MB range.
+            let chunk_size = if seed % 2 == 0 {
+                rng.gen_range(1..=content.len() + 1)
+            } else {
+                rng.gen_range(1..=1024 * 1024)
+            };
+            check_chunk_properties(&content, chunk_size, seed);
+        }
+
+        for (len, chunk_size) in [(0, 1), (1, 1), (10, 10), (11, 10), (9, 10), (1024 * 1024 + 1, 1024 * 1024)] {
+            check_chunk_properties(&vec![7u8; len], chunk_size, 0);
+        }
+    }
+
+    /// Hands out at most three bytes per `read` call.
+    struct TrickleReader<'a>(&'a [u8]);
+
+    impl Read for TrickleReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(3).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_chunk_reader_fills_chunks_from_short_reads() {
+        let content: Vec<u8> = (0..25).collect();
+        let file_id = Uuid::new_v4();
+
+        let mut reader = ChunkReader::new(TrickleReader(&content), file_id, 10).with_total(3);
+        let (first, data) = reader.next().unwrap().unwrap();
+        assert_eq!(first, ChunkMetadata::new(file_id, 0, 10, 3));
+        assert_eq!(data, &content[..10]);
+
+        let rest: Vec<Chunk> = reader.collect::<io::Result<_>>().unwrap();
+        assert_eq!(rest.len(), 2);
+        assert_eq!(rest[1].0, ChunkMetadata::new(file_id, 2, 5, 3).with_base_chunk_size(10));
+        assert_eq!(rest[1].1, &content[20..]);
+
+        let unknown_total = ChunkReader::new(TrickleReader(&content), file_id, 10).next().unwrap().unwrap();
+        assert_eq!(unknown_total.0.total_chunks, 0);
+    }
+
+    #[test]
+    fn test_chunk_metadata_display() {
+        let file_id = Uuid::nil();
+        let metadata = ChunkMetadata::new(file_id, 2, 1024, 5);
+        assert_eq!(metadata.to_string(), format!("file={} chunk=2/5 size=1024", file_id));
+    }
+
+    #[test]
+    fn test_optimal_chunk_size() {
+        const MIB: u64 = 1024 * 1024;
+        assert_eq!(optimal_chunk_size(0, 8), MIN_CHUNK_SIZE);
+        assert_eq!(optimal_chunk_size(100, 0), MIN_CHUNK_SIZE);
+        assert_eq!(optimal_chunk_size(64 * MIB, 16), 4 * MIB as usize);
+        // 100 MiB / 16 is 6.25 MiB, rounded up to 8 MiB: 13 chunks.
+        assert_eq!(optimal_chunk_size(100 * MIB, 16), 8 * MIB as usize);
+        assert_eq!(optimal_chunk_size(1024 * 1024 * MIB, 4), MAX_CHUNK_SIZE);
+        assert_eq!(optimal_chunk_size(u64::MAX, 1), MAX_CHUNK_SIZE);
+
+        assert_eq!(optimal_chunk_size_for_peers(64 * MIB, 4), 4 * MIB as usize);
+        assert_eq!(optimal_chunk_size_for_peers(64 * MIB, 0), 16 * MIB as usize);
+    }
+
+    #[test]
+    fn test_merge_chunks_rejects_gaps_and_wrong_sizes() {
+        let (_, chunks) = split_bytes_into_chunks(b"Hello, ShareSphere!", 5);
+        assert_eq!(merge_chunks(&chunks).unwrap(), b"Hello, ShareSphere!");
+        assert_eq!(merge_chunks(&[]).unwrap(), b"");
+
+        let gap = [chunks[0].clone(), chunks[2].clone()];
+        assert_eq!(merge_chunks(&gap).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let duplicate = [chunks[0].clone(), chunks[0].clone()];
+        assert!(merge_chunks(&duplicate).is_err());
+        let mut truncated = chunks.clone();
+        truncated[1].1.pop();
+        assert!(merge_chunks(&truncated).unwrap_err().to_string().contains("chunk 1 is 4 bytes, expected 5"));
+    }
+
+    #[tokio::test]
+    async fn test_merge_chunks_to_file() {
+        use crate::file_manager::storage::{initialize_storage, save_chunk};
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_id = Uuid::new_v4();
+        let storage_dir = initialize_storage(temp_dir.path(), file_id).unwrap();
+
+        let content = b"HelloShareSphereFileChunkingTest!";
+        for (i, data) in content.chunks(5).enumerate() {
+            save_chunk(&storage_dir, &ChunkMetadata::new(file_id, i, data.len(), 7), data).unwrap();
+        }
+        let manifest = FileManifest::new(file_id, "test.txt".to_string(), content.len() as u64, 5, 7);
+
+        let destination = temp_dir.path().join("out.txt");
+        merge_chunks_to_file(&storage_dir, &manifest, &destination).await.unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), content);
+
+        std::fs::remove_file(storage_dir.join("chunk_6.bin")).unwrap();
+        assert!(matches!(
+            merge_chunks_to_file(&storage_dir, &manifest, &destination).await,
+            Err(StorageError::ChunkNotFound { file_id: id, chunk_index: 6 }) if id == file_id
+        ));
+    }
 }
@@ -0,0 +1,132 @@
+// src/file_manager/download_state.rs
+
+//! Which chunks a download has fetched so far, so a download cut short by a
+//! crash picks up where it stopped.
+
+use crate::history::unix_now;
+use crate::json::{self, JsonError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Holds one `<file_id>.state.json` per download in progress.
+pub const DOWNLOADS_DIRNAME: &str = "downloads";
+
+#[derive(Error, Debug)]
+pub enum DownloadStateError {
+    #[error("I/O Error: {0}")]
+    IoError(#[from] io::Error),
+
+    #[error("Serialization Error: {0}")]
+    SerializationError(#[from] JsonError),
+}
+
+/// A download in progress. `started_at` is in seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadState {
+    pub file_id: Uuid,
+    pub total_chunks: usize,
+    pub fetched: BTreeSet<usize>,
+    pub started_at: u64,
+}
+
+impl DownloadState {
+    pub fn new(file_id: Uuid, total_chunks: usize) -> Self {
+        DownloadState { file_id, total_chunks, fetched: BTreeSet::new(), started_at: unix_now() }
+    }
+}
+
+/// Keeps `<storage_path>/downloads/<file_id>.state.json` up to date while a
+/// download runs. Clones share the state, so chunks fetched concurrently
+/// are all recorded.
+#[derive(Debug, Clone)]
+pub struct WatchedDownload {
+    path: PathBuf,
+    state: Arc<Mutex<DownloadState>>,
+}
+
+impl WatchedDownload {
+    /// The state an interrupted download of `file_id` left, if any.
+    pub fn load<P: AsRef<Path>>(storage_root: P, file_id: &Uuid) -> Result<Option<DownloadState>, DownloadStateError> {
+        match fs::read_to_string(state_path(storage_root.as_ref(), file_id)) {
+            Ok(contents) => Ok(Some(json::from_str(&contents)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Tracks `state` from here on; nothing is written until [`Self::save`]
+    /// or the first [`Self::chunk_fetched`].
+    pub fn new<P: AsRef<Path>>(storage_root: P, state: DownloadState) -> Self {
+        WatchedDownload {
+            path: state_path(storage_root.as_ref(), &state.file_id),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn fetched(&self) -> BTreeSet<usize> {
+        self.state.lock().unwrap().fetched.clone()
+    }
+
+    pub fn save(&self) -> Result<(), DownloadStateError> {
+        let state = self.state.lock().unwrap();
+        self.write(&state)
+    }
+
+    pub fn chunk_fetched(&self, chunk_index: usize) -> Result<(), DownloadStateError> {
+        let mut state = self.state.lock().unwrap();
+        state.fetched.insert(chunk_index);
+        self.write(&state)
+    }
+
+    /// Removes the state file of a finished download.
+    pub fn finish(&self) -> Result<(), DownloadStateError> {
+        let _state = self.state.lock().unwrap();
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn write(&self, state: &DownloadState) -> Result<(), DownloadStateError> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        json::write_atomic(&self.path, state)
+    }
+}
+
+fn state_path(storage_root: &Path, file_id: &Uuid) -> PathBuf {
+    storage_root.join(DOWNLOADS_DIRNAME).join(format!("{}.state.json", file_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_download_state_is_kept_until_finished() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_id = Uuid::new_v4();
+        assert_eq!(WatchedDownload::load(temp_dir.path(), &file_id).unwrap(), None);
+
+        let download = WatchedDownload::new(temp_dir.path(), DownloadState::new(file_id, 4));
+        download.chunk_fetched(2).unwrap();
+        download.clone().chunk_fetched(0).unwrap();
+
+        let path = temp_dir.path().join(DOWNLOADS_DIRNAME).join(format!("{}.state.json", file_id));
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains(r#""fetched":[0,2]"#), "{}", contents);
+        let state = WatchedDownload::load(temp_dir.path(), &file_id).unwrap().unwrap();
+        assert_eq!((state.total_chunks, state.fetched), (4, BTreeSet::from([0, 2])));
+
+        download.finish().unwrap();
+        assert!(!path.exists());
+        assert_eq!(WatchedDownload::load(temp_dir.path(), &file_id).unwrap(), None);
+    }
+}
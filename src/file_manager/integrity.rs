@@ -0,0 +1,55 @@
+// src/file_manager/integrity.rs
+
+//! Whole-file checks on top of the per-chunk hashes.
+
+use crate::file_manager::manifest::FileManifest;
+use crate::file_manager::storage::{get_chunk, StorageError};
+use std::path::Path;
+
+/// Hashes the chunks in `storage_dir` in order with the manifest's hash
+/// algorithm and compares the digest with the one the uploader recorded in
+/// `manifest`. Manifests without a recorded hash pass, since there is nothing
+/// to compare against.
+pub fn verify_file_hash(storage_dir: &Path, manifest: &FileManifest) -> Result<bool, StorageError> {
+    let Some(expected) = manifest.file_hash else {
+        return Ok(true);
+    };
+    let mut hasher = manifest.hash_algorithm.hasher();
+    for i in 0..manifest.total_chunks {
+        hasher.update(&get_chunk(storage_dir, i)?);
+    }
+    Ok(hasher.finalize() == expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_manager::chunker::split_bytes_into_chunks;
+    use crate::file_manager::hash::{blake3, sha256, HashAlgorithm};
+    use crate::file_manager::storage::{initialize_storage, save_chunk};
+
+    #[test]
+    fn test_verify_file_hash_detects_flipped_bit() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let (file_id, chunks) = split_bytes_into_chunks(&content, 1024);
+        let storage_dir = initialize_storage(temp_dir.path(), file_id).unwrap();
+        for (metadata, data) in &chunks {
+            save_chunk(&storage_dir, metadata, data).unwrap();
+        }
+        let mut manifest = FileManifest::new(file_id, "data.bin".to_string(), content.len() as u64, 1024, chunks.len());
+        assert!(verify_file_hash(&storage_dir, &manifest).unwrap());
+
+        manifest.file_hash = Some(blake3(&content));
+        assert!(verify_file_hash(&storage_dir, &manifest).unwrap());
+        // Older manifests hashed the file with SHA-256.
+        let legacy = FileManifest { hash_algorithm: HashAlgorithm::Sha256, file_hash: Some(sha256(&content)), ..manifest.clone() };
+        assert!(verify_file_hash(&storage_dir, &legacy).unwrap());
+
+        let (metadata, data) = &chunks[2];
+        let mut damaged = data.clone();
+        damaged[100] ^= 0x01;
+        save_chunk(&storage_dir, metadata, &damaged).unwrap();
+        assert!(!verify_file_hash(&storage_dir, &manifest).unwrap());
+    }
+}
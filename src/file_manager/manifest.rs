@@ -0,0 +1,211 @@
+// src/file_manager/manifest.rs
+
+use crate::file_manager::hash::HashAlgorithm;
+#[cfg(not(feature = "wasm"))]
+use crate::file_manager::storage::{file_id_of, StorageError};
+#[cfg(not(feature = "wasm"))]
+use crate::json;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+#[cfg(not(feature = "wasm"))]
+use std::fs;
+#[cfg(not(feature = "wasm"))]
+use std::io;
+#[cfg(not(feature = "wasm"))]
+use std::path::Path;
+use std::path::{Component, PathBuf};
+use uuid::Uuid;
+
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// What the chunks of a stored file contain.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileKind {
+    /// The contents of a regular file.
+    #[default]
+    File,
+    /// A serialized [`DirManifest`] listing the files of an uploaded directory.
+    Directory,
+}
+
+/// Describes a stored file: its identity and how it was split into chunks.
+/// Saved as `manifest.json` next to the chunks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileManifest {
+    pub file_id: Uuid,
+    pub file_name: String,
+    pub file_size: u64,
+    /// Size of every chunk except possibly the last.
+    #[serde(rename = "chunk_size")]
+    pub base_chunk_size: usize,
+    pub total_chunks: usize,
+    /// What the chunk hashes, `merkle_root` and `file_hash` are made with.
+    /// Manifests written before this was recorded are SHA-256.
+    #[serde(default = "HashAlgorithm::legacy")]
+    pub hash_algorithm: HashAlgorithm,
+    #[serde(default)]
+    pub kind: FileKind,
+    /// Root of the [`MerkleTree`](crate::file_manager::merkle::MerkleTree) over the chunk hashes,
+    /// stored as a hex string. Absent in manifests written before Merkle verification existed.
+    #[serde(default, with = "hex_digest")]
+    pub merkle_root: Option<[u8; 32]>,
+    /// Hash of the whole file as uploaded, as a hex string. Older manifests
+    /// call it `sha256`; it is absent in those written before full-file
+    /// hashes were recorded.
+    #[serde(default, alias = "sha256", with = "hex_digest")]
+    pub file_hash: Option<[u8; 32]>,
+    /// The file's own key, encrypted with the node's `encryption_key` as
+    /// `<nonce hex>:<ciphertext hex>`. Absent when the file has no key of its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrapped_key: Option<String>,
+}
+
+/// One file of an uploaded directory, stored under its own file ID.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirEntry {
+    pub relative_path: PathBuf,
+    pub file_id: Uuid,
+}
+
+/// Contents of a `FileKind::Directory` upload.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirManifest {
+    pub entries: Vec<DirEntry>,
+}
+
+impl DirEntry {
+    /// Entry paths come from remote peers, so they must stay inside the restore directory.
+    pub fn is_safe_path(&self) -> bool {
+        self.relative_path.components().next().is_some()
+            && self.relative_path.components().all(|c| matches!(c, Component::Normal(_)))
+    }
+}
+
+impl FileManifest {
+    pub fn new(file_id: Uuid, file_name: String, file_size: u64, base_chunk_size: usize, total_chunks: usize) -> Self {
+        Self {
+            file_id,
+            file_name,
+            file_size,
+            base_chunk_size,
+            total_chunks,
+            hash_algorithm: HashAlgorithm::default(),
+            kind: FileKind::File,
+            merkle_root: None,
+            file_hash: None,
+            wrapped_key: None,
+        }
+    }
+}
+
+/// `file=<uuid> name=<name> chunks=<N> size=<bytes>`, for logs.
+impl fmt::Display for FileManifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "file={} name={} chunks={} size={}",
+            self.file_id, self.file_name, self.total_chunks, self.file_size
+        )
+    }
+}
+
+/// Serializes an optional 32-byte digest as a hex string (or `null`).
+mod hex_digest {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(digest: &Option<[u8; 32]>, serializer: S) -> Result<S::Ok, S::Error> {
+        match digest {
+            Some(bytes) => serializer.serialize_str(&hex::encode(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error> {
+        let Some(s) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        let bytes = hex::decode(&s).map_err(de::Error::custom)?;
+        let digest = bytes
+            .try_into()
+            .map_err(|_| de::Error::custom("expected a 32-byte hex digest"))?;
+        Ok(Some(digest))
+    }
+}
+
+/// Writes the manifest to `<storage_dir>/manifest.json`, through a temporary
+/// file so readers never see a partly written manifest.
+#[cfg(not(feature = "wasm"))]
+pub fn save_manifest<P: AsRef<Path>>(
+    storage_dir: P,
+    manifest: &FileManifest,
+) -> Result<(), StorageError> {
+    json::write_atomic_pretty(&storage_dir.as_ref().join(MANIFEST_FILENAME), manifest)
+}
+
+/// Reads the manifest from `<storage_dir>/manifest.json`, failing with
+/// [`StorageError::ManifestNotFound`] if there is none.
+#[cfg(not(feature = "wasm"))]
+pub fn load_manifest<P: AsRef<Path>>(
+    storage_dir: P,
+) -> Result<FileManifest, StorageError> {
+    let contents = fs::read_to_string(storage_dir.as_ref().join(MANIFEST_FILENAME)).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => StorageError::ManifestNotFound(file_id_of(storage_dir.as_ref())),
+        _ => e.into(),
+    })?;
+    Ok(json::from_str(&contents)?)
+}
+
+#[cfg(all(test, not(feature = "wasm")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load_manifest() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut manifest = FileManifest::new(Uuid::new_v4(), "report.pdf".to_string(), 2500, 1024, 3);
+        manifest.merkle_root = Some([7u8; 32]);
+
+        save_manifest(temp_dir.path(), &manifest).unwrap();
+        let loaded = load_manifest(temp_dir.path()).unwrap();
+        assert_eq!(loaded, manifest);
+        let contents = fs::read_to_string(temp_dir.path().join(MANIFEST_FILENAME)).unwrap();
+        assert!(contents.contains(&format!("\"merkle_root\": \"{}\"", hex::encode([7u8; 32]))));
+
+        // Older manifests have no root at all.
+        let legacy = contents.replace(&format!("\"{}\"", hex::encode([7u8; 32])), "null");
+        assert_eq!(json::from_str::<FileManifest>(&legacy).unwrap().merkle_root, None);
+    }
+
+    #[test]
+    fn test_manifests_before_blake3_are_sha256() {
+        let mut manifest = FileManifest::new(Uuid::new_v4(), "report.pdf".to_string(), 2500, 1024, 3);
+        assert_eq!(manifest.hash_algorithm, HashAlgorithm::Blake3);
+        manifest.file_hash = Some([9u8; 32]);
+        let contents = json::to_string_pretty(&manifest).unwrap();
+        assert!(contents.contains("\"hash_algorithm\": \"blake3\""), "{}", contents);
+
+        let legacy: String = contents
+            .lines()
+            .filter(|line| !line.contains("hash_algorithm"))
+            .map(|line| line.replace("\"file_hash\"", "\"sha256\"") + "\n")
+            .collect();
+        let loaded = json::from_str::<FileManifest>(&legacy).unwrap();
+        assert_eq!((loaded.hash_algorithm, loaded.file_hash), (HashAlgorithm::Sha256, Some([9u8; 32])));
+    }
+
+    #[test]
+    fn test_dir_entry_paths_stay_inside_destination() {
+        let entry = |path: &str| DirEntry { relative_path: PathBuf::from(path), file_id: Uuid::nil() };
+        assert!(entry("docs/readme.md").is_safe_path());
+        assert!(!entry("../etc/passwd").is_safe_path());
+        assert!(!entry("/etc/passwd").is_safe_path());
+        assert!(!entry("").is_safe_path());
+    }
+
+    #[test]
+    fn test_manifest_display() {
+        let file_id = Uuid::nil();
+        let manifest = FileManifest::new(file_id, "a.txt".to_string(), 3000, 1024, 3);
+        assert_eq!(manifest.to_string(), format!("file={} name=a.txt chunks=3 size=3000", file_id));
+    }
+}
@@ -0,0 +1,169 @@
+// src/file_manager/merkle.rs
+
+use crate::file_manager::hash::HashAlgorithm;
+
+/// Binary Merkle tree over a file's chunk hashes.
+///
+/// Interior nodes are `H(0x01 || left || right)`, with `H` the file's
+/// [`HashAlgorithm`]; the prefix keeps a chunk hash from ever being mistaken
+/// for an interior node. A node without a sibling is carried up to the next
+/// level unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    algorithm: HashAlgorithm,
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+/// Sibling hashes from a leaf up to the root, lowest level first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl MerkleTree {
+    pub fn from_hashes(algorithm: HashAlgorithm, chunk_hashes: &[[u8; 32]]) -> MerkleTree {
+        let mut levels = vec![chunk_hashes.to_vec()];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => hash_pair(algorithm, left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree { algorithm, levels }
+    }
+
+    /// The root hash. An empty tree has the hash of empty input as its root.
+    pub fn root(&self) -> [u8; 32] {
+        match self.levels.last().unwrap().first() {
+            Some(root) => *root,
+            None => self.algorithm.digest(b""),
+        }
+    }
+
+    /// Builds the inclusion proof for `chunk_index`. Out-of-range indices yield an empty proof.
+    pub fn proof(&self, chunk_index: usize) -> MerkleProof {
+        let mut siblings = Vec::new();
+        let mut index = chunk_index;
+        if chunk_index < self.levels[0].len() {
+            for level in &self.levels[..self.levels.len() - 1] {
+                if let Some(sibling) = level.get(index ^ 1) {
+                    siblings.push(*sibling);
+                }
+                index /= 2;
+            }
+        }
+        MerkleProof { siblings }
+    }
+}
+
+impl MerkleProof {
+    /// Checks that `chunk_hash` is leaf `index` of a `total`-leaf tree with
+    /// the given root, built with `algorithm`.
+    pub fn verify(&self, algorithm: HashAlgorithm, chunk_hash: [u8; 32], root: [u8; 32], index: usize, total: usize) -> bool {
+        if index >= total {
+            return false;
+        }
+        let mut siblings = self.siblings.iter();
+        let mut hash = chunk_hash;
+        let mut index = index;
+        let mut width = total;
+        while width > 1 {
+            if index % 2 == 1 {
+                match siblings.next() {
+                    Some(left) => hash = hash_pair(algorithm, left, &hash),
+                    None => return false,
+                }
+            } else if index + 1 < width {
+                match siblings.next() {
+                    Some(right) => hash = hash_pair(algorithm, &hash, right),
+                    None => return false,
+                }
+            }
+            index /= 2;
+            width = width.div_ceil(2);
+        }
+        siblings.next().is_none() && hash == root
+    }
+
+    /// Encodes the proof as comma-separated hex hashes, for the wire protocol.
+    pub fn to_hex(&self) -> String {
+        self.siblings.iter().map(hex::encode).collect::<Vec<_>>().join(",")
+    }
+
+    /// Parses the output of [`MerkleProof::to_hex`].
+    pub fn from_hex(s: &str) -> Option<MerkleProof> {
+        if s.is_empty() {
+            return Some(MerkleProof::default());
+        }
+        let siblings = s
+            .split(',')
+            .map(|part| hex::decode(part).ok()?.try_into().ok())
+            .collect::<Option<Vec<[u8; 32]>>>()?;
+        Some(MerkleProof { siblings })
+    }
+}
+
+fn hash_pair(algorithm: HashAlgorithm, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = algorithm.hasher();
+    hasher.update(&[0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_manager::hash::blake3;
+
+    const ALGORITHM: HashAlgorithm = HashAlgorithm::Blake3;
+
+    fn leaves(n: usize) -> Vec<[u8; 32]> {
+        (0..n).map(|i| blake3(format!("chunk {}", i).as_bytes())).collect()
+    }
+
+    #[test]
+    fn test_every_proof_verifies() {
+        for total in 1..=9 {
+            let hashes = leaves(total);
+            let tree = MerkleTree::from_hashes(ALGORITHM, &hashes);
+            for (index, hash) in hashes.iter().enumerate() {
+                let proof = tree.proof(index);
+                assert!(proof.verify(ALGORITHM, *hash, tree.root(), index, total), "total {} index {}", total, index);
+                assert_eq!(MerkleProof::from_hex(&proof.to_hex()), Some(proof));
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_wrong_chunk_index_or_root() {
+        let hashes = leaves(5);
+        let tree = MerkleTree::from_hashes(ALGORITHM, &hashes);
+        let proof = tree.proof(2);
+        assert!(!proof.verify(ALGORITHM, hashes[3], tree.root(), 2, 5));
+        assert!(!proof.verify(ALGORITHM, hashes[2], tree.root(), 3, 5));
+        assert!(!proof.verify(ALGORITHM, hashes[2], tree.root(), 2, 3));
+        assert!(!proof.verify(ALGORITHM, hashes[2], blake3(b"other"), 2, 5));
+        assert!(!MerkleProof::default().verify(ALGORITHM, hashes[2], tree.root(), 2, 5));
+        // A root made with SHA-256 interior nodes does not verify as BLAKE3.
+        let legacy = MerkleTree::from_hashes(HashAlgorithm::Sha256, &hashes);
+        assert!(legacy.proof(2).verify(HashAlgorithm::Sha256, hashes[2], legacy.root(), 2, 5));
+        assert!(!legacy.proof(2).verify(ALGORITHM, hashes[2], legacy.root(), 2, 5));
+    }
+
+    #[test]
+    fn test_single_chunk_root_is_chunk_hash() {
+        let hashes = leaves(1);
+        let tree = MerkleTree::from_hashes(ALGORITHM, &hashes);
+        assert_eq!(tree.root(), hashes[0]);
+        assert!(tree.proof(0).siblings.is_empty());
+        assert_eq!(MerkleProof::from_hex("zz"), None);
+    }
+}
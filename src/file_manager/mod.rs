@@ -1,5 +1,20 @@
 // src/file_manager/mod.rs
 
 pub mod chunker;
+#[cfg(not(feature = "wasm"))]
 pub mod storage;
+#[cfg(not(feature = "wasm"))]
 pub mod replication;
+pub mod manifest;
+pub mod hash;
+pub mod merkle;
+#[cfg(not(feature = "wasm"))]
+pub mod prefetch;
+#[cfg(not(feature = "wasm"))]
+pub mod integrity;
+#[cfg(not(feature = "wasm"))]
+pub mod pending_uploads;
+#[cfg(not(feature = "wasm"))]
+pub mod download_state;
+#[cfg(not(feature = "wasm"))]
+pub mod seed_index;
@@ -0,0 +1,170 @@
+// src/file_manager/pending_uploads.rs
+
+//! Uploads that were started but not finished, so the next run can resume
+//! them instead of starting over.
+
+use crate::json::{self, JsonError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use uuid::Uuid;
+
+pub const PENDING_UPLOADS_FILENAME: &str = "pending_uploads.json";
+
+#[derive(Error, Debug)]
+pub enum PendingUploadError {
+    #[error("I/O Error: {0}")]
+    IoError(#[from] io::Error),
+
+    #[error("Serialization Error: {0}")]
+    SerializationError(#[from] JsonError),
+}
+
+/// An upload in progress: the source file, the chunks already saved locally
+/// and the peers that accepted each replicated chunk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingUpload {
+    pub file_id: Uuid,
+    pub path: PathBuf,
+    pub chunk_size: usize,
+    pub chunks_saved: BTreeSet<usize>,
+    #[serde(with = "chunk_index_keys")]
+    pub replicated_to: BTreeMap<usize, Vec<String>>,
+}
+
+/// JSON object keys are strings, which do not parse back into numbers on
+/// their own.
+mod chunk_index_keys {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer>(map: &BTreeMap<usize, Vec<String>>, serializer: S) -> Result<S::Ok, S::Error> {
+        let by_key: BTreeMap<String, &Vec<String>> = map.iter().map(|(index, peers)| (index.to_string(), peers)).collect();
+        by_key.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<usize, Vec<String>>, D::Error> {
+        BTreeMap::<String, Vec<String>>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, peers)| key.parse().map(|index| (index, peers)).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+impl PendingUpload {
+    pub fn new(file_id: Uuid, path: impl Into<PathBuf>, chunk_size: usize) -> Self {
+        PendingUpload {
+            file_id,
+            path: path.into(),
+            chunk_size,
+            chunks_saved: BTreeSet::new(),
+            replicated_to: BTreeMap::new(),
+        }
+    }
+}
+
+/// Keeps `<storage_path>/pending_uploads.json` up to date while uploads run.
+/// Clones share a lock, so the per-chunk replication tasks of one upload
+/// don't lose each other's updates.
+#[derive(Debug, Clone)]
+pub struct WatchedUploader {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl WatchedUploader {
+    pub fn open<P: AsRef<Path>>(storage_root: P) -> Self {
+        WatchedUploader {
+            path: storage_root.as_ref().join(PENDING_UPLOADS_FILENAME),
+            lock: Arc::default(),
+        }
+    }
+
+    /// Records an upload as started, replacing any earlier record of the file.
+    pub fn start(&self, upload: PendingUpload) -> Result<(), PendingUploadError> {
+        self.update(|uploads| {
+            uploads.retain(|u| u.file_id != upload.file_id);
+            uploads.push(upload);
+        })
+    }
+
+    pub fn chunk_saved(&self, file_id: &Uuid, chunk_index: usize) -> Result<(), PendingUploadError> {
+        self.update(|uploads| {
+            if let Some(upload) = uploads.iter_mut().find(|u| u.file_id == *file_id) {
+                upload.chunks_saved.insert(chunk_index);
+            }
+        })
+    }
+
+    pub fn chunk_replicated(&self, file_id: &Uuid, chunk_index: usize, peers: Vec<String>) -> Result<(), PendingUploadError> {
+        self.update(|uploads| {
+            if let Some(upload) = uploads.iter_mut().find(|u| u.file_id == *file_id) {
+                upload.replicated_to.insert(chunk_index, peers);
+            }
+        })
+    }
+
+    /// Drops the record of a finished upload.
+    pub fn finish(&self, file_id: &Uuid) -> Result<(), PendingUploadError> {
+        self.update(|uploads| uploads.retain(|u| u.file_id != *file_id))
+    }
+
+    pub fn pending(&self) -> Result<Vec<PendingUpload>, PendingUploadError> {
+        let _guard = self.lock.lock().unwrap();
+        self.load()
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Vec<PendingUpload>)) -> Result<(), PendingUploadError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut uploads = self.load()?;
+        change(&mut uploads);
+        if uploads.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        json::write_atomic(&self.path, &uploads)
+    }
+
+    fn load(&self) -> Result<Vec<PendingUpload>, PendingUploadError> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => Ok(json::from_str(&contents)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_uploads_are_tracked_until_finished() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let uploader = WatchedUploader::open(temp_dir.path());
+        let file_id = Uuid::new_v4();
+        uploader.start(PendingUpload::new(file_id, "/data/big.iso", 1024)).unwrap();
+        uploader.chunk_saved(&file_id, 0).unwrap();
+        uploader.chunk_saved(&file_id, 1).unwrap();
+        uploader.chunk_replicated(&file_id, 0, vec!["10.0.0.1:8080".to_string()]).unwrap();
+
+        let pending = WatchedUploader::open(temp_dir.path()).pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].chunks_saved, BTreeSet::from([0, 1]));
+        assert_eq!(pending[0].replicated_to[&0], ["10.0.0.1:8080"]);
+        let contents = fs::read_to_string(temp_dir.path().join(PENDING_UPLOADS_FILENAME)).unwrap();
+        assert!(contents.contains(r#""chunks_saved":[0,1]"#), "{}", contents);
+        assert!(contents.contains(r#""replicated_to":{"0":["10.0.0.1:8080"]}"#), "{}", contents);
+
+        uploader.finish(&file_id).unwrap();
+        assert!(uploader.pending().unwrap().is_empty());
+        assert!(!temp_dir.path().join(PENDING_UPLOADS_FILENAME).exists());
+    }
+}
@@ -0,0 +1,132 @@
+// src/file_manager/prefetch.rs
+
+//! Speculative fetching of the chunks after the one just downloaded, so a
+//! sequential download overlaps waiting on the network for chunk `n + 1`
+//! with handling chunk `n`.
+
+use crate::peer::transport::ConnectionError;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::task::JoinHandle;
+
+type PrefetchTask = JoinHandle<Result<(), ConnectionError>>;
+type StartFn = Box<dyn Fn(usize) -> PrefetchTask + Send + Sync>;
+
+/// Prefetch tasks still running or not yet collected, oldest first. Dropping
+/// the queue aborts them, e.g. when a download fails part way.
+pub struct PrefetchQueue {
+    queue: Mutex<VecDeque<(usize, PrefetchTask)>>,
+    lookahead: usize,
+    total_chunks: usize,
+    start: StartFn,
+}
+
+impl PrefetchQueue {
+    /// `fetch` downloads one chunk; it runs as its own task for each prefetched index.
+    pub fn new<F, Fut>(lookahead: usize, total_chunks: usize, fetch: F) -> Self
+    where
+        F: Fn(usize) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ConnectionError>> + Send + 'static,
+    {
+        PrefetchQueue {
+            queue: Mutex::new(VecDeque::new()),
+            lookahead,
+            total_chunks,
+            start: Box::new(move |chunk_index| tokio::spawn(fetch(chunk_index))),
+        }
+    }
+
+    /// Called once `completed` is downloaded: starts prefetching the next
+    /// `lookahead` chunks that are not already being prefetched.
+    pub fn advance(&self, completed: usize) {
+        let end = completed.saturating_add(self.lookahead).min(self.total_chunks.saturating_sub(1));
+        let mut queue = self.queue.lock().unwrap();
+        for chunk_index in completed + 1..=end {
+            if !queue.iter().any(|(index, _)| *index == chunk_index) {
+                queue.push_back((chunk_index, (self.start)(chunk_index)));
+            }
+        }
+    }
+
+    /// Waits for the prefetch of `chunk_index`, if one was started. `None`
+    /// means the chunk still has to be fetched.
+    pub async fn wait_for(&self, chunk_index: usize) -> Option<Result<(), ConnectionError>> {
+        let handle = {
+            let mut queue = self.queue.lock().unwrap();
+            let position = queue.iter().position(|(index, _)| *index == chunk_index)?;
+            queue.remove(position)?.1
+        };
+        Some(match handle.await {
+            Ok(result) => result,
+            Err(e) => Err(ConnectionError::Session(format!("prefetch of chunk {} failed: {}", chunk_index, e))),
+        })
+    }
+
+    /// Aborts every outstanding prefetch.
+    pub fn cancel_all(&self) {
+        for (_, handle) in self.queue.lock().unwrap().drain(..) {
+            handle.abort();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for PrefetchQueue {
+    fn drop(&mut self) {
+        self.cancel_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn test_advance_starts_lookahead_once() {
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let recorded = started.clone();
+        let prefetch = PrefetchQueue::new(3, 6, move |chunk_index| {
+            recorded.lock().unwrap().push(chunk_index);
+            async { Ok(()) }
+        });
+
+        prefetch.advance(0);
+        assert_eq!(*started.lock().unwrap(), vec![1, 2, 3]);
+        assert!(matches!(prefetch.wait_for(1).await, Some(Ok(()))));
+        assert!(prefetch.wait_for(1).await.is_none());
+
+        prefetch.advance(1);
+        prefetch.advance(4);
+        // Only chunks not already queued are started, and never past the last chunk.
+        assert_eq!(*started.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(prefetch.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_drop_aborts_outstanding_prefetches() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let prefetch = PrefetchQueue::new(1, 2, move |_| {
+            let guard = tx.lock().unwrap().take();
+            async move {
+                let _guard = guard;
+                std::future::pending::<()>().await;
+                Ok(())
+            }
+        });
+        prefetch.advance(0);
+        drop(prefetch);
+        // The aborted task drops the sender without sending.
+        assert!(rx.await.is_err());
+    }
+}
@@ -1,45 +1,539 @@
-use crate::peer::discovery::Peer;
-use crate::peer::connection::send_chunk_to_peer;
+use crate::config::Config;
+use crate::file_manager::hash::sha256;
+use crate::peer::discovery::{Peer, PeerRegistry};
+use crate::file_manager::manifest::load_manifest;
+use crate::file_manager::storage::{list_all_files, recorded_chunk_hash, StorageError};
+use crate::history::unix_now;
+use crate::indexing::dht::{DhtError, DHT};
+use crate::json::{self, JsonError};
+use crate::peer::connection::{request_chunk, send_chunk_to_peer, send_file_manifest};
+use crate::peer::latency::{latency_of, PeerLatency};
+use crate::peer::reputation::ReputationStore;
+use crate::peer::stats::SharedNetworkStats;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
 use std::{error::Error, path::Path};
-use log::{info, error};
+use log::{debug, info, error};
+use thiserror::Error;
+use uuid::Uuid;
 
 const REPLICATION_FACTOR: usize = 2;
 
+pub const REPLICATION_STATUS_FILENAME: &str = "replication_status.json";
+
+/// How many of the fastest remaining candidates are considered for each
+/// replica slot when trading latency for address diversity.
+const DIVERSITY_WINDOW: usize = 2 * REPLICATION_FACTOR;
+
+/// Replication tasks currently running per peer address.
+pub type PeerLoad = Arc<RwLock<HashMap<String, u32>>>;
+
+/// How `replicate_chunk` chooses the peers that receive a chunk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicationPolicy {
+    /// Rotates through the peer list, starting one peer further for each chunk.
+    RoundRobin,
+    /// Picks peers uniformly at random.
+    Random,
+    /// Prefers peers with the fewest replication tasks in flight.
+    LeastLoaded,
+    /// Prefers low ping latency, spreading replicas across addresses.
+    #[default]
+    LatencyBased,
+}
+
+#[derive(Error, Debug)]
+pub enum ReplicationError {
+    #[error("I/O Error: {0}")]
+    IoError(#[from] io::Error),
+
+    #[error("Serialization Error: {0}")]
+    SerializationError(#[from] JsonError),
+
+    #[error("Storage Error: {0}")]
+    StorageError(#[from] StorageError),
+
+    #[error("DHT Error: {0}")]
+    DhtError(#[from] DhtError),
+}
+
+/// A chunk a peer accepted. `confirmed_at` is in seconds since the Unix epoch;
+/// `verified` is set once the peer served the chunk back intact.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicationStatus {
+    pub file_id: Uuid,
+    pub chunk_index: usize,
+    pub peer_address: String,
+    pub confirmed_at: u64,
+    pub verified: bool,
+}
+
+/// Persistent table of [`ReplicationStatus`] records, one per chunk and peer,
+/// kept as a JSON array in `<storage_path>/replication_status.json`. Clones
+/// share a lock, so concurrent replication tasks don't lose each other's updates.
+#[derive(Debug, Clone)]
+pub struct ReplicationStatusStore {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl ReplicationStatusStore {
+    pub fn open<P: AsRef<Path>>(storage_root: P) -> Self {
+        ReplicationStatusStore {
+            path: storage_root.as_ref().join(REPLICATION_STATUS_FILENAME),
+            lock: Arc::default(),
+        }
+    }
+
+    /// Records that `peer_address` accepted a chunk. Sending it again resets `verified`.
+    pub fn record_sent(&self, file_id: Uuid, chunk_index: usize, peer_address: &str) -> Result<(), ReplicationError> {
+        self.update(|records| {
+            records.retain(|r| !(r.file_id == file_id && r.chunk_index == chunk_index && r.peer_address == peer_address));
+            records.push(ReplicationStatus {
+                file_id,
+                chunk_index,
+                peer_address: peer_address.to_string(),
+                confirmed_at: unix_now(),
+                verified: false,
+            });
+        })
+    }
+
+    pub fn set_verified(&self, file_id: Uuid, chunk_index: usize, peer_address: &str, verified: bool) -> Result<(), ReplicationError> {
+        self.update(|records| {
+            for record in records.iter_mut() {
+                if record.file_id == file_id && record.chunk_index == chunk_index && record.peer_address == peer_address {
+                    record.verified = verified;
+                }
+            }
+        })
+    }
+
+    /// Records for `file_id`, by chunk index and then peer address.
+    pub fn get_replication_status(&self, file_id: &Uuid) -> Result<Vec<ReplicationStatus>, ReplicationError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut records: Vec<ReplicationStatus> = self.load()?.into_iter().filter(|r| r.file_id == *file_id).collect();
+        records.sort_by(|a, b| a.chunk_index.cmp(&b.chunk_index).then_with(|| a.peer_address.cmp(&b.peer_address)));
+        Ok(records)
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Vec<ReplicationStatus>)) -> Result<(), ReplicationError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut records = self.load()?;
+        change(&mut records);
+        json::write_atomic(&self.path, &records)
+    }
+
+    fn load(&self) -> Result<Vec<ReplicationStatus>, ReplicationError> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => Ok(json::from_str(&contents)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Shared state that replication reads and updates.
+#[derive(Debug, Clone)]
+pub struct ReplicationContext {
+    pub network_stats: SharedNetworkStats,
+    pub latency: PeerLatency,
+    pub peer_load: PeerLoad,
+    pub config: Config,
+    pub status: ReplicationStatusStore,
+    pub reputation: ReputationStore,
+}
+
 pub async fn replicate_chunks(
     peers: &[Peer],
     storage_dir: &str,
     file_id: &uuid::Uuid,
+    context: &ReplicationContext,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    for chunk_index in 0..get_total_chunks(storage_dir, file_id)? {
-        let peers_to_replicate = select_peers_for_replication(peers, file_id, chunk_index)?;
-
-        for peer in peers_to_replicate {
-            if let Err(e) = send_chunk_to_peer(peer, storage_dir, file_id, chunk_index).await {
-                error!("Failed to replicate chunk {} to peer {}: {}", chunk_index, peer.address, e);
-            } else {
-                info!("Replicated chunk {} to peer {}", chunk_index, peer.address);
+    let manifest = load_manifest(Path::new(storage_dir).join(file_id.to_string())).ok();
+    let mut has_manifest: HashSet<String> = HashSet::new();
+    for chunk_index in 0..get_total_chunks(storage_dir, file_id).await? {
+        let peers_to_replicate = select_peers(peers, file_id, chunk_index, context)?;
+        // Each peer gets the manifest before its first chunk of the file.
+        if let Some(manifest) = &manifest {
+            for peer in &peers_to_replicate {
+                if has_manifest.insert(peer.address.clone()) {
+                    if let Err(e) = send_file_manifest(peer, manifest, &context.config).await {
+                        error!("Failed to send manifest of file={} to {}: {}", file_id, peer, e);
+                    }
+                }
             }
         }
+        send_to_peers(&peers_to_replicate, storage_dir, file_id, chunk_index, context).await;
     }
     Ok(())
 }
 
-fn get_total_chunks(storage_dir: &str, file_id: &uuid::Uuid) -> Result<usize, Box<dyn Error + Send + Sync>> {
-    use crate::file_manager::storage::list_chunks;
+/// Sends one stored chunk to `REPLICATION_FACTOR` peers chosen by the
+/// configured [`ReplicationPolicy`] and returns the addresses of those that
+/// accepted it. Failures to reach an individual peer are logged; only a lack
+/// of peers is an error.
+pub async fn replicate_chunk(
+    peers: &[Peer],
+    storage_dir: &str,
+    file_id: &uuid::Uuid,
+    chunk_index: usize,
+    context: &ReplicationContext,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let peers_to_replicate = select_peers(peers, file_id, chunk_index, context)?;
+    Ok(send_to_peers(&peers_to_replicate, storage_dir, file_id, chunk_index, context).await)
+}
+
+/// The peers the configured [`ReplicationPolicy`] sends a chunk to, for
+/// callers that send it themselves, e.g. through a [`ReplicationQueue`].
+pub fn select_peers<'a>(
+    peers: &'a [Peer],
+    file_id: &uuid::Uuid,
+    chunk_index: usize,
+    context: &ReplicationContext,
+) -> Result<Vec<&'a Peer>, Box<dyn Error + Send + Sync>> {
+    select_peers_for_replication(
+        peers,
+        file_id,
+        chunk_index,
+        context.config.transfer.replication_policy,
+        &context.latency,
+        &context.peer_load,
+        &context.reputation,
+    )
+}
+
+async fn send_to_peers(
+    peers: &[&Peer],
+    storage_dir: &str,
+    file_id: &uuid::Uuid,
+    chunk_index: usize,
+    context: &ReplicationContext,
+) -> Vec<String> {
+    let mut accepted = Vec::new();
+    for peer in peers {
+        if send_to_peer(peer, storage_dir, file_id, chunk_index, context).await {
+            accepted.push(peer.address.clone());
+        }
+    }
+    accepted
+}
+
+/// Sends one chunk to one peer, keeping the peer's load, reputation and
+/// replication status up to date. Returns whether the peer accepted it.
+async fn send_to_peer(
+    peer: &Peer,
+    storage_dir: &str,
+    file_id: &uuid::Uuid,
+    chunk_index: usize,
+    context: &ReplicationContext,
+) -> bool {
+    let _load = LoadGuard::start(&context.peer_load, &peer.address);
+    if let Err(e) = send_chunk_to_peer(peer, storage_dir, file_id, chunk_index, &context.network_stats, &context.config).await {
+        error!("Failed to replicate file={} chunk={} to {}: {}", file_id, chunk_index, peer, e);
+        context.reputation.record_failure(&peer.address);
+        return false;
+    }
+    info!("Replicated file={} chunk={} to {}", file_id, chunk_index, peer);
+    context.reputation.record_success(&peer.address);
+    if let Err(e) = context.status.record_sent(*file_id, chunk_index, &peer.address) {
+        error!("Failed to record replication of file={} chunk={} to {}: {}", file_id, chunk_index, peer, e);
+    }
+    true
+}
+
+/// One chunk of a locally stored file to send to one peer.
+#[derive(Debug, Clone)]
+pub struct ReplicationTask {
+    pub file_id: Uuid,
+    pub chunk_index: usize,
+    pub target_peer: Peer,
+}
+
+/// What became of the tasks a [`ReplicationQueue`] ran for one file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplicationSummary {
+    /// The peers that accepted each chunk, for every chunk that had a task.
+    pub replicated_to: BTreeMap<usize, Vec<String>>,
+    /// Tasks whose peer could not be reached or refused the chunk.
+    pub failed: usize,
+}
+
+#[derive(Debug, Default)]
+struct FileProgress {
+    outstanding: usize,
+    summary: ReplicationSummary,
+}
+
+type Progress = Arc<Mutex<HashMap<Uuid, FileProgress>>>;
+
+/// Runs replication tasks on a fixed pool of `concurrency` workers, so an
+/// upload cannot open more connections at once than that however many
+/// chunks it has. [`ReplicationQueue::enqueue`] waits while every worker is
+/// busy and the queue is full.
+pub struct ReplicationQueue {
+    sender: mpsc::Sender<ReplicationTask>,
+    concurrency: usize,
+    progress: Progress,
+    finished: Arc<Notify>,
+}
+
+impl ReplicationQueue {
+    /// Starts the workers on the current tokio runtime. They stop once the
+    /// queue is dropped and the tasks already queued are done.
+    pub fn new(context: ReplicationContext, concurrency: usize) -> Self {
+        let concurrency = concurrency.max(1);
+        let (sender, receiver) = mpsc::channel(concurrency);
+        let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+        let progress = Progress::default();
+        let finished = Arc::new(Notify::new());
+        for _ in 0..concurrency {
+            tokio::spawn(run_worker(receiver.clone(), context.clone(), progress.clone(), finished.clone()));
+        }
+        ReplicationQueue { sender, concurrency, progress, finished }
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    pub async fn enqueue(&self, task: ReplicationTask) {
+        self.progress.lock().unwrap().entry(task.file_id).or_default().outstanding += 1;
+        // Only fails if every worker panicked; count the task as failed so
+        // waiting for its file still ends.
+        if let Err(mpsc::error::SendError(task)) = self.sender.send(task).await {
+            error!("No replication worker is left to send file={} chunk={}", task.file_id, task.chunk_index);
+            record_outcome(&self.progress, &self.finished, &task, false);
+        }
+    }
+
+    /// Waits until every task queued so far for `file_id` has finished, and
+    /// returns their outcome. The summary is only handed out once; a file
+    /// with no tasks yields an empty one.
+    pub async fn wait_for_completion(&self, file_id: &Uuid) -> ReplicationSummary {
+        loop {
+            let finished = self.finished.notified();
+            tokio::pin!(finished);
+            // Registered before checking, so a task finishing in between still wakes us.
+            finished.as_mut().enable();
+            {
+                let mut progress = self.progress.lock().unwrap();
+                if progress.get(file_id).is_none_or(|file| file.outstanding == 0) {
+                    return progress.remove(file_id).map(|file| file.summary).unwrap_or_default();
+                }
+            }
+            finished.await;
+        }
+    }
+}
+
+async fn run_worker(
+    receiver: Arc<tokio::sync::Mutex<mpsc::Receiver<ReplicationTask>>>,
+    context: ReplicationContext,
+    progress: Progress,
+    finished: Arc<Notify>,
+) {
+    loop {
+        let Some(task) = receiver.lock().await.recv().await else {
+            return;
+        };
+        let accepted = send_to_peer(&task.target_peer, &context.config.storage.storage_path, &task.file_id, task.chunk_index, &context).await;
+        record_outcome(&progress, &finished, &task, accepted);
+    }
+}
+
+fn record_outcome(progress: &Progress, finished: &Notify, task: &ReplicationTask, accepted: bool) {
+    let mut progress = progress.lock().unwrap();
+    let file = progress.entry(task.file_id).or_default();
+    file.outstanding = file.outstanding.saturating_sub(1);
+    let peers = file.summary.replicated_to.entry(task.chunk_index).or_default();
+    if accepted {
+        peers.push(task.target_peer.address.clone());
+    } else {
+        file.summary.failed += 1;
+    }
+    drop(progress);
+    finished.notify_waiters();
+}
+
+/// Asks every peer on record for `file_id` to serve its chunks back with a
+/// `CHUNK_REQUEST`, and marks each record verified if the data matches the
+/// local copy. Returns how many records are verified afterwards.
+pub async fn verify_replication(
+    storage_dir: &str,
+    file_id: &Uuid,
+    context: &ReplicationContext,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let local_dir = Path::new(storage_dir).join(file_id.to_string());
+    let mut verified_count = 0;
+    for record in context.status.get_replication_status(file_id)? {
+        let (algorithm, expected) = recorded_chunk_hash(&local_dir, record.chunk_index)?;
+        let peer = Peer::new(record.peer_address.clone());
+        let verified = match request_chunk(&peer, *file_id, record.chunk_index, &context.config).await {
+            Ok((data, _)) => algorithm.digest(&data) == expected,
+            Err(e) => {
+                error!("Failed to verify chunk {} on peer {}: {}", record.chunk_index, record.peer_address, e);
+                false
+            }
+        };
+        context.status.set_verified(*file_id, record.chunk_index, &record.peer_address, verified)?;
+        verified_count += usize::from(verified);
+    }
+    Ok(verified_count)
+}
+
+/// Outcome of [`repair_under_replicated_files`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RepairSummary {
+    pub files_checked: usize,
+    pub files_repaired: usize,
+    pub files_failed: usize,
+}
+
+/// Replicates every locally stored file that the DHT places on fewer than
+/// `target_factor` peers, counting the local node as one of them. Only
+/// peers not already holding the file receive it. As with
+/// [`replicate_chunks`], a file counts as repaired once its chunks were
+/// offered to enough peers; individual peers that fail are logged.
+pub async fn repair_under_replicated_files(
+    dht: &DHT,
+    storage_root: &Path,
+    local_peer: &Peer,
+    peers: &[Peer],
+    target_factor: usize,
+    context: &ReplicationContext,
+) -> Result<RepairSummary, ReplicationError> {
+    let mut summary = RepairSummary::default();
+    for file_id in list_all_files(storage_root)? {
+        summary.files_checked += 1;
+        let holders: Vec<String> = dht
+            .get_file_locations(&file_id)?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|peer| peer.address)
+            .filter(|address| *address != local_peer.address)
+            .collect();
+        if holders.len() + 1 >= target_factor {
+            continue;
+        }
+        let candidates: Vec<Peer> = peers
+            .iter()
+            .filter(|peer| peer.address != local_peer.address && !holders.contains(&peer.address))
+            .cloned()
+            .collect();
+        match replicate_chunks(&candidates, &storage_root.to_string_lossy(), &file_id, context).await {
+            Ok(()) => {
+                info!("Repaired file={}, which had {} of {} replicas", file_id, holders.len() + 1, target_factor);
+                summary.files_repaired += 1;
+            }
+            Err(e) => {
+                error!("Failed to repair file={}: {}", file_id, e);
+                summary.files_failed += 1;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// Runs [`repair_under_replicated_files`] over the known peers every
+/// `repair_interval_secs`, so files lost along with a departed peer regain
+/// their replicas without anyone running `repair`.
+pub struct RepairTask {
+    dht: DHT,
+    peers: PeerRegistry,
+    local_peer: Peer,
+    context: ReplicationContext,
+}
+
+impl RepairTask {
+    pub fn new(dht: DHT, peers: PeerRegistry, local_peer: Peer, context: ReplicationContext) -> Self {
+        RepairTask { dht, peers, local_peer, context }
+    }
+
+    /// Runs a round every `repair_interval_secs`, forever. The first round
+    /// waits one interval, giving gossip time to fill the DHT.
+    pub async fn run(self) {
+        let period = Duration::from_secs(self.context.config.storage.repair_interval_secs.max(1));
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            let known_peers = self.peers.all();
+            let storage_root = Path::new(&self.context.config.storage.storage_path);
+            let target_factor = self.context.config.storage.replication_target_factor;
+            match repair_under_replicated_files(&self.dht, storage_root, &self.local_peer, &known_peers, target_factor, &self.context).await {
+                Ok(summary) => debug!("Repair round: {:?}", summary),
+                Err(e) => error!("Repairing under-replicated files failed: {}", e),
+            }
+        }
+    }
+}
+
+/// Counts one replication task against a peer for as long as it is alive.
+struct LoadGuard<'a> {
+    peer_load: &'a PeerLoad,
+    address: String,
+}
+
+impl<'a> LoadGuard<'a> {
+    fn start(peer_load: &'a PeerLoad, address: &str) -> Self {
+        *peer_load.write().unwrap().entry(address.to_string()).or_insert(0) += 1;
+        LoadGuard { peer_load, address: address.to_string() }
+    }
+}
+
+impl Drop for LoadGuard<'_> {
+    fn drop(&mut self) {
+        let mut load = self.peer_load.write().unwrap();
+        if let Some(count) = load.get_mut(&self.address) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                load.remove(&self.address);
+            }
+        }
+    }
+}
+
+fn load_of(peer_load: &PeerLoad, address: &str) -> u32 {
+    peer_load.read().unwrap().get(address).copied().unwrap_or(0)
+}
+
+async fn get_total_chunks(storage_dir: &str, file_id: &uuid::Uuid) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    use crate::file_manager::storage::list_chunks_async;
     let storage_path = Path::new(storage_dir).join(file_id.to_string());
-    let chunks = list_chunks(&storage_path)?;
+    let chunks = list_chunks_async(&storage_path).await?;
     Ok(chunks.len())
 }
 
+/// Picks `REPLICATION_FACTOR` peers according to `policy`.
 fn select_peers_for_replication<'a>(
     peers: &'a [Peer],
-    file_id: &'a uuid::Uuid,
+    file_id: &uuid::Uuid,
     chunk_index: usize,
+    policy: ReplicationPolicy,
+    latency: &PeerLatency,
+    peer_load: &PeerLoad,
+    reputation: &ReputationStore,
 ) -> Result<Vec<&'a Peer>, Box<dyn Error + Send + Sync>> {
-    let available_peers: Vec<&Peer> = peers.iter()
+    let mut available_peers: Vec<&Peer> = peers.iter()
         .filter(|peer| !peer.address.contains(&file_id.to_string())) // Avoid self-replication
         .collect();
 
+    // Most reliable first; a stable sort keeps equally scored peers in order.
+    // Deprioritized peers sort last and are only used when nobody else is left.
+    available_peers.sort_by(|a, b| reputation.score(&b.address).total_cmp(&reputation.score(&a.address)));
+    let trusted = available_peers.iter().filter(|peer| !reputation.is_deprioritized(&peer.address)).count();
+    if trusted >= REPLICATION_FACTOR {
+        available_peers.truncate(trusted);
+    }
+
     if available_peers.len() < REPLICATION_FACTOR {
         return Err(format!(
             "Not enough peers to replicate chunk {}. Required: {}, Available: {}",
@@ -49,7 +543,64 @@ fn select_peers_for_replication<'a>(
         ).into());
     }
 
-    Ok(available_peers[..REPLICATION_FACTOR].to_vec())
+    let selected = match policy {
+        ReplicationPolicy::RoundRobin => {
+            let start = chunk_index % available_peers.len();
+            available_peers.rotate_left(start);
+            available_peers.truncate(REPLICATION_FACTOR);
+            available_peers
+        }
+        ReplicationPolicy::Random => {
+            available_peers.choose_multiple(&mut rand::thread_rng(), REPLICATION_FACTOR).copied().collect()
+        }
+        ReplicationPolicy::LeastLoaded => {
+            // Stable sort: equally loaded peers keep their relative order.
+            available_peers.sort_by_key(|peer| load_of(peer_load, &peer.address));
+            available_peers.truncate(REPLICATION_FACTOR);
+            available_peers
+        }
+        ReplicationPolicy::LatencyBased => select_by_latency(available_peers, latency),
+    };
+
+    Ok(selected)
+}
+
+/// Prefers low latency. Peers without a measured latency follow in slice
+/// order. Each slot is filled from the `DIVERSITY_WINDOW` fastest remaining
+/// peers, choosing the one whose address hash is furthest from those already
+/// chosen, as a cheap proxy for spreading replicas across networks.
+fn select_by_latency<'a>(mut available_peers: Vec<&'a Peer>, latency: &PeerLatency) -> Vec<&'a Peer> {
+    // Stable sort: peers without a latency keep their relative order.
+    available_peers.sort_by_key(|peer| latency_of(latency, &peer.address).unwrap_or(std::time::Duration::MAX));
+
+    let mut selected: Vec<&Peer> = Vec::with_capacity(REPLICATION_FACTOR);
+    while selected.len() < REPLICATION_FACTOR {
+        let window = available_peers.len().min(DIVERSITY_WINDOW);
+        let best = (0..window)
+            .max_by_key(|&i| {
+                let distance = selected
+                    .iter()
+                    .map(|chosen| address_distance(chosen, available_peers[i]))
+                    .min()
+                    .unwrap_or(0);
+                // Prefer the earlier (faster) candidate on ties.
+                (distance, std::cmp::Reverse(i))
+            })
+            .unwrap();
+        selected.push(available_peers.remove(best));
+    }
+
+    selected
+}
+
+/// Hamming distance between the hashes of two peer addresses.
+fn address_distance(a: &Peer, b: &Peer) -> u32 {
+    (address_hash(&a.address) ^ address_hash(&b.address)).count_ones()
+}
+
+fn address_hash(address: &str) -> u64 {
+    let digest = sha256(address.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
 }
 
 #[cfg(test)]
@@ -61,15 +612,6 @@ mod tests {
     use std::fs;
     use tempfile::TempDir;
 
-    async fn mock_send_chunk_to_peer(
-        peer: &Peer,
-        storage_dir: &str,
-        file_id: &Uuid,
-        chunk_index: usize,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        Ok(())
-    }
-
     #[tokio::test]
     async fn test_replicate_chunks_success() {
         let temp_dir = TempDir::new().unwrap();
@@ -86,13 +628,21 @@ mod tests {
         }
 
         let peers = vec![
-            Peer { address: "127.0.0.1:8081".to_string() },
-            Peer { address: "127.0.0.1:8082".to_string() },
-            Peer { address: "127.0.0.1:8083".to_string() },
+            Peer::new("127.0.0.1:8081"),
+            Peer::new("127.0.0.1:8082"),
+            Peer::new("127.0.0.1:8083"),
         ];
 
-        let result = replicate_chunks(&peers, storage_dir.to_str().unwrap(), &file_id).await;
+        let network_stats = SharedNetworkStats::default();
+        let result = replicate_chunks(&peers, storage_root.to_str().unwrap(), &file_id, &test_context(&network_stats)).await;
         assert!(result.is_ok());
+        // Nothing listens on these ports, so every attempt is counted as an error.
+        // After chunk 0 fails on the first two peers, the untried third one is
+        // preferred for chunk 1.
+        let stats = network_stats.read().unwrap();
+        assert_eq!(stats.values().map(|s| s.errors).sum::<u64>(), 6);
+        assert!(stats["127.0.0.1:8081"].errors >= 1 && stats["127.0.0.1:8082"].errors >= 1);
+        assert!(stats["127.0.0.1:8083"].errors >= 1);
     }
 
     #[tokio::test]
@@ -111,10 +661,242 @@ mod tests {
         }
 
         let peers = vec![
-            Peer { address: "127.0.0.1:8081".to_string() },
+            Peer::new("127.0.0.1:8081"),
         ];
 
-        let result = replicate_chunks(&peers, storage_dir.to_str().unwrap(), &file_id).await;
+        let result = replicate_chunks(&peers, storage_root.to_str().unwrap(), &file_id, &test_context(&SharedNetworkStats::default())).await;
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_replication_queue_waits_for_file_tasks() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_id = Uuid::new_v4();
+        let storage_dir = crate::file_manager::storage::initialize_storage(temp_dir.path(), file_id).unwrap();
+        for i in 0..2 {
+            let metadata = ChunkMetadata::new(file_id, i, 5, 2);
+            crate::file_manager::storage::save_chunk(&storage_dir, &metadata, b"Chunk").unwrap();
+        }
+
+        let network_stats = SharedNetworkStats::default();
+        let context = ReplicationContext {
+            config: Config::with_storage_path(temp_dir.path()),
+            ..test_context(&network_stats)
+        };
+        let queue = ReplicationQueue::new(context, 2);
+        assert_eq!(queue.concurrency(), 2);
+        for (chunk_index, address) in [(0, "127.0.0.1:1"), (0, "127.0.0.1:2"), (1, "127.0.0.1:1")] {
+            queue.enqueue(ReplicationTask { file_id, chunk_index, target_peer: Peer::new(address) }).await;
+        }
+
+        // Nothing listens on these ports, so every task fails.
+        let summary = queue.wait_for_completion(&file_id).await;
+        assert_eq!(summary.failed, 3);
+        assert_eq!(summary.replicated_to, BTreeMap::from([(0, vec![]), (1, vec![])]));
+        assert_eq!(network_stats.read().unwrap().values().map(|s| s.errors).sum::<u64>(), 3);
+        assert_eq!(queue.wait_for_completion(&file_id).await, ReplicationSummary::default());
+    }
+
+    #[test]
+    fn test_replication_status_store() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = ReplicationStatusStore::open(temp_dir.path());
+        let file_id = Uuid::new_v4();
+        assert!(store.get_replication_status(&file_id).unwrap().is_empty());
+
+        store.record_sent(file_id, 1, "10.0.0.2:8080").unwrap();
+        store.record_sent(file_id, 0, "10.0.0.1:8080").unwrap();
+        store.record_sent(Uuid::new_v4(), 0, "10.0.0.1:8080").unwrap();
+        store.set_verified(file_id, 1, "10.0.0.2:8080", true).unwrap();
+
+        let records = ReplicationStatusStore::open(temp_dir.path()).get_replication_status(&file_id).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].chunk_index, records[0].verified), (0, false));
+        assert_eq!((records[1].chunk_index, records[1].verified), (1, true));
+
+        // Sending a chunk again replaces its record and clears the verification.
+        store.record_sent(file_id, 1, "10.0.0.2:8080").unwrap();
+        let records = store.get_replication_status(&file_id).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(!records[1].verified);
+    }
+
+    #[tokio::test]
+    async fn test_repair_under_replicated_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage_root = temp_dir.path();
+        let [well_replicated, under_replicated] = [Uuid::new_v4(), Uuid::new_v4()];
+        for file_id in [well_replicated, under_replicated] {
+            let storage_dir = crate::file_manager::storage::initialize_storage(storage_root, file_id).unwrap();
+            let metadata = ChunkMetadata::new(file_id, 0, 5, 1);
+            crate::file_manager::storage::save_chunk(&storage_dir, &metadata, b"Chunk").unwrap();
+        }
+
+        let local_peer = Peer::new("127.0.0.1:8080");
+        let peers: Vec<Peer> = [&local_peer.address, "127.0.0.1:8081", "127.0.0.1:8082", "127.0.0.1:8083"]
+            .into_iter()
+            .map(Peer::new)
+            .collect();
+        let dht = DHT::new();
+        for peer in &peers[..3] {
+            dht.register_file_location(well_replicated, peer.clone()).unwrap();
+        }
+        dht.register_file_location(under_replicated, local_peer.clone()).unwrap();
+
+        let network_stats = SharedNetworkStats::default();
+        let context = test_context(&network_stats);
+        let summary = repair_under_replicated_files(&dht, storage_root, &local_peer, &peers, 2, &context).await.unwrap();
+        assert_eq!(summary, RepairSummary { files_checked: 2, files_repaired: 1, files_failed: 0 });
+        // Nothing listens on these ports; each attempt shows up as an error.
+        assert_eq!(network_stats.read().unwrap().values().map(|s| s.errors).sum::<u64>(), 2);
+        assert!(!network_stats.read().unwrap().contains_key(&local_peer.address));
+
+        // Only 127.0.0.1:8083 lacks the well-replicated file, which is too few to reach four replicas.
+        let summary = repair_under_replicated_files(&dht, storage_root, &local_peer, &peers, 4, &context).await.unwrap();
+        assert_eq!(summary, RepairSummary { files_checked: 2, files_repaired: 1, files_failed: 1 });
+    }
+
+    fn test_config() -> Config {
+        Config::with_port(0)
+    }
+
+    fn test_context(network_stats: &SharedNetworkStats) -> ReplicationContext {
+        ReplicationContext {
+            network_stats: network_stats.clone(),
+            latency: PeerLatency::default(),
+            peer_load: PeerLoad::default(),
+            config: test_config(),
+            status: ReplicationStatusStore::open(std::env::temp_dir().join(Uuid::new_v4().to_string())),
+            reputation: ReputationStore::default(),
+        }
+    }
+
+    fn select(peers: &[Peer], chunk_index: usize, policy: ReplicationPolicy, latency: &PeerLatency, peer_load: &PeerLoad) -> Vec<String> {
+        select_peers_for_replication(peers, &Uuid::new_v4(), chunk_index, policy, latency, peer_load, &ReputationStore::default())
+            .unwrap()
+            .into_iter()
+            .map(|peer| peer.address.clone())
+            .collect()
+    }
+
+    fn latency_map(entries: &[(&str, u64)]) -> PeerLatency {
+        let latency = PeerLatency::default();
+        for (address, millis) in entries {
+            crate::peer::latency::record_latency(&latency, address, std::time::Duration::from_millis(*millis));
+        }
+        latency
+    }
+
+    #[test]
+    fn test_selection_prefers_low_latency() {
+        let peers = vec![Peer::new("10.0.0.1:8080"), Peer::new("10.0.0.2:8080"), Peer::new("10.0.0.3:8080")];
+        // With only three candidates every peer is in the diversity window, so
+        // the fastest is always chosen first and the slowest can still lose to diversity.
+        let latency = latency_map(&[("10.0.0.1:8080", 300), ("10.0.0.2:8080", 5), ("10.0.0.3:8080", 40)]);
+        let selected = select_peers_for_replication(&peers, &Uuid::new_v4(), 0, ReplicationPolicy::LatencyBased, &latency, &PeerLoad::default(), &ReputationStore::default()).unwrap();
+        assert_eq!(selected[0].address, "10.0.0.2:8080");
+    }
+
+    #[test]
+    fn test_selection_keeps_unknown_peers_out_of_window() {
+        let peers: Vec<Peer> = (1..=8).map(|i| Peer::new(format!("10.0.0.{}:8080", i))).collect();
+        let latency = latency_map(&[
+            ("10.0.0.8:8080", 10),
+            ("10.0.0.7:8080", 20),
+            ("10.0.0.6:8080", 30),
+            ("10.0.0.5:8080", 40),
+            ("10.0.0.4:8080", 900),
+        ]);
+        let selected = select_peers_for_replication(&peers, &Uuid::new_v4(), 0, ReplicationPolicy::LatencyBased, &latency, &PeerLoad::default(), &ReputationStore::default()).unwrap();
+        assert_eq!(selected.len(), REPLICATION_FACTOR);
+        assert_eq!(selected[0].address, "10.0.0.8:8080");
+        // The second slot is filled from the DIVERSITY_WINDOW fastest remaining peers,
+        // so peers without a measured latency are never reached.
+        let fastest = ["10.0.0.7:8080", "10.0.0.6:8080", "10.0.0.5:8080", "10.0.0.4:8080"];
+        assert!(fastest.contains(&selected[1].address.as_str()));
+    }
+
+    #[test]
+    fn test_selection_without_latency_falls_back_to_slice_order() {
+        let peers = vec![Peer::new("10.0.0.1:8080"), Peer::new("10.0.0.2:8080")];
+        let selected = select_peers_for_replication(&peers, &Uuid::new_v4(), 0, ReplicationPolicy::LatencyBased, &PeerLatency::default(), &PeerLoad::default(), &ReputationStore::default()).unwrap();
+        let addresses: Vec<&str> = selected.iter().map(|p| p.address.as_str()).collect();
+        assert_eq!(addresses, ["10.0.0.1:8080", "10.0.0.2:8080"]);
+    }
+
+    #[test]
+    fn test_selection_prefers_reliable_peers() {
+        let peers: Vec<Peer> = (1..=3).map(|i| Peer::new(format!("10.0.0.{}:8080", i))).collect();
+        let reputation = ReputationStore::default();
+        reputation.record_failure("10.0.0.1:8080");
+        reputation.record_success("10.0.0.3:8080");
+        reputation.record_failure("10.0.0.3:8080");
+        let pick = |reputation: &ReputationStore| -> Vec<String> {
+            select_peers_for_replication(&peers, &Uuid::new_v4(), 0, ReplicationPolicy::RoundRobin, &PeerLatency::default(), &PeerLoad::default(), reputation)
+                .unwrap()
+                .into_iter()
+                .map(|peer| peer.address.clone())
+                .collect()
+        };
+        // 10.0.0.1 has only failed, so it is deprioritized while two others remain.
+        assert_eq!(pick(&reputation), ["10.0.0.2:8080", "10.0.0.3:8080"]);
+
+        // With a single trusted peer the deprioritized one fills the last slot.
+        reputation.record_failure("10.0.0.3:8080");
+        reputation.record_failure("10.0.0.3:8080");
+        reputation.record_failure("10.0.0.3:8080");
+        reputation.record_failure("10.0.0.3:8080");
+        assert_eq!(pick(&reputation).len(), REPLICATION_FACTOR);
+    }
+
+    #[test]
+    fn test_round_robin_rotates_per_chunk() {
+        let peers: Vec<Peer> = (1..=3).map(|i| Peer::new(format!("10.0.0.{}:8080", i))).collect();
+        let none = (PeerLatency::default(), PeerLoad::default());
+        assert_eq!(select(&peers, 0, ReplicationPolicy::RoundRobin, &none.0, &none.1), ["10.0.0.1:8080", "10.0.0.2:8080"]);
+        assert_eq!(select(&peers, 1, ReplicationPolicy::RoundRobin, &none.0, &none.1), ["10.0.0.2:8080", "10.0.0.3:8080"]);
+        assert_eq!(select(&peers, 2, ReplicationPolicy::RoundRobin, &none.0, &none.1), ["10.0.0.3:8080", "10.0.0.1:8080"]);
+    }
+
+    #[test]
+    fn test_random_picks_distinct_known_peers() {
+        let peers: Vec<Peer> = (1..=5).map(|i| Peer::new(format!("10.0.0.{}:8080", i))).collect();
+        for _ in 0..20 {
+            let selected = select(&peers, 0, ReplicationPolicy::Random, &PeerLatency::default(), &PeerLoad::default());
+            assert_eq!(selected.len(), REPLICATION_FACTOR);
+            assert_ne!(selected[0], selected[1]);
+            assert!(selected.iter().all(|a| peers.iter().any(|p| &p.address == a)));
+        }
+    }
+
+    #[test]
+    fn test_least_loaded_prefers_idle_peers() {
+        let peers: Vec<Peer> = (1..=3).map(|i| Peer::new(format!("10.0.0.{}:8080", i))).collect();
+        let peer_load = PeerLoad::default();
+        let _busy = LoadGuard::start(&peer_load, "10.0.0.1:8080");
+        {
+            let _also_busy = LoadGuard::start(&peer_load, "10.0.0.2:8080");
+            let _twice = LoadGuard::start(&peer_load, "10.0.0.2:8080");
+            assert_eq!(load_of(&peer_load, "10.0.0.2:8080"), 2);
+            assert_eq!(
+                select(&peers, 0, ReplicationPolicy::LeastLoaded, &PeerLatency::default(), &peer_load),
+                ["10.0.0.3:8080", "10.0.0.1:8080"]
+            );
+        }
+        assert_eq!(load_of(&peer_load, "10.0.0.2:8080"), 0);
+        assert_eq!(
+            select(&peers, 0, ReplicationPolicy::LeastLoaded, &PeerLatency::default(), &peer_load),
+            ["10.0.0.2:8080", "10.0.0.3:8080"]
+        );
+    }
+
+    #[test]
+    fn test_latency_based_uses_ping_times() {
+        let peers: Vec<Peer> = (1..=2).map(|i| Peer::new(format!("10.0.0.{}:8080", i))).collect();
+        let latency = latency_map(&[("10.0.0.2:8080", 5), ("10.0.0.1:8080", 50)]);
+        assert_eq!(
+            select(&peers, 0, ReplicationPolicy::LatencyBased, &latency, &PeerLoad::default()),
+            ["10.0.0.2:8080", "10.0.0.1:8080"]
+        );
+    }
 }
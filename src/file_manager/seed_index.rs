@@ -0,0 +1,117 @@
+// src/file_manager/seed_index.rs
+
+//! The files `seed` uploaded from its directory, so a restart only uploads
+//! what was added or changed since.
+
+use crate::json::{self, JsonError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use thiserror::Error;
+use uuid::Uuid;
+
+pub const SEED_INDEX_FILENAME: &str = "seeded.json";
+
+#[derive(Error, Debug)]
+pub enum SeedIndexError {
+    #[error("I/O Error: {0}")]
+    IoError(#[from] io::Error),
+
+    #[error("Serialization Error: {0}")]
+    SerializationError(#[from] JsonError),
+}
+
+/// A seeded file as it was when uploaded. `modified_ms` is its modification
+/// time in milliseconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeededFile {
+    pub path: PathBuf,
+    pub file_id: Uuid,
+    pub size: u64,
+    pub modified_ms: u64,
+}
+
+impl SeededFile {
+    /// Whether `metadata`, read from the file now, shows the size and
+    /// modification time it was uploaded with.
+    pub fn is_current(&self, metadata: &fs::Metadata) -> bool {
+        self.size == metadata.len() && self.modified_ms == modified_ms(metadata)
+    }
+}
+
+pub fn modified_ms(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+/// `<storage_path>/seeded.json`, held in memory by path and written back on
+/// [`SeedIndex::save`].
+#[derive(Debug)]
+pub struct SeedIndex {
+    path: PathBuf,
+    files: BTreeMap<PathBuf, SeededFile>,
+}
+
+impl SeedIndex {
+    pub fn open<P: AsRef<Path>>(storage_root: P) -> Result<Self, SeedIndexError> {
+        let path = storage_root.as_ref().join(SEED_INDEX_FILENAME);
+        let files: Vec<SeededFile> = match fs::read_to_string(&path) {
+            Ok(contents) => json::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(SeedIndex { path, files: files.into_iter().map(|file| (file.path.clone(), file)).collect() })
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&SeededFile> {
+        self.files.get(path)
+    }
+
+    /// Records `file`, returning the entry it replaces.
+    pub fn insert(&mut self, file: SeededFile) -> Option<SeededFile> {
+        self.files.insert(file.path.clone(), file)
+    }
+
+    pub fn remove(&mut self, path: &Path) -> Option<SeededFile> {
+        self.files.remove(path)
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &SeededFile> {
+        self.files.values()
+    }
+
+    pub fn save(&self) -> Result<(), SeedIndexError> {
+        let files: Vec<&SeededFile> = self.files.values().collect();
+        json::write_atomic(&self.path, &files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seed_index_round_trip() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = temp_dir.path().join("a.txt");
+        fs::write(&source, b"seed me").unwrap();
+        let metadata = fs::metadata(&source).unwrap();
+
+        let mut index = SeedIndex::open(temp_dir.path()).unwrap();
+        let file = SeededFile { path: source.clone(), file_id: Uuid::new_v4(), size: 7, modified_ms: modified_ms(&metadata) };
+        assert!(index.insert(file.clone()).is_none());
+        index.save().unwrap();
+
+        let index = SeedIndex::open(temp_dir.path()).unwrap();
+        assert_eq!(index.get(&source), Some(&file));
+        assert!(file.is_current(&metadata));
+        fs::write(&source, b"seed me again").unwrap();
+        assert!(!file.is_current(&fs::metadata(&source).unwrap()));
+    }
+}
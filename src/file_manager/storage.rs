@@ -1,12 +1,31 @@
 // src/file_manager/storage.rs
 
 use crate::file_manager::chunker::ChunkMetadata;
+use crate::file_manager::download_state::DOWNLOADS_DIRNAME;
+use crate::config::Config;
+use crate::file_manager::hash::{format_tagged, parse_tagged, HashAlgorithm};
+use crate::file_manager::manifest::load_manifest;
+use crate::indexing::dht::{DhtError, DHT};
+use crate::peer::connection::fetch_chunk_from_peer;
+use log::{error, info, warn};
+use crate::json::JsonError;
+use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::io::{self, Write, Read};
+use std::io::{self, Write, Read, SeekFrom};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWriteExt, ReadBuf};
+use tokio::sync::RwLock;
 use uuid::Uuid;
 use thiserror::Error;
 
+const PIN_MARKER: &str = ".pinned";
+
 /// Represents errors that can occur during storage operations.
 #[derive(Error, Debug)]
 pub enum StorageError {
@@ -15,6 +34,27 @@ pub enum StorageError {
 
     #[error("Invalid Path: {0}")]
     InvalidPath(String),
+
+    #[error("Serialization Error: {0}")]
+    SerializationError(#[from] JsonError),
+
+    #[error("Invalid hash file for chunk {0}")]
+    InvalidHash(usize),
+
+    #[error("DHT Error: {0}")]
+    DhtError(#[from] DhtError),
+
+    #[error("Assembled file {0} does not match its recorded file hash")]
+    FileHashMismatch(Uuid),
+
+    #[error("File {0} is missing chunks")]
+    IncompleteFile(Uuid),
+
+    #[error("Chunk {chunk_index} of file {file_id} is not stored")]
+    ChunkNotFound { file_id: Uuid, chunk_index: usize },
+
+    #[error("No manifest is stored for file {0}")]
+    ManifestNotFound(Uuid),
 }
 
 /// Initializes the storage directory for a given file.
@@ -29,16 +69,17 @@ pub fn initialize_storage<P: AsRef<Path>>(
 }
 
 /// Saves a file chunk to the storage directory.
-/// The chunk is saved as `chunk_<index>.bin`.
+/// The chunk is saved as `chunk_<index>.bin`, with its hash in `chunk_<index>.hash` as
+/// `blake3:<hex>`; see [`format_tagged`].
 pub fn save_chunk<P: AsRef<Path>>(
     storage_dir: P,
     metadata: &ChunkMetadata,
     data: &[u8],
 ) -> Result<(), StorageError> {
-    let chunk_filename = format!("chunk_{}.bin", metadata.chunk_index);
-    let chunk_path = storage_dir.as_ref().join(chunk_filename);
-    let mut file = File::create(chunk_path)?;
+    let mut file = File::create(chunk_path(&storage_dir, metadata.chunk_index))?;
     file.write_all(data)?;
+    let algorithm = HashAlgorithm::default();
+    fs::write(hash_path(&storage_dir, metadata.chunk_index), format_tagged(algorithm, &algorithm.digest(data)))?;
     Ok(())
 }
 
@@ -48,14 +89,77 @@ pub fn get_chunk<P: AsRef<Path>>(
     storage_dir: P,
     chunk_index: usize,
 ) -> Result<Vec<u8>, StorageError> {
-    let chunk_filename = format!("chunk_{}.bin", chunk_index);
-    let chunk_path = storage_dir.as_ref().join(chunk_filename);
-    let mut file = File::open(chunk_path)?;
+    let mut file = File::open(chunk_path(&storage_dir, chunk_index)).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => StorageError::ChunkNotFound { file_id: file_id_of(storage_dir.as_ref()), chunk_index },
+        _ => e.into(),
+    })?;
     let mut data = Vec::new();
     file.read_to_end(&mut data)?;
     Ok(data)
 }
 
+/// Checks whether a chunk is present without reading the directory or the chunk.
+pub fn chunk_exists<P: AsRef<Path>>(storage_dir: P, chunk_index: usize) -> bool {
+    chunk_path(storage_dir, chunk_index).exists()
+}
+
+/// Returns the size of a stored chunk from its file metadata.
+pub fn chunk_size_on_disk<P: AsRef<Path>>(
+    storage_dir: P,
+    chunk_index: usize,
+) -> Result<u64, StorageError> {
+    Ok(fs::metadata(chunk_path(storage_dir, chunk_index))?.len())
+}
+
+/// Returns the hash recorded for a chunk when it was saved, with the
+/// algorithm it was made with. Chunks stored before hash files existed are
+/// hashed from their data.
+pub fn recorded_chunk_hash<P: AsRef<Path>>(
+    storage_dir: P,
+    chunk_index: usize,
+) -> Result<(HashAlgorithm, [u8; 32]), StorageError> {
+    match fs::read_to_string(hash_path(&storage_dir, chunk_index)) {
+        Ok(contents) => parse_tagged(&contents).ok_or(StorageError::InvalidHash(chunk_index)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let algorithm = HashAlgorithm::default();
+            Ok((algorithm, algorithm.digest(&get_chunk(storage_dir, chunk_index)?)))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns a chunk's `algorithm` hash: the recorded one if it was made with
+/// `algorithm`, otherwise one computed from the data, e.g. for a chunk of a
+/// SHA-256 file saved since chunks were recorded with BLAKE3.
+pub fn chunk_hash<P: AsRef<Path>>(
+    storage_dir: P,
+    chunk_index: usize,
+    algorithm: HashAlgorithm,
+) -> Result<[u8; 32], StorageError> {
+    match recorded_chunk_hash(&storage_dir, chunk_index)? {
+        (recorded, digest) if recorded == algorithm => Ok(digest),
+        _ => Ok(algorithm.digest(&get_chunk(storage_dir, chunk_index)?)),
+    }
+}
+
+/// The file ID a storage directory is named after; nil for directories
+/// named otherwise, such as those of tests.
+pub(crate) fn file_id_of(storage_dir: &Path) -> Uuid {
+    storage_dir
+        .file_name()
+        .and_then(OsStr::to_str)
+        .and_then(|name| Uuid::parse_str(name).ok())
+        .unwrap_or_default()
+}
+
+fn chunk_path<P: AsRef<Path>>(storage_dir: P, chunk_index: usize) -> PathBuf {
+    storage_dir.as_ref().join(format!("chunk_{}.bin", chunk_index))
+}
+
+fn hash_path<P: AsRef<Path>>(storage_dir: P, chunk_index: usize) -> PathBuf {
+    storage_dir.as_ref().join(format!("chunk_{}.hash", chunk_index))
+}
+
 /// Lists all stored chunks for a given file.
 /// Returns a sorted list of chunk indices.
 pub fn list_chunks<P: AsRef<Path>>(
@@ -64,16 +168,24 @@ pub fn list_chunks<P: AsRef<Path>>(
     let mut chunk_indices = Vec::new();
     for entry in fs::read_dir(storage_dir)? {
         let entry = entry?;
-        let path = entry.path();
-        if path.is_file() {
-            if let Some(filename) = path.file_name().and_then(|n| n.to_str()) {
-                if filename.starts_with("chunk_") && filename.ends_with(".bin") {
-                    if let Some(index_str) = filename.strip_prefix("chunk_").and_then(|s| s.strip_suffix(".bin")) {
-                        if let Ok(index) = index_str.parse::<usize>() {
-                            chunk_indices.push(index);
-                        }
-                    }
-                }
+        if entry.file_type()?.is_file() {
+            if let Some(index) = chunk_index_of(&entry.file_name()) {
+                chunk_indices.push(index);
+            }
+        }
+    }
+    chunk_indices.sort_unstable();
+    Ok(chunk_indices)
+}
+
+/// [`list_chunks`] for async callers, without blocking the runtime on directory reads.
+pub async fn list_chunks_async(storage_dir: &Path) -> Result<Vec<usize>, StorageError> {
+    let mut chunk_indices = Vec::new();
+    let mut dir = tokio::fs::read_dir(storage_dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        if entry.file_type().await?.is_file() {
+            if let Some(index) = chunk_index_of(&entry.file_name()) {
+                chunk_indices.push(index);
             }
         }
     }
@@ -81,13 +193,908 @@ pub fn list_chunks<P: AsRef<Path>>(
     Ok(chunk_indices)
 }
 
+/// A stored chunk file as found on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkOnDisk {
+    pub index: usize,
+    pub size_bytes: u64,
+    pub modified: SystemTime,
+    pub path: PathBuf,
+}
+
+/// Lists stored chunks with their size and modification time, sorted by index.
+pub fn list_chunks_with_metadata(storage_dir: &Path) -> Result<Vec<ChunkOnDisk>, StorageError> {
+    let mut chunks = Vec::new();
+    for entry in fs::read_dir(storage_dir)? {
+        let entry = entry?;
+        let Some(index) = chunk_index_of(&entry.file_name()) else {
+            continue;
+        };
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            chunks.push(ChunkOnDisk {
+                index,
+                size_bytes: metadata.len(),
+                modified: metadata.modified()?,
+                path: entry.path(),
+            });
+        }
+    }
+    chunks.sort_unstable_by_key(|chunk| chunk.index);
+    Ok(chunks)
+}
+
+/// Every chunk file under a storage root, as returned by [`iter_storage`].
+/// Files are visited in directory order, not sorted.
+pub struct StorageIterator {
+    /// `None` once the root has been read to the end, or could not be read.
+    root: Option<fs::ReadDir>,
+    /// The file directory being read, if any.
+    current: Option<(Uuid, fs::ReadDir)>,
+    /// Error opening the root, yielded once.
+    pending_error: Option<StorageError>,
+}
+
+/// Walks `storage_root`, yielding one item per chunk file. Plain files and
+/// the node's bookkeeping directories are skipped; any other directory that is
+/// not named after a file ID is skipped with a warning. Files or directories
+/// removed while the walk is in progress are skipped too, so it can run while
+/// files are being deleted.
+pub fn iter_storage(storage_root: &Path) -> StorageIterator {
+    match fs::read_dir(storage_root) {
+        Ok(root) => StorageIterator { root: Some(root), current: None, pending_error: None },
+        Err(e) => StorageIterator { root: None, current: None, pending_error: Some(e.into()) },
+    }
+}
+
+impl StorageIterator {
+    /// Opens the next file directory; `None` once the root is exhausted.
+    fn next_file_dir(&mut self) -> Option<Result<(), StorageError>> {
+        loop {
+            let entry = match self.root.as_mut()?.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) if e.kind() == io::ErrorKind::NotFound => continue,
+                Some(Err(e)) => return Some(Err(e.into())),
+                None => {
+                    self.root = None;
+                    return None;
+                }
+            };
+            let path = entry.path();
+            if entry.file_type().is_ok_and(|file_type| file_type.is_file()) {
+                // The node's own bookkeeping, such as `history.json`.
+                continue;
+            }
+            let name = entry.file_name();
+            let Some(file_id) = name.to_str().and_then(|name| Uuid::parse_str(name).ok()) else {
+                if !is_bookkeeping_dir(&name) {
+                    warn!("Skipping {}: not a file directory", path.display());
+                }
+                continue;
+            };
+            match fs::read_dir(&path) {
+                Ok(dir) => {
+                    self.current = Some((file_id, dir));
+                    return Some(Ok(()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) if e.kind() == io::ErrorKind::NotADirectory => {
+                    warn!("Skipping {}: not a file directory", path.display());
+                    continue;
+                }
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+/// Directories under the storage root that are not file directories but are
+/// expected there: in-progress download state, and the copies
+/// [`move_chunks`] makes before renaming them into place.
+fn is_bookkeeping_dir(name: &OsStr) -> bool {
+    name == DOWNLOADS_DIRNAME || name.to_str().is_some_and(|name| name.ends_with(".tmp"))
+}
+
+impl Iterator for StorageIterator {
+    type Item = Result<(Uuid, ChunkOnDisk), StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.pending_error.take() {
+            return Some(Err(e));
+        }
+        loop {
+            let Some((file_id, dir)) = self.current.as_mut() else {
+                if let Err(e) = self.next_file_dir()? {
+                    return Some(Err(e));
+                }
+                continue;
+            };
+            let file_id = *file_id;
+            let entry = match dir.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) if e.kind() == io::ErrorKind::NotFound => continue,
+                Some(Err(e)) => return Some(Err(e.into())),
+                None => {
+                    self.current = None;
+                    continue;
+                }
+            };
+            let Some(index) = chunk_index_of(&entry.file_name()) else {
+                continue;
+            };
+            let metadata = match entry.metadata() {
+                Ok(metadata) if metadata.is_file() => metadata,
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let chunk = metadata.modified().map(|modified| ChunkOnDisk {
+                index,
+                size_bytes: metadata.len(),
+                modified,
+                path: entry.path(),
+            });
+            return Some(chunk.map(|chunk| (file_id, chunk)).map_err(StorageError::from));
+        }
+    }
+}
+
+/// Parses the index out of a `chunk_<index>.bin` file name.
+fn chunk_index_of(file_name: &OsStr) -> Option<usize> {
+    file_name.to_str()?.strip_prefix("chunk_")?.strip_suffix(".bin")?.parse().ok()
+}
+
+/// Returns the total size in bytes of everything stored for a given file.
+pub fn stored_file_size<P: AsRef<Path>>(
+    storage_root: P,
+    file_id: Uuid,
+) -> Result<u64, StorageError> {
+    let file_dir = storage_root.as_ref().join(file_id.to_string());
+    let mut total = 0;
+    for entry in fs::read_dir(file_dir)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Returns the total size in bytes of the chunks stored under `storage_root`.
+pub fn storage_usage<P: AsRef<Path>>(storage_root: P) -> Result<u64, StorageError> {
+    let mut total = 0;
+    for chunk in iter_storage(storage_root.as_ref()) {
+        total += chunk?.1.size_bytes;
+    }
+    Ok(total)
+}
+
+/// Lists the IDs of all files with a directory under `storage_root`, sorted.
+/// Entries whose names are not UUIDs are skipped.
+pub fn list_all_files<P: AsRef<Path>>(storage_root: P) -> Result<Vec<Uuid>, StorageError> {
+    let mut file_ids = Vec::new();
+    for entry in fs::read_dir(storage_root)? {
+        let path = entry?.path();
+        if let Some(file_id) = path.file_name().and_then(|n| n.to_str()).and_then(|n| Uuid::parse_str(n).ok()) {
+            if path.is_dir() {
+                file_ids.push(file_id);
+            }
+        }
+    }
+    file_ids.sort_unstable();
+    Ok(file_ids)
+}
+
+/// [`list_all_files`], each with whether it is pinned.
+pub fn list_all_files_with_pinned<P: AsRef<Path>>(storage_root: P) -> Result<Vec<(Uuid, bool)>, StorageError> {
+    let storage_root = storage_root.as_ref();
+    Ok(list_all_files(storage_root)?
+        .into_iter()
+        .map(|file_id| (file_id, is_pinned(storage_root, &file_id)))
+        .collect())
+}
+
+/// Marks a stored file as pinned by creating `<file_id>/.pinned`.
+/// Pinned files are never removed by cleanup.
+pub fn pin_file<P: AsRef<Path>>(
+    storage_root: P,
+    file_id: Uuid,
+) -> Result<(), StorageError> {
+    let file_dir = storage_root.as_ref().join(file_id.to_string());
+    if !file_dir.is_dir() {
+        return Err(StorageError::InvalidPath(format!("File {} is not stored locally", file_id)));
+    }
+    File::create(file_dir.join(PIN_MARKER))?;
+    Ok(())
+}
+
+/// Removes the pin marker of a file. Unpinning a file that isn't pinned is a no-op.
+pub fn unpin_file<P: AsRef<Path>>(
+    storage_root: P,
+    file_id: Uuid,
+) -> Result<(), StorageError> {
+    match fs::remove_file(storage_root.as_ref().join(file_id.to_string()).join(PIN_MARKER)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+pub fn is_pinned<P: AsRef<Path>>(storage_root: P, file_id: &Uuid) -> bool {
+    storage_root.as_ref().join(file_id.to_string()).join(PIN_MARKER).exists()
+}
+
+/// Deletes the storage directory of a given file along with all of its chunks.
+pub fn delete_file<P: AsRef<Path>>(
+    storage_root: P,
+    file_id: Uuid,
+) -> Result<(), StorageError> {
+    let file_dir = storage_root.as_ref().join(file_id.to_string());
+    fs::remove_dir_all(file_dir)?;
+    Ok(())
+}
+
+/// Moves everything stored for `file_id` from `src_root` to `dst_root`.
+///
+/// Every file in the source directory is copied into `<file_id>.tmp` under
+/// `dst_root` and each chunk is checked against its recorded hash. Only then is
+/// the copy renamed into place and the source removed. If a chunk does not
+/// match, the partial copy is removed and the source is left untouched. Fails
+/// with [`StorageError::InvalidPath`] if both roots are the same directory.
+pub fn move_chunks(src_root: &Path, dst_root: &Path, file_id: Uuid) -> Result<(), StorageError> {
+    let src_dir = src_root.join(file_id.to_string());
+    if !src_dir.is_dir() {
+        return Err(StorageError::InvalidPath(format!("File {} is not stored in {}", file_id, src_root.display())));
+    }
+    fs::create_dir_all(dst_root)?;
+    if fs::canonicalize(src_root)? == fs::canonicalize(dst_root)? {
+        return Err(StorageError::InvalidPath(format!("{} is already the storage directory", src_root.display())));
+    }
+
+    let tmp_dir = dst_root.join(format!("{}.tmp", file_id));
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir)?;
+    }
+    fs::create_dir(&tmp_dir)?;
+    for entry in fs::read_dir(&src_dir)? {
+        let path = entry?.path();
+        if let Some(name) = path.file_name().filter(|_| path.is_file()) {
+            fs::copy(&path, tmp_dir.join(name))?;
+        }
+    }
+
+    for chunk_index in list_chunks(&src_dir)? {
+        let (algorithm, expected) = recorded_chunk_hash(&src_dir, chunk_index)?;
+        if algorithm.digest(&get_chunk(&tmp_dir, chunk_index)?) != expected {
+            fs::remove_dir_all(&tmp_dir)?;
+            return Err(StorageError::InvalidHash(chunk_index));
+        }
+    }
+
+    let dst_dir = dst_root.join(file_id.to_string());
+    if dst_dir.exists() {
+        // Left by an earlier, interrupted move: the verified copy replaces it file by file.
+        for entry in fs::read_dir(&tmp_dir)? {
+            let entry = entry?;
+            fs::rename(entry.path(), dst_dir.join(entry.file_name()))?;
+        }
+        fs::remove_dir(&tmp_dir)?;
+    } else {
+        fs::rename(&tmp_dir, &dst_dir)?;
+    }
+    fs::remove_dir_all(&src_dir)?;
+    Ok(())
+}
+
+/// Where chunk data lives. [`FileSystemBackend`] is the on-disk layout used
+/// everywhere else in this module; [`MemoryBackend`] keeps chunks in memory,
+/// for tests that don't need real files.
+pub trait StorageBackend: Send + Sync {
+    fn save_chunk(&self, file_id: Uuid, index: usize, data: &[u8]) -> Result<(), StorageError>;
+
+    /// Fails with [`StorageError::ChunkNotFound`] if the chunk is not stored.
+    fn get_chunk(&self, file_id: Uuid, index: usize) -> Result<Vec<u8>, StorageError>;
+
+    /// Indices of the stored chunks of `file_id`, sorted; empty for unknown files.
+    fn list_chunks(&self, file_id: Uuid) -> Result<Vec<usize>, StorageError>;
+
+    fn delete_chunk(&self, file_id: Uuid, index: usize) -> Result<(), StorageError>;
+
+    /// Removes everything stored for `file_id`.
+    fn delete_file(&self, file_id: Uuid) -> Result<(), StorageError> {
+        for index in self.list_chunks(file_id)? {
+            self.delete_chunk(file_id, index)?;
+        }
+        Ok(())
+    }
+}
+
+/// Chunks as `<root>/<file_id>/chunk_<index>.bin` with their `.hash` files.
+#[derive(Debug, Clone)]
+pub struct FileSystemBackend {
+    root: PathBuf,
+}
+
+impl FileSystemBackend {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        FileSystemBackend { root: root.as_ref().to_path_buf() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl StorageBackend for FileSystemBackend {
+    fn save_chunk(&self, file_id: Uuid, index: usize, data: &[u8]) -> Result<(), StorageError> {
+        let storage_dir = initialize_storage(&self.root, file_id)?;
+        // Only the index is used to place the chunk.
+        save_chunk(storage_dir, &ChunkMetadata::new(file_id, index, data.len(), 0), data)
+    }
+
+    fn get_chunk(&self, file_id: Uuid, index: usize) -> Result<Vec<u8>, StorageError> {
+        get_chunk(self.root.join(file_id.to_string()), index)
+    }
+
+    fn list_chunks(&self, file_id: Uuid) -> Result<Vec<usize>, StorageError> {
+        match list_chunks(self.root.join(file_id.to_string())) {
+            Err(StorageError::IoError(e)) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            result => result,
+        }
+    }
+
+    fn delete_chunk(&self, file_id: Uuid, index: usize) -> Result<(), StorageError> {
+        let storage_dir = self.root.join(file_id.to_string());
+        fs::remove_file(chunk_path(&storage_dir, index))?;
+        match fs::remove_file(hash_path(&storage_dir, index)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes the whole file directory, manifest and pin marker included.
+    fn delete_file(&self, file_id: Uuid) -> Result<(), StorageError> {
+        delete_file(&self.root, file_id)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    chunks: Mutex<HashMap<(Uuid, usize), Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn save_chunk(&self, file_id: Uuid, index: usize, data: &[u8]) -> Result<(), StorageError> {
+        self.chunks.lock().unwrap().insert((file_id, index), data.to_vec());
+        Ok(())
+    }
+
+    fn get_chunk(&self, file_id: Uuid, index: usize) -> Result<Vec<u8>, StorageError> {
+        self.chunks
+            .lock()
+            .unwrap()
+            .get(&(file_id, index))
+            .cloned()
+            .ok_or(StorageError::ChunkNotFound { file_id, chunk_index: index })
+    }
+
+    fn list_chunks(&self, file_id: Uuid) -> Result<Vec<usize>, StorageError> {
+        let mut indices: Vec<usize> = self.chunks.lock().unwrap().keys().filter(|(id, _)| *id == file_id).map(|(_, i)| *i).collect();
+        indices.sort_unstable();
+        Ok(indices)
+    }
+
+    fn delete_chunk(&self, file_id: Uuid, index: usize) -> Result<(), StorageError> {
+        self.chunks.lock().unwrap().remove(&(file_id, index));
+        Ok(())
+    }
+}
+
+/// Serializes access to stored files: any number of readers, or one writer,
+/// per file. Files are locked independently. A file's lock is created when the
+/// file is first seen and dropped by [`StorageManager::delete_file`].
+#[derive(Debug)]
+pub struct StorageManager<B: StorageBackend = FileSystemBackend> {
+    backend: Arc<B>,
+    locks: Arc<Mutex<HashMap<Uuid, Arc<RwLock<()>>>>>,
+}
+
+impl<B: StorageBackend> Clone for StorageManager<B> {
+    fn clone(&self) -> Self {
+        StorageManager {
+            backend: self.backend.clone(),
+            locks: self.locks.clone(),
+        }
+    }
+}
+
+impl StorageManager<FileSystemBackend> {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self::with_backend(FileSystemBackend::new(root))
+    }
+
+    pub fn root(&self) -> &Path {
+        self.backend.root()
+    }
+
+    /// See [`garbage_collect`].
+    pub fn garbage_collect(&self, min_age: Duration) -> Result<GcReport, StorageError> {
+        garbage_collect(self.root(), min_age)
+    }
+}
+
+impl<B: StorageBackend> StorageManager<B> {
+    pub fn with_backend(backend: B) -> Self {
+        StorageManager {
+            backend: Arc::new(backend),
+            locks: Arc::default(),
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// The table mutex is held only long enough to look up the file's lock.
+    fn file_lock(&self, file_id: Uuid) -> Arc<RwLock<()>> {
+        self.locks.lock().unwrap().entry(file_id).or_default().clone()
+    }
+
+    pub async fn save_chunk_managed(&self, metadata: &ChunkMetadata, data: &[u8]) -> Result<(), StorageError> {
+        let lock = self.file_lock(metadata.file_id);
+        let _guard = lock.write().await;
+        self.backend.save_chunk(metadata.file_id, metadata.chunk_index, data)
+    }
+
+    pub async fn get_chunk_managed(&self, file_id: Uuid, chunk_index: usize) -> Result<Vec<u8>, StorageError> {
+        let lock = self.file_lock(file_id);
+        let _guard = lock.read().await;
+        self.backend.get_chunk(file_id, chunk_index)
+    }
+
+    pub async fn list_chunks_managed(&self, file_id: Uuid) -> Result<Vec<usize>, StorageError> {
+        let lock = self.file_lock(file_id);
+        let _guard = lock.read().await;
+        self.backend.list_chunks(file_id)
+    }
+
+    /// Deletes the file once no reader or writer holds it, and forgets its lock.
+    pub async fn delete_file(&self, file_id: Uuid) -> Result<(), StorageError> {
+        let lock = self.file_lock(file_id);
+        let _guard = lock.write().await;
+        self.backend.delete_file(file_id)?;
+        self.locks.lock().unwrap().remove(&file_id);
+        Ok(())
+    }
+}
+
+/// Outcome of [`garbage_collect`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    pub files_removed: usize,
+    pub bytes_freed: u64,
+}
+
+/// Removes leftovers of interrupted writes anywhere under `storage_root`:
+/// `*.tmp` files that were never renamed into place, and `chunk_<n>.hash`
+/// files whose chunk is gone. Files modified less than `min_age` ago are
+/// kept, since a write may still be in progress.
+pub fn garbage_collect(storage_root: &Path, min_age: Duration) -> Result<GcReport, StorageError> {
+    let mut report = GcReport::default();
+    collect_garbage_in(storage_root, min_age, SystemTime::now(), &mut report)?;
+    Ok(report)
+}
+
+fn collect_garbage_in(dir: &Path, min_age: Duration, now: SystemTime, report: &mut GcReport) -> Result<(), StorageError> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_garbage_in(&path, min_age, now, report)?;
+            continue;
+        }
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let orphaned_hash = name
+            .strip_prefix("chunk_")
+            .and_then(|n| n.strip_suffix(".hash"))
+            .is_some_and(|index| !dir.join(format!("chunk_{}.bin", index)).exists());
+        if !(name.ends_with(".tmp") || orphaned_hash) {
+            continue;
+        }
+        let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+        if age >= min_age {
+            fs::remove_file(&path)?;
+            info!("Removed stale file {}", path.display());
+            report.files_removed += 1;
+            report.bytes_freed += metadata.len();
+        }
+    }
+    Ok(())
+}
+
+/// Outcome of [`scan_and_repair`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    pub chunks_checked: usize,
+    /// Chunks that were missing or did not match their recorded hash.
+    pub corrupted_found: usize,
+    pub repaired: usize,
+    /// Damaged chunks that no peer could provide a verified copy of.
+    pub unrepaired: usize,
+}
+
+/// Returns the indices of chunks in `0..total_chunks` that are missing or
+/// whose data no longer matches the `.hash` file written alongside it.
+pub fn find_damaged_chunks(storage_dir: &Path, total_chunks: usize) -> Result<Vec<usize>, StorageError> {
+    let mut damaged = Vec::new();
+    for chunk_index in 0..total_chunks {
+        if !chunk_is_intact(storage_dir, chunk_index)? {
+            damaged.push(chunk_index);
+        }
+    }
+    Ok(damaged)
+}
+
+/// Whether a chunk is on disk and matches its `.hash` file.
+fn chunk_is_intact(storage_dir: &Path, chunk_index: usize) -> Result<bool, StorageError> {
+    match get_chunk(storage_dir, chunk_index) {
+        Ok(data) => match recorded_chunk_hash(storage_dir, chunk_index) {
+            Ok((algorithm, expected)) => Ok(algorithm.digest(&data) == expected),
+            Err(StorageError::InvalidHash(_)) => Ok(false),
+            Err(e) => Err(e),
+        },
+        Err(StorageError::ChunkNotFound { .. }) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks every chunk of a stored file against its recorded hash. The chunk
+/// count comes from the manifest, so missing chunks are found too; without a
+/// manifest only the chunks up to the highest one on disk are checked.
+/// Returns the damaged chunk indices with a report of the scan.
+fn scan_file(storage_dir: &Path) -> Result<(Vec<usize>, RepairReport), StorageError> {
+    let total_chunks = match load_manifest(storage_dir) {
+        Ok(manifest) => manifest.total_chunks,
+        Err(StorageError::ManifestNotFound(_)) => list_chunks(storage_dir)?.last().map_or(0, |last| last + 1),
+        Err(e) => return Err(e),
+    };
+    let damaged = find_damaged_chunks(storage_dir, total_chunks)?;
+    let report = RepairReport {
+        chunks_checked: total_chunks,
+        corrupted_found: damaged.len(),
+        ..RepairReport::default()
+    };
+    Ok((damaged, report))
+}
+
+/// Reports damaged chunks of a stored file without repairing them.
+pub fn verify_file(storage_root: &Path, file_id: Uuid) -> Result<RepairReport, StorageError> {
+    Ok(scan_file(&storage_root.join(file_id.to_string()))?.1)
+}
+
+/// A problem found by [`validate_storage_directory`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityIssue {
+    /// A file directory without a readable manifest.
+    MissingManifest(Uuid),
+    /// A chunk the manifest lists that is not on disk.
+    MissingChunk { file_id: Uuid, expected_index: usize },
+    /// A chunk whose data does not match its `.hash` file.
+    CorruptChunk { file_id: Uuid, index: usize },
+    /// A chunk file outside any file directory, or past the manifest's last chunk.
+    OrphanChunk { path: PathBuf },
+}
+
+impl IntegrityIssue {
+    /// Whether chunk data the node should hold is missing or unusable, as
+    /// opposed to bookkeeping left behind by an interrupted write.
+    pub fn is_data_loss(&self) -> bool {
+        matches!(self, IntegrityIssue::MissingChunk { .. } | IntegrityIssue::CorruptChunk { .. })
+    }
+}
+
+impl std::fmt::Display for IntegrityIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntegrityIssue::MissingManifest(file_id) => write!(f, "file {} has no readable manifest", file_id),
+            IntegrityIssue::MissingChunk { file_id, expected_index } => {
+                write!(f, "chunk {} of file {} is missing", expected_index, file_id)
+            }
+            IntegrityIssue::CorruptChunk { file_id, index } => {
+                write!(f, "chunk {} of file {} does not match its hash", index, file_id)
+            }
+            IntegrityIssue::OrphanChunk { path } => write!(f, "{} belongs to no stored file", path.display()),
+        }
+    }
+}
+
+/// Checks everything under `storage_root` without changing it: every file
+/// directory against its manifest and every chunk against its hash. A node
+/// holding replicas of only some chunks of a file reports the others as
+/// missing, since the manifest lists them all.
+pub fn validate_storage_directory(storage_root: &Path) -> Result<Vec<IntegrityIssue>, StorageError> {
+    let mut issues = Vec::new();
+    for entry in fs::read_dir(storage_root)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_id = path.file_name().and_then(|n| n.to_str()).and_then(|n| Uuid::parse_str(n).ok());
+        match file_id {
+            Some(file_id) if entry.file_type()?.is_dir() => validate_file_directory(&path, file_id, &mut issues)?,
+            _ => find_orphan_chunks(&path, &mut issues)?,
+        }
+    }
+    Ok(issues)
+}
+
+fn validate_file_directory(storage_dir: &Path, file_id: Uuid, issues: &mut Vec<IntegrityIssue>) -> Result<(), StorageError> {
+    let present = list_chunks(storage_dir)?;
+    let total_chunks = match load_manifest(storage_dir) {
+        Ok(manifest) => Some(manifest.total_chunks),
+        Err(_) => {
+            issues.push(IntegrityIssue::MissingManifest(file_id));
+            None
+        }
+    };
+    if let Some(total_chunks) = total_chunks {
+        for expected_index in (0..total_chunks).filter(|i| present.binary_search(i).is_err()) {
+            issues.push(IntegrityIssue::MissingChunk { file_id, expected_index });
+        }
+    }
+    for index in present {
+        if total_chunks.is_some_and(|total| index >= total) {
+            issues.push(IntegrityIssue::OrphanChunk { path: chunk_path(storage_dir, index) });
+        } else if !chunk_is_intact(storage_dir, index)? {
+            issues.push(IntegrityIssue::CorruptChunk { file_id, index });
+        }
+    }
+    Ok(())
+}
+
+/// Every `chunk_<n>.bin` at or below `path`, which is not a file directory.
+fn find_orphan_chunks(path: &Path, issues: &mut Vec<IntegrityIssue>) -> Result<(), StorageError> {
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            find_orphan_chunks(&entry?.path(), issues)?;
+        }
+    } else if path.file_name().and_then(chunk_index_of).is_some() {
+        issues.push(IntegrityIssue::OrphanChunk { path: path.to_path_buf() });
+    }
+    Ok(())
+}
+
+/// Like [`verify_file`], then fetches a fresh copy of each damaged chunk from
+/// the peers the DHT lists for the file.
+pub async fn scan_and_repair(
+    storage_root: &Path,
+    file_id: Uuid,
+    dht: &DHT,
+    config: &Config,
+) -> Result<RepairReport, StorageError> {
+    let storage_dir = storage_root.join(file_id.to_string());
+    let (damaged, mut report) = scan_file(&storage_dir)?;
+    if damaged.is_empty() {
+        return Ok(report);
+    }
+
+    // The local node is registered in the DHT too, but only holds the damaged copy.
+    let local_address = format!("127.0.0.1:{}", config.discovery.peer_port);
+    let peers: Vec<_> = dht
+        .get_file_locations(&file_id)?
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|peer| peer.address != local_address)
+        .collect();
+
+    for chunk_index in damaged {
+        let mut repaired = false;
+        for peer in &peers {
+            match fetch_chunk_from_peer(peer, &storage_dir, file_id, chunk_index, config).await {
+                Ok(()) => {
+                    repaired = true;
+                    break;
+                }
+                Err(e) => error!("Failed to repair file={} chunk={} from {}: {}", file_id, chunk_index, peer, e),
+            }
+        }
+        if repaired {
+            info!("Repaired file={} chunk={}", file_id, chunk_index);
+            report.repaired += 1;
+        } else {
+            report.unrepaired += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// Writes a file's chunks straight to their offsets in the output file, so
+/// chunks can arrive in any order and a resumed download can pick up where it
+/// stopped. The file is pre-allocated with `set_len`, which leaves the
+/// unwritten ranges sparse where the filesystem supports it.
+#[derive(Debug)]
+pub struct SparseWriter {
+    file: tokio::fs::File,
+    chunk_size: usize,
+    written: HashSet<usize>,
+}
+
+impl SparseWriter {
+    /// Opens `path` without truncating it and sizes it to `total_size` bytes.
+    pub async fn create<P: AsRef<Path>>(path: P, chunk_size: usize, total_size: u64) -> Result<Self, StorageError> {
+        if chunk_size == 0 {
+            return Err(StorageError::InvalidPath("chunk size must be greater than zero".to_string()));
+        }
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await?;
+        file.set_len(total_size).await?;
+        Ok(SparseWriter { file, chunk_size, written: HashSet::new() })
+    }
+
+    pub async fn write_chunk(&mut self, chunk_index: usize, data: &[u8]) -> Result<(), StorageError> {
+        self.file.seek(SeekFrom::Start((chunk_index * self.chunk_size) as u64)).await?;
+        self.file.write_all(data).await?;
+        self.written.insert(chunk_index);
+        Ok(())
+    }
+
+    /// Whether every chunk in `0..total_chunks` has been written.
+    pub fn is_complete(&self, total_chunks: usize) -> bool {
+        (0..total_chunks).all(|i| self.written.contains(&i))
+    }
+
+    /// Flushes and syncs the file to disk.
+    pub async fn finish(mut self) -> Result<(), StorageError> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        Ok(())
+    }
+}
+
+type ChunkLoad = Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send>>;
+
+/// Reads a stored file's chunks back to back as one stream, holding one chunk
+/// in memory at a time, e.g. to pipe a download to stdout with
+/// `tokio::io::copy`. A missing chunk fails the read with
+/// [`io::ErrorKind::NotFound`].
+pub struct ChunkStreamReader {
+    storage_dir: PathBuf,
+    total_chunks: usize,
+    current_chunk: usize,
+    buffer: Vec<u8>,
+    pos: usize,
+    loading: Option<ChunkLoad>,
+}
+
+impl ChunkStreamReader {
+    pub fn new<P: AsRef<Path>>(storage_dir: P, total_chunks: usize) -> Self {
+        ChunkStreamReader {
+            storage_dir: storage_dir.as_ref().to_path_buf(),
+            total_chunks,
+            current_chunk: 0,
+            buffer: Vec::new(),
+            pos: 0,
+            loading: None,
+        }
+    }
+
+    fn load(&self, chunk_index: usize) -> impl Future<Output = io::Result<Vec<u8>>> + Send + 'static {
+        let path = chunk_path(&self.storage_dir, chunk_index);
+        let file_id = file_id_of(&self.storage_dir);
+        async move {
+            tokio::fs::read(path).await.map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => io::Error::new(e.kind(), StorageError::ChunkNotFound { file_id, chunk_index }),
+                _ => e,
+            })
+        }
+    }
+}
+
+impl AsyncRead for ChunkStreamReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.pos == this.buffer.len() {
+            if this.current_chunk == this.total_chunks {
+                return Poll::Ready(Ok(()));
+            }
+            if this.loading.is_none() {
+                this.loading = Some(Box::pin(this.load(this.current_chunk)));
+            }
+            let loaded = ready!(this.loading.as_mut().unwrap().as_mut().poll(cx));
+            this.loading = None;
+            this.buffer = loaded?;
+            this.pos = 0;
+            this.current_chunk += 1;
+        }
+        let n = buf.remaining().min(this.buffer.len() - this.pos);
+        buf.put_slice(&this.buffer[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::file_manager::chunker::{ChunkMetadata, split_file_into_chunks};
+    use crate::file_manager::hash::{blake3, sha256};
     use std::io::Write;
     use tempfile::NamedTempFile;
 
+    #[test]
+    fn test_iter_storage_yields_every_chunk_and_skips_stray_entries() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage_root = temp_dir.path();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        for (file_id, chunks) in [(first, 2), (second, 1)] {
+            let storage_dir = initialize_storage(storage_root, file_id).unwrap();
+            for i in 0..chunks {
+                save_chunk(&storage_dir, &ChunkMetadata::new(file_id, i, 5, chunks), b"Hello").unwrap();
+            }
+        }
+        fs::create_dir(storage_root.join("not-a-uuid")).unwrap();
+        fs::write(storage_root.join(Uuid::new_v4().to_string()), b"a file, not a directory").unwrap();
+        for name in ["history.json", "replication_status.json", "pending_uploads.json", "seeded.json", "node_keys.json"] {
+            fs::write(storage_root.join(name), b"[]").unwrap();
+        }
+        fs::create_dir(storage_root.join(DOWNLOADS_DIRNAME)).unwrap();
+        fs::write(storage_root.join(DOWNLOADS_DIRNAME).join(format!("{}.state.json", first)), b"{}").unwrap();
+        fs::create_dir(storage_root.join(format!("{}.tmp", second))).unwrap();
+        fs::write(storage_root.join(format!("{}.tmp", second)).join("chunk_0.bin"), b"Copy").unwrap();
+        // Only the unexpected directory is worth a warning.
+        assert!(is_bookkeeping_dir(OsStr::new(DOWNLOADS_DIRNAME)));
+        assert!(is_bookkeeping_dir(OsStr::new(&format!("{}.tmp", second))));
+        assert!(!is_bookkeeping_dir(OsStr::new("not-a-uuid")));
+
+        let mut found: Vec<(Uuid, usize, u64)> = iter_storage(storage_root)
+            .map(|item| item.map(|(file_id, chunk)| (file_id, chunk.index, chunk.size_bytes)))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        found.sort_unstable();
+        let mut expected = vec![(first, 0, 5), (first, 1, 5), (second, 0, 5)];
+        expected.sort_unstable();
+        assert_eq!(found, expected);
+        assert_eq!(storage_usage(storage_root).unwrap(), 15);
+
+        let mut missing = iter_storage(&storage_root.join("missing"));
+        assert!(matches!(missing.next(), Some(Err(StorageError::IoError(_)))));
+        assert!(missing.next().is_none());
+    }
+
+    #[test]
+    fn test_garbage_collect_removes_stale_temp_and_orphaned_hash_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage_root = temp_dir.path();
+        let file_id = Uuid::new_v4();
+        let storage_dir = initialize_storage(storage_root, file_id).unwrap();
+        save_chunk(&storage_dir, &ChunkMetadata::new(file_id, 0, 5, 2), b"Hello").unwrap();
+        save_chunk(&storage_dir, &ChunkMetadata::new(file_id, 1, 5, 2), b"World").unwrap();
+        fs::remove_file(chunk_path(&storage_dir, 1)).unwrap();
+        fs::write(storage_dir.join("manifest.json.tmp"), b"{}").unwrap();
+        fs::write(storage_root.join("history.json.tmp"), b"[]").unwrap();
+
+        // Everything is newer than an hour, so nothing is old enough yet.
+        assert_eq!(garbage_collect(storage_root, Duration::from_secs(3600)).unwrap(), GcReport::default());
+
+        let report = StorageManager::new(storage_root).garbage_collect(Duration::ZERO).unwrap();
+        assert_eq!(report.files_removed, 3);
+        assert_eq!(report.bytes_freed, 71 + 2 + 2);
+        assert!(!hash_path(&storage_dir, 1).exists());
+        assert!(!storage_dir.join("manifest.json.tmp").exists());
+        assert_eq!(get_chunk(&storage_dir, 0).unwrap(), b"Hello");
+        assert!(hash_path(&storage_dir, 0).exists());
+    }
+
     #[test]
     fn test_save_and_get_chunk() {
         let temp_dir = tempfile::tempdir().unwrap();
@@ -107,6 +1114,12 @@ mod tests {
         // Retrieve the chunk
         let retrieved_data = get_chunk(&storage_dir, 0).unwrap();
         assert_eq!(retrieved_data, data);
+
+        assert!(matches!(
+            get_chunk(&storage_dir, 1),
+            Err(StorageError::ChunkNotFound { file_id: id, chunk_index: 1 }) if id == file_id
+        ));
+        assert!(matches!(load_manifest(&storage_dir), Err(StorageError::ManifestNotFound(id)) if id == file_id));
     }
 
     #[test]
@@ -130,6 +1143,333 @@ mod tests {
         assert_eq!(chunks, vec![0, 1, 2, 3, 4]);
     }
 
+    #[tokio::test]
+    async fn test_list_chunks_async_and_with_metadata() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_id = Uuid::new_v4();
+        let storage_dir = initialize_storage(temp_dir.path(), file_id).unwrap();
+        for i in [10, 2, 0] {
+            let data = vec![7u8; i + 1];
+            save_chunk(&storage_dir, &ChunkMetadata::new(file_id, i, data.len(), 11), &data).unwrap();
+        }
+        fs::write(storage_dir.join("chunk_x.bin"), b"not a chunk").unwrap();
+
+        assert_eq!(list_chunks_async(&storage_dir).await.unwrap(), vec![0, 2, 10]);
+        assert_eq!(list_chunks_async(&storage_dir).await.unwrap(), list_chunks(&storage_dir).unwrap());
+
+        let on_disk = list_chunks_with_metadata(&storage_dir).unwrap();
+        let summary: Vec<(usize, u64)> = on_disk.iter().map(|c| (c.index, c.size_bytes)).collect();
+        assert_eq!(summary, vec![(0, 1), (2, 3), (10, 11)]);
+        assert!(on_disk.iter().all(|c| c.modified <= SystemTime::now()));
+    }
+
+    #[test]
+    fn test_chunk_exists_and_size() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_id = Uuid::new_v4();
+        let storage_dir = initialize_storage(temp_dir.path(), file_id).unwrap();
+
+        save_chunk(&storage_dir, &ChunkMetadata::new(file_id, 2, 5, 3), b"Hello").unwrap();
+
+        assert!(chunk_exists(&storage_dir, 2));
+        assert!(!chunk_exists(&storage_dir, 0));
+        assert_eq!(chunk_size_on_disk(&storage_dir, 2).unwrap(), 5);
+        assert!(chunk_size_on_disk(&storage_dir, 0).is_err());
+    }
+
+    #[test]
+    fn test_chunk_hash() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_id = Uuid::new_v4();
+        let storage_dir = initialize_storage(temp_dir.path(), file_id).unwrap();
+
+        save_chunk(&storage_dir, &ChunkMetadata::new(file_id, 0, 5, 2), b"Hello").unwrap();
+        assert_eq!(recorded_chunk_hash(&storage_dir, 0).unwrap(), (HashAlgorithm::Blake3, blake3(b"Hello")));
+        // The SHA-256 of a file hashed that way is computed from the data.
+        assert_eq!(chunk_hash(&storage_dir, 0, HashAlgorithm::Sha256).unwrap(), sha256(b"Hello"));
+
+        // A chunk without a hash file is hashed from its contents.
+        fs::write(storage_dir.join("chunk_1.bin"), b"World").unwrap();
+        assert_eq!(chunk_hash(&storage_dir, 1, HashAlgorithm::Blake3).unwrap(), blake3(b"World"));
+
+        // Hash files written before BLAKE3 hold a bare SHA-256.
+        fs::write(storage_dir.join("chunk_1.hash"), hex::encode(sha256(b"World"))).unwrap();
+        assert_eq!(recorded_chunk_hash(&storage_dir, 1).unwrap(), (HashAlgorithm::Sha256, sha256(b"World")));
+        assert_eq!(find_damaged_chunks(&storage_dir, 2).unwrap(), Vec::<usize>::new());
+
+        fs::write(storage_dir.join("chunk_0.hash"), "not hex").unwrap();
+        assert!(matches!(recorded_chunk_hash(&storage_dir, 0), Err(StorageError::InvalidHash(0))));
+    }
+
+    #[test]
+    fn test_pin_and_unpin() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage_root = temp_dir.path();
+        let file_id = Uuid::new_v4();
+
+        assert!(pin_file(storage_root, file_id).is_err());
+        initialize_storage(storage_root, file_id).unwrap();
+        assert!(!is_pinned(storage_root, &file_id));
+
+        pin_file(storage_root, file_id).unwrap();
+        assert!(is_pinned(storage_root, &file_id));
+        assert_eq!(list_all_files(storage_root).unwrap(), vec![file_id]);
+
+        unpin_file(storage_root, file_id).unwrap();
+        unpin_file(storage_root, file_id).unwrap();
+        assert!(!is_pinned(storage_root, &file_id));
+    }
+
+    #[test]
+    fn test_list_all_files_with_pinned() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage_root = temp_dir.path();
+        let mut file_ids = [Uuid::new_v4(), Uuid::new_v4()];
+        file_ids.sort_unstable();
+        for file_id in file_ids {
+            initialize_storage(storage_root, file_id).unwrap();
+        }
+        pin_file(storage_root, file_ids[1]).unwrap();
+        fs::create_dir(storage_root.join("not-a-file")).unwrap();
+        fs::write(storage_root.join(Uuid::new_v4().to_string()), b"not a directory").unwrap();
+
+        assert_eq!(list_all_files(storage_root).unwrap(), file_ids.to_vec());
+        assert_eq!(
+            list_all_files_with_pinned(storage_root).unwrap(),
+            vec![(file_ids[0], false), (file_ids[1], true)]
+        );
+    }
+
+    #[test]
+    fn test_delete_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage_root = temp_dir.path();
+        let file_id = Uuid::new_v4();
+
+        let storage_dir = initialize_storage(storage_root, file_id).unwrap();
+        for i in 0..3 {
+            let metadata = ChunkMetadata::new(file_id, i, 5, 3);
+            save_chunk(&storage_dir, &metadata, b"Chunk").unwrap();
+        }
+        // Three 5-byte chunks plus a `blake3:<64 hex>` hash file for each.
+        assert_eq!(stored_file_size(storage_root, file_id).unwrap(), 15 + 3 * 71);
+
+        delete_file(storage_root, file_id).unwrap();
+        assert!(!storage_dir.exists());
+    }
+
+    #[test]
+    fn test_move_chunks() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let file_id = Uuid::new_v4();
+
+        let storage_dir = initialize_storage(src.path(), file_id).unwrap();
+        for i in 0..3 {
+            save_chunk(&storage_dir, &ChunkMetadata::new(file_id, i, 5, 3), format!("Chunk{}", i).as_bytes()).unwrap();
+        }
+        pin_file(src.path(), file_id).unwrap();
+
+        move_chunks(src.path(), dst.path(), file_id).unwrap();
+        assert!(!storage_dir.exists());
+        let moved_dir = dst.path().join(file_id.to_string());
+        assert_eq!(list_chunks(&moved_dir).unwrap(), vec![0, 1, 2]);
+        assert_eq!(get_chunk(&moved_dir, 1).unwrap(), b"Chunk1");
+        assert!(is_pinned(dst.path(), &file_id));
+
+        // A chunk that no longer matches its hash aborts the move and keeps the source.
+        fs::write(moved_dir.join("chunk_2.bin"), b"Corrupt").unwrap();
+        assert!(matches!(move_chunks(dst.path(), src.path(), file_id), Err(StorageError::InvalidHash(2))));
+        assert!(moved_dir.exists());
+        assert!(!src.path().join(file_id.to_string()).exists());
+        assert!(!src.path().join(format!("{}.tmp", file_id)).exists());
+    }
+
+    #[test]
+    fn test_move_chunks_onto_the_same_root_keeps_the_chunks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let file_id = Uuid::new_v4();
+        let storage_dir = initialize_storage(root, file_id).unwrap();
+        save_chunk(&storage_dir, &ChunkMetadata::new(file_id, 0, 5, 1), b"Hello").unwrap();
+
+        assert!(matches!(move_chunks(root, root, file_id), Err(StorageError::InvalidPath(_))));
+        // The same directory, spelled differently.
+        assert!(matches!(move_chunks(&root.join("."), root, file_id), Err(StorageError::InvalidPath(_))));
+        assert_eq!(get_chunk(&storage_dir, 0).unwrap(), b"Hello");
+        assert!(find_damaged_chunks(&storage_dir, 1).unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_storage_manager_concurrent_access() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let manager = StorageManager::new(temp_dir.path());
+        let file_id = Uuid::new_v4();
+
+        // Writers race on the same chunk; every read must see one complete write.
+        let mut tasks = Vec::new();
+        for i in 0..16u8 {
+            let manager = manager.clone();
+            tasks.push(tokio::spawn(async move {
+                let data = vec![i; 4096];
+                manager.save_chunk_managed(&ChunkMetadata::new(file_id, 0, data.len(), 1), &data).await.unwrap();
+                let read = manager.get_chunk_managed(file_id, 0).await.unwrap();
+                assert_eq!(read.len(), 4096);
+                assert!(read.iter().all(|b| *b == read[0]));
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(chunk_hash(temp_dir.path().join(file_id.to_string()), 0, HashAlgorithm::Blake3).unwrap(), blake3(&manager.get_chunk_managed(file_id, 0).await.unwrap()));
+
+        manager.delete_file(file_id).await.unwrap();
+        assert!(!temp_dir.path().join(file_id.to_string()).exists());
+        assert!(manager.locks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_storage_manager_over_memory_backend() {
+        let manager = StorageManager::with_backend(MemoryBackend::new());
+        let file_id = Uuid::new_v4();
+        for i in [2, 0, 1] {
+            manager.save_chunk_managed(&ChunkMetadata::new(file_id, i, 5, 3), format!("Chunk{}", i).as_bytes()).await.unwrap();
+        }
+        assert_eq!(manager.list_chunks_managed(file_id).await.unwrap(), vec![0, 1, 2]);
+        assert_eq!(manager.get_chunk_managed(file_id, 1).await.unwrap(), b"Chunk1");
+
+        manager.backend().delete_chunk(file_id, 1).unwrap();
+        assert!(matches!(
+            manager.get_chunk_managed(file_id, 1).await,
+            Err(StorageError::ChunkNotFound { chunk_index: 1, .. })
+        ));
+        manager.delete_file(file_id).await.unwrap();
+        assert!(manager.list_chunks_managed(file_id).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_file_system_backend_matches_free_functions() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let backend = FileSystemBackend::new(temp_dir.path());
+        let file_id = Uuid::new_v4();
+        assert!(backend.list_chunks(file_id).unwrap().is_empty());
+
+        backend.save_chunk(file_id, 0, b"Hello").unwrap();
+        backend.save_chunk(file_id, 1, b"World").unwrap();
+        let storage_dir = temp_dir.path().join(file_id.to_string());
+        assert_eq!(get_chunk(&storage_dir, 1).unwrap(), b"World");
+        assert_eq!(chunk_hash(&storage_dir, 0, HashAlgorithm::Blake3).unwrap(), blake3(b"Hello"));
+
+        backend.delete_chunk(file_id, 0).unwrap();
+        assert_eq!(backend.list_chunks(file_id).unwrap(), vec![1]);
+        backend.delete_file(file_id).unwrap();
+        assert!(!storage_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_sparse_writer_out_of_order() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("out.bin");
+        let content = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+        let mut writer = SparseWriter::create(&path, 10, content.len() as u64).await.unwrap();
+        for i in [3, 1, 0] {
+            writer.write_chunk(i, &content[i * 10..((i + 1) * 10).min(content.len())]).await.unwrap();
+        }
+        assert!(!writer.is_complete(4));
+        writer.write_chunk(2, &content[20..30]).await.unwrap();
+        assert!(writer.is_complete(4));
+        writer.finish().await.unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_stream_reader_matches_merge_chunks() {
+        use crate::file_manager::chunker::{merge_chunks, split_bytes_into_chunks};
+        use tokio::io::AsyncReadExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let (file_id, chunks) = split_bytes_into_chunks(&content, 1024);
+        let storage_dir = initialize_storage(temp_dir.path(), file_id).unwrap();
+        for (metadata, data) in &chunks {
+            save_chunk(&storage_dir, metadata, data).unwrap();
+        }
+
+        let mut streamed = Vec::new();
+        tokio::io::copy(&mut ChunkStreamReader::new(&storage_dir, chunks.len()), &mut streamed).await.unwrap();
+        assert_eq!(streamed, merge_chunks(&chunks).unwrap());
+
+        // Reads smaller than a chunk pick up where the last one stopped.
+        let mut reader = ChunkStreamReader::new(&storage_dir, chunks.len());
+        let mut head = [0u8; 1500];
+        reader.read_exact(&mut head).await.unwrap();
+        assert_eq!(head, content[..1500]);
+
+        fs::remove_file(chunk_path(&storage_dir, 3)).unwrap();
+        let mut partial = Vec::new();
+        let e = tokio::io::copy(&mut ChunkStreamReader::new(&storage_dir, chunks.len()), &mut partial).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert_eq!(partial, content[..3 * 1024]);
+    }
+
+    #[test]
+    fn test_validate_storage_directory() {
+        use crate::file_manager::manifest::{save_manifest, FileManifest};
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage_root = temp_dir.path();
+        let complete = Uuid::new_v4();
+        let storage_dir = initialize_storage(storage_root, complete).unwrap();
+        for i in 0..3 {
+            save_chunk(&storage_dir, &ChunkMetadata::new(complete, i, 6, 3), format!("Chunk{}", i).as_bytes()).unwrap();
+        }
+        save_manifest(&storage_dir, &FileManifest::new(complete, "a.bin".to_string(), 18, 6, 3)).unwrap();
+        assert!(validate_storage_directory(storage_root).unwrap().is_empty());
+
+        fs::write(chunk_path(&storage_dir, 1), b"Broken").unwrap();
+        fs::remove_file(chunk_path(&storage_dir, 2)).unwrap();
+        save_chunk(&storage_dir, &ChunkMetadata::new(complete, 5, 6, 3), b"Extra!").unwrap();
+        let without_manifest = Uuid::new_v4();
+        let other_dir = initialize_storage(storage_root, without_manifest).unwrap();
+        save_chunk(&other_dir, &ChunkMetadata::new(without_manifest, 0, 5, 1), b"Loose").unwrap();
+        fs::create_dir(storage_root.join("lost+found")).unwrap();
+        fs::write(storage_root.join("lost+found/chunk_0.bin"), b"Stray").unwrap();
+
+        let mut issues = validate_storage_directory(storage_root).unwrap();
+        issues.sort_by_key(|issue| issue.to_string());
+        let mut expected = vec![
+            IntegrityIssue::MissingChunk { file_id: complete, expected_index: 2 },
+            IntegrityIssue::CorruptChunk { file_id: complete, index: 1 },
+            IntegrityIssue::OrphanChunk { path: chunk_path(&storage_dir, 5) },
+            IntegrityIssue::MissingManifest(without_manifest),
+            IntegrityIssue::OrphanChunk { path: storage_root.join("lost+found/chunk_0.bin") },
+        ];
+        expected.sort_by_key(|issue| issue.to_string());
+        assert_eq!(issues, expected);
+        assert_eq!(issues.iter().filter(|issue| issue.is_data_loss()).count(), 2);
+    }
+
+    #[test]
+    fn test_find_damaged_chunks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_id = Uuid::new_v4();
+        let storage_dir = initialize_storage(temp_dir.path(), file_id).unwrap();
+        for i in 0..4 {
+            let metadata = ChunkMetadata::new(file_id, i, 6, 4);
+            save_chunk(&storage_dir, &metadata, format!("Chunk{}", i).as_bytes()).unwrap();
+        }
+        assert!(find_damaged_chunks(&storage_dir, 4).unwrap().is_empty());
+
+        fs::write(storage_dir.join("chunk_1.bin"), b"Broken").unwrap();
+        fs::remove_file(storage_dir.join("chunk_2.bin")).unwrap();
+        assert_eq!(find_damaged_chunks(&storage_dir, 4).unwrap(), vec![1, 2]);
+
+        let report = verify_file(temp_dir.path(), file_id).unwrap();
+        assert_eq!(report, RepairReport { chunks_checked: 4, corrupted_found: 2, repaired: 0, unrepaired: 0 });
+    }
+
     #[test]
     fn test_split_and_save_chunks() {
         let temp_dir = tempfile::tempdir().unwrap();
@@ -0,0 +1,243 @@
+// src/history.rs
+
+use crate::json::{self, JsonError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use uuid::Uuid;
+
+pub const HISTORY_FILENAME: &str = "history.json";
+
+#[derive(Error, Debug)]
+pub enum HistoryError {
+    #[error("I/O Error: {0}")]
+    IoError(#[from] io::Error),
+
+    #[error("Serialization Error: {0}")]
+    SerializationError(#[from] JsonError),
+
+    #[error("Unknown transfer record {0}")]
+    UnknownRecord(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferDirection {
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferStatus {
+    InProgress,
+    Completed,
+    Failed,
+}
+
+/// One upload or download. Timestamps are seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferRecord {
+    pub id: i64,
+    pub direction: TransferDirection,
+    pub file_id: Uuid,
+    pub file_name: String,
+    pub bytes: u64,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub status: TransferStatus,
+}
+
+/// Persistent log of transfers, kept as a JSON array in `<storage_path>/history.json`.
+/// Every change rewrites the file through a temporary file and a rename. Clones
+/// share a lock, so transfers running side by side don't lose each other's records.
+#[derive(Debug, Clone)]
+pub struct HistoryStore {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl HistoryStore {
+    pub fn open<P: AsRef<Path>>(storage_root: P) -> Self {
+        HistoryStore {
+            path: storage_root.as_ref().join(HISTORY_FILENAME),
+            lock: Arc::default(),
+        }
+    }
+
+    /// Records the start of a transfer and returns its record ID.
+    pub fn start(
+        &self,
+        direction: TransferDirection,
+        file_id: Uuid,
+        file_name: &str,
+        bytes: u64,
+    ) -> Result<i64, HistoryError> {
+        self.update(|records| {
+            let id = records.iter().map(|r| r.id).max().unwrap_or(0) + 1;
+            records.push(TransferRecord {
+                id,
+                direction,
+                file_id,
+                file_name: file_name.to_string(),
+                bytes,
+                started_at: unix_now(),
+                finished_at: None,
+                status: TransferStatus::InProgress,
+            });
+            Ok(id)
+        })
+    }
+
+    /// Marks a transfer as finished with the given status.
+    pub fn finish(&self, id: i64, status: TransferStatus) -> Result<(), HistoryError> {
+        self.update(|records| {
+            let record = records
+                .iter_mut()
+                .find(|r| r.id == id)
+                .ok_or(HistoryError::UnknownRecord(id))?;
+            record.status = status;
+            record.finished_at = Some(unix_now());
+            Ok(())
+        })
+    }
+
+    /// Returns up to `limit` records, most recently started first.
+    pub fn recent(&self, limit: usize) -> Result<Vec<TransferRecord>, HistoryError> {
+        let mut records = {
+            let _guard = self.lock.lock().unwrap();
+            self.load()?
+        };
+        records.sort_by(|a, b| b.started_at.cmp(&a.started_at).then(b.id.cmp(&a.id)));
+        records.truncate(limit);
+        Ok(records)
+    }
+
+    fn load(&self) -> Result<Vec<TransferRecord>, HistoryError> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => Ok(json::from_str(&contents)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Loads the records, applies `change` and saves them, holding the lock
+    /// throughout. Nothing is saved if `change` fails.
+    fn update<T>(&self, change: impl FnOnce(&mut Vec<TransferRecord>) -> Result<T, HistoryError>) -> Result<T, HistoryError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut records = self.load()?;
+        let result = change(&mut records)?;
+        self.save(&records)?;
+        Ok(result)
+    }
+
+    fn save(&self, records: &[TransferRecord]) -> Result<(), HistoryError> {
+        json::write_atomic(&self.path, &records)
+    }
+}
+
+impl fmt::Display for TransferDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferDirection::Upload => write!(f, "upload"),
+            TransferDirection::Download => write!(f, "download"),
+        }
+    }
+}
+
+impl fmt::Display for TransferStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferStatus::InProgress => write!(f, "in progress"),
+            TransferStatus::Completed => write!(f, "completed"),
+            TransferStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Formats seconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS` (UTC).
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Civil-from-days conversion (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start_finish_and_recent() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = HistoryStore::open(temp_dir.path());
+
+        let upload = store.start(TransferDirection::Upload, Uuid::new_v4(), "a.txt", 10).unwrap();
+        let download = store.start(TransferDirection::Download, Uuid::new_v4(), "b.txt", 20).unwrap();
+        store.finish(upload, TransferStatus::Completed).unwrap();
+
+        let records = store.recent(10).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id, download);
+        assert_eq!(records[0].status, TransferStatus::InProgress);
+        assert_eq!(records[1].status, TransferStatus::Completed);
+        assert!(records[1].finished_at.is_some());
+
+        assert_eq!(store.recent(1).unwrap().len(), 1);
+        assert!(matches!(store.finish(99, TransferStatus::Failed), Err(HistoryError::UnknownRecord(99))));
+    }
+
+    #[test]
+    fn test_concurrent_starts_keep_every_record() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = HistoryStore::open(temp_dir.path());
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    (0..5)
+                        .map(|j| store.start(TransferDirection::Upload, Uuid::new_v4(), &format!("{}-{}.txt", i, j), 1).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut ids: Vec<i64> = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, (1..=40).collect::<Vec<_>>());
+        assert_eq!(store.recent(100).unwrap().len(), 40);
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-14 22:13:20");
+    }
+}
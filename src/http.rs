@@ -0,0 +1,292 @@
+// src/http.rs
+
+//! Optional HTTP server for load balancers and orchestrators.
+//!
+//! `GET /health` answers with a JSON [`HealthReport`]; `GET /ready` answers
+//! 503 until the node knows at least one peer; `GET /metrics` exposes the
+//! DHT counters in the Prometheus text format. All are served from cached
+//! values, so a request never waits on disk I/O. `GET /openapi.json`
+//! describes them, and `GET /docs` renders that description with Swagger UI.
+
+use crate::file_manager::storage::storage_usage;
+use crate::indexing::dht::{DhtMetrics, DHT};
+use crate::json;
+use crate::openapi::{openapi_json, SWAGGER_UI_HTML};
+use crate::peer::discovery::PeerRegistry;
+use log::{error, info};
+use serde::Serialize;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// How often the cached storage usage is recomputed.
+pub const STORAGE_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A request whose headers have not arrived by then is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest request head read; anything longer is rejected.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Healthy,
+    /// No peers are known, or storage is above 90% of the quota.
+    Degraded,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub peer_count: usize,
+    pub dht_files: usize,
+    pub storage_used_bytes: u64,
+    pub uptime_secs: u64,
+}
+
+/// What the health endpoints report on, shared with the rest of the node.
+#[derive(Debug, Clone)]
+pub struct HealthState {
+    started: Instant,
+    peers: PeerRegistry,
+    dht: DHT,
+    storage_used_bytes: Arc<AtomicU64>,
+    storage_quota_bytes: Option<u64>,
+}
+
+impl HealthState {
+    pub fn new(peers: PeerRegistry, dht: DHT, storage_quota_bytes: Option<u64>) -> Self {
+        HealthState {
+            started: Instant::now(),
+            peers,
+            dht,
+            storage_used_bytes: Arc::default(),
+            storage_quota_bytes,
+        }
+    }
+
+    pub fn set_storage_used_bytes(&self, bytes: u64) {
+        self.storage_used_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Recomputes the storage usage under `storage_root` every
+    /// `STORAGE_REFRESH_INTERVAL`, forever.
+    pub async fn refresh_storage_usage(self, storage_root: PathBuf) {
+        let mut interval = tokio::time::interval(STORAGE_REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let root = storage_root.clone();
+            match tokio::task::spawn_blocking(move || storage_usage(root)).await {
+                Ok(Ok(bytes)) => self.set_storage_used_bytes(bytes),
+                Ok(Err(e)) => error!("Failed to measure storage usage: {}", e),
+                Err(e) => error!("Storage usage task failed: {}", e),
+            }
+        }
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let peer_count = self.peers.len();
+        let storage_used_bytes = self.storage_used_bytes.load(Ordering::Relaxed);
+        let over_quota = self
+            .storage_quota_bytes
+            .is_some_and(|quota| storage_used_bytes as f64 > quota as f64 * 0.9);
+        let status = if peer_count == 0 || over_quota {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Healthy
+        };
+        HealthReport {
+            status,
+            peer_count,
+            dht_files: self.dht.file_count().unwrap_or(0),
+            storage_used_bytes,
+            uptime_secs: self.started.elapsed().as_secs(),
+        }
+    }
+}
+
+/// Binds `port` on all interfaces and serves the health endpoints until the task is dropped.
+pub async fn start_http_server(port: u16, state: HealthState) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    info!("HTTP server listening on {}", listener.local_addr()?);
+    serve(listener, state).await
+}
+
+pub async fn serve(listener: TcpListener, state: HealthState) -> Result<(), Box<dyn Error + Send + Sync>> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, &state).await {
+                error!("Failed to serve HTTP request from {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, state: &HealthState) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let head = match tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream)).await {
+        Ok(head) => head?,
+        Err(_) => return Err("timed out reading request".into()),
+    };
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    // Query strings do not change the answer.
+    let path = path.split('?').next().unwrap_or_default();
+
+    let json = "application/json";
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/health") => ("200 OK", json, json::to_string(&state.report())?),
+        ("GET", "/ready") => {
+            let ready = !state.peers.is_empty();
+            let status = if ready { "200 OK" } else { "503 Service Unavailable" };
+            (status, json, format!("{{\"ready\":{}}}", ready))
+        }
+        ("GET", "/metrics") => ("200 OK", "text/plain; version=0.0.4", render_metrics(state.dht.metrics())),
+        ("GET", "/openapi.json") => ("200 OK", json, openapi_json()?),
+        ("GET", "/docs") => ("200 OK", "text/html; charset=utf-8", SWAGGER_UI_HTML.to_string()),
+        (_, "/health" | "/ready" | "/metrics" | "/openapi.json" | "/docs") => {
+            ("405 Method Not Allowed", json, r#"{"error":"method not allowed"}"#.to_string())
+        }
+        _ => ("404 Not Found", json, r#"{"error":"not found"}"#.to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// The DHT counters as Prometheus counters.
+fn render_metrics(metrics: &DhtMetrics) -> String {
+    let counters = [
+        ("dht_inserts_total", "File locations added to the DHT.", metrics.inserts()),
+        ("dht_lookups_total", "DHT file and chunk location lookups.", metrics.lookups()),
+        ("dht_evictions_total", "File locations removed from the DHT.", metrics.evictions()),
+    ];
+    counters
+        .iter()
+        .map(|(name, help, value)| format!("# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n"))
+        .collect()
+}
+
+/// Reads up to the blank line that ends the request headers.
+async fn read_request_head(stream: &mut TcpStream) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.len() > MAX_REQUEST_BYTES {
+            return Err("request headers too large".into());
+        }
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::discovery::Peer;
+    use uuid::Uuid;
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn test_report_is_degraded_without_peers_or_near_quota() {
+        let peers = PeerRegistry::default();
+        let dht = DHT::new();
+        dht.register_file_location(Uuid::new_v4(), Peer::new("10.0.0.1:8080")).unwrap();
+        let state = HealthState::new(peers.clone(), dht, Some(1000));
+
+        let report = state.report();
+        assert_eq!(report.status, HealthStatus::Degraded);
+        assert_eq!((report.peer_count, report.dht_files), (0, 1));
+
+        peers.add(Peer::new("10.0.0.1:8080"));
+        state.set_storage_used_bytes(900);
+        assert_eq!(state.report().status, HealthStatus::Healthy);
+        state.set_storage_used_bytes(901);
+        assert_eq!(state.report().status, HealthStatus::Degraded);
+    }
+
+    #[tokio::test]
+    async fn test_health_and_ready_endpoints() {
+        let peers = PeerRegistry::default();
+        let state = HealthState::new(peers.clone(), DHT::new(), None);
+        state.set_storage_used_bytes(42);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, state));
+
+        let ready = get(addr, "/ready").await;
+        assert!(ready.starts_with("HTTP/1.1 503"), "{}", ready);
+
+        peers.add(Peer::new("10.0.0.1:8080"));
+        assert!(get(addr, "/ready").await.starts_with("HTTP/1.1 200"));
+
+        let health = get(addr, "/health").await;
+        assert!(health.starts_with("HTTP/1.1 200"));
+        let body = health.split("\r\n\r\n").nth(1).unwrap();
+        assert!(body.contains(r#""status":"healthy""#), "{}", body);
+        assert!(body.contains(r#""peer_count":1"#));
+        assert!(body.contains(r#""storage_used_bytes":42"#));
+
+        assert!(get(addr, "/status").await.starts_with("HTTP/1.1 404"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_exports_dht_counters() {
+        let dht = DHT::new();
+        let file_id = Uuid::new_v4();
+        dht.register_file_location(file_id, Peer::new("10.0.0.1:8080")).unwrap();
+        dht.get_file_locations(&file_id).unwrap();
+        let state = HealthState::new(PeerRegistry::default(), dht, None);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, state));
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+        assert!(response.contains("# TYPE dht_inserts_total counter\ndht_inserts_total 1\n"), "{}", response);
+        assert!(response.contains("\ndht_lookups_total 1\n"));
+        assert!(response.contains("\ndht_evictions_total 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_openapi_and_docs_endpoints() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, HealthState::new(PeerRegistry::default(), DHT::new(), None)));
+
+        let response = get(addr, "/openapi.json").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        let spec: json::Value = json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert!(spec["paths"]["/metrics"]["get"].is_mapping());
+
+        let docs = get(addr, "/docs").await;
+        assert!(docs.contains("Content-Type: text/html"));
+        assert!(docs.contains(r#"url: "/openapi.json""#));
+    }
+}
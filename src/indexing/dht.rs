@@ -1,54 +1,542 @@
 // src/indexing/dht.rs
 
+use crate::config::Config;
+use crate::peer::connection::send_identity;
 use crate::peer::discovery::Peer;
+use crate::peer::session::{PeerCapabilities, PeerSession};
+use crate::peer::transport;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::error::Error as StdError;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 use log::info;
 
-#[derive(Clone, Debug)]
+/// How long a [`DHT::query_remote`] may take, forwarding included.
+pub const QUERY_REMOTE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum DhtError {
+    #[error("DHT lock poisoned")]
+    Poisoned,
+
+    #[error("Remote DHT query failed: {0}")]
+    Remote(String),
+}
+
+/// Owned copy of the DHT contents, detached from the live map so it can be
+/// serialized or sent without holding the lock.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DhtSnapshot {
+    pub entries: HashMap<Uuid, Vec<String>>,
+}
+
+/// The DHT as `dht-dump` writes it and `dht-import` reads it back, one entry
+/// per file, sorted by file ID.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DhtDump {
+    pub entries: Vec<DhtDumpEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DhtDumpEntry {
+    pub file_id: Uuid,
+    /// The DHT only holds locations, so this is filled in from elsewhere,
+    /// e.g. the local manifest, and is `null` when nothing is known.
+    #[serde(default)]
+    pub metadata: Option<DhtFileMetadata>,
+    pub peers: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DhtFileMetadata {
+    pub file_name: String,
+    pub file_size: u64,
+    pub total_chunks: usize,
+}
+
+impl DhtDump {
+    /// The entries of `snapshot`, with the metadata `describe` finds for each file.
+    pub fn new(snapshot: DhtSnapshot, describe: impl Fn(&Uuid) -> Option<DhtFileMetadata>) -> Self {
+        let mut entries: Vec<DhtDumpEntry> = snapshot
+            .entries
+            .into_iter()
+            .map(|(file_id, peers)| DhtDumpEntry { file_id, metadata: describe(&file_id), peers })
+            .collect();
+        entries.sort_by_key(|entry| entry.file_id);
+        DhtDump { entries }
+    }
+}
+
+/// Drops the metadata. Entries listing the same file are combined.
+impl From<DhtDump> for DhtSnapshot {
+    fn from(dump: DhtDump) -> Self {
+        let mut entries: HashMap<Uuid, Vec<String>> = HashMap::new();
+        for entry in dump.entries {
+            let addresses = entries.entry(entry.file_id).or_default();
+            for peer in entry.peers {
+                if !addresses.contains(&peer) {
+                    addresses.push(peer);
+                }
+            }
+        }
+        DhtSnapshot { entries }
+    }
+}
+
+/// Running counts of DHT operations, shared by all clones of a [`DHT`].
+#[derive(Debug, Default)]
+pub struct DhtMetrics {
+    /// File locations added.
+    inserts: AtomicU64,
+    /// File and chunk location queries.
+    lookups: AtomicU64,
+    /// Batches of entries merged from peers.
+    merges: AtomicU64,
+    /// File locations removed.
+    evictions: AtomicU64,
+    /// Registrations of a location that was already known.
+    conflicts: AtomicU64,
+}
+
+impl DhtMetrics {
+    pub fn inserts(&self) -> u64 {
+        self.inserts.load(Ordering::Relaxed)
+    }
+
+    pub fn lookups(&self) -> u64 {
+        self.lookups.load(Ordering::Relaxed)
+    }
+
+    pub fn merges(&self) -> u64 {
+        self.merges.load(Ordering::Relaxed)
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    pub fn conflicts(&self) -> u64 {
+        self.conflicts.load(Ordering::Relaxed)
+    }
+
+    fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Peers per `(file_id, chunk_index)`.
+type ChunkLocations = HashMap<(Uuid, usize), Vec<Peer>>;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, Default)]
 pub struct DHT {
     inner: Arc<Mutex<HashMap<Uuid, Vec<Peer>>>>,
+    /// Peers known to hold individual chunks, for files whose chunks are
+    /// spread across peers rather than held whole by each of them.
+    chunks: Arc<Mutex<ChunkLocations>>,
+    metrics: Arc<DhtMetrics>,
 }
 
 impl DHT {
     pub fn new() -> Self {
         DHT {
             inner: Arc::new(Mutex::new(HashMap::new())),
+            chunks: Arc::new(Mutex::new(HashMap::new())),
+            metrics: Arc::default(),
+        }
+    }
+
+    pub fn metrics(&self) -> &DhtMetrics {
+        &self.metrics
+    }
+
+    /// A panic while the lock was held may have left the map half-updated,
+    /// so poisoning is reported instead of recovered from.
+    fn lock(&self) -> Result<MutexGuard<'_, HashMap<Uuid, Vec<Peer>>>, DhtError> {
+        self.inner.lock().map_err(|_| DhtError::Poisoned)
+    }
+
+    fn lock_chunks(&self) -> Result<MutexGuard<'_, ChunkLocations>, DhtError> {
+        self.chunks.lock().map_err(|_| DhtError::Poisoned)
+    }
+
+    pub fn register_file_location(&self, file_id: Uuid, peer: Peer) -> Result<(), DhtError> {
+        let mut map = self.lock()?;
+        let peers = map.entry(file_id).or_default();
+        if peers.iter().any(|p| p.address == peer.address) {
+            DhtMetrics::increment(&self.metrics.conflicts);
+        } else {
+            peers.push(peer.clone());
+            DhtMetrics::increment(&self.metrics.inserts);
+        }
+        info!("Registered file={} at {}", file_id, peer);
+        Ok(())
+    }
+
+    /// Registers `peer` for the whole file and for each of its `total_chunks` chunks.
+    pub fn register_file_location_with_chunks(
+        &self,
+        file_id: Uuid,
+        peer: Peer,
+        total_chunks: usize,
+    ) -> Result<(), DhtError> {
+        self.register_file_location(file_id, peer.clone())?;
+        for chunk_index in 0..total_chunks {
+            self.register_chunk_location(file_id, chunk_index, peer.clone())?;
+        }
+        Ok(())
+    }
+
+    pub fn register_chunk_location(&self, file_id: Uuid, chunk_index: usize, peer: Peer) -> Result<(), DhtError> {
+        let mut chunks = self.lock_chunks()?;
+        let peers = chunks.entry((file_id, chunk_index)).or_default();
+        if !peers.iter().any(|p| p.address == peer.address) {
+            peers.push(peer);
+        }
+        Ok(())
+    }
+
+    /// Peers holding chunk `chunk_index` of `file_id`. Without any per-chunk
+    /// entry, every peer holding the file is assumed to hold all of its chunks.
+    pub fn find_peers_for_chunk(&self, file_id: &Uuid, chunk_index: usize) -> Result<Vec<Peer>, DhtError> {
+        if let Some(peers) = self.lock_chunks()?.get(&(*file_id, chunk_index)) {
+            DhtMetrics::increment(&self.metrics.lookups);
+            return Ok(peers.clone());
         }
+        Ok(self.get_file_locations(file_id)?.unwrap_or_default())
     }
 
-    pub fn register_file_location(&self, file_id: Uuid, peer: Peer) {
-        let mut map = self.inner.lock().unwrap();
-        map.entry(file_id).or_insert_with(Vec::new);
-        if let Some(peers) = map.get_mut(&file_id) {
-            if !peers.iter().any(|p| p.address == peer.address) {
-                peers.push(peer.clone());
+    /// Removes `address` from the peers storing `file_id`, dropping the entry
+    /// entirely once no peers remain. Returns whether anything was removed.
+    pub fn deregister_file_location(&self, file_id: &Uuid, address: &str) -> Result<bool, DhtError> {
+        let mut map = self.lock()?;
+        let removed = match map.get_mut(file_id) {
+            Some(peers) => {
+                let before = peers.len();
+                peers.retain(|p| p.address != address);
+                before != peers.len()
             }
+            None => false,
+        };
+        if map.get(file_id).is_some_and(|peers| peers.is_empty()) {
+            map.remove(file_id);
         }
-        info!("Registered file {} at peer {}", file_id, peer.address);
+        drop(map);
+        let mut chunks = self.lock_chunks()?;
+        chunks.retain(|(id, _), peers| {
+            if id == file_id {
+                peers.retain(|p| p.address != address);
+            }
+            !peers.is_empty()
+        });
+        if removed {
+            DhtMetrics::increment(&self.metrics.evictions);
+            info!("Deregistered file {} from peer {}", file_id, address);
+        }
+        Ok(removed)
+    }
+
+    pub fn get_file_locations(&self, file_id: &Uuid) -> Result<Option<Vec<Peer>>, DhtError> {
+        let map = self.lock()?;
+        DhtMetrics::increment(&self.metrics.lookups);
+        Ok(map.get(file_id).cloned())
+    }
+
+    /// Asks `via_peer` where `target_file_id` is stored with `FIND_FILE:<file_id>`.
+    /// The peer answers `FILE_FOUND:<N>` and `N` address lines, or
+    /// `FILE_NOT_FOUND:<file_id>`, after asking its own peers if it knows no
+    /// location itself. The answer is not added to this DHT.
+    pub async fn query_remote(&self, target_file_id: &Uuid, via_peer: &Peer, config: &Config) -> Result<Vec<Peer>, DhtError> {
+        query_remote(target_file_id, via_peer, None, config).await
+    }
+
+    /// Number of distinct files with at least one known location.
+    pub fn file_count(&self) -> Result<usize, DhtError> {
+        let map = self.lock()?;
+        Ok(map.len())
+    }
+
+    /// Copies the whole map under a single short lock.
+    pub fn snapshot(&self) -> Result<DhtSnapshot, DhtError> {
+        let map = self.lock()?;
+        let entries = map
+            .iter()
+            .map(|(file_id, peers)| (*file_id, peers.iter().map(|p| p.address.clone()).collect()))
+            .collect();
+        Ok(DhtSnapshot { entries })
     }
 
-    pub fn get_file_locations(&self, file_id: &Uuid) -> Option<Vec<Peer>> {
-        let map = self.inner.lock().unwrap();
-        map.get(file_id).cloned()
+    /// Replaces the whole map with the contents of `snapshot`. Snapshots only
+    /// carry file locations, so the per-chunk index is cleared.
+    pub fn restore(&self, snapshot: DhtSnapshot) -> Result<(), DhtError> {
+        let entries: HashMap<Uuid, Vec<Peer>> = snapshot
+            .entries
+            .into_iter()
+            .filter(|(_, addresses)| !addresses.is_empty())
+            .map(|(file_id, addresses)| (file_id, addresses.into_iter().map(Peer::new).collect()))
+            .collect();
+        *self.lock()? = entries;
+        self.lock_chunks()?.clear();
+        Ok(())
     }
 
-    pub fn all_entries(&self) -> Vec<(Uuid, String)> {
-        let map = self.inner.lock().unwrap();
+    pub fn all_entries(&self) -> Result<Vec<(Uuid, String)>, DhtError> {
         let mut entries = Vec::new();
-        for (file_id, peers) in map.iter() {
-            for p in peers {
-                entries.push((*file_id, p.address.clone()));
+        for (file_id, addresses) in self.snapshot()?.entries {
+            for address in addresses {
+                entries.push((file_id, address));
             }
         }
-        entries
+        Ok(entries)
     }
 
-    pub fn merge_entries(&self, entries: &[(Uuid, String)]) {
+    pub fn merge_entries(&self, entries: &[(Uuid, String)]) -> Result<(), DhtError> {
+        DhtMetrics::increment(&self.metrics.merges);
         for (file_id, address) in entries {
-            let peer = Peer { address: address.clone() };
-            self.register_file_location(*file_id, peer);
+            let peer = Peer::new(address.clone());
+            self.register_file_location(*file_id, peer)?;
         }
+        Ok(())
+    }
+}
+
+/// Sends `FIND_FILE:<file_id>`, or `FIND_FILE:<file_id>:<hops>` when `hops`
+/// is given; the remote forwards the query to its own peers only while the
+/// hops, 1 when left out, are above zero.
+pub(crate) async fn query_remote(file_id: &Uuid, via_peer: &Peer, hops: Option<u8>, config: &Config) -> Result<Vec<Peer>, DhtError> {
+    let query = async {
+        let stream = transport::connect(via_peer, config).await?;
+        let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await?;
+        session.send_trace_context().await?;
+        send_identity(&mut session, config).await?;
+        let query = match hops {
+            Some(hops) => format!("FIND_FILE:{}:{}\n", file_id, hops),
+            None => format!("FIND_FILE:{}\n", file_id),
+        };
+        session.send(query.as_bytes()).await?;
+        session.stream.flush().await?;
+
+        let not_found = format!("FILE_NOT_FOUND:{}", file_id);
+        while let Some(line) = session.read_line().await? {
+            if line == not_found {
+                return Ok(Vec::new());
+            }
+            // Session chatter such as the welcome message and DHT_REQUEST.
+            let Some(count) = line.strip_prefix("FILE_FOUND:") else {
+                continue;
+            };
+            let count: usize = count.parse().map_err(|_| format!("malformed reply {}", line))?;
+            let mut peers = Vec::with_capacity(count);
+            for _ in 0..count {
+                let address = session.read_line().await?.ok_or("connection closed in the middle of the reply")?;
+                peers.push(Peer::new(address));
+            }
+            return Ok(peers);
+        }
+        Err::<_, Box<dyn StdError + Send + Sync>>("connection closed before the reply".into())
+    };
+    match tokio::time::timeout(QUERY_REMOTE_TIMEOUT, query).await {
+        Ok(result) => result.map_err(|e| DhtError::Remote(format!("{}: {}", via_peer, e))),
+        Err(_) => Err(DhtError::Remote(format!("{}: timed out", via_peer))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_manager::storage::initialize_storage;
+    use crate::indexing::search::search_file;
+    use crate::peer::access_control::{AclStore, NodeKeyStore};
+    use crate::peer::connection::handle_connection;
+    use crate::peer::discovery::PeerRegistry;
+    use crate::peer::stats::SharedNetworkStats;
+    use tokio::net::TcpListener;
+
+    /// Serves `dht` on a local port, forwarding `FIND_FILE` to `peers`.
+    async fn spawn_node(dht: &DHT, peers: Vec<Peer>) -> Peer {
+        spawn_node_with(Config::default(), dht, peers).await
+    }
+
+    async fn spawn_node_with(config: Config, dht: &DHT, peers: Vec<Peer>) -> Peer {
+        let registry = PeerRegistry::default();
+        for peer in peers {
+            registry.add(peer);
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = Peer::new(listener.local_addr().unwrap().to_string());
+        let dht = dht.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let node = handle_connection(stream, config.clone(), registry.clone(), dht.clone(), Peer::new("127.0.0.1:0"), SharedNetworkStats::default());
+                tokio::spawn(node);
+            }
+        });
+        peer
+    }
+
+    #[test]
+    fn test_dht_dump_round_trips_through_json() {
+        let (stored, remote) = (Uuid::new_v4(), Uuid::new_v4());
+        let dht = DHT::new();
+        dht.register_file_location(stored, Peer::new("127.0.0.1:8080")).unwrap();
+        dht.register_file_location(stored, Peer::new("10.0.0.2:8080")).unwrap();
+        dht.register_file_location(remote, Peer::new("10.0.0.3:8080")).unwrap();
+
+        let metadata = DhtFileMetadata { file_name: "a.txt".to_string(), file_size: 7, total_chunks: 1 };
+        let dump = DhtDump::new(dht.snapshot().unwrap(), |file_id| (*file_id == stored).then(|| metadata.clone()));
+        let encoded = crate::json::to_string_pretty(&dump).unwrap();
+        let value: crate::json::Value = crate::json::from_str(&encoded).unwrap();
+        let entry = value["entries"].as_sequence().unwrap().iter().find(|e| e["file_id"].as_str() == Some(&stored.to_string())).unwrap();
+        assert_eq!(entry["metadata"]["file_name"].as_str(), Some("a.txt"));
+        assert_eq!(entry["peers"].as_sequence().unwrap().len(), 2);
+
+        let restored = DHT::new();
+        let decoded: DhtDump = crate::json::from_str(&encoded).unwrap();
+        assert_eq!(decoded, dump);
+        restored.restore(decoded.into()).unwrap();
+        assert_eq!(restored.snapshot().unwrap(), dht.snapshot().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_query_remote_forwards_one_hop() {
+        let file_id = Uuid::new_v4();
+        let far_dht = DHT::new();
+        far_dht.register_file_location(file_id, Peer::new("10.0.0.9:8080")).unwrap();
+        let far = spawn_node(&far_dht, Vec::new()).await;
+        let near = spawn_node(&DHT::new(), vec![far.clone()]).await;
+        let beyond_reach = spawn_node(&DHT::new(), vec![near.clone()]).await;
+
+        let config = Config::default();
+        let local = DHT::new();
+        let found = local.query_remote(&file_id, &near, &config).await.unwrap();
+        assert_eq!(found, vec![Peer::new("10.0.0.9:8080")]);
+        // Two hops away: `near` receives the query with no hops left.
+        assert!(local.query_remote(&file_id, &beyond_reach, &config).await.unwrap().is_empty());
+        assert!(local.get_file_locations(&file_id).unwrap().is_none());
+
+        let results = search_file(&local, &file_id.to_string(), &[beyond_reach, near], &config).await;
+        assert_eq!(results, vec!["10.0.0.9:8080"]);
+        assert_eq!(local.get_file_locations(&file_id).unwrap().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_query_remote_hides_files_the_acl_denies() {
+        let storage = tempfile::tempdir().unwrap();
+        let file_id = Uuid::new_v4();
+        initialize_storage(storage.path(), file_id).unwrap();
+        AclStore::new(storage.path()).share(&file_id, "00aa", "00bb").unwrap();
+        NodeKeyStore::new(storage.path()).insert("00bb", &[0xbb; 32]).unwrap();
+        let dht = DHT::new();
+        dht.register_file_location(file_id, Peer::new("10.0.0.9:8080")).unwrap();
+        let node = spawn_node_with(Config::with_storage_path(storage.path()), &dht, Vec::new()).await;
+
+        let local = DHT::new();
+        assert!(local.query_remote(&file_id, &node, &Config::default()).await.unwrap().is_empty());
+        let shared = Config {
+            node_id: Some("00bb".to_string()),
+            node_key: Some(hex::encode([0xbb; 32]).into()),
+            ..Config::default()
+        };
+        assert_eq!(local.query_remote(&file_id, &node, &shared).await.unwrap(), vec![Peer::new("10.0.0.9:8080")]);
+    }
+
+    #[test]
+    fn test_register_and_deregister() {
+        let dht = DHT::new();
+        let file_id = Uuid::new_v4();
+        dht.register_file_location(file_id, Peer::new("127.0.0.1:1")).unwrap();
+        dht.merge_entries(&[(file_id, "127.0.0.1:1".to_string()), (file_id, "127.0.0.1:2".to_string())]).unwrap();
+        assert_eq!(dht.get_file_locations(&file_id).unwrap().unwrap().len(), 2);
+        assert_eq!(dht.file_count().unwrap(), 1);
+
+        assert!(dht.deregister_file_location(&file_id, "127.0.0.1:1").unwrap());
+        assert!(dht.deregister_file_location(&file_id, "127.0.0.1:2").unwrap());
+        assert!(dht.get_file_locations(&file_id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_chunk_locations() {
+        let dht = DHT::new();
+        let file_id = Uuid::new_v4();
+        dht.register_file_location_with_chunks(file_id, Peer::new("127.0.0.1:1"), 3).unwrap();
+        dht.register_chunk_location(file_id, 1, Peer::new("127.0.0.1:2")).unwrap();
+        dht.register_chunk_location(file_id, 1, Peer::new("127.0.0.1:2")).unwrap();
+
+        assert_eq!(dht.find_peers_for_chunk(&file_id, 0).unwrap(), vec![Peer::new("127.0.0.1:1")]);
+        assert_eq!(
+            dht.find_peers_for_chunk(&file_id, 1).unwrap(),
+            vec![Peer::new("127.0.0.1:1"), Peer::new("127.0.0.1:2")]
+        );
+        // No per-chunk entry: falls back to the file's locations.
+        dht.merge_entries(&[(file_id, "127.0.0.1:3".to_string())]).unwrap();
+        assert_eq!(dht.find_peers_for_chunk(&file_id, 5).unwrap().len(), 2);
+
+        dht.deregister_file_location(&file_id, "127.0.0.1:1").unwrap();
+        assert_eq!(dht.find_peers_for_chunk(&file_id, 1).unwrap(), vec![Peer::new("127.0.0.1:2")]);
+        assert_eq!(dht.find_peers_for_chunk(&file_id, 0).unwrap(), vec![Peer::new("127.0.0.1:3")]);
+        assert!(dht.find_peers_for_chunk(&Uuid::new_v4(), 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let dht = DHT::new();
+        let file_id = Uuid::new_v4();
+        dht.register_file_location(file_id, Peer::new("127.0.0.1:1")).unwrap();
+        dht.register_file_location(file_id, Peer::new("127.0.0.1:2")).unwrap();
+
+        let snapshot = dht.snapshot().unwrap();
+        assert_eq!(snapshot.entries[&file_id], vec!["127.0.0.1:1", "127.0.0.1:2"]);
+        let encoded = crate::json::to_string(&snapshot).unwrap();
+
+        let other = DHT::new();
+        other.register_file_location(Uuid::new_v4(), Peer::new("127.0.0.1:3")).unwrap();
+        other.restore(crate::json::from_str(&encoded).unwrap()).unwrap();
+        assert_eq!(other.snapshot().unwrap(), snapshot);
+        assert_eq!(other.file_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_metrics_count_operations() {
+        let dht = DHT::new();
+        let file_id = Uuid::new_v4();
+        dht.register_file_location(file_id, Peer::new("127.0.0.1:1")).unwrap();
+        dht.merge_entries(&[(file_id, "127.0.0.1:1".to_string()), (file_id, "127.0.0.1:2".to_string())]).unwrap();
+        dht.get_file_locations(&file_id).unwrap();
+        dht.register_chunk_location(file_id, 0, Peer::new("127.0.0.1:1")).unwrap();
+        dht.find_peers_for_chunk(&file_id, 0).unwrap();
+        // Falls back to the file locations: still one lookup.
+        dht.find_peers_for_chunk(&file_id, 1).unwrap();
+        dht.deregister_file_location(&file_id, "127.0.0.1:2").unwrap();
+        dht.deregister_file_location(&file_id, "127.0.0.1:9").unwrap();
+
+        // Clones share the counters.
+        let clone = dht.clone();
+        let metrics = clone.metrics();
+        assert_eq!(metrics.inserts(), 2);
+        assert_eq!(metrics.conflicts(), 1);
+        assert_eq!(metrics.merges(), 1);
+        assert_eq!(metrics.lookups(), 3);
+        assert_eq!(metrics.evictions(), 1);
+    }
+
+    #[test]
+    fn test_poisoned_lock_returns_error() {
+        let dht = DHT::new();
+        let poisoner = dht.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.inner.lock().unwrap();
+            panic!("poison the DHT lock");
+        })
+        .join();
+
+        assert!(matches!(dht.register_file_location(Uuid::new_v4(), Peer::new("127.0.0.1:1")), Err(DhtError::Poisoned)));
+        assert!(matches!(dht.get_file_locations(&Uuid::new_v4()), Err(DhtError::Poisoned)));
+        assert!(matches!(dht.all_entries(), Err(DhtError::Poisoned)));
     }
 }
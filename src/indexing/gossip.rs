@@ -0,0 +1,289 @@
+// src/indexing/gossip.rs
+
+//! Epidemic spreading of DHT entries. Each round, a node sends the entries it
+//! has not gossiped yet to a few random peers, which merge them and pass them
+//! on in their own rounds. A node learns about a file's location this way
+//! without connecting to the peer that holds it.
+//!
+//! A node that has just stored a file does not wait for gossip: it announces
+//! the new location to every peer it knows with [`announce_to_dht`].
+
+use crate::config::Config;
+use crate::indexing::dht::{DhtError, DHT};
+use crate::peer::discovery::{Peer, PeerRegistry};
+use crate::peer::protocol::{DhtEntry, Message, ProtocolVersion};
+use crate::peer::session::{PeerCapabilities, PeerSession};
+use crate::peer::transport;
+use log::{debug, error};
+use rand::seq::SliceRandom;
+use std::collections::HashSet;
+use std::error::Error;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+#[derive(Error, Debug)]
+pub enum AnnouncementError {
+    #[error("No peer received the announcement of file {0}")]
+    NoPeerReached(Uuid),
+}
+
+/// How often a gossip round runs.
+pub const GOSSIP_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct GossipTask {
+    dht: DHT,
+    peers: PeerRegistry,
+    local_peer: Peer,
+    config: Config,
+    /// Entries already passed on; each is gossiped by this node at most once.
+    gossiped: HashSet<(Uuid, String)>,
+}
+
+impl GossipTask {
+    pub fn new(dht: DHT, peers: PeerRegistry, local_peer: Peer, config: Config) -> Self {
+        GossipTask {
+            dht,
+            peers,
+            local_peer,
+            config,
+            gossiped: HashSet::new(),
+        }
+    }
+
+    /// Runs a gossip round every `GOSSIP_INTERVAL`, forever.
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(GOSSIP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match self.round().await {
+                Ok(sent) => debug!("Gossiped {} DHT entries", sent),
+                Err(e) => error!("Gossip round failed: {}", e),
+            }
+        }
+    }
+
+    /// Sends up to `max_gossip_entries` new entries to `gossip_fanout` random
+    /// peers. Entries count as gossiped once at least one peer received them.
+    /// Returns how many entries were sent.
+    pub async fn round(&mut self) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let entries = self.pending_entries()?;
+        if entries.is_empty() {
+            return Ok(0);
+        }
+
+        let candidates: Vec<Peer> = self.peers.all().into_iter().filter(|p| p.address != self.local_peer.address).collect();
+        let targets: Vec<Peer> = candidates
+            .choose_multiple(&mut rand::thread_rng(), self.config.gossip_fanout)
+            .cloned()
+            .collect();
+
+        let mut delivered = false;
+        for peer in &targets {
+            match send_gossip(peer, &entries, &self.config).await {
+                Ok(()) => delivered = true,
+                Err(e) => error!("Failed to gossip to {}: {}", peer, e),
+            }
+        }
+        if !delivered {
+            return Ok(0);
+        }
+
+        self.gossiped.extend(entries.iter().map(|e| (e.file_id, e.address.clone())));
+        Ok(entries.len())
+    }
+
+    fn pending_entries(&self) -> Result<Vec<DhtEntry>, DhtError> {
+        let mut entries: Vec<DhtEntry> = self
+            .dht
+            .all_entries()?
+            .into_iter()
+            .filter(|entry| !self.gossiped.contains(entry))
+            .map(|(file_id, address)| DhtEntry { file_id, address })
+            .collect();
+        entries.truncate(self.config.max_gossip_entries);
+        Ok(entries)
+    }
+}
+
+/// Merges entries received from a peer's gossip round, returning how many
+/// `(file, peer)` locations were added.
+pub fn receive_gossip_entries(dht: &DHT, entries: &[(Uuid, Vec<String>)]) -> Result<usize, DhtError> {
+    let mut added = 0;
+    for (file_id, addresses) in entries {
+        let known = dht.get_file_locations(file_id)?.unwrap_or_default();
+        for address in addresses {
+            if !known.iter().any(|p| &p.address == address) {
+                dht.register_file_location(*file_id, Peer::new(address.clone()))?;
+                added += 1;
+            }
+        }
+    }
+    Ok(added)
+}
+
+/// Groups flat entries by file, in first-seen order.
+pub fn group_entries(entries: Vec<DhtEntry>) -> Vec<(Uuid, Vec<String>)> {
+    let mut grouped: Vec<(Uuid, Vec<String>)> = Vec::new();
+    for entry in entries {
+        match grouped.iter_mut().find(|(file_id, _)| *file_id == entry.file_id) {
+            Some((_, addresses)) => addresses.push(entry.address),
+            None => grouped.push((entry.file_id, vec![entry.address])),
+        }
+    }
+    grouped
+}
+
+/// Tells every peer in `known_peers` that `local_peer` stores `file_id`, with
+/// a `DHT_ANNOUNCE:<file_id>:<peer address>` line. Peers that cannot be
+/// reached are logged and skipped; it is an error only if none could be.
+pub async fn announce_to_dht(
+    file_id: Uuid,
+    local_peer: &Peer,
+    known_peers: &[Peer],
+    config: &Config,
+) -> Result<(), AnnouncementError> {
+    let targets: Vec<&Peer> = known_peers.iter().filter(|p| p.address != local_peer.address).collect();
+    if targets.is_empty() {
+        return Ok(());
+    }
+    let mut delivered = 0;
+    for peer in &targets {
+        match send_announcement(peer, file_id, local_peer, config).await {
+            Ok(()) => delivered += 1,
+            Err(e) => error!("Failed to announce file={} to {}: {}", file_id, peer, e),
+        }
+    }
+    debug!("Announced file {} to {} of {} peers", file_id, delivered, targets.len());
+    if delivered == 0 {
+        return Err(AnnouncementError::NoPeerReached(file_id));
+    }
+    Ok(())
+}
+
+async fn send_announcement(
+    peer: &Peer,
+    file_id: Uuid,
+    local_peer: &Peer,
+    config: &Config,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let stream = transport::connect(peer, config).await?;
+    let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await?;
+    session.send_trace_context().await?;
+    session.send(format!("DHT_ANNOUNCE:{}:{}\n", file_id, local_peer.address).as_bytes()).await?;
+    session.stream.flush().await?;
+    Ok(())
+}
+
+async fn send_gossip(peer: &Peer, entries: &[DhtEntry], config: &Config) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let stream = transport::connect(peer, config).await?;
+    let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await?;
+    session.send_trace_context().await?;
+    if session.negotiate_version(ProtocolVersion::V2).await? != ProtocolVersion::V2 {
+        return Err(format!("Peer {} does not support gossip", peer.address).into());
+    }
+    session.send_message(&Message::Gossip { entries: entries.to_vec() }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::connection::handle_connection;
+    use crate::peer::stats::SharedNetworkStats;
+    use tokio::net::TcpListener;
+
+    fn test_config() -> Config {
+        Config { max_gossip_entries: 2, ..Config::with_port(0) }
+    }
+
+    #[test]
+    fn test_receive_gossip_entries_skips_known_locations() {
+        let dht = DHT::new();
+        let file_id = Uuid::new_v4();
+        dht.register_file_location(file_id, Peer::new("10.0.0.1:8080")).unwrap();
+
+        let entries = group_entries(vec![
+            DhtEntry { file_id, address: "10.0.0.1:8080".to_string() },
+            DhtEntry { file_id, address: "10.0.0.2:8080".to_string() },
+        ]);
+        assert_eq!(entries, vec![(file_id, vec!["10.0.0.1:8080".to_string(), "10.0.0.2:8080".to_string()])]);
+        assert_eq!(receive_gossip_entries(&dht, &entries).unwrap(), 1);
+        assert_eq!(receive_gossip_entries(&dht, &entries).unwrap(), 0);
+        assert_eq!(dht.get_file_locations(&file_id).unwrap().unwrap().len(), 2);
+    }
+
+    /// Serves `remote_dht` through `handle_connection` on a local port.
+    async fn spawn_remote_peer(remote_dht: &DHT) -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = Peer::new(listener.local_addr().unwrap().to_string());
+        let served_dht = remote_dht.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(handle_connection(
+                    stream,
+                    Config::default(),
+                    PeerRegistry::default(),
+                    served_dht.clone(),
+                    Peer::new("127.0.0.1:0"),
+                    SharedNetworkStats::default(),
+                ));
+            }
+        });
+        remote
+    }
+
+    #[tokio::test]
+    async fn test_announce_to_dht_registers_location_on_peers() {
+        let remote_dht = DHT::new();
+        let remote = spawn_remote_peer(&remote_dht).await;
+        let local_peer = Peer::new("127.0.0.1:8080");
+        let file_id = Uuid::new_v4();
+
+        // Only ourselves: nothing to announce to.
+        announce_to_dht(file_id, &local_peer, std::slice::from_ref(&local_peer), &test_config()).await.unwrap();
+        let unreachable = [Peer::new("127.0.0.1:1")];
+        assert!(matches!(
+            announce_to_dht(file_id, &local_peer, &unreachable, &test_config()).await,
+            Err(AnnouncementError::NoPeerReached(id)) if id == file_id
+        ));
+
+        announce_to_dht(file_id, &local_peer, &[unreachable[0].clone(), remote], &test_config()).await.unwrap();
+        for _ in 0..100 {
+            if let Some(locations) = remote_dht.get_file_locations(&file_id).unwrap() {
+                assert_eq!(locations, vec![local_peer]);
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("remote DHT never learned about file {}", file_id);
+    }
+
+    #[tokio::test]
+    async fn test_round_sends_only_new_entries() {
+        let remote_dht = DHT::new();
+        let remote = spawn_remote_peer(&remote_dht).await;
+
+        let dht = DHT::new();
+        for _ in 0..3 {
+            dht.register_file_location(Uuid::new_v4(), Peer::new("10.0.0.9:8080")).unwrap();
+        }
+        let peers = PeerRegistry::default();
+        peers.add(remote);
+        let mut task = GossipTask::new(dht, peers, Peer::new("127.0.0.1:8080"), test_config());
+
+        // max_gossip_entries bounds the first round; the second sends the rest.
+        assert_eq!(task.round().await.unwrap(), 2);
+        assert_eq!(task.round().await.unwrap(), 1);
+        assert_eq!(task.round().await.unwrap(), 0);
+
+        for _ in 0..100 {
+            if remote_dht.file_count().unwrap() == 3 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("remote DHT has {} files", remote_dht.file_count().unwrap());
+    }
+}
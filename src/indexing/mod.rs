@@ -1,2 +1,2 @@
 pub mod search;
-pub mod dht;
\ No newline at end of file
+pub mod dht;pub mod gossip;
@@ -1,19 +1,31 @@
 // src/indexing/search.rs
 
+use crate::config::Config;
 use crate::indexing::dht::DHT;
+use crate::peer::discovery::Peer;
 use uuid::Uuid;
 use log::{info, error};
 
-pub fn search_file(dht: &DHT, query: &str) -> Vec<String> {
+/// Looks the file up in the local DHT and, if no location is known, asks each
+/// of `peers` in turn with [`DHT::query_remote`]. Locations learned that way
+/// are added to the local DHT, so a download can use them.
+pub async fn search_file(dht: &DHT, query: &str, peers: &[Peer], config: &Config) -> Vec<String> {
     match Uuid::parse_str(query) {
         Ok(file_id) => {
-            if let Some(peers) = dht.get_file_locations(&file_id) {
-                let addresses: Vec<String> = peers.into_iter().map(|p| p.address).collect();
-                info!("Found file {} at peers: {:?}", file_id, addresses);
-                addresses
-            } else {
-                info!("No peers found for file {}", file_id);
-                Vec::new()
+            match dht.get_file_locations(&file_id) {
+                Ok(Some(peers)) => {
+                    let addresses: Vec<String> = peers.into_iter().map(|p| p.address).collect();
+                    info!("Found file {} at peers: {:?}", file_id, addresses);
+                    addresses
+                }
+                Ok(None) => {
+                    info!("No peers found for file {} locally; asking {} peers", file_id, peers.len());
+                    search_remote(dht, &file_id, peers, config).await
+                }
+                Err(e) => {
+                    error!("Failed to look up file {}: {}", file_id, e);
+                    Vec::new()
+                }
             }
         }
         Err(_) => {
@@ -22,3 +34,23 @@ pub fn search_file(dht: &DHT, query: &str) -> Vec<String> {
         }
     }
 }
+
+async fn search_remote(dht: &DHT, file_id: &Uuid, peers: &[Peer], config: &Config) -> Vec<String> {
+    for peer in peers {
+        match dht.query_remote(file_id, peer, config).await {
+            Ok(found) if !found.is_empty() => {
+                let addresses: Vec<String> = found.iter().map(|p| p.address.clone()).collect();
+                info!("{} knows file {} at peers: {:?}", peer, file_id, addresses);
+                for location in found {
+                    if let Err(e) = dht.register_file_location(*file_id, location) {
+                        error!("Failed to record a location of file {}: {}", file_id, e);
+                    }
+                }
+                return addresses;
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to search {} for file {}: {}", peer, file_id, e),
+        }
+    }
+    Vec::new()
+}
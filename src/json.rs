@@ -0,0 +1,217 @@
+// src/json.rs
+
+//! Minimal JSON support built on the `serde_yaml` data model.
+//!
+//! Values are serialized into a `serde_yaml::Value` and rendered as JSON text.
+//! Parsing relies on JSON being valid YAML flow syntax. Enums that carry data
+//! must use an internally tagged representation (`#[serde(tag = "...")]`);
+//! externally tagged variants have no JSON form here and are rejected.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_yaml::Value as YamlValue;
+use std::fmt::Write;
+#[cfg(not(feature = "wasm"))]
+use std::{ffi::OsString, fs, io, path::Path};
+use thiserror::Error;
+
+pub type Value = YamlValue;
+
+#[derive(Error, Debug)]
+pub enum JsonError {
+    #[error("Serde Error: {0}")]
+    SerdeError(#[from] serde_yaml::Error),
+
+    #[error("Value cannot be represented as JSON: {0}")]
+    Unsupported(String),
+}
+
+pub fn to_value<T: Serialize>(value: &T) -> Result<Value, JsonError> {
+    Ok(serde_yaml::to_value(value)?)
+}
+
+pub fn to_string<T: Serialize>(value: &T) -> Result<String, JsonError> {
+    let mut out = String::new();
+    write_value(&mut out, &to_value(value)?, None, 0)?;
+    Ok(out)
+}
+
+pub fn to_string_pretty<T: Serialize>(value: &T) -> Result<String, JsonError> {
+    let mut out = String::new();
+    write_value(&mut out, &to_value(value)?, Some(2), 0)?;
+    Ok(out)
+}
+
+pub fn from_str<T: DeserializeOwned>(s: &str) -> Result<T, JsonError> {
+    Ok(serde_yaml::from_str(s)?)
+}
+
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, JsonError> {
+    Ok(serde_yaml::from_value(value)?)
+}
+
+/// Writes `value` to `path` as JSON through [`replace_file`], so readers
+/// never see a partly written file.
+#[cfg(not(feature = "wasm"))]
+pub fn write_atomic<T, E>(path: &Path, value: &T) -> Result<(), E>
+where
+    T: Serialize,
+    E: From<io::Error> + From<JsonError>,
+{
+    Ok(replace_file(path, &to_string(value)?)?)
+}
+
+/// [`write_atomic`] with the layout of [`to_string_pretty`], for files people read.
+#[cfg(not(feature = "wasm"))]
+pub fn write_atomic_pretty<T, E>(path: &Path, value: &T) -> Result<(), E>
+where
+    T: Serialize,
+    E: From<io::Error> + From<JsonError>,
+{
+    Ok(replace_file(path, &to_string_pretty(value)?)?)
+}
+
+/// Replaces the file at `path` with `contents` by writing `<path>.tmp` and
+/// renaming it over `path`. Leftover `.tmp` files are removed by the storage
+/// garbage collector.
+#[cfg(not(feature = "wasm"))]
+pub fn replace_file(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp_path = OsString::from(path);
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)
+}
+
+fn write_value(out: &mut String, value: &Value, indent: Option<usize>, depth: usize) -> Result<(), JsonError> {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => {
+            if n.as_f64().is_some_and(|f| !f.is_finite()) {
+                return Err(JsonError::Unsupported(format!("non-finite number {}", n)));
+            }
+            let _ = write!(out, "{}", n);
+        }
+        Value::String(s) => write_string(out, s),
+        Value::Sequence(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                newline(out, indent, depth + 1);
+                write_value(out, item, indent, depth + 1)?;
+            }
+            if !items.is_empty() {
+                newline(out, indent, depth);
+            }
+            out.push(']');
+        }
+        Value::Mapping(map) => {
+            out.push('{');
+            for (i, (key, item)) in map.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                newline(out, indent, depth + 1);
+                match key {
+                    Value::String(s) => write_string(out, s),
+                    Value::Number(n) => write_string(out, &n.to_string()),
+                    Value::Bool(b) => write_string(out, &b.to_string()),
+                    other => return Err(JsonError::Unsupported(format!("map key {:?}", other))),
+                }
+                out.push(':');
+                if indent.is_some() {
+                    out.push(' ');
+                }
+                write_value(out, item, indent, depth + 1)?;
+            }
+            if !map.is_empty() {
+                newline(out, indent, depth);
+            }
+            out.push('}');
+        }
+        Value::Tagged(tagged) => {
+            return Err(JsonError::Unsupported(format!("externally tagged enum variant {}", tagged.tag)));
+        }
+    }
+    Ok(())
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn newline(out: &mut String, indent: Option<usize>, depth: usize) {
+    if let Some(width) = indent {
+        out.push('\n');
+        out.extend(std::iter::repeat_n(' ', width * depth));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    enum Event {
+        Ping,
+        Chunk { index: usize, name: String, data: Vec<u8> },
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let events = vec![
+            Event::Ping,
+            Event::Chunk { index: 7, name: "a \"quoted\"\n\u{1}name".to_string(), data: vec![0, 255] },
+        ];
+        let compact = to_string(&events).unwrap();
+        assert_eq!(
+            compact,
+            r#"[{"type":"Ping"},{"type":"Chunk","index":7,"name":"a \"quoted\"\n\u0001name","data":[0,255]}]"#
+        );
+        assert_eq!(from_str::<Vec<Event>>(&compact).unwrap(), events);
+        assert_eq!(from_str::<Vec<Event>>(&to_string_pretty(&events).unwrap()).unwrap(), events);
+    }
+
+    #[test]
+    #[cfg(not(feature = "wasm"))]
+    fn test_write_atomic_replaces_the_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("events.json");
+        std::fs::write(&path, "[]").unwrap();
+
+        write_atomic::<_, Box<dyn std::error::Error>>(&path, &[Event::Ping]).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), r#"[{"type":"Ping"}]"#);
+        write_atomic_pretty::<_, Box<dyn std::error::Error>>(&path, &[Event::Ping]).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(from_str::<Vec<Event>>(&contents).unwrap(), vec![Event::Ping]);
+        assert!(contents.contains('\n'));
+        assert!(!temp_dir.path().join("events.json.tmp").exists());
+    }
+
+    #[test]
+    fn test_rejects_externally_tagged_enums() {
+        #[derive(Serialize)]
+        enum External {
+            Data(u8),
+        }
+        assert!(matches!(to_string(&External::Data(1)), Err(JsonError::Unsupported(_))));
+    }
+}
@@ -0,0 +1,22 @@
+#[cfg(not(feature = "wasm"))]
+pub mod config;
+pub mod json;
+pub mod base64;
+#[cfg(not(feature = "wasm"))]
+pub mod history;
+pub mod peer;
+pub mod file_manager;
+#[cfg(not(feature = "wasm"))]
+pub mod indexing;
+#[cfg(not(feature = "wasm"))]
+pub mod ui;
+#[cfg(not(feature = "wasm"))]
+pub mod telemetry;
+#[cfg(not(feature = "wasm"))]
+pub mod events;
+#[cfg(not(feature = "wasm"))]
+pub mod http;
+#[cfg(not(feature = "wasm"))]
+pub mod openapi;
+#[cfg(feature = "wasm")]
+pub mod wasm;
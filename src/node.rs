@@ -0,0 +1,344 @@
+// src/node.rs
+
+//! The node binary: the command line, startup and the main loop.
+
+use clap::{CommandFactory, Parser, Subcommand, ValueHint};
+use env_logger::Env;
+use log::{error, info, warn};
+use peerchunks::config::{rotate_encryption_key, update_bootstrap_peers, MultiConfig};
+use peerchunks::telemetry;
+use peerchunks::http::{start_http_server, HealthState};
+use peerchunks::openapi::openapi_json;
+use peerchunks::peer::access_control::{parse_node_key, AclStore, NodeKeyStore};
+use peerchunks::peer::discovery::{connect_to_peer, discover_peers, start_peer_discovery, OutgoingConnections, Peer, PeerRegistry};
+use peerchunks::peer::url::PeerUrl;
+use peerchunks::peer::session::PeerCapabilities;
+use peerchunks::peer::latency::PeerLatency;
+use peerchunks::peer::stats::SharedNetworkStats;
+use peerchunks::ui::cli::{read_commands, run_cli, run_seed, Cli as PromptCli};
+use peerchunks::ui::completions::{self, Shell};
+use peerchunks::ui::output::{OutputFormat, Printable};
+use peerchunks::indexing::dht::DHT;
+use peerchunks::file_manager::replication::{repair_under_replicated_files, ReplicationContext, ReplicationStatusStore};
+use peerchunks::file_manager::storage::{garbage_collect, list_all_files, validate_storage_directory};
+use std::error::Error;
+use tokio::sync::mpsc;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Leftovers at least this old are removed when the node starts.
+const STARTUP_GC_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Parser)]
+#[command(name = "ShareSphere")]
+#[command(about = "A peer-to-peer distributed file sharing system", long_about = None)]
+struct Cli {
+    /// Highest-priority config file, layered over the system-wide and user ones.
+    #[arg(short, long, default_value = "config.yaml", value_hint = ValueHint::FilePath)]
+    config: String,
+
+    /// Refuse to start if stored chunks are missing or corrupt.
+    #[arg(long)]
+    strict: bool,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    Upload {
+        #[arg(value_hint = ValueHint::FilePath)]
+        file_path: String,
+    },
+    Download {
+        #[arg(value_hint = ValueHint::Other)]
+        file_id: String,
+        #[arg(value_hint = ValueHint::AnyPath)]
+        destination: String,
+    },
+    Search {
+        #[arg(value_hint = ValueHint::Other)]
+        query: String,
+    },
+    /// Re-wrap stored file keys with a new master key and write it to the config file.
+    RotateKey {
+        /// 64 hex characters.
+        new_key: String,
+    },
+    /// Remove temporary files left by interrupted writes and hash files
+    /// whose chunk is gone.
+    Gc {
+        /// Only remove files at least this many seconds old.
+        #[arg(long, default_value_t = STARTUP_GC_MIN_AGE.as_secs())]
+        min_age_secs: u64,
+    },
+    /// Let a peer download a stored file. The first share restricts the file
+    /// to this node, as its owner, and the peers it is shared with.
+    Share {
+        #[arg(value_hint = ValueHint::Other)]
+        file_id: String,
+        /// The peer's node ID, in hex.
+        #[arg(value_hint = ValueHint::Other)]
+        peer_id: String,
+        /// The peer's `node_key`, which proves its node ID. Needed the first
+        /// time a file is shared with the peer.
+        #[arg(long, value_hint = ValueHint::Other)]
+        key: Option<String>,
+    },
+    /// Stop a peer from downloading a stored file it was shared with.
+    Revoke {
+        #[arg(value_hint = ValueHint::Other)]
+        file_id: String,
+        #[arg(value_hint = ValueHint::Other)]
+        peer_id: String,
+    },
+    /// Start the node and connect to a peer that is not a bootstrap peer.
+    Connect {
+        /// `host:port` or a `sharesphere://` URL.
+        #[arg(value_hint = ValueHint::Other)]
+        peer_addr: String,
+        /// Also add the peer to `bootstrap_peers` in the config file.
+        #[arg(long)]
+        persist: bool,
+    },
+    /// Probe the bootstrap peers, the nodes that answer a LAN discovery
+    /// beacon and the peers they are connected to, and list the ones that
+    /// answered. Nothing is saved.
+    Discover {
+        /// Stop waiting for answers after this many seconds.
+        #[arg(long, default_value_t = 5)]
+        timeout_secs: u64,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Keep every file under a directory uploaded: upload new and changed
+    /// files as they appear, and keep re-announcing and repairing them.
+    Seed {
+        #[arg(value_hint = ValueHint::DirPath)]
+        directory: String,
+        /// Replicas to keep of each file; defaults to `replication_target_factor`.
+        #[arg(long)]
+        replication_factor: Option<usize>,
+    },
+    /// Replicate stored files that fewer than `replication_target_factor`
+    /// peers hold, asking the bootstrap peers where each file is.
+    Repair,
+    /// Write the OpenAPI description of the HTTP endpoints to a file, as
+    /// served on `/openapi.json`.
+    GenerateSchema {
+        #[arg(value_hint = ValueHint::FilePath)]
+        output: String,
+    },
+    /// Print a shell completion script to stdout. `rlwrap` prints the words
+    /// of the commands typed at the node's prompt instead.
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let cli = Cli::parse();
+    if let Some(Commands::Completions { shell }) = cli.command {
+        let mut command = match shell {
+            Shell::Rlwrap => PromptCli::command(),
+            _ => Cli::command(),
+        };
+        completions::generate(shell, &mut command, env!("CARGO_BIN_NAME"), &mut std::io::stdout())?;
+        return Ok(());
+    }
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    if let Some(Commands::GenerateSchema { output }) = &cli.command {
+        fs::write(output, openapi_json()?)?;
+        info!("Wrote the OpenAPI schema to {}", output);
+        return Ok(());
+    }
+    info!("Starting ShareSphere...");
+
+    let layers = MultiConfig::new().with_path(&cli.config);
+    let mut config = layers.load().unwrap_or_else(|err| {
+        error!("Failed to load configuration: {}", err);
+        std::process::exit(1);
+    });
+    info!("Configuration loaded successfully.");
+
+    if let Some(Commands::RotateKey { new_key }) = &cli.command {
+        let key_layer = layers.encryption_key_layer()?;
+        let report = rotate_encryption_key(&config, new_key, Path::new(&config.storage.storage_path), key_layer).await?;
+        for (file_id, reason) in &report.failed {
+            error!("Cannot re-wrap the key of file {}: {}", file_id, reason);
+        }
+        if !report.failed.is_empty() {
+            return Err("Key rotation aborted; nothing was changed".into());
+        }
+        info!("Rotated the keys of {} files; {} had no key of their own", report.rotated.len(), report.skipped.len());
+        return Ok(());
+    }
+
+    match &cli.command {
+        Some(Commands::Share { file_id, peer_id, key }) => {
+            let Some(owner) = &config.node_id else {
+                return Err("Set node_id in the config before sharing files".into());
+            };
+            let node_keys = NodeKeyStore::new(&config.storage.storage_path);
+            match key {
+                Some(key) => {
+                    let key = parse_node_key(key).ok_or("--key must be 64 hex characters (32 bytes)")?;
+                    node_keys.insert(peer_id, &key)?;
+                }
+                None if node_keys.get(peer_id).is_none() => {
+                    return Err(format!("No key is known for {}; pass its node_key with --key", peer_id).into());
+                }
+                None => {}
+            }
+            let acl = AclStore::new(&config.storage.storage_path).share(&Uuid::parse_str(file_id)?, owner, peer_id)?;
+            info!("File {} is shared with {} peers", file_id, acl.allowed_peers.len());
+            return Ok(());
+        }
+        Some(Commands::Discover { timeout_secs, format }) => {
+            let probes = discover_peers(&config, Duration::from_secs(*timeout_secs)).await;
+            if probes.is_empty() && *format == OutputFormat::Table {
+                println!("No peers answered.");
+            } else {
+                probes.print(*format);
+            }
+            return Ok(());
+        }
+        Some(Commands::Revoke { file_id, peer_id }) => {
+            if AclStore::new(&config.storage.storage_path).revoke(&Uuid::parse_str(file_id)?, peer_id)? {
+                info!("Revoked access of {} to file {}", peer_id, file_id);
+            } else {
+                info!("File {} was not shared with {}", file_id, peer_id);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    if let Some(endpoint) = &config.otlp_endpoint {
+        telemetry::init(endpoint)?;
+        info!("Exporting traces to {}", endpoint);
+    }
+
+    if !Path::new(&config.storage.storage_path).exists() {
+        fs::create_dir_all(&config.storage.storage_path)?;
+        info!("Created storage directory at {}", config.storage.storage_path);
+    }
+
+    if let Some(Commands::Gc { min_age_secs }) = &cli.command {
+        let report = garbage_collect(Path::new(&config.storage.storage_path), Duration::from_secs(*min_age_secs))?;
+        info!("Removed {} stale files, freeing {} bytes", report.files_removed, report.bytes_freed);
+        return Ok(());
+    }
+    match garbage_collect(Path::new(&config.storage.storage_path), STARTUP_GC_MIN_AGE) {
+        Ok(report) if report.files_removed > 0 => {
+            info!("Removed {} stale files, freeing {} bytes", report.files_removed, report.bytes_freed)
+        }
+        Ok(_) => {}
+        Err(e) => error!("Failed to clean up stale files: {}", e),
+    }
+    match validate_storage_directory(Path::new(&config.storage.storage_path)) {
+        Ok(issues) => {
+            for issue in &issues {
+                warn!("Storage check: {}", issue);
+            }
+            let data_loss = issues.iter().filter(|issue| issue.is_data_loss()).count();
+            if cli.strict && data_loss > 0 {
+                return Err(format!("{} stored chunks are missing or corrupt; run `verify --repair` or start without --strict", data_loss).into());
+            }
+        }
+        Err(e) => error!("Failed to check the storage directory: {}", e),
+    }
+
+    let dht = DHT::new();
+    let local_peer = Peer::builder()
+        .address(format!("127.0.0.1:{}", config.discovery.peer_port))
+        .capability_flags(PeerCapabilities::local().to_bits())
+        .build()?;
+
+    if let Some(Commands::Repair) = &cli.command {
+        let peers: Vec<Peer> = config.discovery.bootstrap_peers.iter().filter_map(|url| Peer::try_from(url.clone()).ok()).collect();
+        // A node that was not running knows no locations of its own.
+        for file_id in list_all_files(&config.storage.storage_path)? {
+            for peer in &peers {
+                match dht.query_remote(&file_id, peer, &config).await {
+                    Ok(found) => found.into_iter().try_for_each(|holder| dht.register_file_location(file_id, holder))?,
+                    Err(e) => warn!("Failed to ask {} where file {} is: {}", peer, file_id, e),
+                }
+            }
+        }
+        let context = ReplicationContext {
+            network_stats: SharedNetworkStats::default(),
+            latency: PeerLatency::default(),
+            peer_load: Default::default(),
+            config: config.clone(),
+            status: ReplicationStatusStore::open(&config.storage.storage_path),
+            reputation: Default::default(),
+        };
+        let storage_root = Path::new(&config.storage.storage_path);
+        let summary = repair_under_replicated_files(&dht, storage_root, &local_peer, &peers, config.storage.replication_target_factor, &context).await?;
+        info!(
+            "Checked {} files: {} repaired, {} failed",
+            summary.files_checked, summary.files_repaired, summary.files_failed
+        );
+        return Ok(());
+    }
+
+    let (tx, rx) = mpsc::channel(100);
+
+    let peers = PeerRegistry::new(config.peer_event_buffer);
+    let network_stats = SharedNetworkStats::default();
+    let latency = PeerLatency::default();
+
+    if let Some(http_port) = config.http_port {
+        let health = HealthState::new(peers.clone(), dht.clone(), config.storage.storage_quota_bytes);
+        tokio::spawn(health.clone().refresh_storage_usage(config.storage.storage_path.clone().into()));
+        tokio::spawn(async move {
+            if let Err(e) = start_http_server(http_port, health).await {
+                error!("HTTP server stopped: {}", e);
+            }
+        });
+    }
+
+    let connections = OutgoingConnections::default();
+    let peer_discovery_handle = tokio::spawn(start_peer_discovery(config.clone(), tx.clone(), dht.clone(), local_peer.clone(), peers.clone(), network_stats.clone(), latency.clone(), connections.clone()));
+    if let Some(Commands::Connect { peer_addr, persist }) = &cli.command {
+        let url: PeerUrl = peer_addr.parse()?;
+        if config.discovery.bootstrap_peers.iter().any(|p| p.address() == url.address()) {
+            return Err(format!("{} is already a bootstrap peer", url).into());
+        }
+        let peer = Peer::try_from(url.clone())?;
+        let connected = connect_to_peer(peer, &config, dht.clone(), local_peer.clone(), peers.clone(), network_stats.clone(), &connections).await?;
+        info!(
+            "Connected to {} using protocol version {}; peer capabilities: {}",
+            url,
+            connected.protocol_version.number(),
+            connected.capabilities
+        );
+        if *persist {
+            let Some(layer) = layers.writable_layer() else {
+                return Err(format!("No config file to add {} to; create {} first", url, cli.config).into());
+            };
+            update_bootstrap_peers(layer, |peers| peers.push(url.clone()))?;
+            config.discovery.bootstrap_peers.push(url.clone());
+            info!("Added {} to the bootstrap peers in {}", url, layer.display());
+        }
+    }
+    if let Some(Commands::Seed { directory, replication_factor }) = &cli.command {
+        // Only returns if seeding cannot start.
+        return run_seed(directory.into(), *replication_factor, dht, config, peers, local_peer, network_stats, latency).await;
+    }
+    let shared_config = Arc::new(RwLock::new(config));
+    tokio::spawn(read_commands(tx));
+    let cli_handle = tokio::spawn(run_cli(rx, dht, shared_config, layers.writable_layer().map(Path::to_path_buf), peers, local_peer, network_stats, latency, connections));
+
+    let _ = tokio::join!(peer_discovery_handle, cli_handle);
+
+    info!("ShareSphere has stopped.");
+    Ok(())
+}
@@ -0,0 +1,251 @@
+// src/openapi.rs
+
+//! OpenAPI 3.0 description of the endpoints in [`crate::http`], served as
+//! `GET /openapi.json` and written by the `generate-schema` subcommand.
+//!
+//! The document is assembled from [`ApiSchema`] implementations next to the
+//! list of endpoints below, so a new endpoint or response field has to be
+//! added here as well.
+
+use crate::http::{HealthReport, HealthStatus};
+use crate::json::{self, JsonError};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+pub const OPENAPI_VERSION: &str = "3.0.3";
+
+/// A Swagger UI page that renders `/openapi.json`. The UI itself is loaded
+/// from a CDN, so the page needs internet access to show anything.
+pub const SWAGGER_UI_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>ShareSphere API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+/// A type that appears in a response body, under `components.schemas`.
+pub trait ApiSchema {
+    const NAME: &'static str;
+
+    fn schema() -> Schema;
+}
+
+/// The subset of the OpenAPI Schema Object the endpoints need.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Schema {
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<&'static str>,
+    #[serde(rename = "enum", skip_serializing_if = "Vec::is_empty")]
+    pub enum_values: Vec<&'static str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<&'static str, Schema>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub required: Vec<&'static str>,
+    #[serde(rename = "$ref", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+}
+
+impl Schema {
+    fn of_type(kind: &'static str) -> Self {
+        Schema { kind: Some(kind), ..Schema::default() }
+    }
+
+    pub fn string() -> Self {
+        Schema::of_type("string")
+    }
+
+    pub fn boolean() -> Self {
+        Schema::of_type("boolean")
+    }
+
+    /// An unsigned integer counter or size.
+    pub fn uint64() -> Self {
+        Schema { format: Some("int64"), ..Schema::of_type("integer") }
+    }
+
+    /// An object whose properties are all required.
+    pub fn object(properties: impl IntoIterator<Item = (&'static str, Schema)>) -> Self {
+        let properties: BTreeMap<_, _> = properties.into_iter().collect();
+        Schema { required: properties.keys().copied().collect(), properties, ..Schema::of_type("object") }
+    }
+
+    pub fn reference<T: ApiSchema>() -> Self {
+        Schema { reference: Some(format!("#/components/schemas/{}", T::NAME)), ..Schema::default() }
+    }
+
+    pub fn with_description(self, description: &'static str) -> Self {
+        Schema { description: Some(description), ..self }
+    }
+}
+
+impl ApiSchema for HealthStatus {
+    const NAME: &'static str = "HealthStatus";
+
+    fn schema() -> Schema {
+        Schema {
+            enum_values: vec!["healthy", "degraded"],
+            ..Schema::string().with_description("`degraded` when no peers are known or storage is above 90% of the quota.")
+        }
+    }
+}
+
+impl ApiSchema for HealthReport {
+    const NAME: &'static str = "HealthReport";
+
+    fn schema() -> Schema {
+        Schema::object([
+            ("status", Schema::reference::<HealthStatus>()),
+            ("peer_count", Schema::uint64()),
+            ("dht_files", Schema::uint64()),
+            ("storage_used_bytes", Schema::uint64()),
+            ("uptime_secs", Schema::uint64()),
+        ])
+    }
+}
+
+/// The body of `GET /ready`.
+pub struct Readiness;
+
+impl ApiSchema for Readiness {
+    const NAME: &'static str = "Readiness";
+
+    fn schema() -> Schema {
+        Schema::object([("ready", Schema::boolean())])
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpenApi {
+    pub openapi: &'static str,
+    pub info: Info,
+    pub paths: BTreeMap<&'static str, PathItem>,
+    pub components: Components,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Info {
+    pub title: &'static str,
+    pub version: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PathItem {
+    pub get: Operation,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Operation {
+    pub summary: &'static str,
+    #[serde(rename = "operationId")]
+    pub operation_id: &'static str,
+    pub responses: BTreeMap<&'static str, Response>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Response {
+    pub description: &'static str,
+    pub content: BTreeMap<&'static str, MediaType>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MediaType {
+    pub schema: Schema,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Components {
+    pub schemas: BTreeMap<&'static str, Schema>,
+}
+
+fn response(description: &'static str, content_type: &'static str, schema: Schema) -> Response {
+    Response { description, content: BTreeMap::from([(content_type, MediaType { schema })]) }
+}
+
+fn get(summary: &'static str, operation_id: &'static str, responses: impl IntoIterator<Item = (&'static str, Response)>) -> PathItem {
+    PathItem { get: Operation { summary, operation_id, responses: responses.into_iter().collect() } }
+}
+
+/// The description of every endpoint [`crate::http::serve`] answers.
+pub fn openapi_spec() -> OpenApi {
+    let json = "application/json";
+    let paths = BTreeMap::from([
+        (
+            "/health",
+            get("Node health", "getHealth", [("200", response("Current health, from cached values", json, Schema::reference::<HealthReport>()))]),
+        ),
+        (
+            "/ready",
+            get(
+                "Whether the node knows at least one peer",
+                "getReady",
+                [
+                    ("200", response("At least one peer is known", json, Schema::reference::<Readiness>())),
+                    ("503", response("No peer is known yet", json, Schema::reference::<Readiness>())),
+                ],
+            ),
+        ),
+        (
+            "/metrics",
+            get(
+                "DHT counters",
+                "getMetrics",
+                [("200", response("Counters in the Prometheus text format", "text/plain; version=0.0.4", Schema::string()))],
+            ),
+        ),
+        (
+            "/openapi.json",
+            get("This document", "getOpenApi", [("200", response("The OpenAPI document", json, Schema::of_type("object")))]),
+        ),
+        (
+            "/docs",
+            get("Swagger UI for this document", "getDocs", [("200", response("An HTML page", "text/html", Schema::string()))]),
+        ),
+    ]);
+    let schemas = BTreeMap::from([
+        (HealthReport::NAME, HealthReport::schema()),
+        (HealthStatus::NAME, HealthStatus::schema()),
+        (Readiness::NAME, Readiness::schema()),
+    ]);
+    OpenApi {
+        openapi: OPENAPI_VERSION,
+        info: Info { title: "ShareSphere node API", version: env!("CARGO_PKG_VERSION") },
+        paths,
+        components: Components { schemas },
+    }
+}
+
+pub fn openapi_json() -> Result<String, JsonError> {
+    json::to_string_pretty(&openapi_spec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec_is_json_and_resolves_references() {
+        let spec: json::Value = json::from_str(&openapi_json().unwrap()).unwrap();
+        assert_eq!(spec["openapi"].as_str(), Some(OPENAPI_VERSION));
+        let schemas = &spec["components"]["schemas"];
+        let health = &spec["paths"]["/health"]["get"]["responses"]["200"]["content"]["application/json"]["schema"];
+        let reference = health["$ref"].as_str().unwrap();
+        let name = reference.strip_prefix("#/components/schemas/").unwrap();
+        assert_eq!(schemas[name]["required"].as_sequence().unwrap().len(), 5);
+        assert_eq!(schemas["HealthStatus"]["enum"].as_sequence().unwrap().len(), 2);
+        for path in ["/health", "/ready", "/metrics", "/openapi.json", "/docs"] {
+            assert!(spec["paths"][path]["get"]["operationId"].is_string(), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,232 @@
+// src/peer/access_control.rs
+
+//! Which peers may download a file. A file without an ACL is served to
+//! anyone; one with an ACL only to its owner and the peers it lists.
+//!
+//! Peers are told apart by the node ID they send in their `NODE_ID` line,
+//! which carries an HMAC of the connection's session key under the node's
+//! secret key (its `node_key`). An owner learns that key with
+//! `share --key` and keeps it in [`NodeKeyStore`]; a node ID whose key is
+//! unknown, or whose MAC does not match, is ignored, leaving the peer
+//! anonymous. The session key is new for every connection, so a recorded
+//! `NODE_ID` line cannot be replayed.
+
+use crate::file_manager::hash::hmac_sha256;
+use crate::file_manager::storage::StorageError;
+use crate::json;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Stored next to the file's chunks, in `<storage_root>/<file_id>/`.
+pub const ACL_FILENAME: &str = "acl.json";
+
+/// The keys of other nodes, in `<storage_root>/node_keys.json`.
+pub const NODE_KEYS_FILENAME: &str = "node_keys.json";
+
+/// A node ID in lowercase hex, as in `sharesphere://<node-id>@host:port`.
+pub type NodeId = String;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Acl {
+    pub allowed_peers: HashSet<NodeId>,
+    pub owner: NodeId,
+}
+
+impl Acl {
+    pub fn new(owner: impl Into<NodeId>) -> Self {
+        Acl { allowed_peers: HashSet::new(), owner: owner.into() }
+    }
+
+    pub fn allows(&self, node_id: &str) -> bool {
+        self.owner == node_id || self.allowed_peers.contains(node_id)
+    }
+}
+
+/// The ACLs of the files stored under one storage root.
+#[derive(Debug, Clone)]
+pub struct AclStore {
+    storage_root: PathBuf,
+}
+
+impl AclStore {
+    pub fn new(storage_root: impl AsRef<Path>) -> Self {
+        AclStore { storage_root: storage_root.as_ref().to_path_buf() }
+    }
+
+    fn path(&self, file_id: &Uuid) -> PathBuf {
+        self.storage_root.join(file_id.to_string()).join(ACL_FILENAME)
+    }
+
+    /// The file's ACL, or `None` if it has none.
+    pub fn load(&self, file_id: &Uuid) -> Result<Option<Acl>, StorageError> {
+        match fs::read_to_string(self.path(file_id)) {
+            Ok(contents) => Ok(Some(json::from_str(&contents)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the ACL through a temporary file. The file must be stored locally.
+    pub fn save(&self, file_id: &Uuid, acl: &Acl) -> Result<(), StorageError> {
+        let path = self.path(file_id);
+        if !path.parent().is_some_and(Path::is_dir) {
+            return Err(StorageError::InvalidPath(format!("File {} is not stored locally", file_id)));
+        }
+        json::write_atomic_pretty(&path, acl)
+    }
+
+    /// Adds `peer_id` to the file's ACL, creating one owned by `owner` if
+    /// the file has none yet.
+    pub fn share(&self, file_id: &Uuid, owner: &str, peer_id: &str) -> Result<Acl, StorageError> {
+        let mut acl = self.load(file_id)?.unwrap_or_else(|| Acl::new(owner));
+        acl.allowed_peers.insert(peer_id.to_ascii_lowercase());
+        self.save(file_id, &acl)?;
+        Ok(acl)
+    }
+
+    /// Removes `peer_id` from the file's ACL. Returns whether it was listed.
+    /// The ACL stays in place even when no peers are left, so the file is
+    /// then served to its owner only.
+    pub fn revoke(&self, file_id: &Uuid, peer_id: &str) -> Result<bool, StorageError> {
+        let Some(mut acl) = self.load(file_id)? else {
+            return Ok(false);
+        };
+        let removed = acl.allowed_peers.remove(&peer_id.to_ascii_lowercase());
+        if removed {
+            self.save(file_id, &acl)?;
+        }
+        Ok(removed)
+    }
+
+    /// Whether a peer sending `node_id` may download the file. Peers that
+    /// sent no node ID are only served files without an ACL, and an ACL
+    /// that cannot be read denies everyone.
+    pub fn is_allowed(&self, file_id: &Uuid, node_id: Option<&str>) -> bool {
+        match self.load(file_id) {
+            Ok(None) => true,
+            Ok(Some(acl)) => node_id.is_some_and(|node_id| acl.allows(node_id)),
+            Err(e) => {
+                error!("Failed to read the ACL of file {}: {}", file_id, e);
+                false
+            }
+        }
+    }
+}
+
+/// The `node_key`s of the peers files were shared with, by node ID.
+#[derive(Debug, Clone)]
+pub struct NodeKeyStore {
+    path: PathBuf,
+}
+
+impl NodeKeyStore {
+    pub fn new(storage_root: impl AsRef<Path>) -> Self {
+        NodeKeyStore { path: storage_root.as_ref().join(NODE_KEYS_FILENAME) }
+    }
+
+    fn load(&self) -> Result<HashMap<NodeId, String>, StorageError> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => Ok(json::from_str(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The key of `node_id`, or `None` if it is unknown or the store cannot be read.
+    pub fn get(&self, node_id: &str) -> Option<[u8; 32]> {
+        let keys = match self.load() {
+            Ok(keys) => keys,
+            Err(e) => {
+                error!("Failed to read {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+        keys.get(&node_id.to_ascii_lowercase()).and_then(|key| parse_node_key(key))
+    }
+
+    /// Records the key of `node_id`, replacing any earlier one.
+    pub fn insert(&self, node_id: &str, key: &[u8; 32]) -> Result<(), StorageError> {
+        let mut keys = self.load()?;
+        keys.insert(node_id.to_ascii_lowercase(), hex::encode(key));
+        json::write_atomic_pretty(&self.path, &keys)
+    }
+}
+
+/// A 64-character hex `node_key` as bytes.
+pub fn parse_node_key(key: &str) -> Option<[u8; 32]> {
+    hex::decode(key).ok()?.try_into().ok()
+}
+
+/// The MAC sent after `node_id` in a `NODE_ID` line: HMAC-SHA256 under
+/// `node_key` of the session key followed by the lowercase node ID.
+pub fn node_id_mac(node_key: &[u8; 32], session_key: &[u8; 32], node_id: &str) -> [u8; 32] {
+    let mut data = session_key.to_vec();
+    data.extend_from_slice(node_id.to_ascii_lowercase().as_bytes());
+    hmac_sha256(node_key, &data)
+}
+
+/// Whether `mac` (hex) is [`node_id_mac`] of `node_id` for this session.
+pub fn verify_node_id(node_key: &[u8; 32], session_key: &[u8; 32], node_id: &str, mac: &str) -> bool {
+    let Ok(mac) = hex::decode(mac) else {
+        return false;
+    };
+    let expected = node_id_mac(node_key, session_key, node_id);
+    // Compared in full, so the time taken does not reveal how much matched.
+    mac.len() == expected.len() && mac.iter().zip(expected).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_manager::storage::initialize_storage;
+
+    #[test]
+    fn test_share_and_revoke() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = AclStore::new(temp_dir.path());
+        let file_id = Uuid::new_v4();
+        assert!(store.share(&file_id, "00aa", "00bb").is_err());
+
+        initialize_storage(temp_dir.path(), file_id).unwrap();
+        assert!(store.is_allowed(&file_id, None));
+
+        let acl = store.share(&file_id, "00aa", "00BB").unwrap();
+        assert_eq!(acl.owner, "00aa");
+        assert_eq!(store.load(&file_id).unwrap(), Some(acl));
+        assert!(store.is_allowed(&file_id, Some("00aa")));
+        assert!(store.is_allowed(&file_id, Some("00bb")));
+        assert!(!store.is_allowed(&file_id, Some("00cc")));
+        assert!(!store.is_allowed(&file_id, None));
+
+        assert!(store.revoke(&file_id, "00bb").unwrap());
+        assert!(!store.revoke(&file_id, "00bb").unwrap());
+        assert!(!store.is_allowed(&file_id, Some("00bb")));
+        assert!(store.is_allowed(&file_id, Some("00aa")));
+
+        fs::write(store.path(&file_id), "not json").unwrap();
+        assert!(!store.is_allowed(&file_id, Some("00aa")));
+    }
+
+    #[test]
+    fn test_node_id_mac_binds_key_session_and_id() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let keys = NodeKeyStore::new(temp_dir.path());
+        assert_eq!(keys.get("00bb"), None);
+        assert_eq!(parse_node_key("not a key"), None);
+        keys.insert("00BB", &parse_node_key(&"Ab".repeat(32)).unwrap()).unwrap();
+        let node_key = keys.get("00bb").unwrap();
+        assert_eq!(node_key, [0xab; 32]);
+
+        let session_key = [1u8; 32];
+        let mac = hex::encode(node_id_mac(&node_key, &session_key, "00BB"));
+        assert!(verify_node_id(&node_key, &session_key, "00bb", &mac));
+        assert!(!verify_node_id(&node_key, &[2u8; 32], "00bb", &mac));
+        assert!(!verify_node_id(&node_key, &session_key, "00cc", &mac));
+        assert!(!verify_node_id(&[0xcd; 32], &session_key, "00bb", &mac));
+        assert!(!verify_node_id(&node_key, &session_key, "00bb", &mac[..62]));
+    }
+}
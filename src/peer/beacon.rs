@@ -0,0 +1,123 @@
+// src/peer/beacon.rs
+
+//! LAN discovery. `discover` sends [`BEACON`] as one UDP datagram to the
+//! multicast group in `lan_beacon_addr`, and every node listening on that
+//! group answers the sender with `SHARESPHERE_PEER:<peer_port>\n`. A reply
+//! only says where a node is; `discover` then probes it like any other peer.
+
+use log::{debug, info, warn};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+pub const BEACON: &[u8] = b"SHARESPHERE_DISCOVER\n";
+
+const REPLY_PREFIX: &str = "SHARESPHERE_PEER:";
+
+/// How long [`send_beacon`] waits for replies after sending the beacon.
+pub const BEACON_REPLY_WINDOW: Duration = Duration::from_secs(1);
+
+pub(crate) fn default_lan_beacon_addr() -> Option<SocketAddr> {
+    Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 42, 99)), 42999))
+}
+
+/// Binds `addr` and answers beacons with `peer_port` until the socket fails.
+/// A multicast `addr` is joined on all interfaces. Only one node per host can
+/// bind the port; the others log a warning and answer no beacons.
+pub async fn run_beacon_responder(addr: SocketAddr, peer_port: u16) {
+    let socket = match bind_responder(addr).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Not answering LAN discovery beacons on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("Answering LAN discovery beacons on {}", addr);
+    if let Err(e) = answer_beacons(socket, peer_port).await {
+        warn!("Stopped answering LAN discovery beacons: {}", e);
+    }
+}
+
+async fn bind_responder(addr: SocketAddr) -> io::Result<UdpSocket> {
+    match addr.ip() {
+        IpAddr::V4(group) if group.is_multicast() => {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, addr.port())).await?;
+            socket.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED)?;
+            Ok(socket)
+        }
+        IpAddr::V6(group) if group.is_multicast() => {
+            let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, addr.port())).await?;
+            socket.join_multicast_v6(&group, 0)?;
+            Ok(socket)
+        }
+        _ => UdpSocket::bind(addr).await,
+    }
+}
+
+/// Replies to every [`BEACON`] received on `socket`; other datagrams are ignored.
+pub async fn answer_beacons(socket: UdpSocket, peer_port: u16) -> io::Result<()> {
+    let reply = format!("{}{}\n", REPLY_PREFIX, peer_port);
+    let mut buf = [0u8; 64];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if &buf[..len] != BEACON {
+            debug!("Ignoring a {}-byte datagram from {} on the beacon port", len, from);
+            continue;
+        }
+        if let Err(e) = socket.send_to(reply.as_bytes(), from).await {
+            debug!("Failed to answer the beacon from {}: {}", from, e);
+        }
+    }
+}
+
+/// Sends one beacon to `addr` and returns the peer address of every node that
+/// answers within `window`, in the order they answered, without duplicates.
+pub async fn send_beacon(addr: SocketAddr, window: Duration) -> io::Result<Vec<SocketAddr>> {
+    let bind_ip: IpAddr = match addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((bind_ip, 0)).await?;
+    socket.send_to(BEACON, addr).await?;
+
+    let deadline = tokio::time::Instant::now() + window;
+    let mut found = Vec::new();
+    let mut buf = [0u8; 64];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = received?;
+        match parse_reply(&buf[..len]) {
+            Some(peer_port) => {
+                let peer = SocketAddr::new(from.ip(), peer_port);
+                if !found.contains(&peer) {
+                    found.push(peer);
+                }
+            }
+            None => debug!("Ignoring a malformed beacon reply from {}", from),
+        }
+    }
+    Ok(found)
+}
+
+fn parse_reply(datagram: &[u8]) -> Option<u16> {
+    std::str::from_utf8(datagram).ok()?.strip_prefix(REPLY_PREFIX)?.strip_suffix('\n')?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_beacons_are_answered_with_the_peer_port() {
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = responder.local_addr().unwrap();
+        tokio::spawn(answer_beacons(responder, 9000));
+
+        let found = send_beacon(addr, Duration::from_millis(500)).await.unwrap();
+        assert_eq!(found, vec!["127.0.0.1:9000".parse::<SocketAddr>().unwrap()]);
+
+        assert_eq!(parse_reply(b"SHARESPHERE_PEER:8080\n"), Some(8080));
+        assert_eq!(parse_reply(b"SHARESPHERE_PEER:80800\n"), None);
+        assert_eq!(parse_reply(b"SHARESPHERE_DISCOVER\n"), None);
+    }
+}
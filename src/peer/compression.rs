@@ -0,0 +1,308 @@
+// src/peer/compression.rs
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = MIN_MATCH + 0x7F;
+const MAX_LITERAL_RUN: usize = 0x80;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+
+/// Uncompressed bytes buffered before a frame is emitted without an explicit flush.
+const MAX_FRAME_INPUT: usize = 64 * 1024;
+/// Upper bound on an incoming compressed frame, to reject bogus length headers.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    #[error("Compressed data is truncated")]
+    Truncated,
+
+    #[error("Invalid back-reference offset {0}")]
+    InvalidOffset(usize),
+}
+
+/// Compresses `data` using a byte-oriented LZ77 scheme.
+///
+/// The output is a sequence of tokens. A token byte below `0x80` introduces a
+/// run of `token + 1` literal bytes; otherwise it is a match of
+/// `(token & 0x7F) + 4` bytes copied from a big-endian `u16` offset back.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut literal_start = 0;
+    let mut pos = 0;
+
+    while pos + MIN_MATCH <= data.len() {
+        let slot = hash(&data[pos..pos + MIN_MATCH]);
+        let candidate = table[slot];
+        table[slot] = pos;
+
+        if candidate != usize::MAX
+            && pos - candidate <= MAX_OFFSET
+            && data[candidate..candidate + MIN_MATCH] == data[pos..pos + MIN_MATCH]
+        {
+            let mut len = MIN_MATCH;
+            while len < MAX_MATCH && pos + len < data.len() && data[candidate + len] == data[pos + len] {
+                len += 1;
+            }
+
+            emit_literals(&mut out, &data[literal_start..pos]);
+            out.push(0x80 | (len - MIN_MATCH) as u8);
+            out.extend_from_slice(&((pos - candidate) as u16).to_be_bytes());
+            pos += len;
+            literal_start = pos;
+        } else {
+            pos += 1;
+        }
+    }
+
+    emit_literals(&mut out, &data[literal_start..]);
+    out
+}
+
+/// Reverses [`compress`].
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let token = data[pos] as usize;
+        pos += 1;
+        if token < 0x80 {
+            let run = token + 1;
+            let literals = data.get(pos..pos + run).ok_or(CompressionError::Truncated)?;
+            out.extend_from_slice(literals);
+            pos += run;
+        } else {
+            let len = (token & 0x7F) + MIN_MATCH;
+            let offset_bytes = data.get(pos..pos + 2).ok_or(CompressionError::Truncated)?;
+            let offset = u16::from_be_bytes([offset_bytes[0], offset_bytes[1]]) as usize;
+            pos += 2;
+            if offset == 0 || offset > out.len() {
+                return Err(CompressionError::InvalidOffset(offset));
+            }
+            let start = out.len() - offset;
+            for i in 0..len {
+                out.push(out[start + i]);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn emit_literals(out: &mut Vec<u8>, mut literals: &[u8]) {
+    while !literals.is_empty() {
+        let run = literals.len().min(MAX_LITERAL_RUN);
+        out.push((run - 1) as u8);
+        out.extend_from_slice(&literals[..run]);
+        literals = &literals[run..];
+    }
+}
+
+/// Wraps a stream so that everything written is compressed into
+/// length-prefixed frames and everything read is decompressed.
+///
+/// Writes are buffered until `flush` (or until the buffer grows past
+/// `MAX_FRAME_INPUT`), so callers should flush after each logical message.
+pub struct CompressedStream<S> {
+    inner: S,
+    raw_in: Vec<u8>,
+    decoded: Vec<u8>,
+    decoded_pos: usize,
+    pending: Vec<u8>,
+    frame_out: Vec<u8>,
+    frame_pos: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> CompressedStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self::with_prefix(inner, Vec::new())
+    }
+
+    /// Creates a stream whose first compressed bytes were already read from `inner`
+    /// (e.g. while reading the handshake line).
+    pub fn with_prefix(inner: S, raw_in: Vec<u8>) -> Self {
+        CompressedStream {
+            inner,
+            raw_in,
+            decoded: Vec::new(),
+            decoded_pos: 0,
+            pending: Vec::new(),
+            frame_out: Vec::new(),
+            frame_pos: 0,
+        }
+    }
+
+    /// Decodes one complete frame from `raw_in`, if available.
+    fn decode_frame(&mut self) -> io::Result<bool> {
+        if self.raw_in.len() < 4 {
+            return Ok(false);
+        }
+        let len = u32::from_be_bytes([self.raw_in[0], self.raw_in[1], self.raw_in[2], self.raw_in[3]]) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Compressed frame too large"));
+        }
+        if self.raw_in.len() < 4 + len {
+            return Ok(false);
+        }
+        let frame: Vec<u8> = self.raw_in.drain(..4 + len).skip(4).collect();
+        self.decoded = decompress(&frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.decoded_pos = 0;
+        Ok(true)
+    }
+
+    /// Writes any encoded frame still waiting to go out.
+    fn poll_write_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.frame_pos == self.frame_out.len() && !self.pending.is_empty() {
+            let compressed = compress(&self.pending);
+            self.pending.clear();
+            self.frame_out.clear();
+            self.frame_out.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+            self.frame_out.extend_from_slice(&compressed);
+            self.frame_pos = 0;
+        }
+        while self.frame_pos < self.frame_out.len() {
+            let n = match Pin::new(&mut self.inner).poll_write(cx, &self.frame_out[self.frame_pos..]) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.frame_pos += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for CompressedStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.decoded_pos < this.decoded.len() {
+                let n = buf.remaining().min(this.decoded.len() - this.decoded_pos);
+                buf.put_slice(&this.decoded[this.decoded_pos..this.decoded_pos + n]);
+                this.decoded_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.decode_frame()? {
+                continue;
+            }
+
+            let mut temp = [0u8; 4096];
+            let mut temp_buf = ReadBuf::new(&mut temp);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut temp_buf) {
+                Poll::Ready(Ok(())) => {
+                    let filled = temp_buf.filled();
+                    if filled.is_empty() {
+                        if this.raw_in.is_empty() {
+                            return Poll::Ready(Ok(()));
+                        }
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "Connection closed mid-frame",
+                        )));
+                    }
+                    this.raw_in.extend_from_slice(filled);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for CompressedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.pending.len() >= MAX_FRAME_INPUT {
+            match this.poll_write_frame(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        this.pending.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match this.poll_write_frame(cx) {
+                Poll::Ready(Ok(())) => {}
+                other => return other,
+            }
+            if this.pending.is_empty() {
+                break;
+            }
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.as_mut().poll_flush(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_compress_round_trip() {
+        let mut dht_dump = String::new();
+        for i in 0..500 {
+            dht_dump.push_str(&format!("{}:127.0.0.1:{}\n", uuid::Uuid::nil(), 8000 + i % 7));
+        }
+        let compressed = compress(dht_dump.as_bytes());
+        assert!(compressed.len() < dht_dump.len() / 4);
+        assert_eq!(decompress(&compressed).unwrap(), dht_dump.as_bytes());
+
+        for data in [&b""[..], b"a", b"abcabcabcabcabcabc", &[0u8; 1000]] {
+            assert_eq!(decompress(&compress(data)).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_decompress_rejects_bad_input() {
+        assert!(matches!(decompress(&[0x05, b'a']), Err(CompressionError::Truncated)));
+        assert!(matches!(decompress(&[0x80, 0x00, 0x01]), Err(CompressionError::InvalidOffset(1))));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_stream_round_trip() {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut writer = CompressedStream::new(a);
+        let mut reader = CompressedStream::new(b);
+
+        let message = "DHT_RESPONSE:1\n".repeat(2000);
+        let expected = message.clone();
+        let write = tokio::spawn(async move {
+            writer.write_all(message.as_bytes()).await.unwrap();
+            writer.shutdown().await.unwrap();
+        });
+
+        let mut received = String::new();
+        reader.read_to_string(&mut received).await.unwrap();
+        write.await.unwrap();
+        assert_eq!(received, expected);
+    }
+}
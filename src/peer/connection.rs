@@ -1,170 +1,1171 @@
 // src/peer/connection.rs
 
-use crate::peer::encryption::{encrypt, decrypt};
-use crate::peer::discovery::Peer;
+use crate::config::Config;
+use crate::peer::encryption::{encrypt, decrypt, NonceTracker};
+use crate::peer::access_control::{parse_node_key, verify_node_id, AclStore, NodeId, NodeKeyStore};
+use crate::peer::discovery::{Peer, PeerRegistry};
+use crate::file_manager::chunker::ChunkMetadata;
+use crate::file_manager::hash::HashAlgorithm;
 use crate::file_manager::storage;
+use crate::file_manager::manifest::{load_manifest, save_manifest, FileManifest};
+use crate::json;
+use crate::file_manager::merkle::{MerkleProof, MerkleTree};
 use crate::indexing::dht::DHT;
+use crate::indexing::gossip::{group_entries, receive_gossip_entries};
+use crate::peer::protocol::{
+    parse_chunk_request, parse_chunk_response_header, parse_dht_entry, parse_dht_response_header, parse_file_entry,
+    parse_list_files_header, parse_peer_list_header, ChunkRequestLine, DhtEntry, Message, RemoteFileEntry, MAX_MESSAGE_LEN, ProtocolVersion, PROTOCOL_VERSION_PREFIX};
+use crate::peer::mux::{self, MultiplexedConnection, MuxRole};
+use crate::peer::rate_limit::{ConnectionLimiter, RATE_LIMITED};
+use crate::peer::session::{PeerCapabilities, PeerSession};
+use crate::peer::stats::{self, SharedNetworkStats};
+use crate::peer::transport::{self, ConnectionError};
+use crate::telemetry::{Span, TraceContext, TRACE_PARENT_PREFIX};
+pub use crate::peer::compression::CompressedStream;
 use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinSet;
+use std::collections::VecDeque;
 use std::error::Error;
 use std::path::Path;
-use log::{info, error};
+use std::time::{Duration, Instant};
+use log::{info, error, warn};
 use uuid::Uuid;
 
+/// Failure reported by a peer in reply to a `CHUNK_REQUEST`.
+#[derive(thiserror::Error, Debug)]
+pub enum ChunkFetchError {
+    #[error("Peer does not have chunk {1} of file {0}")]
+    ChunkNotFound(Uuid, usize),
 
+    #[error("Peer failed to serve chunk {1} of file {0}: {2}")]
+    ChunkError(Uuid, usize, String),
+
+    #[error("Peer denied access to chunk {1} of file {0}")]
+    ChunkForbidden(Uuid, usize),
+}
+
+/// Serves a connection as a single session, without offering multiplexing.
+/// Used for outgoing connections to active peers, where this node is also
+/// the one that connected.
 pub async fn handle_connection(
-    mut stream: TcpStream,
-    encryption_key: String,
-    storage_root: String,
-    peers: Vec<Peer>,
+    stream: TcpStream,
+    config: Config,
+    peers: PeerRegistry,
     dht: DHT,
-    local_peer: Peer,
+    _local_peer: Peer,
+    network_stats: SharedNetworkStats,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let peer_addr = stream.peer_addr()?;
     info!("New connection from {}", peer_addr);
+    let address = peer_addr.to_string();
 
-    let welcome_message = format!("Welcome to ShareSphere, peer {}", peer_addr);
-    let (nonce, encrypted_welcome) = encrypt(welcome_message.as_bytes(), &encryption_key)?;
-    let message = format!("{}:{}\n", nonce, encrypted_welcome);
-    stream.write_all(message.as_bytes()).await?;
+    let result = async {
+        let session =
+            PeerSession::handshake_over(Box::new(stream), Peer::from_socket_addr(peer_addr), PeerCapabilities::local())
+                .await?;
+        serve_session(session, &config, &peers, &dht, &network_stats).await
+    }
+    .await;
+    if result.is_err() {
+        stats::record(&network_stats, &address, |s| s.errors += 1);
+    }
+    result
+}
 
-    stream.write_all(b"DHT_REQUEST\n").await?;
+/// Serves a connection accepted by the listener. A client that asks for
+/// multiplexing in its handshake gets a [`MultiplexedConnection`]: each
+/// stream it opens is served as a session of its own once `limiter` admits
+/// it, and the connection is closed after `heartbeat_timeout_secs` without
+/// open streams. Other clients are served as by [`handle_connection`].
+pub async fn serve_incoming(
+    stream: TcpStream,
+    limiter: ConnectionLimiter,
+    config: Config,
+    peers: PeerRegistry,
+    dht: DHT,
+    _local_peer: Peer,
+    network_stats: SharedNetworkStats,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let peer_addr = stream.peer_addr()?;
+    info!("New connection from {}", peer_addr);
+    let address = peer_addr.to_string();
+    let offered = PeerCapabilities { multiplexing: true, ..PeerCapabilities::local() };
 
-    let mut buffer = Vec::new();
-    loop {
-        let mut temp_buffer = [0u8; 4096];
-        let bytes_read = stream.read(&mut temp_buffer).await?;
-        if bytes_read == 0 {
-            info!("Connection closed by {}", peer_addr);
-            break;
-        }
-        buffer.extend_from_slice(&temp_buffer[..bytes_read]);
-
-        while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
-            let line = buffer.drain(..=pos).collect::<Vec<u8>>();
-            let line_str = String::from_utf8_lossy(&line).trim().to_string();
-
-            if line_str.starts_with("DHT_RESPONSE:") {
-                // Format: DHT_RESPONSE:<N>
-                let parts: Vec<&str> = line_str.split(':').collect();
-                if parts.len() == 2 {
-                    let n = parts[1].parse::<usize>().unwrap_or(0);
-                    let mut entries = Vec::new();
-                    for _ in 0..n {
-                        let entry_line = read_line(&mut buffer, &mut stream).await?;
-                        // FILE_ID:PEER_ADDRESS
-                        let eparts: Vec<&str> = entry_line.split(':').collect();
-                        if eparts.len() == 2 {
-                            if let Ok(fid) = Uuid::parse_str(eparts[0]) {
-                                entries.push((fid, eparts[1].to_string()));
-                            }
-                        }
-                    }
-                    dht.merge_entries(&entries);
+    let result = async {
+        let session = PeerSession::handshake_over(Box::new(stream), Peer::from_socket_addr(peer_addr), offered).await?;
+        if !session.capabilities.multiplexing {
+            return serve_session(session, &config, &peers, &dht, &network_stats).await;
+        }
 
-                    let our_entries = dht.all_entries();
-                    stream.write_all(format!("DHT_RESPONSE:{}\n", our_entries.len()).as_bytes()).await?;
-                    for (fid, addr) in our_entries {
-                        stream.write_all(format!("{}:{}\n", fid, addr).as_bytes()).await?;
-                    }
+        info!("Connection from {} is multiplexed", peer_addr);
+        let connection = MultiplexedConnection::new(session.into_stream(), MuxRole::Server);
+        let idle_timeout = Duration::from_secs(config.discovery.heartbeat_timeout_secs);
+        let mut streams = JoinSet::new();
+        loop {
+            while streams.try_join_next().is_some() {}
+            let mut logical = match tokio::time::timeout(idle_timeout, connection.accept_stream()).await {
+                Ok(Some(logical)) => logical,
+                Ok(None) => break,
+                Err(_) if streams.is_empty() => {
+                    info!("Closing the idle multiplexed connection from {}", peer_addr);
+                    break;
+                }
+                Err(_) => continue,
+            };
+            // Each stream counts as a connection of its own.
+            let Some(permit) = limiter.admit(peer_addr.ip()).await else {
+                warn!("Refusing a stream from {}: too many connections from {}", peer_addr, peer_addr.ip());
+                let _ = logical.write_all(RATE_LIMITED).await;
+                continue;
+            };
+            let (config, peers, dht) = (config.clone(), peers.clone(), dht.clone());
+            let (address, network_stats) = (address.clone(), network_stats.clone());
+            streams.spawn(async move {
+                let _permit = permit;
+                let result = async {
+                    let peer = Peer::from_socket_addr(peer_addr);
+                    let session = PeerSession::handshake_over(Box::new(logical), peer, PeerCapabilities::local()).await?;
+                    serve_session(session, &config, &peers, &dht, &network_stats).await
+                }
+                .await;
+                if let Err(e) = result {
+                    error!("Error serving a multiplexed stream from {}: {}", address, e);
+                    stats::record(&network_stats, &address, |s| s.errors += 1);
+                }
+            });
+        }
+        // Streams still being served keep running; the connection closes with the last of them.
+        while streams.join_next().await.is_some() {}
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        stats::record(&network_stats, &address, |s| s.errors += 1);
+    }
+    result
+}
 
+/// Serves one handshaken session. `FIND_FILE` queries are forwarded to the peers
+/// connected at the time. A `PING` goes out
+/// every `heartbeat_interval_secs`, and the session ends with an error once
+/// the remote has sent nothing for `heartbeat_timeout_secs`.
+async fn serve_session(
+    mut session: PeerSession,
+    config: &Config,
+    peers: &PeerRegistry,
+    dht: &DHT,
+    network_stats: &SharedNetworkStats,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (encryption_key, storage_root) = (config.encryption_key.as_str(), config.storage.storage_path.as_str());
+    let address = session.peer.address.clone();
+    let peer_addr = address.as_str();
+    info!("Negotiated capabilities with {}: {:?}", peer_addr, session.capabilities);
+
+    // Encrypted lines use a key derived for this connection only; peers that
+    // sent no handshake nonce get no encrypted traffic.
+    let session_key = session.establish_session_key(encryption_key)?;
+    let nonces = NonceTracker::new_counter_based();
+    match &session_key {
+        Some(key) => {
+            let welcome_message = format!("Welcome to ShareSphere, peer {}", peer_addr);
+            let (nonce, encrypted_welcome) = encrypt(welcome_message.as_bytes(), key, &nonces)?;
+            let message = format!("{}:{}\n", nonce, encrypted_welcome);
+            session.send(message.as_bytes()).await?;
+        }
+        None => info!("Peer {} sent no session nonce; encrypted messages are disabled", peer_addr),
+    }
+
+    session.send(b"DHT_REQUEST\n").await?;
+    session.stream.flush().await?;
+    record_traffic(network_stats, peer_addr, &mut session);
+
+    // Held until the session ends, so the span covers everything served for the remote's trace.
+    let mut _remote_span = None;
+    let heartbeat_interval = Duration::from_secs(config.discovery.heartbeat_interval_secs.max(1));
+    let heartbeat_timeout = Duration::from_secs(config.discovery.heartbeat_timeout_secs);
+    let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + heartbeat_interval, heartbeat_interval);
+    // Any line counts as a sign of life, so a busy peer is not dropped for a late PONG.
+    let mut last_pong = Instant::now();
+    loop {
+        let line_str = tokio::select! {
+            line = session.read_line() => match line? {
+                Some(line) => line,
+                None => break,
+            },
+            _ = heartbeat.tick() => {
+                if last_pong.elapsed() > heartbeat_timeout {
+                    record_traffic(network_stats, peer_addr, &mut session);
+                    return Err(format!("{} sent nothing for {:?}; closing the connection", peer_addr, last_pong.elapsed()).into());
                 }
-            } else if line_str == "DHT_REQUEST" {
-                let our_entries = dht.all_entries();
-                stream.write_all(format!("DHT_RESPONSE:{}\n", our_entries.len()).as_bytes()).await?;
-                for (fid, addr) in our_entries {
-                    stream.write_all(format!("{}:{}\n", fid, addr).as_bytes()).await?;
+                session.send(b"PING\n").await?;
+                session.stream.flush().await?;
+                continue;
+            }
+        };
+        last_pong = Instant::now();
+        if let Some(context) = TraceContext::from_header(&line_str) {
+            _remote_span = Some(Span::child_of(&context, format!("serve_session {}", peer_addr)));
+        } else if let Some(offered) = line_str.strip_prefix(PROTOCOL_VERSION_PREFIX) {
+            session.protocol_version = ProtocolVersion::answer_to(offered);
+            session.send(format!("{}{}\n", PROTOCOL_VERSION_PREFIX, session.protocol_version.number()).as_bytes()).await?;
+            session.stream.flush().await?;
+            match session.protocol_version {
+                ProtocolVersion::V1 => {}
+                ProtocolVersion::V2 => {
+                    return serve_messages(&mut session, storage_root, dht, peer_addr, network_stats).await
                 }
-            } else if line_str.starts_with("CHUNK_REQUEST:") {
-                // CHUNK_REQUEST:<FILE_ID>:<CHUNK_INDEX>
-                let parts: Vec<&str> = line_str.split(':').collect();
-                if parts.len() == 3 {
-                    let file_id_str = parts[1];
-                    let chunk_index_str = parts[2];
-                    if let Ok(fid) = Uuid::parse_str(file_id_str) {
-                        if let Ok(chunk_index) = chunk_index_str.parse::<usize>() {
-                            let storage_dir = Path::new(&storage_root).join(fid.to_string());
-                            match storage::get_chunk(&storage_dir, chunk_index) {
-                                Ok(data) => {
-                                    let response = format!("CHUNK_RESPONSE:{}:{}:{}:", fid, chunk_index, data.len());
-                                    stream.write_all(response.as_bytes()).await?;
-                                    stream.write_all(&data).await?;
-                                }
-                                Err(e) => {
-                                    error!("Failed to get chunk: {}", e);
-                                }
-                            }
-                        }
-                    }
+            }
+        } else if line_str.starts_with("DHT_RESPONSE:") {
+            handle_dht_response(&mut session, dht, &line_str).await?;
+        } else if let Some(entry) = line_str.strip_prefix("DHT_ANNOUNCE:") {
+            match parse_dht_entry(entry) {
+                Some((file_id, address)) => {
+                    info!("Peer {} announced that {} stores file {}", peer_addr, address, file_id);
+                    dht.register_file_location(file_id, Peer::new(address))?;
                 }
-            } else if line_str.starts_with("CHUNK_RESPONSE:") {
-                // Already handled chunk requests externally (e.g., in download_file)
-                // If handle_connection is also used by the downloading peer, handle it similarly.
-                // This is if we do chunk fetch here. For simplicity, chunk fetch logic might be separate.
+                None => error!("Ignoring malformed DHT announcement from {}: {}", peer_addr, line_str),
+            }
+        } else if let Some(identity) = line_str.strip_prefix("NODE_ID:") {
+            match authenticate_node_id(&session, config, identity) {
+                Some(node_id) => session.peer.node_id = Some(node_id),
+                None => error!("Ignoring unauthenticated node ID from {}: {}", peer_addr, identity),
+            }
+        } else if let Some(query) = line_str.strip_prefix("FIND_FILE:") {
+            answer_find_file(&mut session, query, config, peers, dht).await?;
+        } else if line_str == "DHT_REQUEST" {
+            send_dht_entries(&mut session, dht).await?;
+        } else if line_str == "PING" {
+            session.send(b"PONG\n").await?;
+            session.stream.flush().await?;
+        } else if line_str == "PONG" {
+            // Already recorded in `last_pong`.
+        } else if let Some(file_id) = line_str.strip_prefix("GET_MANIFEST:") {
+            send_manifest(&mut session, storage_root, file_id).await?;
+        } else if line_str == "LIST_FILES_REQUEST" {
+            send_file_list(&mut session, storage_root).await?;
+        } else if line_str == "PEER_LIST_REQUEST" {
+            send_peer_list(&mut session, peers).await?;
+        } else if let Some(header) = line_str.strip_prefix("MANIFEST_PUSH:") {
+            receive_file_manifest(&mut session, storage_root, header).await?;
+        } else if let Some(header) = line_str.strip_prefix("CHUNK_PUSH:") {
+            receive_chunk_push(&mut session, storage_root, header).await?;
+        } else if line_str.starts_with("CHUNK_REQUEST:") {
+            if handle_chunk_request(&mut session, storage_root, &line_str).await? {
+                stats::record(network_stats, peer_addr, |s| s.chunks_sent += 1);
             } else {
-                let parts: Vec<&str> = line_str.split(':').collect();
-                if parts.len() == 2 {
-                    let nonce = parts[0];
-                    let ciphertext = parts[1];
-                    match decrypt(nonce, ciphertext, &encryption_key) {
-                        Ok(decrypted_data) => {
-                            let message = String::from_utf8_lossy(&decrypted_data);
-                            info!("Received from {}: {}", peer_addr, message);
-                        },
-                        Err(e) => {
-                            error!("Failed to decrypt message from {}: {}", peer_addr, e);
-                        }
+                stats::record(network_stats, peer_addr, |s| s.errors += 1);
+            }
+        } else if line_str.starts_with(TRACE_PARENT_PREFIX) {
+            // Sent on its own line after each CHUNK_REQUEST by peers that predate the
+            // trace parent field of the request; such spans are not linked.
+        } else if line_str.starts_with("CHUNK_RESPONSE:") {
+            // Already handled chunk requests externally (e.g., in download_file)
+            // If handle_connection is also used by the downloading peer, handle it similarly.
+            // This is if we do chunk fetch here. For simplicity, chunk fetch logic might be separate.
+        } else {
+            let parts: Vec<&str> = line_str.split(':').collect();
+            if parts.len() == 2 {
+                let nonce = parts[0];
+                let ciphertext = parts[1];
+                let Some(key) = &session_key else {
+                    error!("Ignoring encrypted message from {} without a session key", peer_addr);
+                    stats::record(network_stats, peer_addr, |s| s.errors += 1);
+                    continue;
+                };
+                match decrypt(nonce, ciphertext, key) {
+                    Ok(decrypted_data) => {
+                        let message = String::from_utf8_lossy(&decrypted_data);
+                        info!("Received from {}: {}", peer_addr, message);
+                    },
+                    Err(e) => {
+                        error!("Failed to decrypt message from {}: {}", peer_addr, e);
+                        stats::record(network_stats, peer_addr, |s| s.errors += 1);
                     }
                 }
             }
         }
+        record_traffic(network_stats, peer_addr, &mut session);
     }
+    record_traffic(network_stats, peer_addr, &mut session);
+    info!("Connection closed by {}", peer_addr);
 
     Ok(())
 }
 
-pub async fn send_chunk_to_peer(
-    peer: &Peer,
-    storage_dir: &str,
-    file_id: &Uuid,
-    chunk_index: usize,
+/// The rest of a session after switching to [`ProtocolVersion::V2`]: one
+/// reply per request, until the remote disconnects.
+async fn serve_messages(
+    session: &mut PeerSession,
+    storage_root: &str,
+    dht: &DHT,
+    peer_addr: &str,
+    network_stats: &SharedNetworkStats,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    while let Some(message) = session.read_message().await? {
+        let reply = match message {
+            Message::Ping => Some(Message::Pong),
+            Message::DhtRequest => Some(dht_reply(dht)?),
+            Message::DhtResponse { entries } => {
+                let entries: Vec<(Uuid, String)> = entries.into_iter().map(|e| (e.file_id, e.address)).collect();
+                dht.merge_entries(&entries)?;
+                None
+            }
+            Message::Gossip { entries } => {
+                let added = receive_gossip_entries(dht, &group_entries(entries))?;
+                info!("Learned {} DHT locations from {} by gossip", added, peer_addr);
+                None
+            }
+            Message::GetManifest { file_id } if !is_allowed(session, storage_root, &file_id) => {
+                Some(Message::ManifestNotFound { file_id })
+            }
+            Message::GetManifest { file_id } => Some(manifest_reply(storage_root, file_id)),
+            Message::ChunkRequest { file_id, chunk_index } => {
+                let reply = if is_allowed(session, storage_root, &file_id) {
+                    chunk_reply(storage_root, file_id, chunk_index)
+                } else {
+                    Message::ChunkError { file_id, chunk_index, reason: "forbidden".to_string() }
+                };
+                if matches!(reply, Message::ChunkResponse { .. }) {
+                    stats::record(network_stats, peer_addr, |s| s.chunks_sent += 1);
+                } else {
+                    stats::record(network_stats, peer_addr, |s| s.errors += 1);
+                }
+                Some(reply)
+            }
+            _ => {
+                error!("Ignoring unexpected message from {}", peer_addr);
+                None
+            }
+        };
+        if let Some(reply) = reply {
+            session.send_message(&reply).await?;
+        }
+        record_traffic(network_stats, peer_addr, session);
+    }
+    record_traffic(network_stats, peer_addr, session);
+    info!("Connection closed by {}", peer_addr);
+
+    Ok(())
+}
+
+/// Serves `FIND_FILE:<file_id>[:<hops>]` with `FILE_FOUND:<N>` and `N`
+/// address lines, or `FILE_NOT_FOUND:<file_id>`. Files missing from the local
+/// DHT are looked up on `peers` if `hops` (1 when left out) is above zero,
+/// with one hop less, so a query travels at most one hop.
+async fn answer_find_file(
+    session: &mut PeerSession,
+    query: &str,
+    config: &Config,
+    peers: &PeerRegistry,
+    dht: &DHT,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (file_id, hops) = match query.split_once(':') {
+        Some((file_id, hops)) => (file_id, hops.parse::<u8>().unwrap_or(0)),
+        None => (query, 1),
+    };
+    let Ok(fid) = Uuid::parse_str(file_id) else {
+        error!("Ignoring malformed FIND_FILE from {}: {}", session.peer, query);
+        return Ok(());
+    };
+    if !is_allowed(session, &config.storage.storage_path, &fid) {
+        session.send(format!("FILE_NOT_FOUND:{}\n", fid).as_bytes()).await?;
+        session.stream.flush().await?;
+        return Ok(());
+    }
+    let mut found: Vec<String> = dht.get_file_locations(&fid)?.unwrap_or_default().into_iter().map(|p| p.address).collect();
+    if found.is_empty() && hops > 0 {
+        for peer in peers.all().iter().filter(|p| p.address != session.peer.address) {
+            match crate::indexing::dht::query_remote(&fid, peer, Some(hops - 1), config).await {
+                Ok(locations) if !locations.is_empty() => {
+                    found = locations.into_iter().map(|p| p.address).collect();
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => error!("Failed to forward FIND_FILE for {} to {}: {}", fid, peer, e),
+            }
+        }
+    }
+    if found.is_empty() {
+        session.send(format!("FILE_NOT_FOUND:{}\n", fid).as_bytes()).await?;
+    } else {
+        session.send(format!("FILE_FOUND:{}\n", found.len()).as_bytes()).await?;
+        for address in &found {
+            session.send(format!("{}\n", address).as_bytes()).await?;
+        }
+    }
+    session.stream.flush().await?;
+    Ok(())
+}
+
+/// The node ID of a `NODE_ID:<node_id>:<mac>` line, if the MAC checks out
+/// under that node's key: `node_key` for this node's own ID, or the key
+/// recorded by `share --key` for any other.
+fn authenticate_node_id(session: &PeerSession, config: &Config, identity: &str) -> Option<NodeId> {
+    let (node_id, mac) = identity.split_once(':')?;
+    if node_id.is_empty() || hex::decode(node_id).is_err() {
+        return None;
+    }
+    let node_id = node_id.to_ascii_lowercase();
+    let node_key = if config.node_id.as_deref().is_some_and(|own| own.eq_ignore_ascii_case(&node_id)) {
+        config.node_key.as_ref().and_then(|key| parse_node_key(key))
+    } else {
+        NodeKeyStore::new(&config.storage.storage_path).get(&node_id)
+    };
+    verify_node_id(&node_key?, session.session_key()?, &node_id, mac).then_some(node_id)
+}
+
+/// Derives the session key and proves `config.node_id` to the remote, so its
+/// file ACLs apply to this node; see [`PeerSession::send_node_id`].
+pub(crate) async fn send_identity(session: &mut PeerSession, config: &Config) -> Result<(), Box<dyn Error + Send + Sync>> {
+    session.establish_session_key(&config.encryption_key)?;
+    let node_key = config.node_key.as_ref().and_then(|key| parse_node_key(key));
+    session.send_node_id(config.node_id.as_deref(), node_key.as_ref()).await
+}
+
+/// Whether the file's ACL lets the remote of `session` download it.
+fn is_allowed(session: &PeerSession, storage_root: &str, file_id: &Uuid) -> bool {
+    let allowed = AclStore::new(storage_root).is_allowed(file_id, session.peer.node_id.as_deref());
+    if !allowed {
+        info!("Denied {} access to file {}", session.peer, file_id);
+    }
+    allowed
+}
+
+fn dht_reply(dht: &DHT) -> Result<Message, Box<dyn Error + Send + Sync>> {
+    let snapshot = dht.snapshot()?;
+    let entries = snapshot
+        .entries
+        .into_iter()
+        .flat_map(|(file_id, addresses)| addresses.into_iter().map(move |address| DhtEntry { file_id, address }))
+        .collect();
+    Ok(Message::DhtResponse { entries })
+}
+
+fn record_traffic(network_stats: &SharedNetworkStats, peer_addr: &str, session: &mut PeerSession) {
+    let (sent, received) = session.take_traffic();
+    stats::record(network_stats, peer_addr, |s| {
+        s.bytes_sent += sent;
+        s.bytes_received += received;
+    });
+}
+
+async fn send_dht_entries(
+    session: &mut PeerSession,
+    dht: &DHT,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Snapshot first so the DHT lock is not held while writing to the socket.
+    let snapshot = dht.snapshot()?;
+    let count: usize = snapshot.entries.values().map(Vec::len).sum();
+    session.send(format!("DHT_RESPONSE:{}\n", count).as_bytes()).await?;
+    for (fid, addresses) in &snapshot.entries {
+        for addr in addresses {
+            session.send(format!("{}:{}\n", fid, addr).as_bytes()).await?;
+        }
+    }
+    session.stream.flush().await?;
+    Ok(())
+}
+
+async fn handle_dht_response(
+    session: &mut PeerSession,
+    dht: &DHT,
+    line_str: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some(n) = parse_dht_response_header(line_str) else {
+        return Ok(());
+    };
+    let mut entries = Vec::new();
+    for _ in 0..n {
+        let entry_line = session.read_line().await?.ok_or("Connection closed")?;
+        entries.extend(parse_dht_entry(&entry_line));
+    }
+    dht.merge_entries(&entries)?;
+
+    send_dht_entries(session, dht).await
+}
+
+/// Serves a `CHUNK_REQUEST`. Every request gets exactly one reply: the chunk,
+/// `CHUNK_NOT_FOUND`, `CHUNK_FORBIDDEN` or `CHUNK_ERROR`. Returns whether the
+/// chunk was sent.
+async fn handle_chunk_request(
+    session: &mut PeerSession,
+    storage_root: &str,
+    line_str: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let (fid, chunk_index, trace_parent) = match parse_chunk_request(line_str) {
+        ChunkRequestLine::Valid { file_id, chunk_index, trace_parent } => (file_id, chunk_index, trace_parent),
+        ChunkRequestLine::Malformed { file_id, chunk_index } => {
+            let response = format!("CHUNK_ERROR:{}:{}:malformed request\n", file_id, chunk_index);
+            session.send(response.as_bytes()).await?;
+            session.stream.flush().await?;
+            return Ok(false);
+        }
+        ChunkRequestLine::Invalid => {
+            error!("Ignoring malformed chunk request: {}", line_str);
+            return Ok(false);
+        }
+    };
+
+    // Ignored unless tracing was negotiated, as the request line's own header would be.
+    let parent = trace_parent.filter(|_| session.capabilities.distributed_tracing).and_then(TraceContext::from_traceparent);
+    let _span = parent.map(|parent| Span::child_of(&parent, format!("serve_chunk {}:{}", fid, chunk_index)));
+    if !is_allowed(session, storage_root, &fid) {
+        session.send(format!("CHUNK_FORBIDDEN:{}:{}\n", fid, chunk_index).as_bytes()).await?;
+        session.stream.flush().await?;
+        return Ok(false);
+    }
+    let response = match chunk_reply(storage_root, fid, chunk_index) {
+        Message::ChunkResponse { data, proof, .. } => {
+            let header = format!("CHUNK_RESPONSE:{}:{}:{}:{}\n", fid, chunk_index, data.len(), proof);
+            session.send(header.as_bytes()).await?;
+            session.send(&data).await?;
+            session.stream.flush().await?;
+            return Ok(true);
+        }
+        Message::ChunkError { reason, .. } => {
+            format!("CHUNK_ERROR:{}:{}:{}\n", fid, chunk_index, reason.replace('\n', " "))
+        }
+        _ => format!("CHUNK_NOT_FOUND:{}:{}\n", fid, chunk_index),
+    };
+    session.send(response.as_bytes()).await?;
+    session.stream.flush().await?;
+    Ok(false)
+}
+
+/// Looks up a stored chunk and its Merkle proof, answering with
+/// `ChunkResponse`, `ChunkNotFound` or `ChunkError`.
+fn chunk_reply(storage_root: &str, file_id: Uuid, chunk_index: usize) -> Message {
+    let storage_dir = Path::new(storage_root).join(file_id.to_string());
+    match storage::get_chunk(&storage_dir, chunk_index) {
+        Ok(data) => {
+            // If no proof can be built an empty one is sent, which only verifies for single-chunk files.
+            let proof = match chunk_proof(&storage_dir, chunk_index) {
+                Ok(proof) => proof.to_hex(),
+                Err(e) => {
+                    error!("Failed to build Merkle proof for file={} chunk={}: {}", file_id, chunk_index, e);
+                    String::new()
+                }
+            };
+            Message::ChunkResponse { file_id, chunk_index, data, proof }
+        }
+        Err(storage::StorageError::ChunkNotFound { .. }) => {
+            info!("file={} chunk={} requested but not stored", file_id, chunk_index);
+            Message::ChunkNotFound { file_id, chunk_index }
+        }
+        Err(e) => {
+            error!("Failed to get file={} chunk={}: {}", file_id, chunk_index, e);
+            Message::ChunkError { file_id, chunk_index, reason: e.to_string() }
+        }
+    }
+}
+
+/// Serves `GET_MANIFEST:<file_id>` with `MANIFEST_RESPONSE:<file_id>:<base64 JSON>`,
+/// `MANIFEST_NOT_FOUND:<file_id>` when no manifest is stored for the file, or
+/// `MANIFEST_FORBIDDEN:<file_id>` when its ACL does not list the remote.
+async fn send_manifest(
+    session: &mut PeerSession,
+    storage_root: &str,
+    file_id: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if Uuid::parse_str(file_id).is_ok_and(|fid| !is_allowed(session, storage_root, &fid)) {
+        session.send(format!("MANIFEST_FORBIDDEN:{}\n", file_id).as_bytes()).await?;
+        session.stream.flush().await?;
+        return Ok(());
+    }
+    let response = match Uuid::parse_str(file_id).map(|fid| manifest_reply(storage_root, fid)) {
+        Ok(Message::ManifestResponse { manifest }) => match json::to_string(&manifest) {
+            Ok(encoded) => format!("MANIFEST_RESPONSE:{}:{}\n", file_id, crate::base64::encode(encoded.as_bytes())),
+            Err(e) => {
+                error!("Failed to encode manifest of {}: {}", file_id, e);
+                format!("MANIFEST_NOT_FOUND:{}\n", file_id)
+            }
+        },
+        _ => format!("MANIFEST_NOT_FOUND:{}\n", file_id),
+    };
+    session.send(response.as_bytes()).await?;
+    session.stream.flush().await?;
+    Ok(())
+}
+
+/// Serves `LIST_FILES_REQUEST` with `LIST_FILES_RESPONSE:<N>` and one
+/// `<file_id>:<file_name>:<size_bytes>` line per locally stored manifest
+/// whose ACL lets the remote download the file.
+async fn send_file_list(
+    session: &mut PeerSession,
+    storage_root: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let acls = AclStore::new(storage_root);
+    let node_id = session.peer.node_id.as_deref();
+    let entries: Vec<RemoteFileEntry> =
+        local_file_entries(storage_root).into_iter().filter(|entry| acls.is_allowed(&entry.file_id, node_id)).collect();
+    session.send(format!("LIST_FILES_RESPONSE:{}\n", entries.len()).as_bytes()).await?;
+    for entry in &entries {
+        let file_name = entry.file_name.replace(['\n', '\r'], " ");
+        session.send(format!("{}:{}:{}\n", entry.file_id, file_name, entry.size_bytes).as_bytes()).await?;
+    }
+    session.stream.flush().await?;
+    Ok(())
+}
+
+/// Serves `PEER_LIST_REQUEST` with `PEER_LIST_RESPONSE:<N>` and the address
+/// of each connected peer, one per line.
+async fn send_peer_list(session: &mut PeerSession, peers: &PeerRegistry) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let addresses: Vec<String> = peers.all().into_iter().map(|peer| peer.address).collect();
+    session.send(format!("PEER_LIST_RESPONSE:{}\n", addresses.len()).as_bytes()).await?;
+    for address in &addresses {
+        session.send(format!("{}\n", address).as_bytes()).await?;
+    }
+    session.stream.flush().await?;
+    Ok(())
+}
+
+/// Files with a manifest under `storage_root`; chunks held without one, e.g.
+/// as a replica of a file pushed before manifests were, are not listed.
+fn local_file_entries(storage_root: &str) -> Vec<RemoteFileEntry> {
+    let file_ids = match storage::list_all_files(storage_root) {
+        Ok(file_ids) => file_ids,
+        Err(e) => {
+            error!("Failed to list stored files: {}", e);
+            return Vec::new();
+        }
+    };
+    file_ids
+        .into_iter()
+        .filter_map(|file_id| load_manifest(Path::new(storage_root).join(file_id.to_string())).ok())
+        .map(|manifest| RemoteFileEntry {
+            file_id: manifest.file_id,
+            file_name: manifest.file_name,
+            size_bytes: manifest.file_size,
+        })
+        .collect()
+}
+
+/// Opens a session to `peer` on a stream of the connection shared by all
+/// requests to it; see [`mux::open_stream`].
+async fn open_session(peer: &Peer, config: &Config) -> Result<PeerSession, ConnectionError> {
+    let stream = mux::open_stream(peer, config).await?;
+    PeerSession::handshake_over(stream, peer.clone(), PeerCapabilities::local())
+        .await
+        .map_err(|e| ConnectionError::Session(e.to_string()))
+}
+
+/// Asks `peer` which files it stores.
+pub async fn list_remote_files(peer: &Peer, config: &Config) -> Result<Vec<RemoteFileEntry>, ConnectionError> {
+    let session_error = |e: Box<dyn Error + Send + Sync>| ConnectionError::Session(e.to_string());
+    let stream = transport::connect(peer, config).await?;
+    let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await.map_err(session_error)?;
+    session.send_trace_context().await.map_err(session_error)?;
+    send_identity(&mut session, config).await.map_err(session_error)?;
+    session.send(b"LIST_FILES_REQUEST\n").await.map_err(session_error)?;
+    session.stream.flush().await?;
+
+    while let Some(line) = session.read_line().await.map_err(session_error)? {
+        let Some(count) = parse_list_files_header(&line) else {
+            continue;
+        };
+        let mut entries = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            let line = session
+                .read_line()
+                .await
+                .map_err(session_error)?
+                .ok_or_else(|| ConnectionError::Session("connection closed during the file list".into()))?;
+            let entry = parse_file_entry(&line)
+                .ok_or_else(|| ConnectionError::InvalidResponse(format!("file list from {}: {}", peer.address, line)))?;
+            entries.push(entry);
+        }
+        return Ok(entries);
+    }
+    Err(ConnectionError::Session("connection closed before the file list arrived".into()))
+}
+
+/// Asks `peer` for the addresses of the peers it is connected to. Peers
+/// that don't advertise peer exchange (`pex`) aren't asked and list none.
+pub async fn list_remote_peers(peer: &Peer, config: &Config) -> Result<Vec<String>, ConnectionError> {
+    let session_error = |e: Box<dyn Error + Send + Sync>| ConnectionError::Session(e.to_string());
+    let stream = transport::connect(peer, config).await?;
+    let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await.map_err(session_error)?;
+    if !session.capabilities.pex {
+        return Ok(Vec::new());
+    }
+    session.send_trace_context().await.map_err(session_error)?;
+    session.send(b"PEER_LIST_REQUEST\n").await.map_err(session_error)?;
+    session.stream.flush().await?;
+
+    while let Some(line) = session.read_line().await.map_err(session_error)? {
+        let Some(count) = parse_peer_list_header(&line) else {
+            continue;
+        };
+        let mut addresses = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            let address = session
+                .read_line()
+                .await
+                .map_err(session_error)?
+                .ok_or_else(|| ConnectionError::Session("connection closed during the peer list".into()))?;
+            addresses.push(address);
+        }
+        return Ok(addresses);
+    }
+    Err(ConnectionError::Session("connection closed before the peer list arrived".into()))
+}
+
+fn manifest_reply(storage_root: &str, file_id: Uuid) -> Message {
+    match load_manifest(Path::new(storage_root).join(file_id.to_string())) {
+        Ok(manifest) => Message::ManifestResponse { manifest },
+        Err(_) => Message::ManifestNotFound { file_id },
+    }
+}
+
+/// Serves `MANIFEST_PUSH:<file_id>:<len>` followed by `len` bytes of manifest
+/// JSON, saving the manifest next to the file's chunks. Answers with
+/// `MANIFEST_STORED:<file_id>` or `MANIFEST_REJECTED:<file_id>:<reason>`.
+/// A stored manifest is only replaced by one for the same content and key,
+/// see [`replaces_manifest`].
+async fn receive_file_manifest(
+    session: &mut PeerSession,
+    storage_root: &str,
+    header: &str,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut stream = TcpStream::connect(&peer.address).await?;
-    info!("Connected to peer {}", peer.address);
+    let Some((file_id, len)) = header.split_once(':') else {
+        error!("Ignoring malformed manifest push: {}", header);
+        return Ok(());
+    };
+    let len: usize = match len.parse() {
+        Ok(len) if len <= MAX_MESSAGE_LEN => len,
+        // The payload cannot be skipped without a valid length, so the session ends here.
+        _ => return Err(format!("Invalid manifest length in push for {}: {}", file_id, len).into()),
+    };
+    let data = session.read_exact(len).await?;
 
-    let chunk_data = storage::get_chunk(storage_dir, chunk_index)?;
+    let stored = Uuid::parse_str(file_id)
+        .map_err(|e| e.to_string())
+        .and_then(|fid| {
+            let text = String::from_utf8(data).map_err(|e| e.to_string())?;
+            let manifest: FileManifest = json::from_str(&text).map_err(|e| e.to_string())?;
+            if manifest.file_id != fid {
+                return Err(format!("manifest is for file {}", manifest.file_id));
+            }
+            let storage_dir = storage::initialize_storage(Path::new(storage_root), fid).map_err(|e| e.to_string())?;
+            match load_manifest(&storage_dir) {
+                Ok(existing) if !replaces_manifest(&existing, &manifest) => {
+                    return Err("a different manifest is already stored for this file".to_string());
+                }
+                Ok(_) | Err(storage::StorageError::ManifestNotFound(_)) => {}
+                Err(e) => return Err(format!("stored manifest is unreadable: {}", e)),
+            }
+            save_manifest(&storage_dir, &manifest).map_err(|e| e.to_string())
+        });
+    let response = match stored {
+        Ok(()) => {
+            info!("Stored pushed manifest of file {}", file_id);
+            format!("MANIFEST_STORED:{}\n", file_id)
+        }
+        Err(reason) => {
+            error!("Rejected pushed manifest of file {}: {}", file_id, reason);
+            format!("MANIFEST_REJECTED:{}:{}\n", file_id, reason.replace('\n', " "))
+        }
+    };
+    session.send(response.as_bytes()).await?;
+    session.stream.flush().await?;
+    Ok(())
+}
+
+/// Whether a pushed manifest may take the place of the stored one: only if it
+/// describes the same content, with the same Merkle root and file hash, and
+/// keeps the same wrapped key. Anything else would let a peer swap the file
+/// or destroy its key.
+fn replaces_manifest(existing: &FileManifest, pushed: &FileManifest) -> bool {
+    existing == pushed
+        || (existing.merkle_root.is_some()
+            && existing.merkle_root == pushed.merkle_root
+            && existing.hash_algorithm == pushed.hash_algorithm
+            && existing.file_hash == pushed.file_hash
+            && existing.wrapped_key == pushed.wrapped_key)
+}
+
+/// Stores a chunk sent by [`send_chunk_to_peer`] and answers `OK`, or
+/// `ERROR:<reason>` if it is not stored. A chunk is only written if its
+/// proof verifies against the Merkle root of the file's local manifest, so
+/// the manifest has to arrive first. A chunk already stored is never
+/// replaced; the same bytes pushed again are acknowledged without writing.
+async fn receive_chunk_push(
+    session: &mut PeerSession,
+    storage_root: &str,
+    header: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (file_id, chunk_index, len, proof) = match header.split(':').collect::<Vec<_>>()[..] {
+        [file_id, chunk_index, len] => (file_id, chunk_index, len, ""),
+        [file_id, chunk_index, len, proof] => (file_id, chunk_index, len, proof),
+        _ => return Err(format!("Malformed chunk push: {}", header).into()),
+    };
+    let len: usize = match len.parse() {
+        Ok(len) if len <= MAX_MESSAGE_LEN => len,
+        // The payload cannot be skipped without a valid length, so the session ends here.
+        _ => return Err(format!("Invalid chunk length in push for {}: {}", file_id, len).into()),
+    };
+    let data = session.read_exact(len).await?;
 
-    let message = format!(
-        "{}:{}:{}:",
-        file_id,
-        chunk_index,
-        chunk_data.len()
-    );
+    let stored = match (Uuid::parse_str(file_id), chunk_index.parse::<usize>()) {
+        (Ok(fid), Ok(index)) => store_pushed_chunk(Path::new(storage_root), fid, index, proof, &data),
+        _ => Err("malformed file ID or chunk index".to_string()),
+    };
+    let response = match stored {
+        Ok(()) => {
+            info!("Stored pushed file={} chunk={}", file_id, chunk_index);
+            "OK\n".to_string()
+        }
+        Err(reason) => {
+            error!("Rejected pushed file={} chunk={}: {}", file_id, chunk_index, reason);
+            format!("ERROR:{}\n", reason.replace('\n', " "))
+        }
+    };
+    session.send(response.as_bytes()).await?;
+    session.stream.flush().await?;
+    Ok(())
+}
+
+fn store_pushed_chunk(storage_root: &Path, file_id: Uuid, chunk_index: usize, proof: &str, data: &[u8]) -> Result<(), String> {
+    let storage_dir = storage_root.join(file_id.to_string());
+    let manifest = load_manifest(&storage_dir).map_err(|_| "no manifest for this file".to_string())?;
+    if storage::chunk_exists(&storage_dir, chunk_index) {
+        return match storage::get_chunk(&storage_dir, chunk_index) {
+            Ok(existing) if existing == data => Ok(()),
+            _ => Err("chunk is already stored".to_string()),
+        };
+    }
+    let Some(root) = manifest.merkle_root else {
+        return Err("manifest has no Merkle root to verify the chunk against".to_string());
+    };
+    let algorithm = manifest.hash_algorithm;
+    let verified = MerkleProof::from_hex(proof)
+        .is_some_and(|proof| proof.verify(algorithm, algorithm.digest(data), root, chunk_index, manifest.total_chunks));
+    if !verified {
+        return Err("chunk does not match the file's Merkle root".to_string());
+    }
+    // Only reached for data proven against the root, so the recorded hash is the manifest's.
+    storage::save_chunk(&storage_dir, &ChunkMetadata::new(file_id, chunk_index, data.len(), manifest.total_chunks), data)
+        .map_err(|e| e.to_string())
+}
+
+/// Sends `manifest` to `peer`, so a replica knows the file's chunk count,
+/// Merkle root and name, and waits for it to be stored.
+pub async fn send_file_manifest(peer: &Peer, manifest: &FileManifest, config: &Config) -> Result<(), ConnectionError> {
+    let session_error = |e: Box<dyn Error + Send + Sync>| ConnectionError::Session(e.to_string());
+    let file_id = manifest.file_id;
+    let encoded = json::to_string(manifest).map_err(|e| ConnectionError::Session(e.to_string()))?;
+    let mut session = open_session(peer, config).await?;
+    session.send_trace_context().await.map_err(session_error)?;
+    session.send(format!("MANIFEST_PUSH:{}:{}\n", file_id, encoded.len()).as_bytes()).await.map_err(session_error)?;
+    session.send(encoded.as_bytes()).await.map_err(session_error)?;
+    session.stream.flush().await?;
+
+    let stored = format!("MANIFEST_STORED:{}", file_id);
+    let rejected_prefix = format!("MANIFEST_REJECTED:{}:", file_id);
+    while let Some(line) = session.read_line().await.map_err(session_error)? {
+        if line == stored {
+            return Ok(());
+        }
+        if let Some(reason) = line.strip_prefix(&rejected_prefix) {
+            return Err(ConnectionError::ManifestRejected(file_id, reason.to_string()));
+        }
+    }
+    Err(ConnectionError::Session("connection closed before the manifest was stored".into()))
+}
+
+/// Asks `peer` for the manifest of `file_id`.
+pub async fn get_remote_manifest(
+    peer: &Peer,
+    file_id: &Uuid,
+    config: &Config,
+) -> Result<FileManifest, ConnectionError> {
+    let session_error = |e: Box<dyn Error + Send + Sync>| ConnectionError::Session(e.to_string());
+    let mut session = open_session(peer, config).await?;
+    session.send_trace_context().await.map_err(session_error)?;
+    send_identity(&mut session, config).await.map_err(session_error)?;
+    session.send(format!("GET_MANIFEST:{}\n", file_id).as_bytes()).await.map_err(session_error)?;
+    session.stream.flush().await?;
+
+    let response_prefix = format!("MANIFEST_RESPONSE:{}:", file_id);
+    let not_found = format!("MANIFEST_NOT_FOUND:{}", file_id);
+    let forbidden = format!("MANIFEST_FORBIDDEN:{}", file_id);
+    while let Some(line) = session.read_line().await.map_err(session_error)? {
+        if line == not_found {
+            return Err(ConnectionError::ManifestNotFound(*file_id));
+        }
+        if line == forbidden {
+            return Err(ConnectionError::Forbidden(*file_id));
+        }
+        if let Some(encoded) = line.strip_prefix(&response_prefix) {
+            let invalid = |reason: String| ConnectionError::InvalidResponse(format!("manifest of {}: {}", file_id, reason));
+            let data = crate::base64::decode(encoded).ok_or_else(|| invalid("bad base64".into()))?;
+            let text = String::from_utf8(data).map_err(|e| invalid(e.to_string()))?;
+            let manifest: FileManifest = json::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+            if manifest.file_id != *file_id {
+                return Err(invalid(format!("manifest is for file {}", manifest.file_id)));
+            }
+            return Ok(manifest);
+        }
+    }
+    Err(ConnectionError::Session("connection closed before the manifest arrived".into()))
+}
 
-    stream.write_all(message.as_bytes()).await?;
-    stream.write_all(&chunk_data).await?;
+/// Requests one chunk from `peer` and saves it once it checks out against the
+/// Merkle root in the local manifest. Manifests without a root skip the check.
+pub async fn fetch_chunk_from_peer(
+    peer: &Peer,
+    storage_dir: &Path,
+    file_id: Uuid,
+    chunk_index: usize,
+    config: &Config,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let manifest = load_manifest(storage_dir)?;
+    let (chunk_data, proof) = request_chunk(peer, file_id, chunk_index, config).await?;
 
-    let mut ack = [0u8; 2];
-    stream.read_exact(&mut ack).await?;
-    if &ack != b"OK" {
-        return Err("Failed to receive acknowledgment from peer".into());
+    if let Some(root) = manifest.merkle_root {
+        let algorithm = manifest.hash_algorithm;
+        let verified = MerkleProof::from_hex(&proof)
+            .is_some_and(|proof| proof.verify(algorithm, algorithm.digest(&chunk_data), root, chunk_index, manifest.total_chunks));
+        if !verified {
+            return Err(format!(
+                "Chunk {} of file {} from peer {} failed Merkle verification",
+                chunk_index, file_id, peer.address
+            )
+            .into());
+        }
     }
 
+    storage::save_chunk(
+        storage_dir,
+        &ChunkMetadata::new(file_id, chunk_index, chunk_data.len(), manifest.total_chunks)
+            .with_base_chunk_size(manifest.base_chunk_size),
+        &chunk_data,
+    )?;
+    info!("Fetched file={} chunk={} from {}", file_id, chunk_index, peer);
     Ok(())
 }
 
-async fn read_line(buffer: &mut Vec<u8>, stream: &mut TcpStream) -> Result<String, Box<dyn Error + Send + Sync>> {
+/// What a [`fetch_chunks_batch`] got from one peer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchResult {
+    /// Chunks received and saved, in request order.
+    pub fetched: Vec<usize>,
+    /// Chunks the peer did not have or that failed verification, with why.
+    pub failed: Vec<(usize, String)>,
+}
+
+/// Fetches several chunks over one connection, keeping up to
+/// `max_pipeline_depth` `CHUNK_REQUEST`s in flight; the remote answers them in
+/// order. Chunks are checked against the local manifest like in
+/// [`fetch_chunk_from_peer`] and saved as they arrive. An error means the
+/// connection itself failed; chunks already saved stay on disk.
+pub async fn fetch_chunks_batch(
+    peer: &Peer,
+    file_id: &Uuid,
+    chunk_indices: &[usize],
+    storage_dir: &Path,
+    config: &Config,
+) -> Result<BatchResult, ConnectionError> {
+    let session_error = |e: Box<dyn Error + Send + Sync>| ConnectionError::Session(e.to_string());
+    let invalid = |line: &str| ConnectionError::InvalidResponse(format!("chunk reply from {}: {}", peer.address, line));
+    let manifest = load_manifest(storage_dir).map_err(|e| ConnectionError::Storage(e.to_string()))?;
+    let mut session = open_session(peer, config).await?;
+    session.send_trace_context().await.map_err(session_error)?;
+    send_identity(&mut session, config).await.map_err(session_error)?;
+
+    let mut pending = chunk_indices.iter().copied();
+    // Each request's span ends when its reply has been handled.
+    let mut in_flight: VecDeque<(usize, Span)> = VecDeque::new();
+    let mut result = BatchResult::default();
     loop {
-        if let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
-            let line = buffer.drain(..=pos).collect::<Vec<u8>>();
-            return Ok(String::from_utf8_lossy(&line).trim().to_string());
-        } else {
-            let mut temp = [0u8; 1024];
-            let bytes_read = stream.read(&mut temp).await?;
-            if bytes_read == 0 {
-                return Err("Connection closed".into());
+        while in_flight.len() < config.transfer.max_pipeline_depth.max(1) {
+            let Some(chunk_index) = pending.next() else {
+                break;
+            };
+            let span = Span::start(format!("fetch_chunk {}:{}", file_id, chunk_index));
+            session.send_chunk_request(file_id, chunk_index, &span.context()).await.map_err(session_error)?;
+            in_flight.push_back((chunk_index, span));
+        }
+        let Some(&(chunk_index, _)) = in_flight.front() else {
+            return Ok(result);
+        };
+        session.stream.flush().await?;
+
+        let Some(line) = session.read_line().await.map_err(session_error)? else {
+            return Err(ConnectionError::Session("connection closed with chunk requests pending".into()));
+        };
+        let requested = format!("{}:{}", file_id, chunk_index);
+        if line.strip_prefix("CHUNK_NOT_FOUND:") == Some(requested.as_str()) {
+            info!("{} does not have file={} chunk={}", peer, file_id, chunk_index);
+            result.failed.push((chunk_index, "not found".to_string()));
+        } else if line.strip_prefix("CHUNK_FORBIDDEN:") == Some(requested.as_str()) {
+            info!("{} denied access to file={} chunk={}", peer, file_id, chunk_index);
+            result.failed.push((chunk_index, "forbidden".to_string()));
+        } else if let Some(rest) = line.strip_prefix("CHUNK_ERROR:") {
+            let Some(reason) = rest.strip_prefix(requested.as_str()).and_then(|rest| rest.strip_prefix(':')) else {
+                return Err(invalid(&line));
+            };
+            error!("{} failed to serve file={} chunk={}: {}", peer, file_id, chunk_index, reason);
+            result.failed.push((chunk_index, reason.to_string()));
+        } else if line.starts_with("CHUNK_RESPONSE:") {
+            let header = parse_chunk_response_header(&line)
+                .filter(|h| h.file_id == *file_id && h.chunk_index == chunk_index)
+                .ok_or_else(|| invalid(&line))?;
+            let csize = header.chunk_size;
+            let chunk_data = session.read_exact(csize).await.map_err(session_error)?;
+            let algorithm = manifest.hash_algorithm;
+            let verified = manifest.merkle_root.is_none_or(|root| {
+                MerkleProof::from_hex(header.proof)
+                    .is_some_and(|proof| proof.verify(algorithm, algorithm.digest(&chunk_data), root, chunk_index, manifest.total_chunks))
+            });
+            if verified {
+                storage::save_chunk(
+                    storage_dir,
+                    &ChunkMetadata::new(*file_id, chunk_index, csize, manifest.total_chunks)
+                        .with_base_chunk_size(manifest.base_chunk_size),
+                    &chunk_data,
+                )
+                .map_err(|e| ConnectionError::Storage(e.to_string()))?;
+                result.fetched.push(chunk_index);
+            } else {
+                error!("file={} chunk={} from {} failed Merkle verification", file_id, chunk_index, peer);
+                result.failed.push((chunk_index, "failed Merkle verification".to_string()));
             }
-            buffer.extend_from_slice(&temp[..bytes_read]);
+        } else {
+            // Session chatter such as the welcome message and DHT_REQUEST.
+            continue;
+        }
+        in_flight.pop_front();
+    }
+}
+
+/// [`fetch_chunks_batch`], returning only the indices that were received and saved.
+pub async fn fetch_chunks_pipelined(
+    peer: &Peer,
+    file_id: &Uuid,
+    chunk_indices: &[usize],
+    storage_dir: &Path,
+    config: &Config,
+) -> Result<Vec<usize>, ConnectionError> {
+    Ok(fetch_chunks_batch(peer, file_id, chunk_indices, storage_dir, config).await?.fetched)
+}
+
+/// Sends one `CHUNK_REQUEST` and returns the chunk with its hex Merkle proof, unverified.
+pub async fn request_chunk(
+    peer: &Peer,
+    file_id: Uuid,
+    chunk_index: usize,
+    config: &Config,
+) -> Result<(Vec<u8>, String), Box<dyn Error + Send + Sync>> {
+    let span = Span::start(format!("fetch_chunk {}:{}", file_id, chunk_index));
+    let mut session = open_session(peer, config).await?;
+    session.send_trace_context().await?;
+    send_identity(&mut session, config).await?;
+    session.send_chunk_request(&file_id, chunk_index, &span.context()).await?;
+    session.stream.flush().await?;
+
+    let requested = format!("{}:{}", file_id, chunk_index);
+    while let Some(line_str) = session.read_line().await? {
+        // CHUNK_NOT_FOUND:<FILE_ID>:<CHUNK_INDEX>
+        if line_str.strip_prefix("CHUNK_NOT_FOUND:") == Some(requested.as_str()) {
+            return Err(ChunkFetchError::ChunkNotFound(file_id, chunk_index).into());
+        }
+        // CHUNK_FORBIDDEN:<FILE_ID>:<CHUNK_INDEX>
+        if line_str.strip_prefix("CHUNK_FORBIDDEN:") == Some(requested.as_str()) {
+            return Err(ChunkFetchError::ChunkForbidden(file_id, chunk_index).into());
+        }
+        // CHUNK_ERROR:<FILE_ID>:<CHUNK_INDEX>:<REASON>
+        if let Some(reason) = line_str
+            .strip_prefix("CHUNK_ERROR:")
+            .and_then(|rest| rest.strip_prefix(requested.as_str()))
+            .and_then(|rest| rest.strip_prefix(':'))
+        {
+            return Err(ChunkFetchError::ChunkError(file_id, chunk_index, reason.to_string()).into());
+        }
+        // CHUNK_RESPONSE:<FILE_ID>:<CHUNK_INDEX>:<CHUNK_SIZE>:<PROOF>
+        if !line_str.starts_with("CHUNK_RESPONSE:") {
+            continue;
+        }
+        let header = parse_chunk_response_header(&line_str)
+            .filter(|h| h.file_id == file_id && h.chunk_index == chunk_index)
+            .ok_or_else(|| format!("Unexpected chunk response from {}: {}", peer.address, line_str))?;
+        let chunk_data = session.read_exact(header.chunk_size).await?;
+        return Ok((chunk_data, header.proof.to_string()));
+    }
+    Err("Connection closed".into())
+}
+
+/// Builds the Merkle proof for a stored chunk from the hashes of all of the file's chunks.
+/// Without a manifest (e.g. on a replica), every stored chunk is assumed to be part of the
+/// file, hashed with the default algorithm.
+fn chunk_proof(storage_dir: &Path, chunk_index: usize) -> Result<MerkleProof, storage::StorageError> {
+    let (total_chunks, algorithm) = match load_manifest(storage_dir) {
+        Ok(manifest) => (manifest.total_chunks, manifest.hash_algorithm),
+        Err(_) => (storage::list_chunks(storage_dir)?.len(), HashAlgorithm::default()),
+    };
+    let hashes = (0..total_chunks)
+        .map(|i| storage::chunk_hash(storage_dir, i, algorithm))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(MerkleTree::from_hashes(algorithm, &hashes).proof(chunk_index))
+}
+
+/// Pushes a locally stored chunk of `file_id` under `storage_root` to `peer`.
+pub async fn send_chunk_to_peer(
+    peer: &Peer,
+    storage_root: &str,
+    file_id: &Uuid,
+    chunk_index: usize,
+    network_stats: &SharedNetworkStats,
+    config: &Config,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match push_chunk(peer, storage_root, file_id, chunk_index, config).await {
+        Ok(bytes_sent) => {
+            stats::record(network_stats, &peer.address, |s| {
+                s.bytes_sent += bytes_sent;
+                s.chunks_sent += 1;
+            });
+            Ok(())
+        }
+        Err(e) => {
+            stats::record(network_stats, &peer.address, |s| s.errors += 1);
+            Err(e)
+        }
+    }
+}
+
+/// Sends one chunk as `CHUNK_PUSH:<file_id>:<chunk_index>:<chunk_size>:<proof>`
+/// plus its bytes and waits for the `OK` or `ERROR:<reason>` line, returning
+/// the number of bytes written. The peer needs the file's manifest to check
+/// the proof, see [`send_file_manifest`].
+async fn push_chunk(
+    peer: &Peer,
+    storage_root: &str,
+    file_id: &Uuid,
+    chunk_index: usize,
+    config: &Config,
+) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    let storage_dir = Path::new(storage_root).join(file_id.to_string());
+    let chunk_data = storage::get_chunk(&storage_dir, chunk_index)?;
+    let proof = chunk_proof(&storage_dir, chunk_index)?;
+
+    let mut session = open_session(peer, config).await?;
+    info!("Connected to {}", peer);
+    session.send_trace_context().await?;
+    let header = format!("CHUNK_PUSH:{}:{}:{}:{}\n", file_id, chunk_index, chunk_data.len(), proof.to_hex());
+    session.send(header.as_bytes()).await?;
+    session.send(&chunk_data).await?;
+    session.stream.flush().await?;
+
+    // Other lines, e.g. the welcome message and `DHT_REQUEST`, may come first.
+    while let Some(line) = session.read_line().await? {
+        let line = line.trim();
+        if line == "OK" {
+            return Ok((header.len() + chunk_data.len()) as u64);
+        }
+        if let Some(reason) = line.strip_prefix("ERROR:") {
+            return Err(format!("Peer {} rejected chunk {} of {}: {}", peer.address, chunk_index, file_id, reason).into());
         }
     }
+    Err("Connection closed before the chunk was acknowledged".into())
 }
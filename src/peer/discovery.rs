@@ -1,77 +1,874 @@
 
 use crate::indexing::dht::DHT;
-use crate::peer::connection::handle_connection;
+use crate::config::Config;
+use crate::file_manager::manifest::load_manifest;
+use crate::file_manager::storage::{list_all_files_with_pinned, StorageError};
+use crate::indexing::gossip::{announce_to_dht, GossipTask};
+use crate::peer::connection::{handle_connection, list_remote_files, list_remote_peers, serve_incoming};
+use crate::peer::access_control::NodeId;
+use crate::peer::beacon::{run_beacon_responder, send_beacon, BEACON_REPLY_WINDOW};
+pub(crate) use crate::peer::beacon::default_lan_beacon_addr;
+use crate::peer::latency::{run_pinger, PeerLatency};
+use crate::peer::stats::SharedNetworkStats;
+use crate::peer::protocol::ProtocolVersion;
+use crate::peer::rate_limit::{ConnectionLimiter, RATE_LIMITED};
+use crate::peer::session::{PeerCapabilities, PeerSession};
+use crate::peer::transport;
+use crate::peer::url::PeerUrl;
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, oneshot};
 use tokio::sync::mpsc::Sender;
+use tokio::task::JoinSet;
 use std::error::Error;
-use log::{info, error};
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use crate::history::unix_now;
+use log::{debug, info, warn, error};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Peer {
     pub address: String,
+    /// `PeerCapabilities` bitfield advertised by this peer during the handshake.
+    pub capability_flags: u32,
+    /// Hex node ID, when the peer was given as a `sharesphere://<node-id>@host:port` URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    /// `address` parsed by the first call to [`Peer::to_socket_addr`].
+    #[serde(skip)]
+    resolved: OnceLock<SocketAddr>,
+}
+
+impl PartialEq for Peer {
+    fn eq(&self, other: &Self) -> bool {
+        self.address == other.address && self.capability_flags == other.capability_flags && self.node_id == other.node_id
+    }
+}
+
+impl Eq for Peer {}
+
+/// Formats the peer as a `sharesphere://` URL, or as its bare address if that isn't valid.
+/// `peer@<address>`, for logs. [`PeerUrl`](crate::peer::url::PeerUrl) gives the `sharesphere://` form.
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer@{}", self.address)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PeerError {
+    #[error("Peer address is required")]
+    MissingAddress,
+
+    #[error("Invalid peer address {0}: {1}")]
+    InvalidAddress(String, std::net::AddrParseError),
+
+    #[error("Invalid peer URL {0}: {1}")]
+    InvalidUrl(String, String),
+}
+
+impl Peer {
+    pub fn new(address: impl Into<String>) -> Self {
+        Peer {
+            address: address.into(),
+            capability_flags: 0,
+            node_id: None,
+            resolved: OnceLock::new(),
+        }
+    }
+
+    pub fn from_socket_addr(addr: SocketAddr) -> Self {
+        Peer {
+            address: addr.to_string(),
+            capability_flags: 0,
+            node_id: None,
+            resolved: OnceLock::from(addr),
+        }
+    }
+
+    /// Parses `address`, once; later calls return the cached result.
+    pub fn to_socket_addr(&self) -> Result<SocketAddr, PeerError> {
+        if let Some(addr) = self.resolved.get() {
+            return Ok(*addr);
+        }
+        let addr = self
+            .address
+            .parse::<SocketAddr>()
+            .map_err(|e| PeerError::InvalidAddress(self.address.clone(), e))?;
+        Ok(*self.resolved.get_or_init(|| addr))
+    }
+
+    pub fn builder() -> PeerBuilder {
+        PeerBuilder::default()
+    }
+}
+
+/// Builds a [`Peer`] whose address is checked to be a valid `SocketAddr`.
+#[derive(Debug, Clone, Default)]
+pub struct PeerBuilder {
+    address: Option<String>,
+    capability_flags: u32,
+}
+
+impl PeerBuilder {
+    pub fn address(mut self, address: impl Into<String>) -> PeerBuilder {
+        self.address = Some(address.into());
+        self
+    }
+
+    pub fn capability_flags(mut self, capability_flags: u32) -> PeerBuilder {
+        self.capability_flags = capability_flags;
+        self
+    }
+
+    pub fn build(self) -> Result<Peer, PeerError> {
+        let address = self.address.ok_or(PeerError::MissingAddress)?;
+        let addr = address.parse::<SocketAddr>().map_err(|e| PeerError::InvalidAddress(address.clone(), e))?;
+        Ok(Peer {
+            address,
+            capability_flags: self.capability_flags,
+            node_id: None,
+            resolved: OnceLock::from(addr),
+        })
+    }
+}
+
+/// A peer connection opening or closing. `at` is in seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PeerEvent {
+    Connected { address: String, at: u64 },
+    Disconnected { address: String, at: u64, reason: String },
+}
+
+impl PeerEvent {
+    fn connected(address: &str) -> Self {
+        PeerEvent::Connected { address: address.to_string(), at: unix_now() }
+    }
+
+    fn disconnected(address: &str, result: &Result<(), Box<dyn Error + Send + Sync>>) -> Self {
+        let reason = match result {
+            Ok(()) => "connection closed".to_string(),
+            Err(e) => e.to_string(),
+        };
+        PeerEvent::Disconnected { address: address.to_string(), at: unix_now(), reason }
+    }
+}
+
+/// A peer with a live connection. `connected_since` is in seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub address: String,
+    pub connected_since: u64,
+    pub node_id: Option<NodeId>,
+    pub capability_flags: u32,
+}
+
+impl PeerInfo {
+    fn to_peer(&self) -> Peer {
+        let mut peer = Peer::new(self.address.clone());
+        peer.capability_flags = self.capability_flags;
+        peer.node_id = self.node_id.clone();
+        peer
+    }
+}
+
+/// The peers with a live connection, keyed by address, shared by every task
+/// that talks to peers. Clones share the same peers and subscribers, so each
+/// task sees connections as they open and close. Every change is published
+/// as a [`PeerEvent`].
+#[derive(Debug, Clone)]
+pub struct PeerRegistry {
+    peers: Arc<RwLock<HashMap<String, PeerInfo>>>,
+    update_tx: broadcast::Sender<PeerEvent>,
+}
+
+impl Default for PeerRegistry {
+    fn default() -> Self {
+        PeerRegistry::new(DEFAULT_PEER_EVENT_BUFFER)
+    }
+}
+
+/// Events buffered per subscriber when no `peer_event_buffer` is configured.
+const DEFAULT_PEER_EVENT_BUFFER: usize = 256;
+
+impl PeerRegistry {
+    /// `event_buffer` events are buffered for each subscriber; one that falls
+    /// further behind skips the oldest.
+    pub fn new(event_buffer: usize) -> Self {
+        let (update_tx, _) = broadcast::channel(event_buffer.max(1));
+        PeerRegistry { peers: Arc::default(), update_tx }
+    }
+
+    /// Adds `peer`, replacing any peer known at the same address.
+    pub fn add(&self, peer: Peer) {
+        let info = PeerInfo {
+            connected_since: unix_now(),
+            node_id: peer.node_id.clone(),
+            capability_flags: peer.capability_flags,
+            address: peer.address,
+        };
+        let event = PeerEvent::connected(&info.address);
+        self.peers.write().unwrap().insert(info.address.clone(), info);
+        self.publish(event);
+    }
+
+    /// Removes the peer at `address`, returning it if it was known.
+    pub fn remove(&self, address: &str) -> Option<PeerInfo> {
+        self.remove_after(address, &Ok(()))
+    }
+
+    /// [`remove`](Self::remove), reporting why the connection ended.
+    fn remove_after(&self, address: &str, result: &Result<(), Box<dyn Error + Send + Sync>>) -> Option<PeerInfo> {
+        let removed = self.peers.write().unwrap().remove(address);
+        if removed.is_some() {
+            self.publish(PeerEvent::disconnected(address, result));
+        }
+        removed
+    }
+
+    /// The known peers, longest connected first.
+    pub fn all(&self) -> Vec<Peer> {
+        self.infos().iter().map(PeerInfo::to_peer).collect()
+    }
+
+    /// [`all`](Self::all), with when each peer connected.
+    pub fn infos(&self) -> Vec<PeerInfo> {
+        let mut infos: Vec<PeerInfo> = self.peers.read().unwrap().values().cloned().collect();
+        infos.sort_by(|a, b| a.connected_since.cmp(&b.connected_since).then_with(|| a.address.cmp(&b.address)));
+        infos
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, address: &str) -> bool {
+        self.peers.read().unwrap().contains_key(address)
+    }
+
+    /// Receives every [`PeerEvent`] published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<PeerEvent> {
+        self.update_tx.subscribe()
+    }
+
+    /// Sends `event` to every subscriber. Having none is not an error.
+    fn publish(&self, event: PeerEvent) {
+        let _ = self.update_tx.send(event);
+    }
+}
+
+/// Announces every locally stored file to the known peers every
+/// `re_announce_interval_secs`. DHT entries do not expire on their own, but a
+/// peer that restarts starts with an empty DHT and one that joins later never
+/// saw the original announcement; this keeps both up to date.
+pub struct ReAnnounceTask {
+    peers: PeerRegistry,
+    local_peer: Peer,
+    config: Config,
+}
+
+impl ReAnnounceTask {
+    pub fn new(peers: PeerRegistry, local_peer: Peer, config: Config) -> Self {
+        ReAnnounceTask { peers, local_peer, config }
+    }
+
+    /// Runs a round every `re_announce_interval_secs`, forever.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.re_announce_interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            match self.round().await {
+                Ok(announced) => debug!("Re-announced {} stored files", announced),
+                Err(e) => error!("Re-announcing stored files failed: {}", e),
+            }
+        }
+    }
+
+    /// Announces each stored file that is pinned or has a readable manifest;
+    /// directories with neither are left to orphan cleanup. Returns how many
+    /// files reached at least one peer.
+    pub async fn round(&self) -> Result<usize, StorageError> {
+        let known_peers = self.peers.all();
+        if known_peers.iter().all(|p| p.address == self.local_peer.address) {
+            return Ok(0);
+        }
+        announce_stored_files(&self.local_peer, &known_peers, &self.config).await
+    }
+}
+
+/// Announces each file stored under `config.storage.storage_path` that is pinned or
+/// has a readable manifest to `known_peers`. Returns how many files reached
+/// at least one of them.
+async fn announce_stored_files(local_peer: &Peer, known_peers: &[Peer], config: &Config) -> Result<usize, StorageError> {
+    let storage_root = Path::new(&config.storage.storage_path);
+    let mut announced = 0;
+    for (file_id, pinned) in list_all_files_with_pinned(storage_root)? {
+        let storage_dir = storage_root.join(file_id.to_string());
+        if !pinned && load_manifest(&storage_dir).is_err() {
+            continue;
+        }
+        match announce_to_dht(file_id, local_peer, known_peers, config).await {
+            Ok(()) => announced += 1,
+            Err(e) => warn!("Failed to announce file {}: {}", file_id, e),
+        }
+    }
+    Ok(announced)
+}
+
+/// What a peer agreed to during [`connect_to_peer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectedPeer {
+    pub protocol_version: ProtocolVersion,
+    /// Everything the peer advertised, including features this node lacks.
+    pub capabilities: PeerCapabilities,
+}
+
+/// Connects to a peer that is not a bootstrap peer, e.g. one whose address
+/// a user was given. The handshake and protocol negotiation run on a first
+/// connection, then the stored files are announced to the peer so its DHT
+/// learns about them without waiting for the next re-announce round. The
+/// peer is then served in the background, like a bootstrap peer, until it
+/// disconnects or `connections` closes it.
+#[allow(clippy::too_many_arguments)]
+pub async fn connect_to_peer(
+    peer: Peer,
+    config: &Config,
+    dht: DHT,
+    local_peer: Peer,
+    peers: PeerRegistry,
+    network_stats: SharedNetworkStats,
+    connections: &OutgoingConnections,
+) -> Result<ConnectedPeer, Box<dyn Error + Send + Sync>> {
+    let stream = transport::connect(&peer, config).await?;
+    let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await?;
+    let protocol_version = session.negotiate_version(ProtocolVersion::LATEST).await?;
+    let connected = ConnectedPeer {
+        protocol_version,
+        capabilities: PeerCapabilities::from_bits(session.peer.capability_flags),
+    };
+    drop(session);
+
+    let announced = announce_stored_files(&local_peer, std::slice::from_ref(&peer), config).await?;
+    debug!("Announced {} stored files to {}", announced, peer);
+
+    let stream = transport::connect(&peer, config).await?;
+    let stop = connections.register(&peer.address);
+    let config = config.clone();
+    let peer = Peer { capability_flags: connected.capabilities.to_bits(), ..peer };
+    tokio::spawn(async move {
+        run_peer_connection(stream, peer, &config, dht, local_peer, peers, network_stats, stop).await;
+    });
+    Ok(connected)
+}
+
+/// What [`probe_peer`] found out about a peer, for `discover`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PeerProbe {
+    pub address: String,
+    /// The version both sides agreed on, which is at most [`ProtocolVersion::LATEST`].
+    pub protocol_version: u32,
+    pub files_count: usize,
+    /// Round trip of the protocol negotiation.
+    pub latency_ms: u64,
+    /// The peers it is connected to; empty unless it supports peer exchange.
+    pub known_peers: Vec<String>,
+}
+
+/// Asks `peer` for its protocol version, stored files and connected peers,
+/// each over a connection of its own.
+pub async fn probe_peer(peer: &Peer, config: &Config) -> Result<PeerProbe, Box<dyn Error + Send + Sync>> {
+    let stream = transport::connect(peer, config).await?;
+    let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await?;
+    let started = Instant::now();
+    let protocol_version = session.negotiate_version(ProtocolVersion::LATEST).await?;
+    let latency = started.elapsed();
+    drop(session);
+
+    Ok(PeerProbe {
+        address: peer.address.clone(),
+        protocol_version: protocol_version.number(),
+        files_count: list_remote_files(peer, config).await?.len(),
+        latency_ms: latency.as_millis() as u64,
+        known_peers: list_remote_peers(peer, config).await?,
+    })
+}
+
+/// Probes the bootstrap peers, the nodes that answer a LAN discovery beacon
+/// within [`BEACON_REPLY_WINDOW`], and then every peer those list, for at most
+/// `timeout` in all. Peers that fail or don't answer in time are logged and
+/// left out. Nothing is connected for longer than a probe and nothing is
+/// saved. Results are sorted by address.
+pub async fn discover_peers(config: &Config, timeout: Duration) -> Vec<PeerProbe> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let local_address = format!("127.0.0.1:{}", config.discovery.peer_port);
+    let mut seen: HashSet<String> = HashSet::from([local_address]);
+    let mut probes = JoinSet::new();
+    let mut spawn_probe = |peer: Peer, probes: &mut JoinSet<_>| {
+        if seen.insert(peer.address.clone()) {
+            let config = config.clone();
+            probes.spawn(async move {
+                let result = probe_peer(&peer, &config).await;
+                (peer, result)
+            });
+        }
+    };
+    for url in &config.discovery.bootstrap_peers {
+        match Peer::try_from(url.clone()) {
+            Ok(peer) => spawn_probe(peer, &mut probes),
+            Err(e) => warn!("Skipping bootstrap peer {}: {}", url, e),
+        }
+    }
+    let mut beacon = config.discovery.lan_beacon_addr.map(|addr| {
+        let window = BEACON_REPLY_WINDOW.min(timeout);
+        tokio::spawn(async move { (addr, send_beacon(addr, window).await) })
+    });
+
+    let mut found = Vec::new();
+    loop {
+        if probes.is_empty() && beacon.is_none() {
+            break;
+        }
+        tokio::select! {
+            answered = async { beacon.as_mut().unwrap().await }, if beacon.is_some() => {
+                beacon = None;
+                match answered {
+                    Ok((_, Ok(addresses))) => {
+                        for addr in addresses {
+                            spawn_probe(Peer::from_socket_addr(addr), &mut probes);
+                        }
+                    }
+                    Ok((addr, Err(e))) => warn!("Failed to send a LAN discovery beacon to {}: {}", addr, e),
+                    Err(e) => error!("Beacon task failed: {}", e),
+                }
+            }
+            Some(joined) = probes.join_next() => match joined {
+                Ok((_, Ok(probe))) => {
+                    for address in &probe.known_peers {
+                        spawn_probe(Peer::new(address.clone()), &mut probes);
+                    }
+                    found.push(probe);
+                }
+                Ok((peer, Err(e))) => warn!("Failed to probe {}: {}", peer, e),
+                Err(e) => error!("Probe task failed: {}", e),
+            },
+            _ = tokio::time::sleep_until(deadline) => {
+                warn!("Stopped waiting for {} peers after {:?}", probes.len(), timeout);
+                probes.abort_all();
+                break;
+            }
+        }
+    }
+    found.sort_by(|a, b| a.address.cmp(&b.address));
+    found
+}
+
+/// The `Config` fields deciding which port the node listens on, which peers
+/// it connects to at startup and how it treats the connections it serves.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct PeerDiscoveryConfig {
+    pub peer_port: u16,
+    /// Peers to connect to at startup, as `sharesphere://[node-id@]host:port` URLs.
+    pub bootstrap_peers: Vec<PeerUrl>,
+    /// Incoming connections served at once; further ones wait to be accepted.
+    #[serde(default = "default_max_incoming_connections")]
+    pub max_incoming_connections: usize,
+    /// Incoming connections allowed at once from one IP; further ones get `RATE_LIMITED`.
+    #[serde(default = "default_max_connections_per_ip")]
+    pub max_connections_per_ip: usize,
+    /// Seconds between the `PING`s sent on every served connection.
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+    /// A served connection that has sent nothing, not even a `PONG`, for this
+    /// many seconds is closed as dead.
+    #[serde(default = "default_heartbeat_timeout_secs")]
+    pub heartbeat_timeout_secs: u64,
+    /// Multicast group and UDP port that LAN discovery beacons are sent to and
+    /// answered on; see [`crate::peer::beacon`]. `null` turns LAN discovery off.
+    #[serde(default = "default_lan_beacon_addr")]
+    pub lan_beacon_addr: Option<SocketAddr>,
+}
+
+pub(crate) fn default_max_incoming_connections() -> usize {
+    100
 }
 
+pub(crate) fn default_max_connections_per_ip() -> usize {
+    10
+}
+
+pub(crate) fn default_heartbeat_interval_secs() -> u64 {
+    30
+}
+
+pub(crate) fn default_heartbeat_timeout_secs() -> u64 {
+    90
+}
+
+/// Stop signals for outgoing connections, keyed by peer address, so a
+/// connection can be closed by someone other than the task serving it.
+#[derive(Debug, Clone, Default)]
+pub struct OutgoingConnections {
+    stops: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>,
+}
+
+impl OutgoingConnections {
+    /// Returns a future that completes once [`close`](Self::close) is called
+    /// for `address`, replacing any earlier registration for it.
+    pub fn register(&self, address: &str) -> impl Future<Output = ()> {
+        let (tx, rx) = oneshot::channel();
+        self.stops.lock().unwrap().insert(address.to_string(), tx);
+        async move {
+            let _ = rx.await;
+        }
+    }
+
+    /// Closes the connection to `address`; false if none is open.
+    pub fn close(&self, address: &str) -> bool {
+        self.stops.lock().unwrap().remove(address).is_some_and(|stop| stop.send(()).is_ok())
+    }
+}
+
+/// Serves an outgoing connection to `peer` as an active peer until either
+/// side closes it or `stop` completes.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_peer_connection(
+    stream: TcpStream,
+    peer: Peer,
+    config: &crate::config::Config,
+    dht: DHT,
+    local_peer: Peer,
+    peers: PeerRegistry,
+    network_stats: SharedNetworkStats,
+    stop: impl Future<Output = ()>,
+) {
+    let address = peer.address.clone();
+    peers.add(peer);
+    let connection = handle_connection(
+        stream,
+        config.clone(),
+        peers.clone(),
+        dht,
+        local_peer,
+        network_stats,
+    );
+    let result = tokio::select! {
+        result = connection => result,
+        _ = stop => Ok(()),
+    };
+    if let Err(e) = &result {
+        error!("Error handling connection with peer@{}: {}", address, e);
+    }
+    peers.remove_after(&address, &result);
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn start_peer_discovery(
     config: crate::config::Config,
     _tx: Sender<String>,
     dht: DHT,
     local_peer: Peer,
+    peers: PeerRegistry,
+    network_stats: SharedNetworkStats,
+    latency: PeerLatency,
+    connections: OutgoingConnections,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let listener = TcpListener::bind(("0.0.0.0", config.peer_port)).await?;
-    info!("Listening for peers on port {}", config.peer_port);
-
-    let mut peers = Vec::new();
-    for peer_addr in config.bootstrap_peers.iter() {
-        let peer = Peer { address: peer_addr.clone() };
-        peers.push(peer.clone());
-        let encryption_key = config.encryption_key.clone();
-        let storage_root = config.storage_path.clone();
-        let peers_clone = peers.clone();
-        let dht_clone = dht.clone();
-        let local_peer_clone = local_peer.clone();
+    let listener = TcpListener::bind(("0.0.0.0", config.discovery.peer_port)).await?;
+    info!("Listening for peers on port {}", config.discovery.peer_port);
+
+    tokio::spawn(run_pinger(peers.clone(), latency, config.clone()));
+    tokio::spawn(GossipTask::new(dht.clone(), peers.clone(), local_peer.clone(), config.clone()).run());
+    tokio::spawn(ReAnnounceTask::new(peers.clone(), local_peer.clone(), config.clone()).run());
+    if let Some(addr) = config.discovery.lan_beacon_addr {
+        tokio::spawn(run_beacon_responder(addr, config.discovery.peer_port));
+    }
+
+    for peer_url in config.discovery.bootstrap_peers.iter() {
+        let peer = match Peer::try_from(peer_url.clone()) {
+            Ok(peer) => peer,
+            Err(e) => {
+                error!("Skipping bootstrap peer {}: {}", peer_url, e);
+                continue;
+            }
+        };
+        let config = config.clone();
+        let peers = peers.clone();
+        let dht = dht.clone();
+        let local_peer = local_peer.clone();
+        let network_stats = network_stats.clone();
+        let connections = connections.clone();
 
         tokio::spawn(async move {
-            match TcpStream::connect(&peer.address).await {
+            match transport::connect(&peer, &config).await {
                 Ok(stream) => {
-                    info!("Connected to bootstrap peer {}", peer.address);
-                    if let Err(e) = handle_connection(
-                        stream, 
-                        encryption_key, 
-                        storage_root.clone(), 
-                        peers_clone, 
-                        dht_clone.clone(), 
-                        local_peer_clone.clone()
-                    ).await {
-                        error!("Error handling connection with {}: {}", peer.address, e);
-                    }
-                },
+                    info!("Connected to bootstrap {}", peer);
+                    let stop = connections.register(&peer.address);
+                    run_peer_connection(stream, peer, &config, dht, local_peer, peers, network_stats, stop).await;
+                }
                 Err(e) => {
-                    error!("Failed to connect to bootstrap peer {}: {}", peer.address, e);
+                    error!("Failed to connect to bootstrap {}: {}", peer, e);
                 }
             }
         });
     }
 
+    let limiter = ConnectionLimiter::new(config.discovery.max_incoming_connections, config.discovery.max_connections_per_ip);
     loop {
-        let (stream, addr) = listener.accept().await?;
+        let (mut stream, addr) = listener.accept().await?;
+        let Some(permit) = limiter.admit(addr.ip()).await else {
+            warn!("Refusing connection from {}: too many connections from {}", addr, addr.ip());
+            tokio::spawn(async move {
+                let _ = stream.write_all(RATE_LIMITED).await;
+            });
+            continue;
+        };
         info!("Accepted connection from {}", addr);
-        let encryption_key = config.encryption_key.clone();
-        let storage_root = config.storage_path.clone();
+        let config_clone = config.clone();
         let peers_clone = peers.clone();
         let dht_clone = dht.clone();
         let local_peer_clone = local_peer.clone();
+        let stats_clone = network_stats.clone();
+        let limiter_clone = limiter.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(
-                stream, 
-                encryption_key, 
-                storage_root, 
-                peers_clone, 
+            let _permit = permit;
+            let address = addr.to_string();
+            peers_clone.add(Peer::from_socket_addr(addr));
+            let result = serve_incoming(
+                stream,
+                limiter_clone,
+                config_clone,
+                peers_clone.clone(),
                 dht_clone, 
-                local_peer_clone
-            ).await {
+                local_peer_clone,
+                stats_clone,
+            ).await;
+            if let Err(e) = &result {
                 error!("Error handling connection with {}: {}", addr, e);
             }
+            peers_clone.remove_after(&address, &result);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_json_round_trip() {
+        let peer = Peer::builder().address("10.0.0.5:8080").capability_flags(0b101).build().unwrap();
+        let encoded = crate::json::to_string(&peer).unwrap();
+        assert_eq!(encoded, r#"{"address":"10.0.0.5:8080","capability_flags":5}"#);
+        assert_eq!(crate::json::from_str::<Peer>(&encoded).unwrap(), peer);
+    }
+
+    #[tokio::test]
+    async fn test_registry_shares_peers_between_clones() {
+        let registry = PeerRegistry::default();
+        let mut events = registry.subscribe();
+        let shared = registry.clone();
+        shared.add(Peer::new("10.0.0.1:8080"));
+        shared.add(Peer::new("10.0.0.2:8080"));
+        assert_eq!(registry.all().iter().map(|p| p.address.as_str()).collect::<Vec<_>>(), ["10.0.0.1:8080", "10.0.0.2:8080"]);
+
+        assert!(registry.remove("10.0.0.1:8080").is_some());
+        assert!(registry.remove("10.0.0.1:8080").is_none());
+        assert!(!shared.contains("10.0.0.1:8080"));
+        assert_eq!(shared.len(), 1);
+
+        assert!(matches!(events.recv().await.unwrap(), PeerEvent::Connected { address, .. } if address == "10.0.0.1:8080"));
+        assert!(matches!(events.recv().await.unwrap(), PeerEvent::Connected { address, .. } if address == "10.0.0.2:8080"));
+        assert!(matches!(events.recv().await.unwrap(), PeerEvent::Disconnected { address, .. } if address == "10.0.0.1:8080"));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_builder_validates_address() {
+        assert!(matches!(Peer::builder().build(), Err(PeerError::MissingAddress)));
+        assert!(matches!(
+            Peer::builder().address("not-an-address").build(),
+            Err(PeerError::InvalidAddress(address, _)) if address == "not-an-address"
+        ));
+        assert_eq!(Peer::builder().address("[::1]:9000").build().unwrap(), Peer::new("[::1]:9000"));
+    }
+
+    #[test]
+    fn test_to_socket_addr_caches_parse() {
+        let peer = Peer::new("127.0.0.1:8080");
+        assert_eq!(peer.resolved.get(), None);
+        let addr = peer.to_socket_addr().unwrap();
+        assert_eq!(peer.resolved.get(), Some(&addr));
+        assert_eq!(Peer::from_socket_addr(addr), peer);
+        assert!(matches!(Peer::new("localhost:8080").to_socket_addr(), Err(PeerError::InvalidAddress(..))));
+    }
+
+    /// Serves `remote_dht` through `handle_connection` on a local port.
+    async fn spawn_remote_peer(remote_dht: &DHT) -> Peer {
+        spawn_remote_peer_with(remote_dht, Config::default(), PeerRegistry::default()).await
+    }
+
+    async fn spawn_remote_peer_with(remote_dht: &DHT, config: Config, peers: PeerRegistry) -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = Peer::new(listener.local_addr().unwrap().to_string());
+        let served_dht = remote_dht.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(handle_connection(
+                    stream,
+                    config.clone(),
+                    peers.clone(),
+                    served_dht.clone(),
+                    Peer::new("127.0.0.1:0"),
+                    SharedNetworkStats::default(),
+                ));
+            }
         });
+        remote
+    }
+
+    #[tokio::test]
+    async fn test_re_announce_round_skips_files_without_manifest() {
+        use crate::file_manager::manifest::{save_manifest, FileManifest};
+        use crate::file_manager::storage::{initialize_storage, pin_file};
+        use uuid::Uuid;
+
+        let remote_dht = DHT::new();
+        let remote = spawn_remote_peer(&remote_dht).await;
+
+        let storage = tempfile::tempdir().unwrap();
+        let with_manifest = Uuid::new_v4();
+        let storage_dir = initialize_storage(storage.path(), with_manifest).unwrap();
+        save_manifest(&storage_dir, &FileManifest::new(with_manifest, "a.txt".to_string(), 10, 10, 1)).unwrap();
+        let pinned = Uuid::new_v4();
+        initialize_storage(storage.path(), pinned).unwrap();
+        pin_file(storage.path(), pinned).unwrap();
+        let leftover = Uuid::new_v4();
+        initialize_storage(storage.path(), leftover).unwrap();
+
+        let local_peer = Peer::new("127.0.0.1:8080");
+        let peers = PeerRegistry::default();
+        peers.add(local_peer.clone());
+        let task = ReAnnounceTask::new(peers.clone(), local_peer.clone(), Config::with_storage_path(storage.path()));
+        // No one to announce to yet.
+        assert_eq!(task.round().await.unwrap(), 0);
+
+        peers.add(remote);
+        assert_eq!(task.round().await.unwrap(), 2);
+        for _ in 0..50 {
+            if remote_dht.file_count().unwrap() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        for file_id in [with_manifest, pinned] {
+            assert_eq!(remote_dht.get_file_locations(&file_id).unwrap(), Some(vec![local_peer.clone()]));
+        }
+        assert_eq!(remote_dht.get_file_locations(&leftover).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_peer_announces_files_and_registers_peer() {
+        use crate::file_manager::manifest::{save_manifest, FileManifest};
+        use crate::file_manager::storage::initialize_storage;
+        use uuid::Uuid;
+
+        let remote_dht = DHT::new();
+        let remote = spawn_remote_peer(&remote_dht).await;
+        let storage = tempfile::tempdir().unwrap();
+        let file_id = Uuid::new_v4();
+        let storage_dir = initialize_storage(storage.path(), file_id).unwrap();
+        save_manifest(&storage_dir, &FileManifest::new(file_id, "a.txt".to_string(), 10, 10, 1)).unwrap();
+
+        let local_peer = Peer::new("127.0.0.1:8080");
+        let peers = PeerRegistry::default();
+        let connections = OutgoingConnections::default();
+        let connected = connect_to_peer(
+            remote.clone(),
+            &Config::with_storage_path(storage.path()),
+            DHT::new(),
+            local_peer.clone(),
+            peers.clone(),
+            SharedNetworkStats::default(),
+            &connections,
+        )
+        .await
+        .unwrap();
+        assert_eq!(connected.protocol_version, ProtocolVersion::LATEST);
+        assert_eq!(connected.capabilities, PeerCapabilities::local());
+
+        for _ in 0..50 {
+            if remote_dht.file_count().unwrap() == 1 && peers.contains(&remote.address) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(remote_dht.get_file_locations(&file_id).unwrap(), Some(vec![local_peer]));
+        assert_eq!(peers.all()[0].capability_flags, PeerCapabilities::local().to_bits());
+        assert!(connections.close(&remote.address));
+
+        let unreachable = Peer::new("127.0.0.1:1");
+        let config = Config::with_storage_path(storage.path());
+        assert!(connect_to_peer(unreachable, &config, DHT::new(), Peer::new("127.0.0.1:8080"), peers, SharedNetworkStats::default(), &connections).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_discover_peers_follows_peer_lists() {
+        use crate::file_manager::manifest::{save_manifest, FileManifest};
+        use crate::file_manager::storage::initialize_storage;
+        use uuid::Uuid;
+
+        let storage = tempfile::tempdir().unwrap();
+        let file_id = Uuid::new_v4();
+        let storage_dir = initialize_storage(storage.path(), file_id).unwrap();
+        save_manifest(&storage_dir, &FileManifest::new(file_id, "a.txt".to_string(), 10, 10, 1)).unwrap();
+        let empty = tempfile::tempdir().unwrap();
+
+        let far = spawn_remote_peer_with(&DHT::new(), Config::with_storage_path(storage.path()), PeerRegistry::default()).await;
+        let near_peers = PeerRegistry::default();
+        near_peers.add(far.clone());
+        near_peers.add(Peer::new("127.0.0.1:1"));
+        let near = spawn_remote_peer_with(&DHT::new(), Config::with_storage_path(empty.path()), near_peers).await;
+
+        let mut config = Config::with_port(0);
+        config.discovery.bootstrap_peers = vec![near.address.parse().unwrap()];
+        // Only the peers above; nodes on the LAN could answer a beacon too.
+        config.discovery.lan_beacon_addr = None;
+        let probes = discover_peers(&config, Duration::from_secs(5)).await;
+        let mut expected = vec![(near.address.as_str(), 0), (far.address.as_str(), 1)];
+        expected.sort();
+        let found: Vec<(&str, usize)> = probes.iter().map(|p| (p.address.as_str(), p.files_count)).collect();
+        assert_eq!(found, expected);
+        assert!(probes.iter().all(|p| p.protocol_version == ProtocolVersion::LATEST.number()));
+        let near_probe = probes.iter().find(|p| p.address == near.address).unwrap();
+        assert_eq!(near_probe.known_peers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_discover_peers_probes_nodes_that_answer_the_beacon() {
+        use crate::peer::beacon::answer_beacons;
+        use tokio::net::UdpSocket;
+
+        let storage = tempfile::tempdir().unwrap();
+        let remote = spawn_remote_peer_with(&DHT::new(), Config::with_storage_path(storage.path()), PeerRegistry::default()).await;
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::with_port(0);
+        config.discovery.lan_beacon_addr = Some(responder.local_addr().unwrap());
+        tokio::spawn(answer_beacons(responder, remote.to_socket_addr().unwrap().port()));
+
+        let probes = discover_peers(&config, Duration::from_secs(5)).await;
+        assert_eq!(probes.iter().map(|p| p.address.as_str()).collect::<Vec<_>>(), [remote.address.as_str()]);
+        assert_eq!(probes[0].protocol_version, ProtocolVersion::LATEST.number());
     }
 }
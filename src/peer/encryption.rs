@@ -3,8 +3,13 @@ use aes_gcm::{Aes256Gcm, Nonce};
 use cipher::InvalidLength;
 use rand::RngCore;
 use hex;
+use std::collections::HashSet;
+use std::sync::Mutex;
 use thiserror::Error;
 
+/// Fresh random nonces tried before `encrypt` gives up on finding an unused one.
+const MAX_NONCE_ATTEMPTS: usize = 3;
+
 #[derive(Error, Debug)]
 pub enum EncryptionError {
     #[error("Hex decoding error: {0}")]
@@ -15,6 +20,9 @@ pub enum EncryptionError {
 
     #[error("AES-GCM operation failed")]
     AesGcmError,
+
+    #[error("No unused nonce available for this key")]
+    NonceExhausted,
 }
 
 impl From<aes_gcm::Error> for EncryptionError {
@@ -29,7 +37,67 @@ impl From<InvalidLength> for EncryptionError {
     }
 }
 
-pub fn encrypt(data: &[u8], key: &str) -> Result<(String, String), EncryptionError> {
+/// Hands out AES-GCM nonces that are never repeated for one key. Use one
+/// tracker per key; reusing a nonce under the same key breaks AES-GCM.
+#[derive(Debug)]
+pub struct NonceTracker {
+    source: Mutex<NonceSource>,
+}
+
+#[derive(Debug)]
+enum NonceSource {
+    /// Random nonces, remembered so a repeat is caught.
+    Random { seen: HashSet<[u8; 12]> },
+    /// A random 4-byte prefix followed by a big-endian 64-bit counter.
+    Counter { prefix: [u8; 4], next: u64 },
+}
+
+impl NonceTracker {
+    pub fn new() -> Self {
+        NonceTracker { source: Mutex::new(NonceSource::Random { seen: HashSet::new() }) }
+    }
+
+    /// Counter nonces cannot repeat and need no memory per message, so prefer
+    /// this whenever one tracker sees all encryptions under its key.
+    pub fn new_counter_based() -> Self {
+        let mut prefix = [0u8; 4];
+        OsRng.fill_bytes(&mut prefix);
+        NonceTracker { source: Mutex::new(NonceSource::Counter { prefix, next: 0 }) }
+    }
+
+    /// Returns a nonce this tracker has not handed out before.
+    pub fn next_nonce(&self) -> Result<[u8; 12], EncryptionError> {
+        match &mut *self.source.lock().unwrap() {
+            NonceSource::Random { seen } => {
+                for _ in 0..MAX_NONCE_ATTEMPTS {
+                    let mut nonce = [0u8; 12];
+                    OsRng.fill_bytes(&mut nonce);
+                    if seen.insert(nonce) {
+                        return Ok(nonce);
+                    }
+                }
+                Err(EncryptionError::NonceExhausted)
+            }
+            NonceSource::Counter { prefix, next } => {
+                let counter = *next;
+                *next = next.checked_add(1).ok_or(EncryptionError::NonceExhausted)?;
+                let mut nonce = [0u8; 12];
+                nonce[..4].copy_from_slice(prefix);
+                nonce[4..].copy_from_slice(&counter.to_be_bytes());
+                Ok(nonce)
+            }
+        }
+    }
+}
+
+impl Default for NonceTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encrypts `data` with a nonce from `nonces`, returning the hex nonce and ciphertext.
+pub fn encrypt(data: &[u8], key: &str, nonces: &NonceTracker) -> Result<(String, String), EncryptionError> {
     let key_bytes = hex::decode(key)?;
     if key_bytes.len() != 32 {
         return Err(EncryptionError::InvalidKeyLength(format!(
@@ -39,8 +107,7 @@ pub fn encrypt(data: &[u8], key: &str) -> Result<(String, String), EncryptionErr
     }
 
     let cipher = Aes256Gcm::new_from_slice(&key_bytes)?;
-    let mut nonce_bytes = [0u8; 12];
-    OsRng.fill_bytes(&mut nonce_bytes);
+    let nonce_bytes = nonces.next_nonce()?;
     let nonce = Nonce::from_slice(&nonce_bytes);
     let ciphertext = cipher.encrypt(nonce, data)?;
 
@@ -69,3 +136,34 @@ pub fn decrypt(nonce_hex: &str, ciphertext_hex: &str, key: &str) -> Result<Vec<u
 
     Ok(decrypted_data)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_round_trip_with_unique_nonces() {
+        let key = "ab".repeat(32);
+        for nonces in [NonceTracker::new(), NonceTracker::new_counter_based()] {
+            let (nonce_a, ciphertext) = encrypt(b"hello", &key, &nonces).unwrap();
+            let (nonce_b, _) = encrypt(b"hello", &key, &nonces).unwrap();
+            assert_ne!(nonce_a, nonce_b);
+            assert_eq!(decrypt(&nonce_a, &ciphertext, &key).unwrap(), b"hello");
+        }
+    }
+
+    #[test]
+    fn test_counter_nonces_increment_and_never_wrap() {
+        let nonces = NonceTracker::new_counter_based();
+        let first = nonces.next_nonce().unwrap();
+        let second = nonces.next_nonce().unwrap();
+        assert_eq!(first[..4], second[..4]);
+        assert_eq!(u64::from_be_bytes(second[4..].try_into().unwrap()), 1);
+
+        if let NonceSource::Counter { next, .. } = &mut *nonces.source.lock().unwrap() {
+            *next = u64::MAX;
+        }
+        assert!(matches!(nonces.next_nonce(), Err(EncryptionError::NonceExhausted)));
+        assert!(matches!(nonces.next_nonce(), Err(EncryptionError::NonceExhausted)));
+    }
+}
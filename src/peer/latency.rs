@@ -0,0 +1,62 @@
+// src/peer/latency.rs
+
+use crate::config::Config;
+use crate::peer::discovery::{Peer, PeerRegistry};
+use crate::peer::session::{PeerCapabilities, PeerSession};
+use crate::peer::transport;
+use log::debug;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+
+/// How often connected peers are pinged.
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Last measured round-trip time per peer address.
+pub type PeerLatency = Arc<RwLock<HashMap<String, Duration>>>;
+
+pub fn record_latency(latency: &PeerLatency, address: &str, rtt: Duration) {
+    latency.write().unwrap().insert(address.to_string(), rtt);
+}
+
+pub fn latency_of(latency: &PeerLatency, address: &str) -> Option<Duration> {
+    latency.read().unwrap().get(address).copied()
+}
+
+/// Sends `PING` to `peer` and waits for `PONG`, recording the round-trip time.
+pub async fn ping_peer(
+    peer: &Peer,
+    latency: &PeerLatency,
+    config: &Config,
+) -> Result<Duration, Box<dyn Error + Send + Sync>> {
+    let stream = transport::connect(peer, config).await?;
+    let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await?;
+
+    let started = Instant::now();
+    session.send(b"PING\n").await?;
+    session.stream.flush().await?;
+    while let Some(line) = session.read_line().await? {
+        if line == "PONG" {
+            let rtt = started.elapsed();
+            record_latency(latency, &peer.address, rtt);
+            return Ok(rtt);
+        }
+    }
+    Err("Connection closed before PONG".into())
+}
+
+/// Pings every connected peer each `PING_INTERVAL`, forever.
+pub async fn run_pinger(peers: PeerRegistry, latency: PeerLatency, config: Config) {
+    let mut interval = tokio::time::interval(PING_INTERVAL);
+    loop {
+        interval.tick().await;
+        for peer in peers.all() {
+            match ping_peer(&peer, &latency, &config).await {
+                Ok(rtt) => debug!("Ping to {} took {:?}", peer, rtt),
+                Err(e) => debug!("Ping to {} failed: {}", peer, e),
+            }
+        }
+    }
+}
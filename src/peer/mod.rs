@@ -1,3 +1,29 @@
+#[cfg(not(feature = "wasm"))]
 pub mod discovery;
+#[cfg(not(feature = "wasm"))]
+pub mod beacon;
+#[cfg(not(feature = "wasm"))]
 pub mod connection;
 pub mod encryption;
+#[cfg(not(feature = "wasm"))]
+pub mod compression;
+#[cfg(not(feature = "wasm"))]
+pub mod session;
+#[cfg(not(feature = "wasm"))]
+pub mod protocol;
+#[cfg(not(feature = "wasm"))]
+pub mod stats;
+#[cfg(not(feature = "wasm"))]
+pub mod latency;
+#[cfg(not(feature = "wasm"))]
+pub mod transport;
+#[cfg(not(feature = "wasm"))]
+pub mod rate_limit;
+#[cfg(not(feature = "wasm"))]
+pub mod url;
+#[cfg(not(feature = "wasm"))]
+pub mod mux;
+#[cfg(not(feature = "wasm"))]
+pub mod reputation;
+#[cfg(not(feature = "wasm"))]
+pub mod access_control;
@@ -0,0 +1,627 @@
+// src/peer/mux.rs
+
+//! Several logical streams over one TCP connection.
+//!
+//! A client asks for multiplexing by offering only the `multiplexing`
+//! capability in its handshake; a listener that supports it offers the bit
+//! too (see [`crate::peer::connection::serve_incoming`]). Once both have,
+//! both directions carry frames of `[stream_id: u32][length: u32][data]`,
+//! big-endian. A frame with no data closes its stream in that direction.
+//! Streams opened by the client have odd IDs and those opened by the server
+//! even ones, so the two sides never pick the same ID. Each logical stream
+//! then runs an ordinary session, handshake included.
+//!
+//! Outbound requests get their streams from [`open_stream`], which keeps one
+//! connection per peer and falls back to a connection per request for peers
+//! that don't multiplex.
+
+use crate::config::Config;
+use crate::peer::discovery::Peer;
+use crate::peer::session::{PeerCapabilities, PeerSession, PeerStream};
+use crate::peer::transport::{self, ConnectionError};
+use log::{debug, error, info};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+
+/// Largest data section of a frame; longer writes are split.
+pub const MAX_FRAME_DATA: usize = 64 * 1024;
+
+/// Streams a [`MultiplexedConnection`] opens at once; further
+/// [`open_stream`](MultiplexedConnection::open_stream) calls wait for one to close.
+pub const MAX_OPEN_STREAMS: usize = 16;
+
+/// Streams opened by the remote that are accepted at once; further ones are
+/// closed straight away. Twice [`MAX_OPEN_STREAMS`], so streams the remote has
+/// just closed can still be finishing here when it opens the next ones.
+pub const MAX_ACCEPTED_STREAMS: usize = 2 * MAX_OPEN_STREAMS;
+
+/// Frames queued for the writer. Writers wait for room, so a slow remote
+/// slows down the streams writing to it instead of filling memory.
+const OUTGOING_FRAMES: usize = 64;
+
+/// Frames received for one stream and not yet read. The reader waits for
+/// room, which holds up every stream of the connection until it is read.
+const INCOMING_FRAMES_PER_STREAM: usize = 16;
+
+/// How long a peer that turned multiplexing down is sent a connection per request.
+const UNSUPPORTED_RETRY: Duration = Duration::from_secs(600);
+
+/// Data for one logical stream, or its end (`None`).
+type Frame = (u32, Option<Vec<u8>>);
+
+/// Senders feeding the receive side of each open stream. `None` marks a
+/// stream closed or turned away here whose remote end is still open; its
+/// frames are discarded until the remote closes it too.
+type StreamTable = Arc<Mutex<HashMap<u32, Option<mpsc::Sender<Vec<u8>>>>>>;
+
+/// Streams a connection may track at once, closed ones included. A remote
+/// that keeps more open is not following the limits and is disconnected.
+const MAX_TRACKED_STREAMS: usize = MAX_OPEN_STREAMS + 2 * MAX_ACCEPTED_STREAMS;
+
+/// Which end of the connection this is; decides the IDs of opened streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MuxRole {
+    Client,
+    Server,
+}
+
+impl MuxRole {
+    fn first_id(self) -> u32 {
+        match self {
+            MuxRole::Client => 1,
+            MuxRole::Server => 2,
+        }
+    }
+
+    fn opened_by_remote(self, stream_id: u32) -> bool {
+        (stream_id % 2 == 1) != (self == MuxRole::Client)
+    }
+}
+
+/// Owns the connection's background reader and writer, which stop when it is
+/// dropped.
+pub struct MultiplexedConnection {
+    outgoing: mpsc::Sender<Frame>,
+    streams: StreamTable,
+    accepted: tokio::sync::Mutex<mpsc::Receiver<LogicalStream>>,
+    open_slots: Arc<Semaphore>,
+    next_id: AtomicU32,
+    last_opened: Mutex<Instant>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl MultiplexedConnection {
+    /// Takes over `stream`, which must already be past the handshake that
+    /// agreed on multiplexing.
+    pub fn new<S: PeerStream + 'static>(stream: S, role: MuxRole) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        let (outgoing, frames) = mpsc::channel(OUTGOING_FRAMES);
+        let (accept_tx, accepted) = mpsc::channel(MAX_ACCEPTED_STREAMS);
+        let streams = StreamTable::default();
+        let reader = tokio::spawn(read_frames(reader, role, streams.clone(), outgoing.clone(), accept_tx));
+        let writer = tokio::spawn(write_frames(writer, frames));
+        MultiplexedConnection {
+            outgoing,
+            streams,
+            accepted: tokio::sync::Mutex::new(accepted),
+            open_slots: Arc::new(Semaphore::new(MAX_OPEN_STREAMS)),
+            next_id: AtomicU32::new(role.first_id()),
+            last_opened: Mutex::new(Instant::now()),
+            tasks: [reader, writer],
+        }
+    }
+
+    /// Connects to `peer` and asks for multiplexing, failing with
+    /// [`ConnectionError::MultiplexingUnsupported`] if the peer does not agree.
+    pub async fn connect(peer: &Peer, config: &Config) -> Result<Self, ConnectionError> {
+        let stream = transport::connect(peer, config).await?;
+        let session = PeerSession::handshake_over(Box::new(stream), peer.clone(), PeerCapabilities::multiplexing_only())
+            .await
+            .map_err(|e| ConnectionError::Session(e.to_string()))?;
+        if !session.capabilities.multiplexing {
+            return Err(ConnectionError::MultiplexingUnsupported);
+        }
+        Ok(Self::new(session.into_stream(), MuxRole::Client))
+    }
+
+    /// Opens a new logical stream, waiting while [`MAX_OPEN_STREAMS`] are open.
+    /// The remote learns of it with its first frame.
+    pub async fn open_stream(&self) -> LogicalStream {
+        let slot = self.open_slots.clone().acquire_owned().await.expect("the stream semaphore is never closed");
+        *self.last_opened.lock().unwrap() = Instant::now();
+        let stream_id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(INCOMING_FRAMES_PER_STREAM);
+        self.streams.lock().unwrap().insert(stream_id, Some(tx));
+        let mut stream = LogicalStream::new(stream_id, rx, self.outgoing.clone());
+        stream.slot = Some(slot);
+        stream
+    }
+
+    /// Waits for the remote to open a stream; `None` once the connection is closed.
+    pub async fn accept_stream(&self) -> Option<LogicalStream> {
+        self.accepted.lock().await.recv().await
+    }
+
+    /// Whether the remote closed the connection or it failed.
+    pub fn is_closed(&self) -> bool {
+        self.tasks.iter().any(JoinHandle::is_finished)
+    }
+
+    /// True if no stream opened here is still open and none was opened for `idle`.
+    fn idle_for(&self, idle: Duration) -> bool {
+        self.open_slots.available_permits() == MAX_OPEN_STREAMS && self.last_opened.lock().unwrap().elapsed() >= idle
+    }
+}
+
+impl Drop for MultiplexedConnection {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut reader: R,
+    role: MuxRole,
+    streams: StreamTable,
+    outgoing: mpsc::Sender<Frame>,
+    accepted: mpsc::Sender<LogicalStream>,
+) {
+    let accept_slots = Arc::new(Semaphore::new(MAX_ACCEPTED_STREAMS));
+    let result: io::Result<()> = async {
+        loop {
+            let mut header = [0u8; 8];
+            match reader.read_exact(&mut header).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let stream_id = u32::from_be_bytes(header[..4].try_into().unwrap());
+            let len = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+            if len > MAX_FRAME_DATA {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes", len)));
+            }
+            let mut data = vec![0u8; len];
+            reader.read_exact(&mut data).await?;
+
+            let sender = {
+                let mut table = streams.lock().unwrap();
+                if data.is_empty() {
+                    // Dropping the sender ends the stream's receive side.
+                    table.remove(&stream_id);
+                    continue;
+                }
+                if !table.contains_key(&stream_id) && table.len() >= MAX_TRACKED_STREAMS {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "too many open streams"));
+                }
+                match table.entry(stream_id) {
+                    Entry::Occupied(entry) => match entry.get() {
+                        Some(sender) => sender.clone(),
+                        None => continue,
+                    },
+                    // One of ours, already closed by the remote.
+                    Entry::Vacant(_) if !role.opened_by_remote(stream_id) => continue,
+                    Entry::Vacant(entry) => {
+                        let (tx, rx) = mpsc::channel(INCOMING_FRAMES_PER_STREAM);
+                        let mut stream = LogicalStream::new(stream_id, rx, outgoing.clone());
+                        let Ok(slot) = accept_slots.clone().try_acquire_owned() else {
+                            debug!("Closing stream {}: {} streams are already open", stream_id, MAX_ACCEPTED_STREAMS);
+                            // Dropping the stream tells the remote it is closed.
+                            entry.insert(None);
+                            continue;
+                        };
+                        stream.slot = Some(slot);
+                        // Never full, as every queued stream holds one of the accept slots.
+                        if accepted.try_send(stream).is_err() {
+                            entry.insert(None);
+                            continue;
+                        }
+                        entry.insert(Some(tx.clone()));
+                        tx
+                    }
+                }
+            };
+            if sender.send(data).await.is_err() {
+                // Closed here; the remote's close frame removes it.
+                if let Some(entry) = streams.lock().unwrap().get_mut(&stream_id) {
+                    *entry = None;
+                }
+            }
+        }
+    }
+    .await;
+    if let Err(e) = result {
+        error!("Multiplexed connection failed: {}", e);
+    }
+    // Every open stream sees end of input.
+    streams.lock().unwrap().clear();
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(mut writer: W, mut frames: mpsc::Receiver<Frame>) {
+    let result: io::Result<()> = async {
+        while let Some(mut frame) = frames.recv().await {
+            loop {
+                let (stream_id, data) = frame;
+                let data = data.unwrap_or_default();
+                writer.write_all(&stream_id.to_be_bytes()).await?;
+                writer.write_all(&(data.len() as u32).to_be_bytes()).await?;
+                writer.write_all(&data).await?;
+                // Batch whatever else is already queued into one flush.
+                match frames.try_recv() {
+                    Ok(next) => frame = next,
+                    Err(_) => break,
+                }
+            }
+            writer.flush().await?;
+        }
+        writer.shutdown().await
+    }
+    .await;
+    if let Err(e) = result {
+        error!("Failed to write to multiplexed connection: {}", e);
+    }
+}
+
+type Reservation = Pin<Box<dyn Future<Output = Result<mpsc::OwnedPermit<Frame>, mpsc::error::SendError<()>>> + Send>>;
+
+/// One stream of a [`MultiplexedConnection`]. Dropping it closes the write
+/// side; data the remote still sends is discarded.
+pub struct LogicalStream {
+    stream_id: u32,
+    incoming: mpsc::Receiver<Vec<u8>>,
+    /// Received data not yet handed to the reader.
+    pending: Vec<u8>,
+    outgoing: mpsc::Sender<Frame>,
+    /// Room in the outgoing queue being waited for.
+    reservation: Option<Reservation>,
+    write_closed: bool,
+    /// Counts the stream against its connection's limit until it is dropped.
+    slot: Option<OwnedSemaphorePermit>,
+}
+
+impl LogicalStream {
+    fn new(stream_id: u32, incoming: mpsc::Receiver<Vec<u8>>, outgoing: mpsc::Sender<Frame>) -> Self {
+        LogicalStream {
+            stream_id,
+            incoming,
+            pending: Vec::new(),
+            outgoing,
+            reservation: None,
+            write_closed: false,
+            slot: None,
+        }
+    }
+
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    fn poll_reserve(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<mpsc::OwnedPermit<Frame>>> {
+        let outgoing = &self.outgoing;
+        let reservation = self.reservation.get_or_insert_with(|| Box::pin(outgoing.clone().reserve_owned()));
+        let reserved = ready!(reservation.as_mut().poll(cx));
+        self.reservation = None;
+        Poll::Ready(reserved.map_err(|_| io::ErrorKind::BrokenPipe.into()))
+    }
+}
+
+impl AsyncRead for LogicalStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.pending.is_empty() {
+            match self.incoming.poll_recv(cx) {
+                Poll::Ready(Some(data)) => self.pending = data,
+                // End of stream: no more frames will arrive.
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = buf.remaining().min(self.pending.len());
+        buf.put_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for LogicalStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.write_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let permit = ready!(self.poll_reserve(cx))?;
+        let n = buf.len().min(MAX_FRAME_DATA);
+        permit.send((self.stream_id, Some(buf[..n].to_vec())));
+        Poll::Ready(Ok(n))
+    }
+
+    /// Frames are written out as soon as they are queued.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.write_closed {
+            let permit = ready!(self.poll_reserve(cx))?;
+            permit.send((self.stream_id, None));
+            self.write_closed = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for LogicalStream {
+    fn drop(&mut self) {
+        if self.write_closed {
+            return;
+        }
+        if let Err(mpsc::error::TrySendError::Full(frame)) = self.outgoing.try_send((self.stream_id, None)) {
+            // Queued behind the stream's last data, so the remote still reads all of it.
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                let outgoing = self.outgoing.clone();
+                runtime.spawn(async move {
+                    let _ = outgoing.send(frame).await;
+                });
+            }
+        }
+    }
+}
+
+/// Pooled connections, keyed by peer address and the proxy used to reach it.
+type PoolKey = (String, Option<String>);
+
+#[derive(Default)]
+enum PoolSlot {
+    #[default]
+    Empty,
+    Connected(Arc<MultiplexedConnection>),
+    /// The peer turned multiplexing down at this time.
+    Unsupported(Instant),
+}
+
+static POOL: OnceLock<Mutex<HashMap<PoolKey, Arc<tokio::sync::Mutex<PoolSlot>>>>> = OnceLock::new();
+
+/// Opens a stream to `peer` over the connection shared by every request to
+/// it, connecting first if there is none. A connection is replaced once it
+/// closes, or when it has been idle for `heartbeat_interval_secs`, before the
+/// remote closes it for idling. Peers that don't multiplex get a connection
+/// of their own per stream.
+pub async fn open_stream(peer: &Peer, config: &Config) -> Result<Box<dyn PeerStream>, ConnectionError> {
+    let key = (peer.address.clone(), config.socks5_proxy.clone());
+    let slot = POOL.get_or_init(Mutex::default).lock().unwrap().entry(key).or_default().clone();
+    let idle = Duration::from_secs(config.discovery.heartbeat_interval_secs);
+
+    let connection = {
+        let mut slot = slot.lock().await;
+        match &*slot {
+            PoolSlot::Connected(connection) if !connection.is_closed() && !connection.idle_for(idle) => {
+                connection.clone()
+            }
+            PoolSlot::Unsupported(since) if since.elapsed() < UNSUPPORTED_RETRY => {
+                drop(slot);
+                return Ok(Box::new(transport::connect(peer, config).await?));
+            }
+            _ => match MultiplexedConnection::connect(peer, config).await {
+                Ok(connection) => {
+                    info!("Opened a multiplexed connection to {}", peer);
+                    let connection = Arc::new(connection);
+                    *slot = PoolSlot::Connected(connection.clone());
+                    connection
+                }
+                Err(ConnectionError::MultiplexingUnsupported) => {
+                    debug!("{} does not multiplex; using a connection per request", peer);
+                    *slot = PoolSlot::Unsupported(Instant::now());
+                    drop(slot);
+                    return Ok(Box::new(transport::connect(peer, config).await?));
+                }
+                Err(e) => {
+                    *slot = PoolSlot::Empty;
+                    return Err(e);
+                }
+            },
+        }
+    };
+    Ok(Box::new(connection.open_stream().await))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_streams_are_kept_apart() {
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let client = MultiplexedConnection::new(client_io, MuxRole::Client);
+        let server = MultiplexedConnection::new(server_io, MuxRole::Server);
+
+        let mut first = client.open_stream().await;
+        let mut second = client.open_stream().await;
+        assert_eq!((first.stream_id(), second.stream_id()), (1, 3));
+        second.write_all(b"second").await.unwrap();
+        // Larger than one frame, so it arrives split.
+        let large = vec![7u8; MAX_FRAME_DATA + 10];
+        first.write_all(&large).await.unwrap();
+        first.shutdown().await.unwrap();
+
+        let mut accepted = HashMap::new();
+        for _ in 0..2 {
+            let stream = server.accept_stream().await.unwrap();
+            accepted.insert(stream.stream_id(), stream);
+        }
+        let mut received = Vec::new();
+        accepted.get_mut(&1).unwrap().read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, large);
+
+        let mut reply = server.open_stream().await;
+        assert_eq!(reply.stream_id(), 2);
+        reply.write_all(b"hi").await.unwrap();
+        let mut from_server = client.accept_stream().await.unwrap();
+        let mut buf = [0u8; 2];
+        from_server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+
+        let stream = accepted.get_mut(&3).unwrap();
+        let mut buf = [0u8; 6];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"second");
+
+        // Closing the connection ends every stream.
+        drop(client);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        assert!(server.accept_stream().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_limits_and_bounded_queues() {
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let client = MultiplexedConnection::new(client_io, MuxRole::Client);
+        let server = MultiplexedConnection::new(server_io, MuxRole::Server);
+
+        // A stream that is never read fills its queue and then holds up the
+        // connection, so a writer that keeps going has to wait.
+        let mut unread = client.open_stream().await;
+        let flood = vec![1u8; MAX_FRAME_DATA * (INCOMING_FRAMES_PER_STREAM + OUTGOING_FRAMES + 8)];
+        let blocked = tokio::time::timeout(Duration::from_millis(200), unread.write_all(&flood)).await;
+        assert!(blocked.is_err());
+        let mut accepted = server.accept_stream().await.unwrap();
+        let mut received = vec![0u8; MAX_FRAME_DATA];
+        accepted.read_exact(&mut received).await.unwrap();
+        drop((unread, accepted));
+
+        // The client waits for a free slot rather than exceed its own limit.
+        let mut open = Vec::new();
+        for _ in 0..MAX_OPEN_STREAMS {
+            open.push(client.open_stream().await);
+        }
+        assert!(tokio::time::timeout(Duration::from_millis(50), client.open_stream()).await.is_err());
+        open.pop();
+        tokio::time::timeout(Duration::from_millis(50), client.open_stream()).await.unwrap();
+    }
+
+    /// Listens on a local port, serving each connection with `serve` and
+    /// counting them in the returned counter.
+    async fn spawn_listener<F, R>(serve: F) -> (Peer, Arc<AtomicU32>)
+    where
+        F: Fn(tokio::net::TcpStream) -> R + Send + 'static,
+        R: Future<Output: Send> + Send + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = Peer::new(listener.local_addr().unwrap().to_string());
+        let accepted = Arc::new(AtomicU32::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(serve(stream));
+            }
+        });
+        (peer, accepted)
+    }
+
+    /// Opens a session to `peer` and reads the first line the server sends.
+    async fn greeting(peer: &Peer, config: &Config) -> String {
+        let stream = open_stream(peer, config).await.unwrap();
+        let mut session = PeerSession::handshake_over(stream, peer.clone(), PeerCapabilities::local()).await.unwrap();
+        assert!(session.capabilities.compression);
+        session.read_line().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_open_stream_shares_a_connection_per_peer() {
+        use crate::indexing::dht::DHT;
+        use crate::peer::connection::{handle_connection, serve_incoming};
+        use crate::peer::discovery::PeerRegistry;
+        use crate::peer::rate_limit::ConnectionLimiter;
+        use crate::peer::stats::SharedNetworkStats;
+
+        let storage = tempfile::tempdir().unwrap();
+        let config = Config::with_storage_path(storage.path());
+        let limiter = ConnectionLimiter::new(10, 10);
+        let server_config = config.clone();
+        let (multiplexing, connections) = spawn_listener(move |stream| {
+            let (dht, local, stats) = (DHT::new(), Peer::new("127.0.0.1:0"), SharedNetworkStats::default());
+            serve_incoming(stream, limiter.clone(), server_config.clone(), PeerRegistry::default(), dht, local, stats)
+        })
+        .await;
+        tokio::join!(greeting(&multiplexing, &config), greeting(&multiplexing, &config));
+        greeting(&multiplexing, &config).await;
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+
+        // A listener that doesn't offer multiplexing gets a connection per stream.
+        let server_config = config.clone();
+        let (plain, connections) = spawn_listener(move |stream| {
+            let (dht, local, stats) = (DHT::new(), Peer::new("127.0.0.1:0"), SharedNetworkStats::default());
+            handle_connection(stream, server_config.clone(), PeerRegistry::default(), dht, local, stats)
+        })
+        .await;
+        greeting(&plain, &config).await;
+        greeting(&plain, &config).await;
+        // The connection that asked for multiplexing, then one per stream.
+        assert_eq!(connections.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_streams_count_against_the_connection_limiter() {
+        use crate::indexing::dht::DHT;
+        use crate::peer::connection::serve_incoming;
+        use crate::peer::discovery::PeerRegistry;
+        use crate::peer::rate_limit::{ConnectionLimiter, RATE_LIMITED};
+        use crate::peer::stats::SharedNetworkStats;
+
+        let storage = tempfile::tempdir().unwrap();
+        let config = Config::with_storage_path(storage.path());
+        let limiter = ConnectionLimiter::new(10, 1);
+        let server_config = config.clone();
+        let (peer, _) = spawn_listener(move |stream| {
+            let (dht, local, stats) = (DHT::new(), Peer::new("127.0.0.1:0"), SharedNetworkStats::default());
+            serve_incoming(stream, limiter.clone(), server_config.clone(), PeerRegistry::default(), dht, local, stats)
+        })
+        .await;
+
+        let first = open_stream(&peer, &config).await.unwrap();
+        let _open = PeerSession::handshake_over(first, peer.clone(), PeerCapabilities::local()).await.unwrap();
+        let second = open_stream(&peer, &config).await.unwrap();
+        let mut refused = PeerSession::handshake_over(second, peer.clone(), PeerCapabilities::local()).await.unwrap();
+        let line = refused.read_line().await.unwrap().unwrap();
+        assert_eq!(format!("{}\n", line).as_bytes(), RATE_LIMITED);
+    }
+
+    #[tokio::test]
+    async fn test_streams_beyond_the_accept_limit_are_closed() {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let server = MultiplexedConnection::new(server_io, MuxRole::Server);
+        let (mut raw_reader, mut raw) = tokio::io::split(client_io);
+        let frame = |stream_id: u32| [&stream_id.to_be_bytes()[..], &1u32.to_be_bytes(), b"x"].concat();
+        let over_limit = 2 * MAX_ACCEPTED_STREAMS as u32 + 1;
+        for stream_id in (1..=over_limit).step_by(2) {
+            raw.write_all(&frame(stream_id)).await.unwrap();
+        }
+        let mut accepted = Vec::new();
+        for _ in 0..MAX_ACCEPTED_STREAMS {
+            accepted.push(server.accept_stream().await.unwrap());
+        }
+
+        // The stream over the limit is closed, and stays closed.
+        let mut close = [0u8; 8];
+        raw_reader.read_exact(&mut close).await.unwrap();
+        assert_eq!(close[..4], over_limit.to_be_bytes());
+        assert_eq!(close[4..], 0u32.to_be_bytes());
+        raw.write_all(&frame(over_limit)).await.unwrap();
+        accepted.pop();
+        let extra = tokio::time::timeout(Duration::from_millis(100), server.accept_stream()).await;
+        assert!(extra.is_err());
+    }
+}
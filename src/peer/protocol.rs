@@ -0,0 +1,339 @@
+// src/peer/protocol.rs
+
+//! Structured peer messages, sent as length-prefixed JSON frames.
+//!
+//! Sessions start in the legacy line-based text protocol. A client that sends
+//! `PROTOCOL_VERSION:2` and gets the same line back switches the session to
+//! [`Message`] frames: a 4-byte big-endian length followed by that many bytes
+//! of JSON.
+//!
+//! The parsers for the text protocol's lines live here too, free of any I/O,
+//! so the session code and the fuzz targets under `fuzz/` share them.
+
+use crate::file_manager::manifest::FileManifest;
+use crate::json::{self, JsonError};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+pub const PROTOCOL_VERSION_PREFIX: &str = "PROTOCOL_VERSION:";
+
+/// The newline-delimited text protocol every session starts in.
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+/// Length-prefixed JSON [`Message`] frames.
+pub const JSON_PROTOCOL_VERSION: u32 = 2;
+
+/// The protocol a session runs after [`PeerSession::negotiate_version`](crate::peer::session::PeerSession::negotiate_version).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    /// [`LEGACY_PROTOCOL_VERSION`], where every session starts.
+    #[default]
+    V1,
+    /// [`JSON_PROTOCOL_VERSION`].
+    V2,
+}
+
+impl ProtocolVersion {
+    /// The newest version this node speaks.
+    pub const LATEST: ProtocolVersion = ProtocolVersion::V2;
+
+    pub fn number(self) -> u32 {
+        match self {
+            ProtocolVersion::V1 => LEGACY_PROTOCOL_VERSION,
+            ProtocolVersion::V2 => JSON_PROTOCOL_VERSION,
+        }
+    }
+
+    pub fn from_number(number: u32) -> Option<Self> {
+        match number {
+            LEGACY_PROTOCOL_VERSION => Some(ProtocolVersion::V1),
+            JSON_PROTOCOL_VERSION => Some(ProtocolVersion::V2),
+            _ => None,
+        }
+    }
+
+    /// What a responder answers to an offer of `offered`: the lower of it
+    /// and [`ProtocolVersion::LATEST`], or V1 for an offer that doesn't parse.
+    pub fn answer_to(offered: &str) -> Self {
+        offered
+            .parse::<u32>()
+            .ok()
+            .and_then(|offered| Self::from_number(offered.min(Self::LATEST.number())))
+            .unwrap_or_default()
+    }
+}
+
+/// Upper bound on an incoming frame, to reject bogus length headers.
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    #[error("Invalid message: {0}")]
+    InvalidMessage(#[from] JsonError),
+
+    #[error("Message of {0} bytes exceeds the {MAX_MESSAGE_LEN} byte limit")]
+    MessageTooLarge(usize),
+}
+
+/// One DHT record: a peer that stores (part of) a file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DhtEntry {
+    pub file_id: Uuid,
+    pub address: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Message {
+    Ping,
+    Pong,
+    ChunkRequest {
+        file_id: Uuid,
+        chunk_index: usize,
+    },
+    /// `proof` is the hex-encoded Merkle proof; empty when none could be built.
+    ChunkResponse {
+        file_id: Uuid,
+        chunk_index: usize,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+        proof: String,
+    },
+    ChunkNotFound {
+        file_id: Uuid,
+        chunk_index: usize,
+    },
+    ChunkError {
+        file_id: Uuid,
+        chunk_index: usize,
+        reason: String,
+    },
+    DhtRequest,
+    DhtResponse {
+        entries: Vec<DhtEntry>,
+    },
+    /// DHT entries new to the sender since its previous gossip round. Not answered.
+    Gossip {
+        entries: Vec<DhtEntry>,
+    },
+    GetManifest {
+        file_id: Uuid,
+    },
+    ManifestResponse {
+        manifest: FileManifest,
+    },
+    ManifestNotFound {
+        file_id: Uuid,
+    },
+}
+
+/// Encodes `message` as a length-prefixed JSON frame.
+pub fn encode_frame(message: &Message) -> Result<Vec<u8>, ProtocolError> {
+    let body = json::to_string(message)?;
+    if body.len() > MAX_MESSAGE_LEN {
+        return Err(ProtocolError::MessageTooLarge(body.len()));
+    }
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(body.as_bytes());
+    Ok(frame)
+}
+
+/// Decodes the JSON body of a frame, without its length prefix.
+pub fn decode_message(body: &[u8]) -> Result<Message, ProtocolError> {
+    let text = String::from_utf8_lossy(body);
+    Ok(json::from_str(&text)?)
+}
+
+/// A `CHUNK_REQUEST:<file_id>:<chunk_index>[:<traceparent>]` line. The
+/// `traceparent` is only sent between peers that negotiated distributed tracing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkRequestLine<'a> {
+    Valid { file_id: Uuid, chunk_index: usize, trace_parent: Option<&'a str> },
+    /// Has both fields, but they don't parse; answered with `CHUNK_ERROR`.
+    Malformed { file_id: &'a str, chunk_index: &'a str },
+    /// Not a chunk request, or without the two fields; ignored.
+    Invalid,
+}
+
+pub fn parse_chunk_request(line: &str) -> ChunkRequestLine<'_> {
+    let Some(rest) = line.strip_prefix("CHUNK_REQUEST:") else {
+        return ChunkRequestLine::Invalid;
+    };
+    let (file_id, chunk_index, trace_parent) = match rest.split(':').collect::<Vec<_>>().as_slice() {
+        [file_id, chunk_index] => (*file_id, *chunk_index, None),
+        [file_id, chunk_index, trace_parent] => (*file_id, *chunk_index, Some(*trace_parent)),
+        _ => return ChunkRequestLine::Invalid,
+    };
+    match (Uuid::parse_str(file_id), chunk_index.parse::<usize>()) {
+        (Ok(file_id), Ok(chunk_index)) => ChunkRequestLine::Valid { file_id, chunk_index, trace_parent },
+        _ => ChunkRequestLine::Malformed { file_id, chunk_index },
+    }
+}
+
+/// The header line before a chunk's bytes:
+/// `CHUNK_RESPONSE:<file_id>:<chunk_index>:<chunk_size>:<proof hex>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkResponseHeader<'a> {
+    pub file_id: Uuid,
+    pub chunk_index: usize,
+    pub chunk_size: usize,
+    pub proof: &'a str,
+}
+
+/// `None` unless every field parses and `chunk_size` is at most [`MAX_MESSAGE_LEN`].
+pub fn parse_chunk_response_header(line: &str) -> Option<ChunkResponseHeader<'_>> {
+    let rest = line.strip_prefix("CHUNK_RESPONSE:")?;
+    let [file_id, chunk_index, chunk_size, proof] = rest.split(':').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let chunk_size = chunk_size.parse::<usize>().ok().filter(|&size| size <= MAX_MESSAGE_LEN)?;
+    Some(ChunkResponseHeader {
+        file_id: Uuid::parse_str(file_id).ok()?,
+        chunk_index: chunk_index.parse().ok()?,
+        chunk_size,
+        proof,
+    })
+}
+
+/// The entry count from a `DHT_RESPONSE:<count>` line, which is followed by
+/// that many [`parse_dht_entry`] lines.
+pub fn parse_dht_response_header(line: &str) -> Option<usize> {
+    line.strip_prefix("DHT_RESPONSE:")?.parse().ok()
+}
+
+/// A `<file_id>:<peer address>` line. The address keeps its own `:port`.
+pub fn parse_dht_entry(line: &str) -> Option<(Uuid, String)> {
+    let (file_id, address) = line.split_once(':')?;
+    if address.is_empty() {
+        return None;
+    }
+    Some((Uuid::parse_str(file_id).ok()?, address.to_string()))
+}
+
+/// The address count from a `PEER_LIST_RESPONSE:<count>` line, which is
+/// followed by one `host:port` line per peer the sender is connected to.
+pub fn parse_peer_list_header(line: &str) -> Option<usize> {
+    line.strip_prefix("PEER_LIST_RESPONSE:")?.parse().ok()
+}
+
+/// A file a peer stores, as listed in its `LIST_FILES_RESPONSE`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteFileEntry {
+    pub file_id: Uuid,
+    pub file_name: String,
+    pub size_bytes: u64,
+}
+
+/// The entry count from a `LIST_FILES_RESPONSE:<count>` line, which is
+/// followed by that many [`parse_file_entry`] lines.
+pub fn parse_list_files_header(line: &str) -> Option<usize> {
+    line.strip_prefix("LIST_FILES_RESPONSE:")?.parse().ok()
+}
+
+/// A `<file_id>:<file_name>:<size_bytes>` line. The name may itself contain `:`.
+pub fn parse_file_entry(line: &str) -> Option<RemoteFileEntry> {
+    let (file_id, rest) = line.split_once(':')?;
+    let (file_name, size_bytes) = rest.rsplit_once(':')?;
+    Some(RemoteFileEntry {
+        file_id: Uuid::parse_str(file_id).ok()?,
+        file_name: file_name.to_string(),
+        size_bytes: size_bytes.parse().ok()?,
+    })
+}
+
+/// Binary payloads as base64 strings, since JSON has no byte type.
+mod base64_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&crate::base64::encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        crate::base64::decode(&encoded).ok_or_else(|| D::Error::custom("invalid base64"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_round_trip_through_frames() {
+        let file_id = Uuid::new_v4();
+        let messages = vec![
+            Message::Ping,
+            Message::ChunkRequest { file_id, chunk_index: 3 },
+            Message::ChunkResponse { file_id, chunk_index: 3, data: vec![0, 1, 2, 255], proof: "ab".repeat(32) },
+            Message::ChunkError { file_id, chunk_index: 3, reason: "disk: full\nagain".to_string() },
+            Message::DhtResponse { entries: vec![DhtEntry { file_id, address: "10.0.0.1:8080".to_string() }] },
+            Message::ManifestResponse { manifest: FileManifest::new(file_id, "a.txt".to_string(), 10, 4, 3) },
+        ];
+        for message in messages {
+            let frame = encode_frame(&message).unwrap();
+            let len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+            assert_eq!(len, frame.len() - 4);
+            assert_eq!(decode_message(&frame[4..]).unwrap(), message);
+        }
+        assert!(decode_message(br#"{"type":"Unknown"}"#).is_err());
+    }
+
+    #[test]
+    fn test_responder_answers_with_lower_version() {
+        assert_eq!(ProtocolVersion::answer_to("1"), ProtocolVersion::V1);
+        assert_eq!(ProtocolVersion::answer_to("2"), ProtocolVersion::V2);
+        assert_eq!(ProtocolVersion::answer_to("7"), ProtocolVersion::LATEST);
+        assert_eq!(ProtocolVersion::answer_to("0"), ProtocolVersion::V1);
+        assert_eq!(ProtocolVersion::answer_to("two"), ProtocolVersion::V1);
+    }
+
+    #[test]
+    fn test_parse_text_protocol_lines() {
+        let file_id = Uuid::new_v4();
+        assert_eq!(
+            parse_chunk_request(&format!("CHUNK_REQUEST:{}:7", file_id)),
+            ChunkRequestLine::Valid { file_id, chunk_index: 7, trace_parent: None }
+        );
+        assert_eq!(
+            parse_chunk_request(&format!("CHUNK_REQUEST:{}:7:00-ab-cd-01", file_id)),
+            ChunkRequestLine::Valid { file_id, chunk_index: 7, trace_parent: Some("00-ab-cd-01") }
+        );
+        assert_eq!(
+            parse_chunk_request("CHUNK_REQUEST:nope:-1"),
+            ChunkRequestLine::Malformed { file_id: "nope", chunk_index: "-1" }
+        );
+        for invalid in ["CHUNK_REQUEST:", "CHUNK_REQUEST:a:b:c:d", "PING"] {
+            assert_eq!(parse_chunk_request(invalid), ChunkRequestLine::Invalid);
+        }
+
+        let header = format!("CHUNK_RESPONSE:{}:2:5:abcd", file_id);
+        assert_eq!(
+            parse_chunk_response_header(&header),
+            Some(ChunkResponseHeader { file_id, chunk_index: 2, chunk_size: 5, proof: "abcd" })
+        );
+        let oversized = format!("CHUNK_RESPONSE:{}:2:{}:", file_id, MAX_MESSAGE_LEN + 1);
+        assert_eq!(parse_chunk_response_header(&oversized), None);
+        assert_eq!(parse_chunk_response_header(&format!("CHUNK_RESPONSE:{}:2:5", file_id)), None);
+
+        assert_eq!(parse_dht_response_header("DHT_RESPONSE:3"), Some(3));
+        assert_eq!(parse_dht_response_header("DHT_RESPONSE:x"), None);
+        assert_eq!(
+            parse_dht_entry(&format!("{}:10.0.0.1:8080", file_id)),
+            Some((file_id, "10.0.0.1:8080".to_string()))
+        );
+        assert_eq!(parse_dht_entry(&format!("{}:", file_id)), None);
+        assert_eq!(parse_dht_entry("10.0.0.1:8080"), None);
+
+        assert_eq!(parse_list_files_header("LIST_FILES_RESPONSE:2"), Some(2));
+        assert_eq!(parse_peer_list_header("PEER_LIST_RESPONSE:0"), Some(0));
+        assert_eq!(parse_peer_list_header("PEER_LIST_RESPONSE:-1"), None);
+        assert_eq!(
+            parse_file_entry(&format!("{}:notes: v2.txt:1024", file_id)),
+            Some(RemoteFileEntry { file_id, file_name: "notes: v2.txt".to_string(), size_bytes: 1024 })
+        );
+        assert_eq!(parse_file_entry(&format!("{}:1024", file_id)), None);
+    }
+}
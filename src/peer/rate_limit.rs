@@ -0,0 +1,91 @@
+// src/peer/rate_limit.rs
+
+//! Caps on concurrently served incoming connections, overall and per remote IP.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Sent to a connection that is refused because its IP has too many open.
+pub const RATE_LIMITED: &[u8] = b"RATE_LIMITED\n";
+
+#[derive(Debug, Clone)]
+pub struct ConnectionLimiter {
+    slots: Arc<Semaphore>,
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    max_per_ip: usize,
+}
+
+/// Held for as long as an admitted connection is being served.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    slot: Option<OwnedSemaphorePermit>,
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: usize, max_per_ip: usize) -> Self {
+        ConnectionLimiter {
+            slots: Arc::new(Semaphore::new(max_connections)),
+            per_ip: Arc::default(),
+            max_per_ip,
+        }
+    }
+
+    /// Returns `None` straight away if `ip` is at its limit. Otherwise waits
+    /// for one of the overall slots to free up.
+    pub async fn admit(&self, ip: IpAddr) -> Option<ConnectionPermit> {
+        {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            let count = per_ip.entry(ip).or_insert(0);
+            if *count >= self.max_per_ip {
+                return None;
+            }
+            *count += 1;
+        }
+        // Created before waiting, so the per-IP count is released even if this future is dropped.
+        let mut permit = ConnectionPermit { slot: None, per_ip: self.per_ip.clone(), ip };
+        permit.slot = Some(self.slots.clone().acquire_owned().await.ok()?);
+        Some(permit)
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut per_ip = self.per_ip.lock().unwrap();
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_per_ip_and_overall_limits() {
+        let limiter = ConnectionLimiter::new(3, 2);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = limiter.admit(a).await.unwrap();
+        let _second = limiter.admit(a).await.unwrap();
+        assert!(limiter.admit(a).await.is_none());
+        drop(first);
+        let _third = limiter.admit(a).await.unwrap();
+
+        let _other = limiter.admit(b).await.unwrap();
+        // All three slots are taken, so the next admission waits.
+        let waiting = tokio::time::timeout(Duration::from_millis(50), limiter.admit(b)).await;
+        assert!(waiting.is_err());
+        // The abandoned admission gave back its per-IP reservation.
+        assert_eq!(limiter.per_ip.lock().unwrap()[&b], 1);
+    }
+}
@@ -0,0 +1,102 @@
+// src/peer/reputation.rs
+
+//! How reliable each peer has been, so peer selection can steer around
+//! peers that keep failing.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Peers scoring below this are deprioritized for a while after a failure.
+pub const DEPRIORITIZE_BELOW: f64 = 0.2;
+
+/// How long after its last failure a low-scoring peer stays deprioritized.
+/// Afterwards it is tried again, so a peer that recovers can earn its score back.
+pub const DEPRIORITIZE_FOR: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReputationScore {
+    pub successes: u32,
+    pub failures: u32,
+    /// Failures since the last success.
+    pub consecutive_failures: u32,
+    pub last_failure: Option<Instant>,
+}
+
+impl ReputationScore {
+    /// Share of successful interactions; peers never tried score 1.0.
+    pub fn ratio(&self) -> f64 {
+        let total = self.successes as f64 + self.failures as f64;
+        if total == 0.0 {
+            1.0
+        } else {
+            self.successes as f64 / total
+        }
+    }
+}
+
+/// Scores per peer address, shared by everything that talks to peers.
+#[derive(Debug, Clone, Default)]
+pub struct ReputationStore {
+    scores: Arc<RwLock<HashMap<String, ReputationScore>>>,
+}
+
+impl ReputationStore {
+    pub fn record_success(&self, peer_addr: &str) {
+        let mut scores = self.scores.write().unwrap();
+        let score = scores.entry(peer_addr.to_string()).or_default();
+        score.successes = score.successes.saturating_add(1);
+        score.consecutive_failures = 0;
+    }
+
+    pub fn record_failure(&self, peer_addr: &str) {
+        let mut scores = self.scores.write().unwrap();
+        let score = scores.entry(peer_addr.to_string()).or_default();
+        score.failures = score.failures.saturating_add(1);
+        score.consecutive_failures = score.consecutive_failures.saturating_add(1);
+        score.last_failure = Some(Instant::now());
+    }
+
+    /// `successes / (successes + failures)`; 1.0 for unknown peers.
+    pub fn score(&self, peer_addr: &str) -> f64 {
+        self.get(peer_addr).ratio()
+    }
+
+    pub fn get(&self, peer_addr: &str) -> ReputationScore {
+        self.scores.read().unwrap().get(peer_addr).copied().unwrap_or_default()
+    }
+
+    /// Whether `peer_addr` scores below [`DEPRIORITIZE_BELOW`] and failed
+    /// within the last [`DEPRIORITIZE_FOR`].
+    pub fn is_deprioritized(&self, peer_addr: &str) -> bool {
+        let score = self.get(peer_addr);
+        score.ratio() < DEPRIORITIZE_BELOW && score.last_failure.is_some_and(|at| at.elapsed() < DEPRIORITIZE_FOR)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scores_track_successes_and_failures() {
+        let reputation = ReputationStore::default();
+        assert_eq!(reputation.score("10.0.0.1:8080"), 1.0);
+        assert!(!reputation.is_deprioritized("10.0.0.1:8080"));
+
+        reputation.record_success("10.0.0.1:8080");
+        for _ in 0..4 {
+            reputation.record_failure("10.0.0.1:8080");
+        }
+        let score = reputation.get("10.0.0.1:8080");
+        assert_eq!((score.successes, score.failures, score.consecutive_failures), (1, 4, 4));
+        assert_eq!(reputation.score("10.0.0.1:8080"), 0.2);
+        assert!(!reputation.is_deprioritized("10.0.0.1:8080"));
+
+        reputation.record_failure("10.0.0.1:8080");
+        assert!(reputation.is_deprioritized("10.0.0.1:8080"));
+
+        reputation.record_success("10.0.0.1:8080");
+        assert_eq!(reputation.get("10.0.0.1:8080").consecutive_failures, 0);
+    }
+}
@@ -0,0 +1,481 @@
+// src/peer/session.rs
+
+use crate::file_manager::hash::hkdf_sha256;
+use crate::peer::access_control::node_id_mac;
+use crate::peer::compression::CompressedStream;
+use crate::peer::discovery::Peer;
+use crate::peer::encryption::EncryptionError;
+use crate::peer::protocol::{self, Message, ProtocolError, ProtocolVersion, PROTOCOL_VERSION_PREFIX};
+use crate::peer::transport::ConnectionError;
+use crate::telemetry::{self, TraceContext};
+use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use rand::RngCore;
+use std::error::Error;
+use std::fmt;
+use uuid::Uuid;
+
+/// HKDF `info` for session keys, so keys derived for other purposes never collide.
+const SESSION_KEY_INFO: &[u8] = b"sharesphere session key v1";
+
+const CAP_COMPRESSION: u32 = 1 << 0;
+const CAP_ERASURE_CODING: u32 = 1 << 1;
+const CAP_PEX: u32 = 1 << 2;
+const CAP_FILE_MANIFEST_V2: u32 = 1 << 3;
+const CAP_DISTRIBUTED_TRACING: u32 = 1 << 4;
+const CAP_MULTIPLEXING: u32 = 1 << 5;
+
+/// Optional protocol features, exchanged as a bitfield during the handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerCapabilities {
+    pub compression: bool,
+    pub erasure_coding: bool,
+    pub pex: bool,
+    pub file_manifest_v2: bool,
+    /// Chunk requests carry a `traceparent`; see [`PeerSession::send_chunk_request`].
+    pub distributed_tracing: bool,
+    /// The connection carries [`crate::peer::mux`] frames after the handshake.
+    /// Only offered on a connection's first handshake, so not part of [`Self::local`].
+    pub multiplexing: bool,
+}
+
+impl PeerCapabilities {
+    /// Capabilities supported by this build.
+    pub fn local() -> Self {
+        PeerCapabilities {
+            compression: true,
+            pex: true,
+            distributed_tracing: true,
+            ..Self::default()
+        }
+    }
+
+    /// What a client asking for a [`MultiplexedConnection`](crate::peer::mux::MultiplexedConnection)
+    /// offers: nothing else, as each logical stream negotiates its own features.
+    pub fn multiplexing_only() -> Self {
+        PeerCapabilities { multiplexing: true, ..Self::default() }
+    }
+
+    pub fn to_bits(&self) -> u32 {
+        let mut bits = 0;
+        if self.compression {
+            bits |= CAP_COMPRESSION;
+        }
+        if self.erasure_coding {
+            bits |= CAP_ERASURE_CODING;
+        }
+        if self.pex {
+            bits |= CAP_PEX;
+        }
+        if self.file_manifest_v2 {
+            bits |= CAP_FILE_MANIFEST_V2;
+        }
+        if self.distributed_tracing {
+            bits |= CAP_DISTRIBUTED_TRACING;
+        }
+        if self.multiplexing {
+            bits |= CAP_MULTIPLEXING;
+        }
+        bits
+    }
+
+    /// Unknown bits are ignored so newer peers can advertise features we don't know about.
+    pub fn from_bits(bits: u32) -> Self {
+        Self {
+            compression: bits & CAP_COMPRESSION != 0,
+            erasure_coding: bits & CAP_ERASURE_CODING != 0,
+            pex: bits & CAP_PEX != 0,
+            file_manifest_v2: bits & CAP_FILE_MANIFEST_V2 != 0,
+            distributed_tracing: bits & CAP_DISTRIBUTED_TRACING != 0,
+            multiplexing: bits & CAP_MULTIPLEXING != 0,
+        }
+    }
+
+    /// Features supported by both sides.
+    pub fn intersect(&self, other: &Self) -> Self {
+        Self::from_bits(self.to_bits() & other.to_bits())
+    }
+}
+
+/// The supported features by name, e.g. `compression, pex`, or `none`.
+impl fmt::Display for PeerCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = [
+            (self.compression, "compression"),
+            (self.erasure_coding, "erasure_coding"),
+            (self.pex, "pex"),
+            (self.file_manifest_v2, "file_manifest_v2"),
+            (self.distributed_tracing, "distributed_tracing"),
+            (self.multiplexing, "multiplexing"),
+        ]
+        .into_iter()
+        .filter_map(|(supported, name)| supported.then_some(name))
+        .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/// Byte stream a session talks over: the raw socket, or a wrapper negotiated on top of it.
+pub trait PeerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PeerStream for T {}
+
+/// A connection to a remote peer together with the state negotiated during the handshake.
+pub struct PeerSession {
+    pub stream: Box<dyn PeerStream>,
+    pub peer: Peer,
+    pub capabilities: PeerCapabilities,
+    /// V1 until [`PeerSession::negotiate_version`] agrees on another.
+    pub protocol_version: ProtocolVersion,
+    local_nonce: [u8; 32],
+    /// Sent by the remote with its capabilities; older peers send none.
+    remote_nonce: Option<[u8; 32]>,
+    session_key: Option<[u8; 32]>,
+    buffer: Vec<u8>,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+impl PeerSession {
+    /// Sends `CAPS:<bits>:<nonce>` and reads the remote's `CAPS` line. The
+    /// 32-byte hex nonces feed [`PeerSession::establish_session_key`].
+    /// Peers that don't send a `CAPS` line (e.g. one-shot chunk fetches) are
+    /// treated as supporting no optional features, and their first line is
+    /// kept for the regular message loop.
+    ///
+    /// If both sides support compression, everything after the `CAPS` lines
+    /// goes through a [`CompressedStream`].
+    pub async fn handshake(
+        stream: TcpStream,
+        local: PeerCapabilities,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let peer = Peer::from_socket_addr(stream.peer_addr()?);
+        Self::handshake_over(Box::new(stream), peer, local).await
+    }
+
+    /// [`PeerSession::handshake`] over any stream, e.g. a
+    /// [`LogicalStream`](crate::peer::mux::LogicalStream), with the remote given explicitly.
+    pub async fn handshake_over(
+        mut stream: Box<dyn PeerStream>,
+        peer: Peer,
+        local: PeerCapabilities,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut local_nonce = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut local_nonce);
+        let caps_line = format!("CAPS:{}:{}\n", local.to_bits(), hex::encode(local_nonce));
+        stream.write_all(caps_line.as_bytes()).await?;
+
+        let mut session = PeerSession {
+            stream,
+            peer,
+            capabilities: PeerCapabilities::default(),
+            protocol_version: ProtocolVersion::V1,
+            local_nonce,
+            remote_nonce: None,
+            session_key: None,
+            buffer: Vec::new(),
+            bytes_sent: caps_line.len() as u64,
+            bytes_received: 0,
+        };
+
+        if let Some(line) = session.read_line().await? {
+            match line.strip_prefix("CAPS:").and_then(parse_caps) {
+                Some((bits, nonce)) => {
+                    session.peer.capability_flags = bits;
+                    session.capabilities = local.intersect(&PeerCapabilities::from_bits(bits));
+                    session.remote_nonce = nonce;
+                }
+                None => session.unread_line(&line),
+            }
+        }
+
+        if session.capabilities.compression {
+            let raw = std::mem::replace(&mut session.stream, Box::new(tokio::io::empty()));
+            let prefix = std::mem::take(&mut session.buffer);
+            session.stream = Box::new(CompressedStream::with_prefix(raw, prefix));
+        }
+
+        Ok(session)
+    }
+
+    /// The stream after the handshake, with anything already read from it
+    /// but not yet consumed put back in front.
+    pub fn into_stream(self) -> Box<dyn PeerStream> {
+        let (reader, writer) = tokio::io::split(self.stream);
+        Box::new(tokio::io::join(std::io::Cursor::new(self.buffer).chain(reader), writer))
+    }
+
+    /// Reads the next newline-terminated line, or `None` once the remote closes the connection.
+    pub async fn read_line(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        loop {
+            if let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+                let line = self.buffer.drain(..=pos).collect::<Vec<u8>>();
+                self.bytes_received += line.len() as u64;
+                return Ok(Some(String::from_utf8_lossy(&line).trim().to_string()));
+            }
+            let mut temp = [0u8; 4096];
+            let bytes_read = self.stream.read(&mut temp).await?;
+            if bytes_read == 0 {
+                return Ok(None);
+            }
+            self.buffer.extend_from_slice(&temp[..bytes_read]);
+        }
+    }
+
+    /// Reads exactly `len` bytes of binary payload following the last line read.
+    pub async fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        while self.buffer.len() < len {
+            let mut temp = [0u8; 4096];
+            let bytes_read = self.stream.read(&mut temp).await?;
+            if bytes_read == 0 {
+                return Err("Connection closed mid-message".into());
+            }
+            self.buffer.extend_from_slice(&temp[..bytes_read]);
+        }
+        self.bytes_received += len as u64;
+        Ok(self.buffer.drain(..len).collect())
+    }
+
+    /// Writes `data` to the stream, counting it towards [`PeerSession::take_traffic`].
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.stream.write_all(data).await?;
+        self.bytes_sent += data.len() as u64;
+        Ok(())
+    }
+
+    /// Sends the task's current trace context as a `TRACE_CONTEXT:` line, so
+    /// the remote records its work as part of the same trace. Does nothing
+    /// outside a span.
+    pub async fn send_trace_context(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match telemetry::current() {
+            Some(context) => self.send(format!("{}\n", context.to_header()).as_bytes()).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Sends `CHUNK_REQUEST:<file_id>:<chunk_index>`, followed by
+    /// `:<traceparent>` of `context` if both sides support distributed
+    /// tracing, so the remote serves the request in a child span of it.
+    pub async fn send_chunk_request(
+        &mut self,
+        file_id: &Uuid,
+        chunk_index: usize,
+        context: &TraceContext,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let line = if self.capabilities.distributed_tracing {
+            format!("CHUNK_REQUEST:{}:{}:{}\n", file_id, chunk_index, context.to_traceparent())
+        } else {
+            format!("CHUNK_REQUEST:{}:{}\n", file_id, chunk_index)
+        };
+        self.send(line.as_bytes()).await
+    }
+
+    /// Sends `NODE_ID:<node_id>:<mac>` so the remote can apply its file ACLs,
+    /// with the MAC from [`node_id_mac`] under `node_key` and this session's
+    /// key. Does nothing without all three, as the remote would not believe
+    /// the ID.
+    pub async fn send_node_id(
+        &mut self,
+        node_id: Option<&str>,
+        node_key: Option<&[u8; 32]>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (Some(node_id), Some(node_key), Some(session_key)) = (node_id, node_key, self.session_key) else {
+            return Ok(());
+        };
+        let mac = node_id_mac(node_key, &session_key, node_id);
+        self.send(format!("NODE_ID:{}:{}\n", node_id, hex::encode(mac)).as_bytes()).await
+    }
+
+    /// Offers `ours` with a `PROTOCOL_VERSION:<n>` line. The remote answers
+    /// with the lower of that and the newest version it speaks, which both
+    /// sides then use; it is kept in [`PeerSession::protocol_version`]. Lines
+    /// sent before the answer are discarded.
+    pub async fn negotiate_version(&mut self, ours: ProtocolVersion) -> Result<ProtocolVersion, ConnectionError> {
+        let session_error = |e: Box<dyn Error + Send + Sync>| ConnectionError::Session(e.to_string());
+        self.send(format!("{}{}\n", PROTOCOL_VERSION_PREFIX, ours.number()).as_bytes()).await.map_err(session_error)?;
+        self.stream.flush().await?;
+        while let Some(line) = self.read_line().await.map_err(session_error)? {
+            if let Some(answer) = line.strip_prefix(PROTOCOL_VERSION_PREFIX) {
+                let version = answer
+                    .parse()
+                    .ok()
+                    .and_then(ProtocolVersion::from_number)
+                    .filter(|version| *version <= ours)
+                    .ok_or_else(|| ConnectionError::InvalidResponse(line.clone()))?;
+                self.protocol_version = version;
+                return Ok(version);
+            }
+        }
+        Err(ConnectionError::Session("connection closed during protocol negotiation".to_string()))
+    }
+
+    /// Sends `message` as a length-prefixed JSON frame and flushes it.
+    pub async fn send_message(&mut self, message: &Message) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(&protocol::encode_frame(message)?).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Reads the next JSON frame, or `None` once the remote closes the connection between frames.
+    pub async fn read_message(&mut self) -> Result<Option<Message>, Box<dyn Error + Send + Sync>> {
+        while self.buffer.len() < 4 {
+            let mut temp = [0u8; 4096];
+            let bytes_read = self.stream.read(&mut temp).await?;
+            if bytes_read == 0 && self.buffer.is_empty() {
+                return Ok(None);
+            }
+            if bytes_read == 0 {
+                return Err("Connection closed mid-message".into());
+            }
+            self.buffer.extend_from_slice(&temp[..bytes_read]);
+        }
+        let len = u32::from_be_bytes(self.buffer[..4].try_into().unwrap()) as usize;
+        if len > protocol::MAX_MESSAGE_LEN {
+            return Err(ProtocolError::MessageTooLarge(len).into());
+        }
+        let frame = self.read_exact(4 + len).await?;
+        Ok(Some(protocol::decode_message(&frame[4..])?))
+    }
+
+    /// Derives this connection's key from the shared `master_key` (hex) and
+    /// both handshake nonces, and keeps it for the rest of the session.
+    /// Returns the hex key to encrypt with, or `None` if the remote sent no
+    /// nonce. The master key itself never goes over the wire.
+    pub fn establish_session_key(&mut self, master_key: &str) -> Result<Option<String>, EncryptionError> {
+        let master_key: [u8; 32] = hex::decode(master_key)?
+            .try_into()
+            .map_err(|_| EncryptionError::InvalidKeyLength("Expected a 32-byte master key".into()))?;
+        self.session_key = self
+            .remote_nonce
+            .map(|remote_nonce| derive_session_key(&master_key, &remote_nonce, &self.local_nonce));
+        Ok(self.session_key.map(hex::encode))
+    }
+
+    /// The key derived by [`PeerSession::establish_session_key`], if any.
+    pub fn session_key(&self) -> Option<&[u8; 32]> {
+        self.session_key.as_ref()
+    }
+
+    /// Returns `(bytes_sent, bytes_received)` since the previous call and resets both.
+    pub fn take_traffic(&mut self) -> (u64, u64) {
+        (std::mem::take(&mut self.bytes_sent), std::mem::take(&mut self.bytes_received))
+    }
+
+    fn unread_line(&mut self, line: &str) {
+        let mut restored = format!("{}\n", line).into_bytes();
+        self.bytes_received = self.bytes_received.saturating_sub(restored.len() as u64);
+        restored.append(&mut self.buffer);
+        self.buffer = restored;
+    }
+}
+
+/// Parses `<bits>[:<nonce>]`. A malformed nonce is treated as no nonce.
+fn parse_caps(rest: &str) -> Option<(u32, Option<[u8; 32]>)> {
+    let (bits, nonce) = match rest.split_once(':') {
+        Some((bits, nonce)) => (bits, hex::decode(nonce).ok().and_then(|n| n.try_into().ok())),
+        None => (rest, None),
+    };
+    Some((bits.parse().ok()?, nonce))
+}
+
+/// HKDF-SHA256 of the master key, salted with both sides' nonces. The nonces
+/// are put in a fixed order, so both ends of a connection get the same key.
+pub fn derive_session_key(master_key: &[u8; 32], peer_nonce: &[u8; 32], local_nonce: &[u8; 32]) -> [u8; 32] {
+    let (first, second) = if peer_nonce <= local_nonce { (peer_nonce, local_nonce) } else { (local_nonce, peer_nonce) };
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(first);
+    salt[32..].copy_from_slice(second);
+    let mut key = [0u8; 32];
+    hkdf_sha256(&salt, master_key, SESSION_KEY_INFO, &mut key);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capability_bits_round_trip() {
+        let caps = PeerCapabilities {
+            compression: true,
+            erasure_coding: false,
+            pex: true,
+            file_manifest_v2: false,
+            distributed_tracing: true,
+            multiplexing: false,
+        };
+        assert_eq!(caps.to_bits(), CAP_COMPRESSION | CAP_PEX | CAP_DISTRIBUTED_TRACING);
+        assert_eq!(PeerCapabilities::from_bits(caps.to_bits()), caps);
+        assert_eq!(caps.to_string(), "compression, pex, distributed_tracing");
+        assert_eq!(PeerCapabilities::default().to_string(), "none");
+        assert_eq!(PeerCapabilities::from_bits(1 << 31), PeerCapabilities::default());
+    }
+
+    #[test]
+    fn test_capability_intersection() {
+        let ours = PeerCapabilities::from_bits(CAP_COMPRESSION | CAP_PEX);
+        let theirs = PeerCapabilities::from_bits(CAP_PEX | CAP_FILE_MANIFEST_V2);
+        assert_eq!(ours.intersect(&theirs), PeerCapabilities::from_bits(CAP_PEX));
+    }
+
+    #[test]
+    fn test_derive_session_key_is_symmetric() {
+        let master = [7u8; 32];
+        let (a, b, c) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+        assert_eq!(derive_session_key(&master, &a, &b), derive_session_key(&master, &b, &a));
+        assert_ne!(derive_session_key(&master, &a, &b), derive_session_key(&master, &a, &c));
+        assert_ne!(derive_session_key(&master, &a, &b), derive_session_key(&[8u8; 32], &a, &b));
+        assert_ne!(derive_session_key(&master, &a, &b), master);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_derives_the_same_key_on_both_sides() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await.unwrap();
+            session.establish_session_key(&"ab".repeat(32)).unwrap()
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await.unwrap();
+        let client_key = session.establish_session_key(&"ab".repeat(32)).unwrap();
+
+        assert!(client_key.is_some());
+        assert_eq!(server.await.unwrap(), client_key);
+        assert_ne!(client_key.unwrap(), "ab".repeat(32));
+        assert_eq!(parse_caps("5"), Some((5, None)));
+    }
+
+    #[tokio::test]
+    async fn test_chunk_requests_carry_the_trace_parent() {
+        async fn received(client: PeerCapabilities) -> Vec<String> {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await.unwrap();
+                let mut received = Vec::new();
+                while let Some(line) = session.read_line().await.unwrap() {
+                    received.push(line);
+                }
+                received
+            });
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut session = PeerSession::handshake(stream, client).await.unwrap();
+            session.send_chunk_request(&Uuid::nil(), 3, &TraceContext::from_traceparent(PARENT).unwrap()).await.unwrap();
+            session.stream.shutdown().await.unwrap();
+            server.await.unwrap()
+        }
+        const PARENT: &str = "00-0123456789abcdef0123456789abcdef-0123456789abcdef-01";
+
+        let nil = Uuid::nil();
+        assert_eq!(received(PeerCapabilities::local()).await, vec![format!("CHUNK_REQUEST:{}:3:{}", nil, PARENT)]);
+        let untraced = PeerCapabilities { distributed_tracing: false, ..PeerCapabilities::local() };
+        assert_eq!(received(untraced).await, vec![format!("CHUNK_REQUEST:{}:3", nil)]);
+    }
+}
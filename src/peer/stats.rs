@@ -0,0 +1,75 @@
+// src/peer/stats.rs
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Traffic counters for a single peer. Byte counts are application bytes,
+/// i.e. before compression is applied on the wire.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct NetworkStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub chunks_sent: u64,
+    pub chunks_received: u64,
+    pub errors: u64,
+    /// Only set on the local node's entry: speed of the latest completed upload.
+    pub last_upload_bytes_per_sec: u64,
+    /// Only set on the local node's entry: speed of the latest completed download.
+    pub last_download_bytes_per_sec: u64,
+}
+
+impl NetworkStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes_sent + self.bytes_received
+    }
+}
+
+/// Per-peer counters keyed by peer address, shared between connections and the CLI.
+pub type SharedNetworkStats = Arc<RwLock<HashMap<String, NetworkStats>>>;
+
+/// Applies `update` to the counters for `address`, creating them if needed.
+pub fn record<F: FnOnce(&mut NetworkStats)>(stats: &SharedNetworkStats, address: &str, update: F) {
+    let mut map = stats.write().unwrap();
+    update(map.entry(address.to_string()).or_default());
+}
+
+/// Returns a snapshot of all counters, busiest peers first.
+pub fn sorted_by_traffic(stats: &SharedNetworkStats) -> Vec<(String, NetworkStats)> {
+    let mut entries: Vec<(String, NetworkStats)> = stats
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(address, s)| (address.clone(), *s))
+        .collect();
+    entries.sort_by(|a, b| b.1.total_bytes().cmp(&a.1.total_bytes()).then_with(|| a.0.cmp(&b.0)));
+    entries
+}
+
+pub fn reset(stats: &SharedNetworkStats) {
+    stats.write().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_sort_and_reset() {
+        let stats = SharedNetworkStats::default();
+        record(&stats, "127.0.0.1:1", |s| s.bytes_sent += 10);
+        record(&stats, "127.0.0.1:2", |s| {
+            s.bytes_received += 50;
+            s.chunks_received += 1;
+        });
+        record(&stats, "127.0.0.1:1", |s| s.errors += 1);
+
+        let sorted = sorted_by_traffic(&stats);
+        assert_eq!(sorted.len(), 2);
+        assert_eq!(sorted[0].0, "127.0.0.1:2");
+        assert_eq!(sorted[1].1, NetworkStats { bytes_sent: 10, errors: 1, ..Default::default() });
+
+        reset(&stats);
+        assert!(sorted_by_traffic(&stats).is_empty());
+    }
+}
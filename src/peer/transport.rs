@@ -0,0 +1,320 @@
+// src/peer/transport.rs
+
+//! Outbound TCP connections, optionally tunnelled through a SOCKS5 proxy (RFC 1928).
+
+use crate::config::Config;
+use crate::peer::discovery::Peer;
+use std::net::SocketAddr;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const SOCKS_VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+const USER_PASS_VERSION: u8 = 0x01;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+#[derive(Error, Debug)]
+pub enum ConnectionError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("Invalid address: {0}")]
+    InvalidAddress(String),
+
+    #[error("Invalid SOCKS5 proxy setting: {0}")]
+    InvalidProxy(String),
+
+    #[error("SOCKS5 proxy rejected the request: {0}")]
+    ProxyRejected(String),
+
+    #[error("Peer session failed: {0}")]
+    Session(String),
+
+    #[error("Peer has no manifest for file {0}")]
+    ManifestNotFound(uuid::Uuid),
+
+    #[error("Peer denied access to file {0}")]
+    Forbidden(uuid::Uuid),
+
+    #[error("Peer rejected the manifest for file {0}: {1}")]
+    ManifestRejected(uuid::Uuid, String),
+
+    #[error("Local storage error: {0}")]
+    Storage(String),
+
+    #[error("Invalid response from peer: {0}")]
+    InvalidResponse(String),
+
+    #[error("Peer does not support multiplexing")]
+    MultiplexingUnsupported,
+}
+
+/// A parsed `socks5_proxy` setting: `[socks5://][user:password@]host:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    pub address: String,
+    pub credentials: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+    pub fn parse(setting: &str) -> Result<Socks5Proxy, ConnectionError> {
+        let rest = setting.strip_prefix("socks5://").unwrap_or(setting);
+        let (credentials, address) = match rest.rsplit_once('@') {
+            Some((auth, address)) => {
+                let (user, password) = auth
+                    .split_once(':')
+                    .ok_or_else(|| ConnectionError::InvalidProxy("expected user:password before '@'".into()))?;
+                if user.len() > 255 || password.len() > 255 {
+                    return Err(ConnectionError::InvalidProxy("username and password must be at most 255 bytes".into()));
+                }
+                (Some((user.to_string(), password.to_string())), address)
+            }
+            None => (None, rest),
+        };
+        split_host_port(address).map_err(|_| ConnectionError::InvalidProxy(setting.to_string()))?;
+        Ok(Socks5Proxy { address: address.to_string(), credentials })
+    }
+}
+
+/// Opens a TCP connection to `peer`, through `config.socks5_proxy` when one is set.
+/// The proxy resolves host names itself; direct connections use the peer's
+/// cached `SocketAddr`, falling back to a lookup for host names.
+pub async fn connect(peer: &Peer, config: &Config) -> Result<TcpStream, ConnectionError> {
+    match &config.socks5_proxy {
+        Some(setting) => {
+            let proxy = Socks5Proxy::parse(setting)?;
+            let mut stream = TcpStream::connect(&proxy.address).await?;
+            socks5_handshake(&mut stream, &proxy, &peer.address).await?;
+            Ok(stream)
+        }
+        None => match peer.to_socket_addr() {
+            Ok(addr) => Ok(TcpStream::connect(addr).await?),
+            Err(_) => Ok(TcpStream::connect(peer.address.as_str()).await?),
+        },
+    }
+}
+
+/// Negotiates authentication and issues a CONNECT for `target` on an open proxy connection.
+async fn socks5_handshake(
+    stream: &mut TcpStream,
+    proxy: &Socks5Proxy,
+    target: &str,
+) -> Result<(), ConnectionError> {
+    let greeting: &[u8] = match proxy.credentials {
+        Some(_) => &[SOCKS_VERSION, 2, METHOD_NO_AUTH, METHOD_USER_PASS],
+        None => &[SOCKS_VERSION, 1, METHOD_NO_AUTH],
+    };
+    stream.write_all(greeting).await?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(ConnectionError::ProxyRejected(format!("unsupported version {}", choice[0])));
+    }
+    match (choice[1], &proxy.credentials) {
+        (METHOD_NO_AUTH, _) => {}
+        (METHOD_USER_PASS, Some((user, password))) => {
+            let mut request = vec![USER_PASS_VERSION, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(ConnectionError::ProxyRejected("username/password authentication failed".into()));
+            }
+        }
+        (METHOD_NONE_ACCEPTABLE, _) => {
+            return Err(ConnectionError::ProxyRejected("no acceptable authentication method".into()));
+        }
+        (method, _) => {
+            return Err(ConnectionError::ProxyRejected(format!("unexpected authentication method {}", method)));
+        }
+    }
+
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0x00];
+    request.extend(encode_target(target)?);
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(ConnectionError::ProxyRejected(reply_message(reply[1]).to_string()));
+    }
+    // Skip the bound address and port; they are not needed for a CONNECT.
+    let bound_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        atyp => return Err(ConnectionError::ProxyRejected(format!("unknown address type {}", atyp))),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// Encodes `target` as `ATYP | DST.ADDR | DST.PORT`.
+fn encode_target(target: &str) -> Result<Vec<u8>, ConnectionError> {
+    let mut out = Vec::new();
+    let port = match target.parse::<SocketAddr>() {
+        Ok(SocketAddr::V4(addr)) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Ok(SocketAddr::V6(addr)) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Err(_) => {
+            let (host, port) = split_host_port(target)?;
+            if host.len() > 255 {
+                return Err(ConnectionError::InvalidAddress(target.to_string()));
+            }
+            out.push(ATYP_DOMAIN);
+            out.push(host.len() as u8);
+            out.extend_from_slice(host.as_bytes());
+            port
+        }
+    };
+    out.extend_from_slice(&port.to_be_bytes());
+    Ok(out)
+}
+
+fn split_host_port(addr: &str) -> Result<(&str, u16), ConnectionError> {
+    let invalid = || ConnectionError::InvalidAddress(addr.to_string());
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host, port))
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Minimal SOCKS5 server that accepts one client, checks the optional
+    /// credentials, and relays to the requested IPv4 target.
+    async fn spawn_proxy(credentials: Option<(&'static str, &'static str)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut header = [0u8; 2];
+            client.read_exact(&mut header).await.unwrap();
+            let mut methods = vec![0u8; header[1] as usize];
+            client.read_exact(&mut methods).await.unwrap();
+
+            match credentials {
+                Some((user, password)) => {
+                    assert!(methods.contains(&METHOD_USER_PASS));
+                    client.write_all(&[SOCKS_VERSION, METHOD_USER_PASS]).await.unwrap();
+                    let mut version_and_len = [0u8; 2];
+                    client.read_exact(&mut version_and_len).await.unwrap();
+                    let mut got_user = vec![0u8; version_and_len[1] as usize];
+                    client.read_exact(&mut got_user).await.unwrap();
+                    let mut got_password = vec![0u8; client.read_u8().await.unwrap() as usize];
+                    client.read_exact(&mut got_password).await.unwrap();
+                    let ok = got_user == user.as_bytes() && got_password == password.as_bytes();
+                    client.write_all(&[USER_PASS_VERSION, if ok { 0 } else { 1 }]).await.unwrap();
+                    if !ok {
+                        return;
+                    }
+                }
+                None => client.write_all(&[SOCKS_VERSION, METHOD_NO_AUTH]).await.unwrap(),
+            }
+
+            let mut request = [0u8; 10];
+            client.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[..4], &[SOCKS_VERSION, CMD_CONNECT, 0, ATYP_IPV4]);
+            let ip = std::net::Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+            let port = u16::from_be_bytes([request[8], request[9]]);
+            let mut upstream = TcpStream::connect((ip, port)).await.unwrap();
+            client.write_all(&[SOCKS_VERSION, 0, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            tokio::io::copy_bidirectional(&mut client, &mut upstream).await.ok();
+        });
+        address
+    }
+
+    async fn spawn_echo() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+        address
+    }
+
+    fn config_with_proxy(proxy: &str) -> Config {
+        Config { socks5_proxy: Some(proxy.to_string()), ..Config::with_port(0) }
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_proxy_with_and_without_auth() {
+        for credentials in [None, Some(("alice", "secret"))] {
+            let target = spawn_echo().await;
+            let proxy = spawn_proxy(credentials).await;
+            let setting = match credentials {
+                Some((user, password)) => format!("socks5://{}:{}@{}", user, password, proxy),
+                None => proxy,
+            };
+            let mut stream = connect(&Peer::new(target.clone()), &config_with_proxy(&setting)).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_rejects_bad_credentials() {
+        let target = spawn_echo().await;
+        let proxy = spawn_proxy(Some(("alice", "secret"))).await;
+        let setting = format!("alice:wrong@{}", proxy);
+        let err = connect(&Peer::new(target.clone()), &config_with_proxy(&setting)).await.unwrap_err();
+        assert!(matches!(err, ConnectionError::ProxyRejected(_)));
+    }
+
+    #[test]
+    fn test_parse_proxy_setting_and_targets() {
+        let proxy = Socks5Proxy::parse("socks5://bob:p@ss@proxy.local:1080").unwrap();
+        assert_eq!(proxy.address, "proxy.local:1080");
+        assert_eq!(proxy.credentials, Some(("bob".to_string(), "p@ss".to_string())));
+        assert!(Socks5Proxy::parse("proxy.local").is_err());
+
+        assert_eq!(encode_target("10.0.0.1:80").unwrap(), vec![ATYP_IPV4, 10, 0, 0, 1, 0, 80]);
+        assert_eq!(
+            encode_target("peer.example:8080").unwrap(),
+            [&[ATYP_DOMAIN, 12][..], b"peer.example", &[0x1f, 0x90]].concat()
+        );
+    }
+}
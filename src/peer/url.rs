@@ -0,0 +1,203 @@
+// src/peer/url.rs
+
+//! `sharesphere://[<node-id-hex>@]host:port` peer URLs, as used in
+//! `bootstrap_peers` and log output, and the file links `search` prints.
+
+use crate::peer::discovery::{Peer, PeerError};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+pub const SCHEME: &str = "sharesphere";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerUrl {
+    /// Hex node ID from the user portion of the URL, if given.
+    pub node_id: Option<String>,
+    /// Host name or IP address; IPv6 addresses are kept in brackets.
+    pub host: String,
+    pub port: u16,
+}
+
+impl PeerUrl {
+    /// `host:port`, as stored in [`Peer::address`].
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// Parses a peer URL. A bare `host:port` without a scheme is accepted too, so
+/// configs written before peer URLs keep working.
+impl FromStr for PeerUrl {
+    type Err = PeerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| PeerError::InvalidUrl(s.to_string(), reason.to_string());
+        let rest = match s.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case(SCHEME) => rest,
+            Some(_) => return Err(invalid("scheme must be sharesphere")),
+            None => s,
+        };
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+        if rest.contains(['/', '?', '#']) {
+            return Err(invalid("paths, queries and fragments are not supported"));
+        }
+
+        let (node_id, host_port) = match rest.rsplit_once('@') {
+            Some((node_id, host_port)) => {
+                if node_id.is_empty() || hex::decode(node_id).is_err() {
+                    return Err(invalid("node ID must be hex"));
+                }
+                (Some(node_id.to_ascii_lowercase()), host_port)
+            }
+            None => (None, rest),
+        };
+
+        let (host, port) = host_port.rsplit_once(':').ok_or_else(|| invalid("port is required"))?;
+        if host.is_empty() || host == "[]" {
+            return Err(invalid("host is required"));
+        }
+        if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+            return Err(invalid("IPv6 hosts must be in brackets"));
+        }
+        let port = match port.parse::<u16>() {
+            Ok(port) if port != 0 => port,
+            _ => return Err(invalid("port must be between 1 and 65535")),
+        };
+
+        Ok(PeerUrl { node_id, host: host.to_string(), port })
+    }
+}
+
+impl fmt::Display for PeerUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://", SCHEME)?;
+        if let Some(node_id) = &self.node_id {
+            write!(f, "{}@", node_id)?;
+        }
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// `sharesphere://<peer>/<file_id>?name=<file name>&size=<bytes>`: a file
+/// and one peer that serves it. The name is percent-encoded; name and size
+/// are left out when unknown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileLink {
+    pub peer: PeerUrl,
+    pub file_id: Uuid,
+    pub file_name: Option<String>,
+    pub file_size: Option<u64>,
+}
+
+impl fmt::Display for FileLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.peer, self.file_id)?;
+        let mut separator = '?';
+        if let Some(name) = &self.file_name {
+            write!(f, "{}name={}", separator, percent_encode(name))?;
+            separator = '&';
+        }
+        if let Some(size) = self.file_size {
+            write!(f, "{}size={}", separator, size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Escapes everything but the RFC 3986 unreserved characters.
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+impl Serialize for PeerUrl {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PeerUrl {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl TryFrom<PeerUrl> for Peer {
+    type Error = PeerError;
+
+    fn try_from(url: PeerUrl) -> Result<Self, Self::Error> {
+        let mut peer = Peer::new(url.address());
+        peer.node_id = url.node_id;
+        Ok(peer)
+    }
+}
+
+impl TryFrom<&Peer> for PeerUrl {
+    type Error = PeerError;
+
+    fn try_from(peer: &Peer) -> Result<Self, Self::Error> {
+        let mut url: PeerUrl = peer.address.parse()?;
+        url.node_id = peer.node_id.clone();
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_format_peer_urls() {
+        let url: PeerUrl = "sharesphere://ABCD01@node.example:8080".parse().unwrap();
+        assert_eq!(url, PeerUrl { node_id: Some("abcd01".into()), host: "node.example".into(), port: 8080 });
+        assert_eq!(url.to_string(), "sharesphere://abcd01@node.example:8080");
+
+        let ipv6: PeerUrl = "sharesphere://[::1]:9000/".parse().unwrap();
+        assert_eq!(ipv6.address(), "[::1]:9000");
+        assert_eq!("127.0.0.1:8081".parse::<PeerUrl>().unwrap().to_string(), "sharesphere://127.0.0.1:8081");
+
+        for bad in ["http://host:80", "sharesphere://host", "sharesphere://:80", "sharesphere://host:0",
+                    "sharesphere://host:70000", "sharesphere://xyz@host:80", "sharesphere://::1:80"] {
+            assert!(bad.parse::<PeerUrl>().is_err(), "{} should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn test_file_link() {
+        let file_id = Uuid::parse_str("5b1e8c3a-1f2d-4e6a-9b7c-0d8e4f2a6c1b").unwrap();
+        let mut link = FileLink {
+            peer: "00ff@10.0.0.1:8080".parse().unwrap(),
+            file_id,
+            file_name: Some("My Report (v2).pdf".into()),
+            file_size: Some(1234),
+        };
+        assert_eq!(
+            link.to_string(),
+            "sharesphere://00ff@10.0.0.1:8080/5b1e8c3a-1f2d-4e6a-9b7c-0d8e4f2a6c1b?name=My%20Report%20%28v2%29.pdf&size=1234"
+        );
+        link.file_name = None;
+        assert!(link.to_string().ends_with("/5b1e8c3a-1f2d-4e6a-9b7c-0d8e4f2a6c1b?size=1234"));
+    }
+
+    #[test]
+    fn test_peer_url_conversions() {
+        let url: PeerUrl = "sharesphere://00ff@10.0.0.1:8080".parse().unwrap();
+        let peer = Peer::try_from(url.clone()).unwrap();
+        assert_eq!(peer.address, "10.0.0.1:8080");
+        assert_eq!(peer.node_id.as_deref(), Some("00ff"));
+        assert_eq!(PeerUrl::try_from(&peer).unwrap(), url);
+        assert_eq!(format!("{}", peer), "peer@10.0.0.1:8080");
+
+        let config: Vec<PeerUrl> = serde_yaml::from_str("- sharesphere://10.0.0.2:8080\n- 10.0.0.3:8080\n").unwrap();
+        assert_eq!(config[1].to_string(), "sharesphere://10.0.0.3:8080");
+        assert!(serde_yaml::from_str::<Vec<PeerUrl>>("- ftp://10.0.0.2:21\n").is_err());
+    }
+}
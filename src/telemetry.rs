@@ -0,0 +1,427 @@
+// src/telemetry.rs
+
+//! Distributed tracing across peer connections.
+//!
+//! A [`Span`] covers one operation, such as an upload, a download, or a served
+//! session. The active span's [`TraceContext`] is sent to peers as a
+//! `TRACE_CONTEXT:<hex-trace-id>-<hex-span-id>` line, and the receiving side
+//! opens a child span under it. Peers that negotiate distributed tracing
+//! also tag each chunk request with a W3C `traceparent`, so every served
+//! chunk is a child of the fetch that asked for it. Spans are exported to an OpenTelemetry
+//! collector over OTLP/HTTP (JSON) once [`init`] has been called. Without a
+//! collector, spans are only logged at debug level.
+
+use crate::json;
+use log::{debug, error};
+use rand::RngCore;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+pub const TRACE_CONTEXT_PREFIX: &str = "TRACE_CONTEXT:";
+
+/// A line with a W3C `traceparent` value that older peers send after each
+/// `CHUNK_REQUEST`. The value is now the request's last field instead; see
+/// [`PeerSession::send_chunk_request`](crate::peer::session::PeerSession::send_chunk_request).
+pub const TRACE_PARENT_PREFIX: &str = "TRACE_PARENT:";
+
+/// The only `traceparent` version defined so far.
+const TRACEPARENT_VERSION: &str = "00";
+
+/// `traceparent` flags marking the trace as sampled; every span is exported.
+const TRACEPARENT_SAMPLED: &str = "01";
+
+/// Spans sent in one export request at most.
+const MAX_EXPORT_BATCH: usize = 512;
+
+const SERVICE_NAME: &str = "sharesphere";
+
+static EXPORTER: OnceLock<UnboundedSender<FinishedSpan>> = OnceLock::new();
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+#[derive(Error, Debug)]
+pub enum TelemetryError {
+    #[error("Invalid OTLP endpoint {0}: only http://host:port[/path] is supported")]
+    InvalidEndpoint(String),
+
+    #[error("Telemetry is already initialized")]
+    AlreadyInitialized,
+
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("Collector rejected the export: {0}")]
+    Rejected(String),
+
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] json::JsonError),
+}
+
+/// W3C-style trace and span identifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+}
+
+impl TraceContext {
+    pub fn new_root() -> TraceContext {
+        let mut trace_id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut trace_id);
+        TraceContext { trace_id, span_id: random_span_id() }
+    }
+
+    /// A new span in the same trace.
+    pub fn child(&self) -> TraceContext {
+        TraceContext { trace_id: self.trace_id, span_id: random_span_id() }
+    }
+
+    /// The wire header, without the trailing newline.
+    pub fn to_header(&self) -> String {
+        format!("{}{}-{}", TRACE_CONTEXT_PREFIX, hex::encode(self.trace_id), hex::encode(self.span_id))
+    }
+
+    pub fn from_header(line: &str) -> Option<TraceContext> {
+        let (trace_id, span_id) = line.strip_prefix(TRACE_CONTEXT_PREFIX)?.split_once('-')?;
+        Some(TraceContext {
+            trace_id: hex::decode(trace_id).ok()?.try_into().ok()?,
+            span_id: hex::decode(span_id).ok()?.try_into().ok()?,
+        })
+    }
+}
+
+impl TraceContext {
+    /// The W3C `traceparent` value, `00-<trace-id>-<parent-id>-<flags>`.
+    pub fn to_traceparent(&self) -> String {
+        format!(
+            "{}-{}-{}-{}",
+            TRACEPARENT_VERSION,
+            hex::encode(self.trace_id),
+            hex::encode(self.span_id),
+            TRACEPARENT_SAMPLED
+        )
+    }
+
+    /// Parses a version `00` `traceparent`. All-zero IDs are invalid, as in
+    /// the W3C spec.
+    pub fn from_traceparent(value: &str) -> Option<TraceContext> {
+        let mut parts = value.split('-');
+        let (version, trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version != TRACEPARENT_VERSION || parts.next().is_some() || flags.len() != 2 {
+            return None;
+        }
+        hex::decode(flags).ok()?;
+        let context = TraceContext {
+            trace_id: hex::decode(trace_id).ok()?.try_into().ok()?,
+            span_id: hex::decode(span_id).ok()?.try_into().ok()?,
+        };
+        (context.trace_id != [0; 16] && context.span_id != [0; 8]).then_some(context)
+    }
+}
+
+fn random_span_id() -> [u8; 8] {
+    let mut span_id = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut span_id);
+    span_id
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+/// A timed operation. The span ends and is exported when dropped.
+#[derive(Debug)]
+pub struct Span {
+    name: String,
+    context: TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    start_nanos: u64,
+}
+
+impl Span {
+    /// Starts a span under the task's current span, or a new trace if there is none.
+    pub fn start(name: impl Into<String>) -> Span {
+        match current() {
+            Some(parent) => Span::child_of(&parent, name),
+            None => Span::new(name, TraceContext::new_root(), None),
+        }
+    }
+
+    /// Starts a span under a context received from a peer.
+    pub fn child_of(parent: &TraceContext, name: impl Into<String>) -> Span {
+        Span::new(name, parent.child(), Some(parent.span_id))
+    }
+
+    fn new(name: impl Into<String>, context: TraceContext, parent_span_id: Option<[u8; 8]>) -> Span {
+        Span { name: name.into(), context, parent_span_id, start_nanos: unix_nanos() }
+    }
+
+    pub fn context(&self) -> TraceContext {
+        self.context
+    }
+
+    /// Runs `future` with this span as the task's current span.
+    pub async fn scope<F: Future>(&self, future: F) -> F::Output {
+        CURRENT.scope(self.context, future).await
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let span = FinishedSpan {
+            trace_id: hex::encode(self.context.trace_id),
+            span_id: hex::encode(self.context.span_id),
+            parent_span_id: self.parent_span_id.map(hex::encode).unwrap_or_default(),
+            name: std::mem::take(&mut self.name),
+            kind: SPAN_KIND_INTERNAL,
+            start_time_unix_nano: self.start_nanos.to_string(),
+            end_time_unix_nano: unix_nanos().to_string(),
+        };
+        debug!("Span {} finished (trace {}, span {})", span.name, span.trace_id, span.span_id);
+        if let Some(exporter) = EXPORTER.get() {
+            let _ = exporter.send(span);
+        }
+    }
+}
+
+/// The context of the span the current task is running in, if any.
+pub fn current() -> Option<TraceContext> {
+    CURRENT.try_with(|context| *context).ok()
+}
+
+/// Starts exporting finished spans to `endpoint` from a background task.
+/// Must be called from within a tokio runtime.
+pub fn init(endpoint: &str) -> Result<(), TelemetryError> {
+    let endpoint = OtlpEndpoint::parse(endpoint)?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    EXPORTER.set(tx).map_err(|_| TelemetryError::AlreadyInitialized)?;
+    tokio::spawn(run_exporter(endpoint, rx));
+    Ok(())
+}
+
+async fn run_exporter(endpoint: OtlpEndpoint, mut rx: UnboundedReceiver<FinishedSpan>) {
+    while let Some(span) = rx.recv().await {
+        let mut batch = vec![span];
+        while batch.len() < MAX_EXPORT_BATCH {
+            match rx.try_recv() {
+                Ok(span) => batch.push(span),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = export(&endpoint, batch).await {
+            error!("Failed to export spans to {}: {}", endpoint.host, e);
+        }
+    }
+}
+
+const SPAN_KIND_INTERNAL: u8 = 1;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FinishedSpan {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: String,
+    name: String,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportRequest {
+    resource_spans: Vec<ResourceSpans>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans {
+    resource: Resource,
+    scope_spans: Vec<ScopeSpans>,
+}
+
+#[derive(Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Serialize)]
+struct KeyValue {
+    key: String,
+    value: AnyValue,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AnyValue {
+    string_value: String,
+}
+
+#[derive(Serialize)]
+struct ScopeSpans {
+    scope: Scope,
+    spans: Vec<FinishedSpan>,
+}
+
+#[derive(Serialize)]
+struct Scope {
+    name: String,
+}
+
+/// An `http://host:port/path` collector address. The path defaults to `/v1/traces`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct OtlpEndpoint {
+    host: String,
+    path: String,
+}
+
+impl OtlpEndpoint {
+    fn parse(endpoint: &str) -> Result<OtlpEndpoint, TelemetryError> {
+        let invalid = || TelemetryError::InvalidEndpoint(endpoint.to_string());
+        let rest = endpoint.strip_prefix("http://").ok_or_else(invalid)?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let host = if host.contains(':') { host.to_string() } else { format!("{}:4318", host) };
+        let path = match path.trim_end_matches('/') {
+            "" => "/v1/traces".to_string(),
+            path => path.to_string(),
+        };
+        Ok(OtlpEndpoint { host, path })
+    }
+}
+
+/// Sends one OTLP/HTTP JSON export request.
+async fn export(endpoint: &OtlpEndpoint, spans: Vec<FinishedSpan>) -> Result<(), TelemetryError> {
+    let request = ExportRequest {
+        resource_spans: vec![ResourceSpans {
+            resource: Resource {
+                attributes: vec![KeyValue {
+                    key: "service.name".to_string(),
+                    value: AnyValue { string_value: SERVICE_NAME.to_string() },
+                }],
+            },
+            scope_spans: vec![ScopeSpans {
+                scope: Scope { name: env!("CARGO_PKG_NAME").to_string() },
+                spans,
+            }],
+        }],
+    };
+    let body = json::to_string(&request)?;
+
+    let mut stream = TcpStream::connect(&endpoint.host).await?;
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        endpoint.path,
+        endpoint.host,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(TelemetryError::Rejected(status_line.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_trace_context_header_round_trip() {
+        let root = TraceContext::new_root();
+        let header = root.to_header();
+        assert_eq!(header.len(), TRACE_CONTEXT_PREFIX.len() + 32 + 1 + 16);
+        assert_eq!(TraceContext::from_header(&header), Some(root));
+        assert_eq!(TraceContext::from_header("TRACE_CONTEXT:abc-def"), None);
+
+        let child = Span::child_of(&root, "serve");
+        assert_eq!(child.context().trace_id, root.trace_id);
+        assert_ne!(child.context().span_id, root.span_id);
+        assert_eq!(OtlpEndpoint::parse("http://collector").unwrap().host, "collector:4318");
+        assert!(OtlpEndpoint::parse("https://collector").is_err());
+    }
+
+    #[test]
+    fn test_traceparent_round_trip() {
+        let context = TraceContext { trace_id: [0xab; 16], span_id: [0xcd; 8] };
+        let traceparent = context.to_traceparent();
+        assert_eq!(traceparent, format!("00-{}-{}-01", "ab".repeat(16), "cd".repeat(8)));
+        assert_eq!(TraceContext::from_traceparent(&traceparent), Some(context));
+
+        let sampled_off = format!("00-{}-{}-00", "ab".repeat(16), "cd".repeat(8));
+        assert_eq!(TraceContext::from_traceparent(&sampled_off), Some(context));
+        for invalid in [
+            format!("01-{}-{}-01", "ab".repeat(16), "cd".repeat(8)),
+            format!("00-{}-{}-01", "00".repeat(16), "cd".repeat(8)),
+            format!("00-{}-{}-01", "ab".repeat(16), "00".repeat(8)),
+            format!("00-{}-{}-zz", "ab".repeat(16), "cd".repeat(8)),
+            format!("00-{}-{}-01-extra", "ab".repeat(16), "cd".repeat(8)),
+            format!("00-{}-{}", "ab".repeat(16), "cd".repeat(8)),
+        ] {
+            assert_eq!(TraceContext::from_traceparent(&invalid), None, "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spans_nest_within_scope() {
+        assert_eq!(current(), None);
+        let upload = Span::start("upload");
+        let nested = upload.scope(async { Span::start("fetch").context() }).await;
+        assert_eq!(nested.trace_id, upload.context().trace_id);
+        assert_eq!(current(), None);
+    }
+
+    #[tokio::test]
+    async fn test_export_posts_otlp_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = OtlpEndpoint::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !String::from_utf8_lossy(&request).contains("\"spans\"") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let span = FinishedSpan {
+            trace_id: "ab".repeat(16),
+            span_id: "cd".repeat(8),
+            parent_span_id: String::new(),
+            name: "upload".to_string(),
+            kind: SPAN_KIND_INTERNAL,
+            start_time_unix_nano: "1".to_string(),
+            end_time_unix_nano: "2".to_string(),
+        };
+        export(&endpoint, vec![span]).await.unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+        assert!(request.contains(&format!("\"traceId\":\"{}\"", "ab".repeat(16))));
+        assert!(request.contains("\"service.name\""));
+    }
+}
@@ -1,23 +1,67 @@
 // src/ui/cli.rs
 
-use clap::{Parser, Subcommand};
-use log::{info, error};
+use clap::{Parser, Subcommand, ValueHint};
+use log::{info, error, warn};
 use std::error::Error;
-use crate::file_manager::chunker::split_file_into_chunks;
-use crate::file_manager::storage::{initialize_storage, save_chunk, get_chunk, list_chunks};
-use crate::file_manager::replication::replicate_chunks;
+use crate::config::{update_bootstrap_peers, Config, ConfigError};
+use crate::file_manager::chunker::{merge_chunks_to_file, optimal_chunk_size_for_peers, split_bytes_into_chunks, Chunk, ChunkMetadata, MAX_CHUNK_SIZE};
+use crate::file_manager::download_state::{DownloadState, DownloadStateError, WatchedDownload};
+use crate::file_manager::seed_index::{modified_ms, SeedIndex, SeededFile};
+use crate::file_manager::pending_uploads::{PendingUpload, PendingUploadError, WatchedUploader};
+use crate::file_manager::storage::{chunk_hash, FileSystemBackend, StorageBackend, initialize_storage, StorageError, save_chunk, get_chunk, list_chunks, chunk_exists, stored_file_size, storage_usage, delete_file, list_all_files, list_all_files_with_pinned, pin_file, unpin_file, move_chunks, find_damaged_chunks, scan_and_repair, verify_file, RepairReport};
+use crate::file_manager::replication::{replicate_chunks, select_peers, verify_replication, PeerLoad, RepairTask, ReplicationContext, ReplicationQueue, ReplicationStatus, ReplicationStatusStore, ReplicationTask};
+use crate::file_manager::manifest::{DirEntry, DirManifest, FileKind, FileManifest, save_manifest, load_manifest, MANIFEST_FILENAME};
+use crate::file_manager::hash::HashAlgorithm;
+use crate::file_manager::integrity::verify_file_hash;
+use crate::file_manager::prefetch::PrefetchQueue;
+use crate::file_manager::merkle::MerkleTree;
+use crate::json;
+use crate::history::{format_timestamp, HistoryStore, TransferDirection, TransferRecord, TransferStatus};
 use crate::indexing::search::search_file;
-use crate::indexing::dht::DHT;
-use crate::peer::discovery::Peer;
-use tokio::sync::mpsc::Receiver;
+use crate::indexing::dht::{DhtDump, DhtFileMetadata, DHT};
+use crate::indexing::gossip::announce_to_dht;
+use crate::peer::connection::{fetch_chunk_from_peer, fetch_chunks_batch, get_remote_manifest, list_remote_files, send_file_manifest};
+use crate::peer::protocol::RemoteFileEntry;
+use crate::peer::url::{FileLink, PeerUrl};
+use crate::peer::discovery::{run_peer_connection, OutgoingConnections, Peer, PeerEvent, PeerProbe, PeerRegistry};
+use crate::peer::stats::{self, NetworkStats, SharedNetworkStats};
+use crate::peer::transport::{self, ConnectionError};
+use crate::peer::latency::{latency_of, PeerLatency};
+use crate::peer::reputation::ReputationStore;
+use crate::events::{self, TelemetryEvent, TelemetrySender};
+use crate::telemetry::Span;
+use crate::ui::output::{format_bytes, render_csv, to_json, OutputFormat, Printable, Row};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{Receiver, Sender};
 use uuid::Uuid;
-use std::fs::OpenOptions;
-use std::io::Write;
-use tokio::runtime::Runtime;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::time::Instant;
+use std::time::Duration;
+
+/// How many passes over the peer list are made for each missing chunk.
+const CHUNK_FETCH_RETRIES: usize = 3;
+
+/// How often `seed` looks for new, changed and deleted files.
+const SEED_RESCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A `--chunk-size` may go below
+/// [`MIN_CHUNK_SIZE`](crate::file_manager::chunker::MIN_CHUNK_SIZE), which
+/// only bounds the size picked when none is given.
+const MIN_EXPLICIT_CHUNK_SIZE: usize = 1024;
+
+const DEFAULT_HISTORY_LIMIT: usize = 20;
 
 #[derive(Parser)]
 #[command(name = "ShareSphere CLI")]
 #[command(about = "Interact with the ShareSphere P2P network", long_about = None)]
+// The prompt has no `help` command.
+#[command(disable_help_subcommand = true)]
 pub struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
@@ -26,28 +70,334 @@ pub struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Upload {
+        #[arg(value_hint = ValueHint::FilePath)]
         file_path: String,
+        /// Chunk size in bytes, from 1 KiB to 128 MiB. Smaller chunks allow more
+        /// parallel transfers; larger chunks mean less overhead per chunk.
+        /// Defaults to a power of two that gives each connected peer about
+        /// four chunks, from 64 KiB to 128 MiB.
+        #[arg(long)]
+        chunk_size: Option<usize>,
+    },
+    UploadDir {
+        #[arg(value_hint = ValueHint::DirPath)]
+        dir_path: String,
     },
     Download {
+        #[arg(value_hint = ValueHint::Other)]
         file_id: String,
+        #[arg(value_hint = ValueHint::AnyPath)]
         destination: String,
     },
+    /// Find the peers storing a file, with its name, size and a
+    /// `sharesphere://` link.
     Search {
+        #[arg(value_hint = ValueHint::Other)]
         query: String,
+        /// Download the first result to this path.
+        #[arg(long, value_hint = ValueHint::AnyPath)]
+        download_to: Option<String>,
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    OrphanCleanup {
+        #[arg(long)]
+        dry_run: bool,
+    },
+    Status {
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// List files stored on this node. Pinned files are marked with 📌.
+    ListFiles {
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// List connected peers with their capabilities and last measured latency.
+    ListPeers {
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// List the files a peer stores, from its manifests.
+    PeerFiles {
+        #[arg(value_hint = ValueHint::Other)]
+        peer_addr: String,
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Protect a stored file from cleanup.
+    Pin {
+        file_id: String,
+    },
+    Unpin {
+        file_id: String,
+    },
+    NetworkStats {
+        #[arg(long)]
+        reset: bool,
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    History {
+        #[arg(long)]
+        limit: Option<usize>,
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Reassemble a locally stored file without contacting peers. Writes to
+    /// stdout when `output` is `-`.
+    Export {
+        #[arg(value_hint = ValueHint::Other)]
+        file_id: String,
+        #[arg(value_hint = ValueHint::AnyPath)]
+        output: String,
+    },
+    /// Restore a file from a manifest exported on another node, fetching its
+    /// chunks from the peers that hold them.
+    Import {
+        #[arg(value_hint = ValueHint::FilePath)]
+        manifest_path: String,
+        #[arg(value_hint = ValueHint::AnyPath)]
+        destination: String,
+    },
+    /// Show which peers hold which chunks of a file.
+    ReplicationStatus {
+        #[arg(value_hint = ValueHint::Other)]
+        file_id: String,
+        /// Ask each peer to serve its chunks back before showing the table.
+        #[arg(long)]
+        verify: bool,
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Check a stored file's chunks against their hashes.
+    Verify {
+        #[arg(value_hint = ValueHint::Other)]
+        file_id: String,
+        /// Fetch fresh copies of damaged chunks from other peers.
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Write every DHT entry as JSON, with the name and size of the files
+    /// stored here, to a file or to stdout.
+    DhtDump {
+        #[arg(value_hint = ValueHint::FilePath)]
+        output: Option<String>,
+    },
+    /// Replace the DHT with the entries of a `dht-dump` file.
+    DhtImport {
+        #[arg(value_hint = ValueHint::FilePath)]
+        input: String,
+    },
+    /// Move files stored under a previous `storage_path` into the current one.
+    Migrate {
+        #[arg(value_hint = ValueHint::DirPath)]
+        old_storage_path: String,
+    },
+    /// Print peer connect and disconnect events as JSON lines until Ctrl-C.
+    WatchPeers,
+    /// Change the bootstrap peers without a restart. Changes are saved to the config file.
+    Peers {
+        /// Connect to this peer and add it to the bootstrap peers.
+        #[arg(long, value_hint = ValueHint::Other)]
+        add: Option<String>,
+        /// Close the connection to this peer and remove it from the bootstrap peers.
+        #[arg(long, value_hint = ValueHint::Other)]
+        remove: Option<String>,
+        /// Show the bootstrap and connected peers with their status.
+        #[arg(long)]
+        list: bool,
     },
     Exit,
 }
 
+/// Node-wide state shared by the CLI operations.
+#[derive(Clone)]
+struct NodeContext {
+    config: Config,
+    dht: DHT,
+    local_peer: Peer,
+    history: HistoryStore,
+    network_stats: SharedNetworkStats,
+    latency: PeerLatency,
+    peer_load: PeerLoad,
+    replication_status: ReplicationStatusStore,
+    uploads: WatchedUploader,
+    reputation: ReputationStore,
+    events: TelemetrySender,
+}
+
+impl NodeContext {
+    fn new(config: Config, dht: DHT, local_peer: Peer, network_stats: SharedNetworkStats, latency: PeerLatency) -> Self {
+        let storage_root = config.storage.storage_path.clone();
+        NodeContext {
+            history: HistoryStore::open(&storage_root),
+            replication_status: ReplicationStatusStore::open(&storage_root),
+            uploads: WatchedUploader::open(&storage_root),
+            config,
+            dht,
+            local_peer,
+            network_stats,
+            latency,
+            peer_load: PeerLoad::default(),
+            reputation: ReputationStore::default(),
+            events: events::channel(),
+        }
+    }
+
+    fn emit(&self, event: TelemetryEvent) {
+        events::emit(&self.events, event);
+    }
+
+    fn replication(&self) -> ReplicationContext {
+        ReplicationContext {
+            network_stats: self.network_stats.clone(),
+            latency: self.latency.clone(),
+            peer_load: self.peer_load.clone(),
+            config: self.config.clone(),
+            status: self.replication_status.clone(),
+            reputation: self.reputation.clone(),
+        }
+    }
+}
+
+/// What the `peers` command changes: the bootstrap peers in the shared config
+/// and in the config file changes are saved to, and the live connections.
+struct PeerManager {
+    config: Arc<RwLock<Config>>,
+    /// [`MultiConfig::writable_layer`](crate::config::MultiConfig::writable_layer);
+    /// changes are only kept in memory without one.
+    config_path: Option<PathBuf>,
+    peers: PeerRegistry,
+    connections: OutgoingConnections,
+}
+
+impl PeerManager {
+    /// Connects to `url` and, once connected, adds it to the bootstrap peers.
+    /// The connection is then served in the background like one made at startup.
+    async fn add(&self, node: &NodeContext, url: PeerUrl) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let peer = Peer::try_from(url.clone())?;
+        let config = self.config.read().unwrap().clone();
+        if config.discovery.bootstrap_peers.iter().any(|p| p.address() == peer.address) {
+            return Err(format!("{} is already a bootstrap peer", peer).into());
+        }
+        let stream = transport::connect(&peer, &config).await?;
+
+        self.save(|saved| saved.push(url.clone()))?;
+        self.config.write().unwrap().discovery.bootstrap_peers.push(url);
+
+        let stop = self.connections.register(&peer.address);
+        let (dht, local_peer, network_stats) = (node.dht.clone(), node.local_peer.clone(), node.network_stats.clone());
+        let peers = self.peers.clone();
+        tokio::spawn(async move {
+            run_peer_connection(stream, peer, &config, dht, local_peer, peers, network_stats, stop).await;
+        });
+        Ok(())
+    }
+
+    /// Removes `url` from the bootstrap peers and closes any outgoing
+    /// connection to it. Returns whether it was a bootstrap peer and whether
+    /// a connection was closed.
+    fn remove(&self, url: &PeerUrl) -> Result<(bool, bool), Box<dyn Error + Send + Sync>> {
+        let address = url.address();
+        let mut bootstrap_peers = self.config.read().unwrap().discovery.bootstrap_peers.clone();
+        let count = bootstrap_peers.len();
+        bootstrap_peers.retain(|p| p.address() != address);
+        let was_bootstrap = bootstrap_peers.len() != count;
+        if was_bootstrap {
+            if !self.save(|saved| saved.retain(|p| p.address() != address))? {
+                warn!("{} is not listed in the config file; it comes back on restart if another layer or the environment lists it", url);
+            }
+            self.config.write().unwrap().discovery.bootstrap_peers = bootstrap_peers;
+        }
+        Ok((was_bootstrap, self.connections.close(&address)))
+    }
+
+    /// Applies `update` to the bootstrap peers of the config file, leaving
+    /// those of other layers out. Returns whether the file changed.
+    fn save(&self, update: impl FnOnce(&mut Vec<PeerUrl>)) -> Result<bool, ConfigError> {
+        match &self.config_path {
+            Some(config_path) => update_bootstrap_peers(config_path, update),
+            None => {
+                warn!("No config file exists; the change to the bootstrap peers is lost on restart");
+                Ok(false)
+            }
+        }
+    }
+
+    /// Bootstrap peers first, then any other connected peers.
+    fn statuses(&self) -> Vec<PeerStatusEntry> {
+        let connected: Vec<String> = self.peers.all().into_iter().map(|p| p.address).collect();
+        let mut entries: Vec<PeerStatusEntry> = self
+            .config
+            .read()
+            .unwrap()
+            .discovery
+            .bootstrap_peers
+            .iter()
+            .map(|url| PeerStatusEntry {
+                connected: connected.contains(&url.address()),
+                address: url.address(),
+                bootstrap: true,
+            })
+            .collect();
+        for address in connected {
+            if !entries.iter().any(|e| e.address == address) {
+                entries.push(PeerStatusEntry { address, bootstrap: false, connected: true });
+            }
+        }
+        entries
+    }
+}
+
+/// Sends each line typed on stdin to [`run_cli`], until stdin is closed or
+/// the CLI has stopped.
+pub async fn read_commands(tx: Sender<String>) {
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => {
+                if tx.send(line).await.is_err() {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("Failed to read a command: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run_cli(
     mut rx: Receiver<String>,
     dht: DHT,
-    storage_root: String,
-    peers: Vec<Peer>,
-    encryption_key: String,
+    config: Arc<RwLock<Config>>,
+    config_path: Option<PathBuf>,
+    peers: PeerRegistry,
+    local_peer: Peer,
+    network_stats: SharedNetworkStats,
+    latency: PeerLatency,
+    connections: OutgoingConnections,
 ) {
-    let rt = Runtime::new().unwrap();
+    // Operations read the settings as loaded; only the bootstrap peers change at runtime.
+    let node_config = config.read().unwrap().clone();
+    let peer_manager = PeerManager {
+        config,
+        config_path,
+        peers: peers.clone(),
+        connections,
+    };
+    let storage_root = node_config.storage.storage_path.clone();
+    let node = NodeContext::new(node_config, dht, local_peer, network_stats, latency);
+    tokio::spawn(render_events(node.events.subscribe(), std::io::stdout()));
+    let replication_queue = Arc::new(ReplicationQueue::new(node.replication(), node.config.transfer.max_concurrent_uploads));
+    tokio::spawn(resume_pending_uploads(node.clone(), peers.clone(), replication_queue.clone()));
+    tokio::spawn(RepairTask::new(node.dht.clone(), peers.clone(), node.local_peer.clone(), node.replication()).run());
     loop {
-        println!("Enter command (upload/download/search/exit): ");
+        println!("Enter command (upload/upload-dir/download/search/orphan-cleanup/export/import/status/dht-dump/dht-import/list-files/list-peers/peer-files/pin/unpin/network-stats/history/replication-status/verify/migrate/watch-peers/peers/exit): ");
         let cmd = match rx.recv().await {
             Some(line) => line.trim().to_string(),
             None => {
@@ -61,18 +411,60 @@ pub async fn run_cli(
             continue;
         }
 
+        let format = match parse_flag::<OutputFormat>(&args, "--format") {
+            Ok(format) => format.unwrap_or_default(),
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            }
+        };
+
         match args[0].to_lowercase().as_str() {
             "upload" => {
                 if args.len() < 2 {
-                    error!("Usage: upload <file_path>");
+                    error!("Usage: upload <file_path> [--chunk-size <bytes>]");
                     continue;
                 }
                 let file_path = args[1];
-                match rt.block_on(upload_file(file_path, &storage_root, &peers, &dht)) {
+                let peers = peers.all();
+                let chunk_size = match parse_flag(&args, "--chunk-size") {
+                    Ok(Some(size)) => validate_chunk_size(size),
+                    Ok(None) => std::fs::metadata(file_path)
+                        .map(|metadata| optimal_chunk_size_for_peers(metadata.len(), peers.len()))
+                        .map_err(|e| format!("Cannot read {}: {}", file_path, e)),
+                    Err(e) => Err(e),
+                };
+                let chunk_size = match chunk_size {
+                    Ok(chunk_size) => chunk_size,
+                    Err(e) => {
+                        error!("{}", e);
+                        continue;
+                    }
+                };
+                match Span::start("upload").scope(upload_file(&node, Path::new(file_path), &peers, chunk_size, &replication_queue)).await {
                     Ok(file_id) => info!("Uploaded file {} with file_id {}", file_path, file_id),
                     Err(e) => error!("Upload failed: {}", e),
                 }
             }
+            "upload-dir" => {
+                if args.len() < 2 {
+                    error!("Usage: upload-dir <dir_path>");
+                    continue;
+                }
+                let dir_path = args[1];
+                let chunk_size = match validate_chunk_size(node.config.transfer.default_chunk_size) {
+                    Ok(chunk_size) => chunk_size,
+                    Err(e) => {
+                        error!("{}", e);
+                        continue;
+                    }
+                };
+                let peers = peers.all();
+                match Span::start("upload-dir").scope(upload_directory(&node, Path::new(dir_path), &peers, chunk_size, &replication_queue)).await {
+                    Ok(file_id) => info!("Uploaded directory {} with file_id {}", dir_path, file_id),
+                    Err(e) => error!("Directory upload failed: {}", e),
+                }
+            }
             "download" => {
                 if args.len() < 3 {
                     error!("Usage: download <file_id> <destination>");
@@ -80,24 +472,281 @@ pub async fn run_cli(
                 }
                 let file_id = args[1];
                 let destination = args[2];
-                match rt.block_on(download_file(file_id, destination, &storage_root, &dht, &peers)){
+                let peers = peers.all();
+                match Span::start("download").scope(download_file(&node, file_id, Path::new(destination), &peers)).await {
                     Ok(_) => info!("Downloaded file {} to {}", file_id, destination),
                     Err(e) => error!("Download failed: {}", e),
                 }
             }
             "search" => {
-                if args.len() < 2 {
-                    error!("Usage: search <file_id>");
-                    continue;
-                }
+                let download_to = match parse_flag::<String>(&args, "--download-to") {
+                    Ok(download_to) if args.len() >= 2 => download_to,
+                    _ => {
+                        error!("Usage: search <file_id> [--download-to <path>] [--format <table|json|csv>]");
+                        continue;
+                    }
+                };
                 let query = args[1];
-                let results = search_file(&dht, query);
-                if results.is_empty() {
+                let known_peers = peers.all();
+                let addresses = search_file(&node.dht, query, &known_peers, &node.config).await;
+                let results = match Uuid::parse_str(query) {
+                    Ok(file_id) if !addresses.is_empty() => vec![describe_search_result(&node, file_id, addresses).await],
+                    _ => Vec::new(),
+                };
+                if results.is_empty() && format == OutputFormat::Table {
                     println!("No peers found for file_id {}", query);
                 } else {
-                    println!("Peers storing file {}:", query);
-                    for addr in results {
-                        println!("- {}", addr);
+                    results.print(format);
+                }
+                let Some(result) = results.first() else {
+                    continue;
+                };
+                match download_to {
+                    Some(destination) => {
+                        let file_id = result.file_id.to_string();
+                        match Span::start("download").scope(download_file(&node, &file_id, Path::new(&destination), &known_peers)).await {
+                            Ok(_) => info!("Downloaded file {} to {}", file_id, destination),
+                            Err(e) => error!("Download failed: {}", e),
+                        }
+                    }
+                    None if format == OutputFormat::Table => println!("To download: download {} <destination>", result.file_id),
+                    None => {}
+                }
+            }
+            "orphan-cleanup" => {
+                let dry_run = args[1..].contains(&"--dry-run");
+                match orphan_cleanup(&storage_root, &node.dht, &node.local_peer, dry_run) {
+                    Ok(report) if dry_run => println!(
+                        "{} files would be deleted, {} bytes would be freed, {} DHT entries would be cleaned",
+                        report.files_deleted, report.bytes_freed, report.dht_entries_cleaned
+                    ),
+                    Ok(report) => println!(
+                        "{} files deleted, {} bytes freed, {} DHT entries cleaned",
+                        report.files_deleted, report.bytes_freed, report.dht_entries_cleaned
+                    ),
+                    Err(e) => error!("Orphan cleanup failed: {}", e),
+                }
+            }
+            "export" => {
+                let (Some(file_id), Some(output)) = (args.get(1).and_then(|id| Uuid::parse_str(id).ok()), args.get(2)) else {
+                    error!("Usage: export <file_id> <output|->");
+                    continue;
+                };
+                match export_file(Path::new(&storage_root), file_id, output).await {
+                    Ok(bytes) if *output != "-" => info!("Exported {} bytes of file {} to {}", bytes, file_id, output),
+                    Ok(_) => {}
+                    Err(e) => error!("Export failed: {}", e),
+                }
+            }
+            "import" => {
+                if args.len() < 3 {
+                    error!("Usage: import <manifest_path> <destination>");
+                    continue;
+                }
+                let peers = peers.all();
+                match Span::start("import").scope(import_file(&node, Path::new(args[1]), Path::new(args[2]), &peers)).await {
+                    Ok(file_id) => info!("Imported file {} to {}", file_id, args[2]),
+                    Err(e) => error!("Import failed: {}", e),
+                }
+            }
+            "status" => {
+                let storage_bytes = storage_usage(&storage_root).unwrap_or_else(|e| {
+                    error!("Failed to compute local storage usage: {}", e);
+                    0
+                });
+                let dht_files = node.dht.file_count().unwrap_or_else(|e| {
+                    error!("Failed to read DHT: {}", e);
+                    0
+                });
+                StorageStats {
+                    connected_peers: peers.len(),
+                    dht_files,
+                    storage_bytes,
+                    node_address: node.local_peer.address.clone(),
+                }
+                .print(format);
+            }
+            "dht-dump" => match dump_dht(&node, args.get(1).map(Path::new)) {
+                Ok(count) if args.len() > 1 => info!("Wrote {} DHT entries to {}", count, args[1]),
+                Ok(_) => {}
+                Err(e) => error!("DHT dump failed: {}", e),
+            },
+            "dht-import" => {
+                let Some(input) = args.get(1) else {
+                    error!("Usage: dht-import <input>");
+                    continue;
+                };
+                match import_dht(&node.dht, Path::new(input)) {
+                    Ok(count) => info!("Restored {} DHT entries from {}", count, input),
+                    Err(e) => error!("DHT import failed: {}", e),
+                }
+            }
+            "list-files" => match list_files(&storage_root) {
+                Ok(files) if files.is_empty() && format == OutputFormat::Table => println!("No files stored locally."),
+                Ok(files) => files.print(format),
+                Err(e) => error!("Failed to list files: {}", e),
+            },
+            "list-peers" => {
+                let entries: Vec<PeerEntry> = peers
+                    .all()
+                    .iter()
+                    .map(|peer| PeerEntry {
+                        address: peer.address.clone(),
+                        capability_flags: peer.capability_flags,
+                        latency_ms: latency_of(&node.latency, &peer.address).map(|rtt| rtt.as_millis() as u64),
+                    })
+                    .collect();
+                if entries.is_empty() && format == OutputFormat::Table {
+                    println!("No peers connected.");
+                } else {
+                    entries.print(format);
+                }
+            }
+            "peer-files" => {
+                let Some(peer) = args.get(1).and_then(|addr| addr.parse::<PeerUrl>().ok()).and_then(|url| Peer::try_from(url).ok())
+                else {
+                    error!("Usage: peer-files <peer_addr> [--format <table|json|csv>]");
+                    continue;
+                };
+                match list_remote_files(&peer, &node.config).await {
+                    Ok(files) if files.is_empty() && format == OutputFormat::Table => {
+                        println!("{} stores no files.", peer)
+                    }
+                    Ok(files) => files.print(format),
+                    Err(e) => error!("Failed to list files of {}: {}", peer, e),
+                }
+            }
+            "pin" | "unpin" => {
+                let Some(file_id) = args.get(1).and_then(|id| Uuid::parse_str(id).ok()) else {
+                    error!("Usage: {} <file_id>", args[0]);
+                    continue;
+                };
+                let result = if args[0].eq_ignore_ascii_case("pin") {
+                    pin_file(&storage_root, file_id)
+                } else {
+                    unpin_file(&storage_root, file_id)
+                };
+                match result {
+                    Ok(()) => println!("File {} {}ned", file_id, args[0].to_lowercase()),
+                    Err(e) => error!("Failed to {} file {}: {}", args[0].to_lowercase(), file_id, e),
+                }
+            }
+            "network-stats" => {
+                if args[1..].contains(&"--reset") {
+                    stats::reset(&node.network_stats);
+                    println!("Network statistics reset.");
+                    continue;
+                }
+                let entries: Vec<TrafficEntry> = stats::sorted_by_traffic(&node.network_stats)
+                    .into_iter()
+                    .map(|(peer, stats)| TrafficEntry { peer, stats })
+                    .collect();
+                if entries.is_empty() && format == OutputFormat::Table {
+                    println!("No network activity recorded.");
+                    continue;
+                }
+                entries.print(format);
+            }
+            "history" => {
+                let limit = match parse_flag(&args, "--limit") {
+                    Ok(limit) => limit.unwrap_or(DEFAULT_HISTORY_LIMIT),
+                    Err(_) => {
+                        error!("Usage: history [--limit <N>] [--format <table|json|csv>]");
+                        continue;
+                    }
+                };
+                match node.history.recent(limit) {
+                    Ok(records) if records.is_empty() && format == OutputFormat::Table => println!("No transfers recorded."),
+                    Ok(records) => records.print(format),
+                    Err(e) => error!("Failed to read transfer history: {}", e),
+                }
+            }
+            "replication-status" => {
+                let Some(file_id) = args.get(1).and_then(|id| Uuid::parse_str(id).ok()) else {
+                    error!("Usage: replication-status <file_id> [--verify] [--format <table|json|csv>]");
+                    continue;
+                };
+                if args[2..].contains(&"--verify") {
+                    match verify_replication(&storage_root, &file_id, &node.replication()).await {
+                        Ok(count) => info!("{} replicas of file {} verified", count, file_id),
+                        Err(e) => error!("Failed to verify replicas of file {}: {}", file_id, e),
+                    }
+                }
+                match node.replication_status.get_replication_status(&file_id) {
+                    Ok(records) if records.is_empty() && format == OutputFormat::Table => {
+                        println!("No replicas recorded for file {}.", file_id)
+                    }
+                    Ok(records) => records.print(format),
+                    Err(e) => error!("Failed to read replication status: {}", e),
+                }
+            }
+            "verify" => {
+                let Some(file_id) = args.get(1).and_then(|id| Uuid::parse_str(id).ok()) else {
+                    error!("Usage: verify <file_id> [--repair]");
+                    continue;
+                };
+                let result = if args[2..].contains(&"--repair") {
+                    scan_and_repair(Path::new(&storage_root), file_id, &node.dht, &node.config).await
+                } else {
+                    verify_file(Path::new(&storage_root), file_id)
+                };
+                match result {
+                    Ok(report) => print_repair_report(file_id, &report),
+                    Err(e) => error!("Failed to verify file {}: {}", file_id, e),
+                }
+            }
+            "migrate" => {
+                if args.len() < 2 {
+                    error!("Usage: migrate <old_storage_path>");
+                    continue;
+                }
+                match migrate_storage(&node, Path::new(args[1])) {
+                    Ok(count) => println!("Migrated {} files from {}", count, args[1]),
+                    Err(e) => error!("Migration failed: {}", e),
+                }
+            }
+            "watch-peers" => {
+                let stop = async {
+                    let _ = tokio::signal::ctrl_c().await;
+                };
+                if let Err(e) = watch_peers(peers.subscribe(), std::io::stdout(), stop).await {
+                    error!("Failed to watch peers: {}", e);
+                }
+            }
+            "peers" => {
+                let url_flag = |flag| match args.iter().position(|a| *a == flag) {
+                    Some(pos) => args.get(pos + 1).and_then(|addr| addr.parse::<PeerUrl>().ok()).map(Some).ok_or(()),
+                    None => Ok(None),
+                };
+                let (Ok(add), Ok(remove)) = (url_flag("--add"), url_flag("--remove")) else {
+                    error!("Usage: peers [--add <peer_addr>] [--remove <peer_addr>] [--list] [--format <table|json|csv>]");
+                    continue;
+                };
+                let list = args[1..].contains(&"--list") || (add.is_none() && remove.is_none());
+                if let Some(url) = add {
+                    match peer_manager.add(&node, url.clone()).await {
+                        Ok(()) => info!("Connected to {} and added it to the bootstrap peers", url),
+                        Err(e) => error!("Failed to add peer {}: {}", url, e),
+                    }
+                }
+                if let Some(url) = remove {
+                    match peer_manager.remove(&url) {
+                        Ok((false, false)) => error!("{} is neither a bootstrap peer nor connected", url),
+                        Ok((was_bootstrap, closed)) => info!(
+                            "{} {}{}",
+                            url,
+                            if was_bootstrap { "removed from the bootstrap peers" } else { "was not a bootstrap peer" },
+                            if closed { "; connection closed" } else { "" }
+                        ),
+                        Err(e) => error!("Failed to remove peer {}: {}", url, e),
+                    }
+                }
+                if list {
+                    let entries = peer_manager.statuses();
+                    if entries.is_empty() && format == OutputFormat::Table {
+                        println!("No bootstrap or connected peers.");
+                    } else {
+                        entries.print(format);
                     }
                 }
             }
@@ -106,132 +755,2395 @@ pub async fn run_cli(
                 break;
             }
             _ => {
-                error!("Unknown command. Available commands: upload, download, search, exit");
+                error!("Unknown command. Available commands: upload, upload-dir, download, search, orphan-cleanup, export, import, status, dht-dump, dht-import, list-files, list-peers, peer-files, pin, unpin, network-stats, history, replication-status, verify, migrate, watch-peers, peers, exit");
+            }
+        }
+    }
+}
+
+fn print_repair_report(file_id: Uuid, report: &RepairReport) {
+    println!(
+        "File {}: {} chunks checked, {} damaged",
+        file_id, report.chunks_checked, report.corrupted_found
+    );
+    if report.repaired > 0 || report.unrepaired > 0 {
+        println!("{} repaired, {} could not be repaired", report.repaired, report.unrepaired);
+    }
+}
+
+/// Writes a line to `out` for each transfer event until the node shuts down.
+async fn render_events<W: std::io::Write>(mut events: broadcast::Receiver<TelemetryEvent>, mut out: W) {
+    let mut progress = TransferProgress::default();
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                if writeln!(out, "{}", render_event(&event, &mut progress)).is_err() {
+                    return;
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => error!("Skipped {} transfer events", skipped),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Chunks done and total per transfer in progress. Downloads are only
+/// counted once resumed, since that is when their total is announced.
+#[derive(Debug, Default)]
+struct TransferProgress {
+    uploads: HashMap<Uuid, (usize, usize)>,
+    downloads: HashMap<Uuid, (usize, usize)>,
+}
+
+fn render_event(event: &TelemetryEvent, progress: &mut TransferProgress) -> String {
+    match event {
+        TelemetryEvent::UploadStarted { file_id, path, total_chunks } => {
+            progress.uploads.insert(*file_id, (0, *total_chunks));
+            format!("Uploading {} as {} ({} chunks)", path, file_id, total_chunks)
+        }
+        TelemetryEvent::ChunkUploaded { file_id, chunk_index } => match progress.uploads.get_mut(file_id) {
+            Some((done, total)) => {
+                *done += 1;
+                format!("Upload {}: {}/{} chunks", file_id, done, total)
+            }
+            None => format!("Upload {}: chunk {} done", file_id, chunk_index),
+        },
+        TelemetryEvent::UploadFinished { file_id, bytes, duration } => {
+            progress.uploads.remove(file_id);
+            format!("Upload {} finished: {} in {:.1}s", file_id, format_bytes(*bytes as f64), duration.as_secs_f64())
+        }
+        TelemetryEvent::DownloadStarted { file_id } => format!("Downloading {}", file_id),
+        TelemetryEvent::DownloadResumed { file_id, fetched, total_chunks } => {
+            progress.downloads.insert(*file_id, (*fetched, *total_chunks));
+            format!("Resuming download {}: {}/{} chunks already fetched", file_id, fetched, total_chunks)
+        }
+        TelemetryEvent::ChunkDownloaded { file_id, chunk_index, from_peer } => match progress.downloads.get_mut(file_id) {
+            Some((done, total)) => {
+                *done += 1;
+                format!("Download {}: {}/{} chunks, chunk {} from {}", file_id, done, total, chunk_index, from_peer)
             }
+            None => format!("Download {}: chunk {} from {}", file_id, chunk_index, from_peer),
+        },
+        TelemetryEvent::DownloadFinished { file_id, bytes, duration } => {
+            progress.downloads.remove(file_id);
+            format!("Download {} finished: {} in {:.1}s", file_id, format_bytes(*bytes as f64), duration.as_secs_f64())
         }
+        TelemetryEvent::Error { context, message } => format!("Failed to {}: {}", context, message),
+    }
+}
+
+/// Writes each peer event to `out` as a JSON line until `stop` completes or
+/// the node shuts down, returning how many events were written. Events
+/// dropped because this subscriber fell behind are logged and skipped.
+async fn watch_peers<W: std::io::Write>(
+    mut events: broadcast::Receiver<PeerEvent>,
+    mut out: W,
+    stop: impl Future<Output = ()>,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    tokio::pin!(stop);
+    let mut written = 0;
+    loop {
+        let event = tokio::select! {
+            _ = &mut stop => break,
+            event = events.recv() => event,
+        };
+        match event {
+            Ok(event) => {
+                writeln!(out, "{}", json::to_string(&event)?)?;
+                out.flush()?;
+                written += 1;
+            }
+            Err(RecvError::Lagged(skipped)) => error!("Skipped {} peer events; consider raising peer_event_buffer", skipped),
+            Err(RecvError::Closed) => break,
+        }
+    }
+    Ok(written)
+}
+
+/// Returns the value following `flag` in `args`, if the flag is present.
+fn parse_flag<T: std::str::FromStr>(args: &[&str], flag: &str) -> Result<Option<T>, String> {
+    let Some(pos) = args.iter().position(|a| *a == flag) else {
+        return Ok(None);
+    };
+    args.get(pos + 1)
+        .and_then(|value| value.parse().ok())
+        .map(Some)
+        .ok_or_else(|| format!("Invalid or missing value for {}", flag))
+}
+
+fn validate_chunk_size(chunk_size: usize) -> Result<usize, String> {
+    if (MIN_EXPLICIT_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+        Ok(chunk_size)
+    } else {
+        Err(format!(
+            "Chunk size {} is out of range; it must be between {} and {} bytes",
+            chunk_size, MIN_EXPLICIT_CHUNK_SIZE, MAX_CHUNK_SIZE
+        ))
     }
 }
 
 async fn upload_file(
-    file_path: &str,
-    storage_root: &str,
+    node: &NodeContext,
+    file_path: &Path,
     peers: &[Peer],
-    dht: &DHT,
+    chunk_size: usize,
+    queue: &Arc<ReplicationQueue>,
 ) -> Result<Uuid, Box<dyn Error + Send + Sync>> {
-    let chunk_size = 1024;
-    let (file_id, chunks) = split_file_into_chunks(file_path, chunk_size)?;
+    // Absolute, so a resumed upload finds the file whatever its working directory.
+    let upload = PendingUpload::new(Uuid::new_v4(), std::fs::canonicalize(file_path)?, chunk_size);
+    record_progress(upload.file_id, node.uploads.start(upload.clone()));
+    run_upload(node, &upload, peers, queue).await
+}
 
-    let storage_dir = initialize_storage(storage_root, file_id)?;
-    for (metadata, data) in &chunks {
-        save_chunk(&storage_dir, metadata, data)?;
+/// Continues an upload a previous run did not finish. Chunks it saved are
+/// kept if they still match the file, and those replicated are not sent again.
+async fn resume_upload(
+    node: &NodeContext,
+    upload: &PendingUpload,
+    peers: &[Peer],
+    queue: &Arc<ReplicationQueue>,
+) -> Result<Uuid, Box<dyn Error + Send + Sync>> {
+    run_upload(node, upload, peers, queue).await
+}
+
+/// Resumes the uploads an earlier run left in `pending_uploads.json` once a
+/// peer is connected. Uploads that fail again stay recorded for the next start.
+async fn resume_pending_uploads(node: NodeContext, peers: PeerRegistry, queue: Arc<ReplicationQueue>) {
+    let pending = match node.uploads.pending() {
+        Ok(pending) if pending.is_empty() => return,
+        Ok(pending) => pending,
+        Err(e) => {
+            error!("Failed to read pending uploads: {}", e);
+            return;
+        }
+    };
+    info!("Resuming {} interrupted uploads once a peer is connected", pending.len());
+    let known_peers = loop {
+        let known_peers = peers.all();
+        if !known_peers.is_empty() {
+            break known_peers;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    };
+    for upload in pending {
+        match resume_upload(&node, &upload, &known_peers, &queue).await {
+            Ok(file_id) => info!("Resumed upload of {} as file {}", upload.path.display(), file_id),
+            Err(e) => error!("Failed to resume upload of {}: {}", upload.path.display(), e),
+        }
     }
+}
+
+/// Progress records help resume an upload later, so failing to write one
+/// is logged rather than failing the upload.
+fn record_progress(file_id: Uuid, result: Result<(), PendingUploadError>) {
+    if let Err(e) = result {
+        error!("Failed to record progress of upload {}: {}", file_id, e);
+    }
+}
 
-    let local_peer = Peer { address: "127.0.0.1:8080".to_string() }; // Assuming local peer address known
-    dht.register_file_location(file_id, local_peer.clone());
+/// Uploads the file `upload` describes, skipping the work it records as done.
+/// The record is removed once the upload succeeds.
+async fn run_upload(
+    node: &NodeContext,
+    upload: &PendingUpload,
+    peers: &[Peer],
+    queue: &Arc<ReplicationQueue>,
+) -> Result<Uuid, Box<dyn Error + Send + Sync>> {
+    let started_at = Instant::now();
+    let (file_path, file_id, chunk_size) = (upload.path.as_path(), upload.file_id, upload.chunk_size);
+    let mut file = File::open(file_path).await?;
+    let file_size = file.metadata().await?.len();
 
-    replicate_chunks(peers, storage_root, &file_id).await?;
+    let total_chunks = (file_size as usize).div_ceil(chunk_size);
+    let mut manifest = FileManifest::new(file_id, display_name(file_path), file_size, chunk_size, total_chunks);
+    node.emit(TelemetryEvent::UploadStarted { file_id, path: file_path.display().to_string(), total_chunks });
+    let record = start_record(&node.history, TransferDirection::Upload, &manifest);
+    let result = stream_and_replicate(node, &mut file, &mut manifest, upload, peers, queue).await;
+    finish_record(&node.history, record, &result);
+    if let Err(e) = &result {
+        node.emit(TelemetryEvent::Error { context: format!("upload {}", file_path.display()), message: e.to_string() });
+    }
+    result?;
+    record_progress(file_id, node.uploads.finish(&file_id));
 
+    if let Err(e) = announce_to_dht(file_id, &node.local_peer, peers, &node.config).await {
+        error!("{}; peers will learn of it through gossip", e);
+    }
+    report_transfer_speed(node, TransferDirection::Upload, manifest.file_size, started_at.elapsed());
+    node.emit(TelemetryEvent::UploadFinished { file_id, bytes: manifest.file_size, duration: started_at.elapsed() });
     Ok(file_id)
 }
 
-async fn download_file(
-    file_id_str: &str,
-    destination: &str,
-    storage_root: &str,
-    dht: &DHT,
+/// Prints e.g. `Upload complete: 234 MB in 12.3s (19.0 MB/s)` and keeps the
+/// speed on the local node's entry in the network stats.
+fn report_transfer_speed(node: &NodeContext, direction: TransferDirection, bytes: u64, elapsed: Duration) {
+    let bytes_per_second = bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    let label = match direction {
+        TransferDirection::Upload => "Upload",
+        TransferDirection::Download => "Download",
+    };
+    println!(
+        "{} complete: {} in {:.1}s ({}/s)",
+        label,
+        format_bytes(bytes as f64),
+        elapsed.as_secs_f64(),
+        format_bytes(bytes_per_second)
+    );
+    stats::record(&node.network_stats, &node.local_peer.address, |s| match direction {
+        TransferDirection::Upload => s.last_upload_bytes_per_sec = bytes_per_second as u64,
+        TransferDirection::Download => s.last_download_bytes_per_sec = bytes_per_second as u64,
+    });
+}
+
+/// Reads `file` one chunk at a time, saving and hashing each chunk before
+/// reading the next. The manifest is then written, so its Merkle root is
+/// known, and sent to each chosen peer before its first chunk, which the
+/// peer checks against that root; queueing waits while `queue` is full.
+/// Chunks `done` records as saved are not written again if they still match,
+/// nor sent again if they were replicated. Once every chunk is replicated,
+/// the local peer is registered in the DHT.
+async fn stream_and_replicate(
+    node: &NodeContext,
+    file: &mut File,
+    manifest: &mut FileManifest,
+    done: &PendingUpload,
     peers: &[Peer],
+    queue: &Arc<ReplicationQueue>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let file_id = Uuid::parse_str(file_id_str)?;
-    let peer_addresses = dht.get_file_locations(&file_id).ok_or("File not found in DHT")?;
-
-    let storage_dir = std::path::Path::new(storage_root).join(file_id.to_string());
-    let chunk_indices = list_chunks(&storage_dir)?;
-
-    let mut local_chunk_indices = chunk_indices;
-    if local_chunk_indices.is_empty() {
-        let max_attempts = 10;
-        for i in 0..max_attempts {
-            let mut fetched = false;
-            for peer in &peer_addresses {
-                if let Ok(_) = fetch_chunk_from_peer(peer, &storage_dir, file_id, i).await {
-                    fetched = true;
-                    break;
+    let file_id = manifest.file_id;
+    let storage_dir = initialize_storage(&node.config.storage.storage_path, file_id)?;
+    let replication = node.replication();
+    let mut hashes = Vec::with_capacity(manifest.total_chunks);
+    let algorithm = manifest.hash_algorithm;
+    let mut file_hasher = algorithm.hasher();
+    let mut file_size = 0;
+    let mut buffer = vec![0u8; manifest.base_chunk_size];
+    let mut to_send = Vec::new();
+
+    loop {
+        let bytes_read = read_chunk(file, &mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        let chunk_index = hashes.len();
+        let data = &buffer[..bytes_read];
+        let hash = algorithm.digest(data);
+        let unchanged = done.chunks_saved.contains(&chunk_index)
+            && chunk_hash(&storage_dir, chunk_index, algorithm).is_ok_and(|saved| saved == hash);
+        if !unchanged {
+            let metadata = ChunkMetadata::new(file_id, chunk_index, bytes_read, manifest.total_chunks)
+                .with_base_chunk_size(manifest.base_chunk_size);
+            save_chunk(&storage_dir, &metadata, data)?;
+            record_progress(file_id, node.uploads.chunk_saved(&file_id, chunk_index));
+        }
+        hashes.push(hash);
+        file_hasher.update(data);
+        file_size += bytes_read as u64;
+        if !(unchanged && done.replicated_to.get(&chunk_index).is_some_and(|peers| !peers.is_empty())) {
+            to_send.push(chunk_index);
+        }
+    }
+
+    // The file may have changed size since it was opened; record what was read.
+    manifest.file_size = file_size;
+    manifest.total_chunks = hashes.len();
+    manifest.merkle_root = Some(MerkleTree::from_hashes(algorithm, &hashes).root());
+    manifest.file_hash = Some(file_hasher.finalize());
+    save_manifest(&storage_dir, manifest)?;
+
+    let queued: Result<(), Box<dyn Error + Send + Sync>> = async {
+        let mut has_manifest = HashSet::new();
+        for chunk_index in to_send {
+            for peer in select_peers(peers, &file_id, chunk_index, &replication)? {
+                if has_manifest.insert(peer.address.clone()) {
+                    if let Err(e) = send_file_manifest(peer, manifest, &node.config).await {
+                        error!("Failed to send manifest of file={} to {}: {}", file_id, peer, e);
+                    }
                 }
-            }
-            if !fetched {
-                break;
+                queue.enqueue(ReplicationTask { file_id, chunk_index, target_peer: peer.clone() }).await;
             }
         }
-        local_chunk_indices = list_chunks(&storage_dir)?;
+        Ok(())
     }
+    .await;
 
-    if local_chunk_indices.is_empty() {
-        return Err("No chunks found locally or remotely".into());
+    // Wait even after an error, so no task of this upload outlives it.
+    let summary = queue.wait_for_completion(&file_id).await;
+    for (chunk_index, accepted) in summary.replicated_to {
+        record_progress(file_id, node.uploads.chunk_replicated(&file_id, chunk_index, accepted));
+        node.emit(TelemetryEvent::ChunkUploaded { file_id, chunk_index });
     }
+    queued?;
+    node.dht.register_file_location_with_chunks(file_id, node.local_peer.clone(), manifest.total_chunks)?;
+    Ok(())
+}
 
-    let mut output = OpenOptions::new().create(true).write(true).open(destination)?;
-    for i in 0..local_chunk_indices.len() {
-        let data = get_chunk(&storage_dir, i)?;
-        output.write_all(&data)?;
+/// Fills `buffer` from `file`, returning fewer bytes only at end of file.
+async fn read_chunk(file: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]).await? {
+            0 => break,
+            n => filled += n,
+        }
     }
+    Ok(filled)
+}
 
-    Ok(())
+/// Uploads every file under `dir_path` individually, then uploads a
+/// `DirManifest` mapping their relative paths to file IDs. The returned ID
+/// is the manifest's, which `download` restores as a directory tree.
+async fn upload_directory(
+    node: &NodeContext,
+    dir_path: &Path,
+    peers: &[Peer],
+    chunk_size: usize,
+    queue: &Arc<ReplicationQueue>,
+) -> Result<Uuid, Box<dyn Error + Send + Sync>> {
+    let mut files = Vec::new();
+    collect_files(dir_path, &mut files)?;
+    files.sort();
+
+    let mut dir_manifest = DirManifest::default();
+    for path in files {
+        let file_id = upload_file(node, &path, peers, chunk_size, queue).await?;
+        let relative_path = path.strip_prefix(dir_path)?.to_path_buf();
+        dir_manifest.entries.push(DirEntry { relative_path, file_id });
+    }
+
+    let data = json::to_string_pretty(&dir_manifest)?;
+    let (file_id, chunks) = split_bytes_into_chunks(data.as_bytes(), chunk_size);
+
+    let mut manifest = FileManifest::new(file_id, display_name(dir_path), chunks_size(&chunks), chunk_size, chunks.len());
+    manifest.kind = FileKind::Directory;
+    manifest.merkle_root = Some(merkle_root(manifest.hash_algorithm, &chunks));
+    manifest.file_hash = Some(manifest.hash_algorithm.digest(data.as_bytes()));
+    let record = start_record(&node.history, TransferDirection::Upload, &manifest);
+    let result = store_and_replicate(node, &manifest, &chunks, peers).await;
+    finish_record(&node.history, record, &result);
+    result?;
+
+    Ok(file_id)
 }
 
-async fn fetch_chunk_from_peer(
-    peer: &Peer,
-    storage_dir: &std::path::Path,
-    file_id: Uuid,
-    chunk_index: usize,
+/// Saves chunks and manifest locally, registers the local peer in the DHT
+/// and replicates the chunks to other peers.
+async fn store_and_replicate(
+    node: &NodeContext,
+    manifest: &FileManifest,
+    chunks: &[Chunk],
+    peers: &[Peer],
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    use tokio::net::TcpStream;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let storage_root = node.config.storage.storage_path.as_str();
+    let storage_dir = initialize_storage(storage_root, manifest.file_id)?;
+    for (metadata, data) in chunks {
+        save_chunk(&storage_dir, metadata, data)?;
+    }
+    save_manifest(&storage_dir, manifest)?;
 
-    let mut stream = TcpStream::connect(&peer.address).await?;
-    let request = format!("CHUNK_REQUEST:{}:{}\n", file_id, chunk_index);
-    stream.write_all(request.as_bytes()).await?;
+    node.dht.register_file_location_with_chunks(manifest.file_id, node.local_peer.clone(), manifest.total_chunks)?;
 
-    let mut buffer = Vec::new();
-    let mut temp = [0u8; 4096];
-    loop {
-        let bytes_read = stream.read(&mut temp).await?;
-        if bytes_read == 0 {
-            return Err("Connection closed".into());
-        }
-        buffer.extend_from_slice(&temp[..bytes_read]);
-
-        if let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
-            let line = buffer.drain(..=pos).collect::<Vec<u8>>();
-            let line_str = String::from_utf8_lossy(&line).trim().to_string();
-
-            if line_str.starts_with("CHUNK_RESPONSE:") {
-                // CHUNK_RESPONSE:<FILE_ID>:<CHUNK_INDEX>:<CHUNK_SIZE>:
-                let parts: Vec<&str> = line_str.split(':').collect();
-                if parts.len() == 4 {
-                    let fid_str = parts[1];
-                    let cindex_str = parts[2];
-                    let csize_str = parts[3];
-
-                    let csize: usize = csize_str.parse()?;
-                    let expected_length = csize;
-                    while buffer.len() < expected_length {
-                        let bytes_read_inner = stream.read(&mut temp).await?;
-                        if bytes_read_inner == 0 {
-                            return Err("Connection closed mid-chunk".into());
-                        }
-                        buffer.extend_from_slice(&temp[..bytes_read_inner]);
-                    }
+    replicate_chunks(peers, storage_root, &manifest.file_id, &node.replication()).await?;
 
-                    let chunk_data = buffer.drain(..expected_length).collect::<Vec<u8>>();
-                    save_chunk(
-                        &storage_dir,
-                        &crate::file_manager::chunker::ChunkMetadata::new(file_id, chunk_index, csize, 0),
-                        &chunk_data,
-                    )?;
-                    info!("Fetched chunk {} of file {} from peer {}", chunk_index, file_id, peer.address);
-                    return Ok(());
-                }
-            }
+    Ok(())
+}
+
+/// Records the start of a transfer. History failures are logged, never fatal.
+fn start_record(history: &HistoryStore, direction: TransferDirection, manifest: &FileManifest) -> Option<i64> {
+    history
+        .start(direction, manifest.file_id, &manifest.file_name, manifest.file_size)
+        .map_err(|e| error!("Failed to record {} of {}: {}", direction, manifest, e))
+        .ok()
+}
+
+fn finish_record<T>(history: &HistoryStore, record: Option<i64>, result: &Result<T, Box<dyn Error + Send + Sync>>) {
+    let Some(id) = record else {
+        return;
+    };
+    let status = if result.is_ok() { TransferStatus::Completed } else { TransferStatus::Failed };
+    if let Err(e) = history.finish(id, status) {
+        error!("Failed to update transfer record {}: {}", id, e);
+    }
+}
+
+/// Recursively collects regular files below `dir`. Symlinks are skipped.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
         }
     }
+    Ok(())
+}
+
+/// What one pass of [`sync_seed_directory`] changed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct SeedSync {
+    uploaded: usize,
+    removed: usize,
+    failed: usize,
+}
+
+/// Keeps every file under `directory` uploaded until the node stops, like a
+/// BitTorrent client left seeding. The directory is rescanned every
+/// [`SEED_RESCAN_INTERVAL`]: new and changed files are uploaded, and the
+/// stored copies of changed and deleted ones are dropped. Stored files are
+/// re-announced by the discovery task as usual, and repaired every
+/// `repair_interval_secs` up to `replication_factor` replicas, which defaults
+/// to `replication_target_factor`. A one-line dashboard on stdout is redrawn
+/// every second.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_seed(
+    directory: PathBuf,
+    replication_factor: Option<usize>,
+    dht: DHT,
+    mut config: Config,
+    peers: PeerRegistry,
+    local_peer: Peer,
+    network_stats: SharedNetworkStats,
+    latency: PeerLatency,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !directory.is_dir() {
+        return Err(format!("{} is not a directory", directory.display()).into());
+    }
+    if let Some(factor) = replication_factor {
+        config.storage.replication_target_factor = factor;
+    }
+    let node = NodeContext::new(config, dht, local_peer, network_stats, latency);
+    let queue = Arc::new(ReplicationQueue::new(node.replication(), node.config.transfer.max_concurrent_uploads));
+    tokio::spawn(RepairTask::new(node.dht.clone(), peers.clone(), node.local_peer.clone(), node.replication()).run());
+
+    let mut index = SeedIndex::open(&node.config.storage.storage_path)?;
+    let (totals, watched_totals) = tokio::sync::watch::channel(seed_totals(&index));
+    tokio::spawn(render_seed_dashboard(watched_totals, peers.clone(), std::io::stdout()));
+    let mut rescan = tokio::time::interval(SEED_RESCAN_INTERVAL);
+    loop {
+        rescan.tick().await;
+        match sync_seed_directory(&node, &directory, &mut index, &peers, &queue).await {
+            Ok(sync) if sync == SeedSync::default() => {}
+            Ok(sync) => {
+                info!("Seeding {}: {} uploaded, {} removed, {} failed", directory.display(), sync.uploaded, sync.removed, sync.failed);
+                totals.send_replace(seed_totals(&index));
+            }
+            Err(e) => error!("Failed to scan {}: {}", directory.display(), e),
+        }
+    }
+}
+
+/// Uploads the files under `directory` that `index` has no current entry
+/// for, and stops seeding the ones that are gone. An interrupted upload of
+/// a file is resumed rather than started over.
+async fn sync_seed_directory(
+    node: &NodeContext,
+    directory: &Path,
+    index: &mut SeedIndex,
+    peers: &PeerRegistry,
+    queue: &Arc<ReplicationQueue>,
+) -> Result<SeedSync, Box<dyn Error + Send + Sync>> {
+    let mut files = Vec::new();
+    collect_files(directory, &mut files)?;
+    let mut sync = SeedSync::default();
+    let mut present = HashSet::new();
+    for path in files {
+        let path = std::fs::canonicalize(&path)?;
+        let metadata = std::fs::metadata(&path)?;
+        present.insert(path.clone());
+        if index.get(&path).is_some_and(|seeded| seeded.is_current(&metadata)) {
+            continue;
+        }
+        let known_peers = peers.all();
+        let pending = node.uploads.pending()?.into_iter().find(|upload| upload.path == path);
+        let uploaded = match pending {
+            Some(upload) => resume_upload(node, &upload, &known_peers, queue).await,
+            None => {
+                let chunk_size = optimal_chunk_size_for_peers(metadata.len(), known_peers.len());
+                upload_file(node, &path, &known_peers, chunk_size, queue).await
+            }
+        };
+        match uploaded {
+            Ok(file_id) => {
+                let seeded = SeededFile { path: path.clone(), file_id, size: metadata.len(), modified_ms: modified_ms(&metadata) };
+                if let Some(previous) = index.insert(seeded) {
+                    stop_seeding(node, previous.file_id);
+                }
+                sync.uploaded += 1;
+            }
+            Err(e) => {
+                error!("Failed to seed {}: {}", path.display(), e);
+                sync.failed += 1;
+            }
+        }
+    }
+    let gone: Vec<PathBuf> = index.files().map(|seeded| seeded.path.clone()).filter(|path| !present.contains(path)).collect();
+    for path in gone {
+        if let Some(seeded) = index.remove(&path) {
+            stop_seeding(node, seeded.file_id);
+            sync.removed += 1;
+        }
+    }
+    if sync.uploaded + sync.removed > 0 {
+        index.save()?;
+    }
+    Ok(sync)
+}
+
+/// Withdraws the local copy of a file that is no longer in the seeded directory.
+fn stop_seeding(node: &NodeContext, file_id: Uuid) {
+    if let Err(e) = node.dht.deregister_file_location(&file_id, &node.local_peer.address) {
+        error!("Failed to withdraw file {} from the DHT: {}", file_id, e);
+    }
+    if let Err(e) = delete_file(&node.config.storage.storage_path, file_id) {
+        error!("Failed to delete stored file {}: {}", file_id, e);
+    }
+}
+
+/// Files seeded and their total size.
+fn seed_totals(index: &SeedIndex) -> (usize, u64) {
+    index.files().fold((0, 0), |(count, bytes), seeded| (count + 1, bytes + seeded.size))
+}
+
+/// Redraws the seeding dashboard in place every second until `out` fails.
+async fn render_seed_dashboard<W: std::io::Write>(totals: tokio::sync::watch::Receiver<(usize, u64)>, peers: PeerRegistry, mut out: W) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let (files, bytes) = *totals.borrow();
+        // Carriage return and "erase line" keep the dashboard on one line.
+        let line = format!("\r\x1b[2KSeeding {} files ({}) to {} peers", files, format_bytes(bytes as f64), peers.len());
+        if out.write_all(line.as_bytes()).and_then(|_| out.flush()).is_err() {
+            return;
+        }
+    }
+}
+
+fn display_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string_lossy().into_owned())
+}
+
+/// Merkle root over the `algorithm` hash of each chunk, in chunk order.
+fn merkle_root(algorithm: HashAlgorithm, chunks: &[Chunk]) -> [u8; 32] {
+    let hashes: Vec<[u8; 32]> = chunks.iter().map(|(_, data)| algorithm.digest(data)).collect();
+    MerkleTree::from_hashes(algorithm, &hashes).root()
+}
+
+fn chunks_size(chunks: &[Chunk]) -> u64 {
+    chunks.iter().map(|(_, data)| data.len() as u64).sum()
+}
+
+/// Summary of an `orphan-cleanup` run.
+#[derive(Debug, Default)]
+struct CleanupReport {
+    files_deleted: usize,
+    bytes_freed: u64,
+    dht_entries_cleaned: usize,
+}
+
+/// A locally stored file, as shown by `list-files`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct FileEntry {
+    file_id: Uuid,
+    file_name: String,
+    file_size: u64,
+    total_chunks: usize,
+    pinned: bool,
+}
+
+impl Row for FileEntry {
+    fn headers() -> &'static [&'static str] {
+        &["FILE_ID", "NAME", "BYTES", "CHUNKS", "PINNED"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.file_id.to_string(),
+            self.file_name.clone(),
+            self.file_size.to_string(),
+            self.total_chunks.to_string(),
+            self.pinned.to_string(),
+        ]
+    }
+
+    fn table_cells(&self) -> Vec<String> {
+        let mut cells = self.cells();
+        cells[4] = if self.pinned { "📌".to_string() } else { String::new() };
+        cells
+    }
+}
+
+impl Row for RemoteFileEntry {
+    fn headers() -> &'static [&'static str] {
+        &["FILE_ID", "NAME", "BYTES"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.file_id.to_string(), self.file_name.clone(), self.size_bytes.to_string()]
+    }
+}
+
+impl Row for PeerProbe {
+    fn headers() -> &'static [&'static str] {
+        &["ADDRESS", "PROTOCOL_VERSION", "FILES_COUNT", "LATENCY_MS"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.address.clone(),
+            self.protocol_version.to_string(),
+            self.files_count.to_string(),
+            self.latency_ms.to_string(),
+        ]
+    }
+}
+
+/// A file found by `search`. Name and size are unknown when no peer
+/// storing the file sent its manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct SearchResult {
+    file_id: Uuid,
+    file_name: Option<String>,
+    file_size: Option<u64>,
+    seeders: usize,
+    peers: Vec<String>,
+    /// `sharesphere://` link to the file on its first peer.
+    url: Option<String>,
+}
+
+impl Row for SearchResult {
+    fn headers() -> &'static [&'static str] {
+        &["FILE_ID", "NAME", "BYTES", "SEEDERS", "URL"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.file_id.to_string(),
+            self.file_name.clone().unwrap_or_default(),
+            self.file_size.map(|size| size.to_string()).unwrap_or_default(),
+            self.seeders.to_string(),
+            self.url.clone().unwrap_or_default(),
+        ]
+    }
+
+    fn table_cells(&self) -> Vec<String> {
+        let mut cells = self.cells();
+        cells[2] = self.file_size.map(|size| format_bytes(size as f64)).unwrap_or_default();
+        cells
+    }
+}
+
+/// Describes a file stored at `addresses`, taking its name and size from the
+/// local manifest if there is one, or else from the first peer that sends its own.
+async fn describe_search_result(node: &NodeContext, file_id: Uuid, addresses: Vec<String>) -> SearchResult {
+    let mut manifest = load_manifest(Path::new(&node.config.storage.storage_path).join(file_id.to_string())).ok();
+    for address in addresses.iter().filter(|a| **a != node.local_peer.address) {
+        if manifest.is_some() {
+            break;
+        }
+        match get_remote_manifest(&Peer::new(address.clone()), &file_id, &node.config).await {
+            Ok(remote) => manifest = Some(remote),
+            Err(e) => error!("Failed to get the manifest of file {} from {}: {}", file_id, address, e),
+        }
+    }
+    let url = addresses.first().and_then(|address| address.parse::<PeerUrl>().ok()).map(|peer| {
+        FileLink {
+            peer,
+            file_id,
+            file_name: manifest.as_ref().map(|m| m.file_name.clone()),
+            file_size: manifest.as_ref().map(|m| m.file_size),
+        }
+        .to_string()
+    });
+    SearchResult {
+        file_id,
+        file_name: manifest.as_ref().map(|m| m.file_name.clone()),
+        file_size: manifest.as_ref().map(|m| m.file_size),
+        seeders: addresses.len(),
+        peers: addresses,
+        url,
+    }
+}
+
+/// A connected peer, as shown by `list-peers`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct PeerEntry {
+    address: String,
+    capability_flags: u32,
+    latency_ms: Option<u64>,
+}
+
+impl Row for PeerEntry {
+    fn headers() -> &'static [&'static str] {
+        &["PEER", "CAPABILITIES", "LATENCY_MS"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.address.clone(),
+            format!("{:#x}", self.capability_flags),
+            self.latency_ms.map(|ms| ms.to_string()).unwrap_or_default(),
+        ]
+    }
+}
+
+/// A bootstrap or connected peer, as shown by `peers --list`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct PeerStatusEntry {
+    address: String,
+    bootstrap: bool,
+    connected: bool,
+}
+
+impl Row for PeerStatusEntry {
+    fn headers() -> &'static [&'static str] {
+        &["PEER", "BOOTSTRAP", "STATUS"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.address.clone(),
+            self.bootstrap.to_string(),
+            if self.connected { "connected" } else { "disconnected" }.to_string(),
+        ]
+    }
+}
+
+/// Traffic with one peer, as shown by `network-stats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct TrafficEntry {
+    peer: String,
+    stats: NetworkStats,
+}
+
+impl Row for TrafficEntry {
+    fn headers() -> &'static [&'static str] {
+        &["PEER", "BYTES_SENT", "BYTES_RECV", "CHUNKS_SENT", "CHUNKS_RECV", "ERRORS"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        let s = &self.stats;
+        vec![
+            self.peer.clone(),
+            s.bytes_sent.to_string(),
+            s.bytes_received.to_string(),
+            s.chunks_sent.to_string(),
+            s.chunks_received.to_string(),
+            s.errors.to_string(),
+        ]
+    }
+}
+
+impl Row for TransferRecord {
+    fn headers() -> &'static [&'static str] {
+        &["ID", "DIRECTION", "FILE_ID", "NAME", "BYTES", "STARTED (UTC)", "STATUS"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.direction.to_string(),
+            self.file_id.to_string(),
+            self.file_name.clone(),
+            self.bytes.to_string(),
+            format_timestamp(self.started_at),
+            self.status.to_string(),
+        ]
+    }
+}
+
+impl Row for ReplicationStatus {
+    fn headers() -> &'static [&'static str] {
+        &["CHUNK", "PEER", "CONFIRMED (UTC)", "VERIFIED"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.chunk_index.to_string(),
+            self.peer_address.clone(),
+            format_timestamp(self.confirmed_at),
+            self.verified.to_string(),
+        ]
+    }
+
+    fn table_cells(&self) -> Vec<String> {
+        let mut cells = self.cells();
+        cells[3] = if self.verified { "yes" } else { "no" }.to_string();
+        cells
+    }
+}
+
+/// Node summary shown by `status`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct StorageStats {
+    connected_peers: usize,
+    dht_files: usize,
+    storage_bytes: u64,
+    node_address: String,
+}
+
+impl Printable for StorageStats {
+    fn print(&self, format: OutputFormat) {
+        match format {
+            OutputFormat::Table => {
+                println!("Connected peers: {}", self.connected_peers);
+                println!("DHT entries (files): {}", self.dht_files);
+                println!("Local storage: {:.2} MB", self.storage_bytes as f64 / (1024.0 * 1024.0));
+                println!("Node address: {}", self.node_address);
+            }
+            OutputFormat::Json => {
+                if let Some(json) = to_json(self) {
+                    println!("{}", json);
+                }
+            }
+            OutputFormat::Csv => println!(
+                "{}",
+                render_csv(
+                    &["CONNECTED_PEERS", "DHT_FILES", "STORAGE_BYTES", "NODE_ADDRESS"],
+                    &[vec![
+                        self.connected_peers.to_string(),
+                        self.dht_files.to_string(),
+                        self.storage_bytes.to_string(),
+                        self.node_address.clone(),
+                    ]],
+                )
+            ),
+        }
+    }
+}
+
+/// Lists stored files using their manifests. Files without a manifest
+/// (e.g. replicas) are listed with an empty name and their on-disk size.
+fn list_files(storage_root: &str) -> Result<Vec<FileEntry>, Box<dyn Error + Send + Sync>> {
+    let mut files = Vec::new();
+    for (file_id, pinned) in list_all_files_with_pinned(storage_root)? {
+        let storage_dir = Path::new(storage_root).join(file_id.to_string());
+        let entry = match load_manifest(&storage_dir) {
+            Ok(manifest) => FileEntry {
+                file_id,
+                file_name: manifest.file_name,
+                file_size: manifest.file_size,
+                total_chunks: manifest.total_chunks,
+                pinned,
+            },
+            Err(_) => FileEntry {
+                file_id,
+                file_name: String::new(),
+                file_size: stored_file_size(storage_root, file_id)?,
+                total_chunks: list_chunks(&storage_dir)?.len(),
+                pinned,
+            },
+        };
+        files.push(entry);
+    }
+    Ok(files)
+}
+
+/// Removes file directories in `storage_root` that have no manifest and that the
+/// DHT no longer references, and DHT entries claiming the local peer stores a
+/// file it has no chunks for. A manifest on disk keeps a file even while the DHT
+/// is still empty after a restart. Pinned files are always kept. With `dry_run`,
+/// orphans are only listed and nothing is modified.
+fn orphan_cleanup(
+    storage_root: &str,
+    dht: &DHT,
+    local_peer: &Peer,
+    dry_run: bool,
+) -> Result<CleanupReport, Box<dyn Error + Send + Sync>> {
+    let mut report = CleanupReport::default();
+    let stored_files = list_all_files_with_pinned(storage_root)?;
+
+    for &(file_id, pinned) in &stored_files {
+        let has_manifest = Path::new(storage_root).join(file_id.to_string()).join(MANIFEST_FILENAME).exists();
+        if has_manifest || dht.get_file_locations(&file_id)?.is_some() {
+            continue;
+        }
+        if pinned {
+            info!("Keeping pinned file {} although the DHT no longer references it", file_id);
+            continue;
+        }
+        let size = stored_file_size(storage_root, file_id)?;
+        if dry_run {
+            println!("Orphaned file {} ({} bytes)", file_id, size);
+        } else {
+            delete_file(storage_root, file_id)?;
+            info!("Deleted orphaned file {} ({} bytes)", file_id, size);
+        }
+        report.files_deleted += 1;
+        report.bytes_freed += size;
+    }
+
+    for (file_id, address) in dht.all_entries()? {
+        if address != local_peer.address {
+            continue;
+        }
+        let storage_dir = std::path::Path::new(storage_root).join(file_id.to_string());
+        let has_chunks = stored_files.iter().any(|(stored, _)| *stored == file_id)
+            && !list_chunks(&storage_dir)?.is_empty();
+        if has_chunks {
+            continue;
+        }
+        if dry_run {
+            println!("Stale DHT entry for file {} at {}", file_id, address);
+        } else {
+            dht.deregister_file_location(&file_id, &address)?;
+        }
+        report.dht_entries_cleaned += 1;
+    }
+
+    Ok(report)
+}
+
+/// Writes the DHT as pretty-printed JSON to `output`, or to stdout without
+/// one. Returns the number of files written.
+fn dump_dht(node: &NodeContext, output: Option<&Path>) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let storage_root = Path::new(&node.config.storage.storage_path);
+    let dump = DhtDump::new(node.dht.snapshot()?, |file_id| {
+        let manifest = load_manifest(storage_root.join(file_id.to_string())).ok()?;
+        Some(DhtFileMetadata { file_name: manifest.file_name, file_size: manifest.file_size, total_chunks: manifest.total_chunks })
+    });
+    let encoded = json::to_string_pretty(&dump)?;
+    match output {
+        Some(path) => std::fs::write(path, encoded + "\n")?,
+        None => println!("{}", encoded),
+    }
+    Ok(dump.entries.len())
+}
+
+/// Replaces the DHT with the file locations in a [`dump_dht`] file; the
+/// metadata is ignored. Returns the number of files restored.
+fn import_dht(dht: &DHT, input: &Path) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let dump: DhtDump = json::from_str(&std::fs::read_to_string(input)?)?;
+    dht.restore(dump.into())?;
+    Ok(dht.file_count()?)
+}
+
+/// Moves every file stored under `old_root` into the node's storage and
+/// registers the local peer as its location. Returns the number of files moved.
+fn migrate_storage(node: &NodeContext, old_root: &Path) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let new_root = Path::new(&node.config.storage.storage_path);
+    let file_ids = list_all_files(old_root)?;
+    for file_id in &file_ids {
+        move_chunks(old_root, new_root, *file_id)?;
+        node.dht.register_file_location(*file_id, node.local_peer.clone())?;
+        info!("Migrated file {} to {}", file_id, new_root.display());
+    }
+    Ok(file_ids.len())
+}
+
+async fn download_file(
+    node: &NodeContext,
+    file_id_str: &str,
+    destination: &Path,
+    peers: &[Peer],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let started_at = Instant::now();
+    let file_id = Uuid::parse_str(file_id_str)?;
+    node.emit(TelemetryEvent::DownloadStarted { file_id });
+    let result = download_chunks_and_write(node, file_id, destination, peers).await;
+    match &result {
+        Ok(bytes) => {
+            report_transfer_speed(node, TransferDirection::Download, *bytes, started_at.elapsed());
+            node.emit(TelemetryEvent::DownloadFinished { file_id, bytes: *bytes, duration: started_at.elapsed() });
+        }
+        Err(e) => node.emit(TelemetryEvent::Error { context: format!("download {}", file_id), message: e.to_string() }),
+    }
+    result.map(|_| ())
+}
+
+/// The body of [`download_file`]; returns the size of the downloaded file.
+async fn download_chunks_and_write(
+    node: &NodeContext,
+    file_id: Uuid,
+    destination: &Path,
+    peers: &[Peer],
+) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    let storage_root = node.config.storage.storage_path.as_str();
+    let peer_addresses = node.dht.get_file_locations(&file_id)?.ok_or("File not found in DHT")?;
+
+    let storage_dir = initialize_storage(storage_root, file_id)?;
+    let manifest = match load_manifest(&storage_dir) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            if !matches!(e, StorageError::ManifestNotFound(_)) {
+                error!("Fetching the manifest of {} again; the local copy is unreadable: {}", file_id, e);
+            }
+            let manifest = fetch_remote_manifest(node, &file_id, &peer_addresses).await?;
+            save_manifest(&storage_dir, &manifest)?;
+            manifest
+        }
+    };
+    let record = start_record(&node.history, TransferDirection::Download, &manifest);
+    let download = resume_or_start_download(node, &storage_dir, &manifest);
+
+    let result = async {
+        fetch_batched(node, &storage_dir, &manifest, &peer_addresses, &download).await;
+        let prefetch = prefetch_queue(node, &storage_dir, &manifest, &peer_addresses, &download);
+        fetch_missing_chunks(&storage_dir, manifest.total_chunks, &peer_addresses, |peer, chunk_index| {
+            let storage_dir = storage_dir.clone();
+            let (prefetch, download) = (&prefetch, &download);
+            async move {
+                let prefetched = matches!(prefetch.wait_for(chunk_index).await, Some(Ok(())));
+                if !(prefetched && chunk_exists(&storage_dir, chunk_index)) {
+                    fetch_chunk_from_peer(&peer, &storage_dir, file_id, chunk_index, &node.config).await?;
+                    record_download_progress(file_id, download.chunk_fetched(chunk_index));
+                    node.emit(TelemetryEvent::ChunkDownloaded { file_id, chunk_index, from_peer: peer.address.clone() });
+                }
+                prefetch.advance(chunk_index);
+                Ok(())
+            }
+        })
+        .await?;
+
+        if !verify_file_hash(&storage_dir, &manifest)? {
+            return Err(StorageError::FileHashMismatch(file_id).into());
+        }
+
+        if manifest.kind == FileKind::Directory {
+            return download_directory(node, &storage_dir, &manifest, destination, peers).await;
+        }
+
+        Ok(merge_chunks_to_file(&storage_dir, &manifest, destination).await?)
+    }
+    .await;
+
+    finish_record(&node.history, record, &result);
+    result?;
+    record_download_progress(file_id, download.finish());
+    Ok(manifest.file_size)
+}
+
+/// The progress record of a download of `manifest`: the one an interrupted
+/// download of the file left, or a new one listing the chunks already stored.
+/// Stored chunks a resumed record does not list may have been cut off
+/// mid-write, so they are removed and fetched again.
+fn resume_or_start_download(node: &NodeContext, storage_dir: &Path, manifest: &FileManifest) -> WatchedDownload {
+    let storage_root = Path::new(&node.config.storage.storage_path);
+    let (file_id, total_chunks) = (manifest.file_id, manifest.total_chunks);
+    let previous = WatchedDownload::load(storage_root, &file_id).unwrap_or_else(|e| {
+        error!("Starting the download of {} over; its progress record is unreadable: {}", file_id, e);
+        None
+    });
+    let state = match previous {
+        Some(state) if state.total_chunks == total_chunks => {
+            let backend = FileSystemBackend::new(storage_root);
+            for chunk_index in (0..total_chunks).filter(|i| !state.fetched.contains(i) && chunk_exists(storage_dir, *i)) {
+                if let Err(e) = backend.delete_chunk(file_id, chunk_index) {
+                    error!("Failed to remove unrecorded chunk {} of {}: {}", chunk_index, file_id, e);
+                }
+            }
+            node.emit(TelemetryEvent::DownloadResumed { file_id, fetched: state.fetched.len(), total_chunks });
+            state
+        }
+        _ => DownloadState {
+            fetched: (0..total_chunks).filter(|&i| chunk_exists(storage_dir, i)).collect(),
+            ..DownloadState::new(file_id, total_chunks)
+        },
+    };
+    let download = WatchedDownload::new(storage_root, state);
+    record_download_progress(file_id, download.save());
+    download
+}
+
+/// Like [`record_progress`]: a lost record only means refetching chunks.
+fn record_download_progress(file_id: Uuid, result: Result<(), DownloadStateError>) {
+    if let Err(e) = result {
+        error!("Failed to record progress of download {}: {}", file_id, e);
+    }
+}
+
+/// Restores a file from a manifest exported on another node. The manifest is
+/// saved locally and the local peer registered for the file, so other nodes
+/// see its interest, before the chunks are downloaded like any other file;
+/// the registration is withdrawn again if the download fails.
+async fn import_file(
+    node: &NodeContext,
+    manifest_path: &Path,
+    destination: &Path,
+    peers: &[Peer],
+) -> Result<Uuid, Box<dyn Error + Send + Sync>> {
+    let manifest: FileManifest = json::from_str(&tokio::fs::read_to_string(manifest_path).await?)?;
+    let file_id = manifest.file_id;
+    let storage_dir = initialize_storage(&node.config.storage.storage_path, file_id)?;
+    save_manifest(&storage_dir, &manifest)?;
+    node.dht.register_file_location(file_id, node.local_peer.clone())?;
+
+    if let Err(e) = download_file(node, &file_id.to_string(), destination, peers).await {
+        node.dht.deregister_file_location(&file_id, &node.local_peer.address)?;
+        return Err(e);
+    }
+    node.dht.register_file_location_with_chunks(file_id, node.local_peer.clone(), manifest.total_chunks)?;
+    Ok(file_id)
+}
+
+/// Fetches as many missing chunks as possible with one batch connection
+/// per peer, in DHT order. Whatever is still missing afterwards is retried
+/// chunk by chunk by `fetch_missing_chunks`.
+async fn fetch_batched(node: &NodeContext, storage_dir: &Path, manifest: &FileManifest, peers: &[Peer], download: &WatchedDownload) {
+    for peer in peers.iter().filter(|p| p.address != node.local_peer.address) {
+        let missing: Vec<usize> = (0..manifest.total_chunks).filter(|&i| !chunk_exists(storage_dir, i)).collect();
+        if missing.is_empty() {
+            return;
+        }
+        match fetch_chunks_batch(peer, &manifest.file_id, &missing, storage_dir, &node.config).await {
+            Ok(batch) => {
+                info!("Fetched {} of {} missing chunks from {}", batch.fetched.len(), missing.len(), peer);
+                for chunk_index in batch.fetched {
+                    record_download_progress(manifest.file_id, download.chunk_fetched(chunk_index));
+                    node.emit(TelemetryEvent::ChunkDownloaded {
+                        file_id: manifest.file_id,
+                        chunk_index,
+                        from_peer: peer.address.clone(),
+                    });
+                }
+            }
+            Err(e) => error!("Batch fetch from {} failed: {}", peer, e),
+        }
+    }
+}
+
+/// Prefetches chunks from the first peer, in DHT order, that has them.
+fn prefetch_queue(
+    node: &NodeContext,
+    storage_dir: &Path,
+    manifest: &FileManifest,
+    peers: &[Peer],
+    download: &WatchedDownload,
+) -> PrefetchQueue {
+    let peers: Arc<Vec<Peer>> = Arc::new(peers.iter().filter(|p| p.address != node.local_peer.address).cloned().collect());
+    let storage_dir = storage_dir.to_path_buf();
+    let file_id = manifest.file_id;
+    let config = node.config.clone();
+    let (events, download) = (node.events.clone(), download.clone());
+    PrefetchQueue::new(node.config.transfer.prefetch_lookahead, manifest.total_chunks, move |chunk_index| {
+        let (peers, storage_dir, config, events) = (peers.clone(), storage_dir.clone(), config.clone(), events.clone());
+        let download = download.clone();
+        async move {
+            if chunk_exists(&storage_dir, chunk_index) {
+                return Ok(());
+            }
+            let mut last_error = String::from("no peers");
+            for peer in peers.iter() {
+                match fetch_chunk_from_peer(peer, &storage_dir, file_id, chunk_index, &config).await {
+                    Ok(()) => {
+                        record_download_progress(file_id, download.chunk_fetched(chunk_index));
+                        let from_peer = peer.address.clone();
+                        events::emit(&events, TelemetryEvent::ChunkDownloaded { file_id, chunk_index, from_peer });
+                        return Ok(());
+                    }
+                    Err(e) => last_error = e.to_string(),
+                }
+            }
+            Err(ConnectionError::Session(format!("prefetch of chunk {} failed: {}", chunk_index, last_error)))
+        }
+    })
+}
+
+/// Asks each peer for the manifest, in DHT order, until one has it.
+async fn fetch_remote_manifest(
+    node: &NodeContext,
+    file_id: &Uuid,
+    peers: &[Peer],
+) -> Result<FileManifest, Box<dyn Error + Send + Sync>> {
+    for peer in peers.iter().filter(|p| p.address != node.local_peer.address) {
+        match get_remote_manifest(peer, file_id, &node.config).await {
+            Ok(manifest) => return Ok(manifest),
+            Err(e) => error!("Failed to get manifest of file={} from {}: {}", file_id, peer, e),
+        }
+    }
+    Err(format!("No peer could provide the manifest of file {}", file_id).into())
+}
+
+/// Restores a directory upload: parses its `DirManifest` and downloads
+/// every entry below `destination`.
+async fn download_directory(
+    node: &NodeContext,
+    storage_dir: &Path,
+    manifest: &FileManifest,
+    destination: &Path,
+    peers: &[Peer],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut data = Vec::new();
+    for i in 0..manifest.total_chunks {
+        data.extend(get_chunk(storage_dir, i)?);
+    }
+    let dir_manifest: DirManifest = json::from_str(std::str::from_utf8(&data)?)?;
+
+    for entry in &dir_manifest.entries {
+        if !entry.is_safe_path() {
+            return Err(format!("Refusing to restore unsafe path {}", entry.relative_path.display()).into());
+        }
+        let target = destination.join(&entry.relative_path);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Box::pin(download_file(node, &entry.file_id.to_string(), &target, peers)).await?;
+    }
+
+    Ok(())
+}
+
+/// Reassembles a stored file from local chunks only, writing it to `output`
+/// or to stdout for `-`. The chunks are checked against the manifest's Merkle
+/// root before anything is written. Chunks are stored unencrypted, so there
+/// is nothing to decrypt. Returns the number of bytes written.
+async fn export_file(storage_root: &Path, file_id: Uuid, output: &str) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    let storage_dir = storage_root.join(file_id.to_string());
+    let manifest = load_manifest(&storage_dir)?;
+    let mut hashes = Vec::with_capacity(manifest.total_chunks);
+    for i in 0..manifest.total_chunks {
+        let data = get_chunk(&storage_dir, i).map_err(|e| format!("Chunk {} of file {} is unreadable: {}", i, file_id, e))?;
+        hashes.push(manifest.hash_algorithm.digest(&data));
+    }
+    match manifest.merkle_root {
+        Some(root) if MerkleTree::from_hashes(manifest.hash_algorithm, &hashes).root() != root => {
+            let damaged = find_damaged_chunks(&storage_dir, manifest.total_chunks)?;
+            return Err(format!("File {} does not match its Merkle root; damaged chunks: {:?}", file_id, damaged).into());
+        }
+        Some(_) => {}
+        None => info!("Manifest of {} has no Merkle root; exporting unverified", manifest),
+    }
+
+    let mut writer: Box<dyn tokio::io::AsyncWrite + Unpin + Send> = if output == "-" {
+        Box::new(tokio::io::stdout())
+    } else {
+        Box::new(File::create(output).await?)
+    };
+    let mut written = 0;
+    for (i, expected) in hashes.iter().enumerate() {
+        let data = get_chunk(&storage_dir, i)?;
+        // Re-read, so check again in case the chunk changed since the scan.
+        if manifest.hash_algorithm.digest(&data) != *expected {
+            return Err(format!("Chunk {} of file {} changed during export", i, file_id).into());
+        }
+        writer.write_all(&data).await?;
+        written += data.len() as u64;
+    }
+    writer.flush().await?;
+    Ok(written)
+}
+
+/// Fetches every chunk in `0..total_chunks` that is not already in `storage_dir`,
+/// trying each peer in turn for up to `CHUNK_FETCH_RETRIES` passes per chunk.
+async fn fetch_missing_chunks<F, Fut>(
+    storage_dir: &Path,
+    total_chunks: usize,
+    peers: &[Peer],
+    mut fetch: F,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    F: FnMut(Peer, usize) -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn Error + Send + Sync>>>,
+{
+    let missing: Vec<usize> = (0..total_chunks).filter(|&i| !chunk_exists(storage_dir, i)).collect();
+
+    for chunk_index in missing {
+        let mut fetched = false;
+        'retry: for attempt in 1..=CHUNK_FETCH_RETRIES {
+            for peer in peers {
+                match fetch(peer.clone(), chunk_index).await {
+                    Ok(()) => {
+                        fetched = true;
+                        break 'retry;
+                    }
+                    Err(e) => error!(
+                        "Attempt {} to fetch chunk={} from {} failed: {}",
+                        attempt, chunk_index, peer, e
+                    ),
+                }
+            }
+        }
+        if !fetched {
+            return Err(format!("Chunk {} could not be fetched from any peer", chunk_index).into());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_manager::chunker::ChunkMetadata;
+    use crate::file_manager::hash::blake3;
+    use crate::file_manager::merkle::MerkleProof;
+    use crate::peer::access_control::{AclStore, NodeKeyStore};
+    use crate::peer::connection::{request_chunk, ChunkFetchError};
+    use crate::peer::session::{PeerCapabilities, PeerSession};
+    use crate::peer::transport;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_fetch_missing_chunks_skips_present_chunks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_id = Uuid::new_v4();
+        let storage_dir = initialize_storage(temp_dir.path(), file_id).unwrap();
+
+        let present = [5, 23, 47];
+        for &i in &present {
+            save_chunk(&storage_dir, &ChunkMetadata::new(file_id, i, 5, 50), b"Chunk").unwrap();
+        }
+
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let peers = vec![Peer::new("127.0.0.1:8081")];
+        fetch_missing_chunks(&storage_dir, 50, &peers, |_peer, chunk_index| {
+            let requested = requested.clone();
+            async move {
+                requested.lock().unwrap().push(chunk_index);
+                Ok(())
+            }
+        })
+        .await
+        .unwrap();
+
+        let requested = requested.lock().unwrap();
+        let expected: Vec<usize> = (0..50).filter(|i| !present.contains(i)).collect();
+        assert_eq!(*requested, expected);
+    }
+
+    fn test_config(storage_path: &Path) -> Config {
+        let mut config = Config::with_storage_path(storage_path);
+        config.transfer.download_write_buffer_bytes = 1024;
+        config.transfer.default_chunk_size = 1024;
+        config
+    }
+
+    #[tokio::test]
+    async fn test_upload_and_download_directory() {
+        let source = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(source.path().join("docs/nested")).unwrap();
+        std::fs::write(source.path().join("top.txt"), b"top level").unwrap();
+        std::fs::write(source.path().join("docs/nested/deep.bin"), vec![7u8; 3000]).unwrap();
+
+        let storage = tempfile::tempdir().unwrap();
+        let node = NodeContext {
+            config: test_config(storage.path()),
+            dht: DHT::new(),
+            local_peer: Peer::new("127.0.0.1:8080"),
+            history: HistoryStore::open(storage.path()),
+            network_stats: SharedNetworkStats::default(),
+            latency: PeerLatency::default(),
+            peer_load: PeerLoad::default(),
+            replication_status: ReplicationStatusStore::open(storage.path()),
+            uploads: WatchedUploader::open(storage.path()),
+            reputation: ReputationStore::default(),
+            events: events::channel(),
+        };
+        let queue = Arc::new(ReplicationQueue::new(node.replication(), 1));
+        // Unreachable peers: replication fails per chunk but the upload itself succeeds.
+        let peers = vec![Peer::new("127.0.0.1:1"), Peer::new("127.0.0.1:2")];
+
+        let dir_id = upload_directory(&node, source.path(), &peers, 1024, &queue).await.unwrap();
+
+        let destination = tempfile::tempdir().unwrap();
+        download_file(&node, &dir_id.to_string(), destination.path(), &peers)
+            .await
+            .unwrap();
+        // Two files plus the directory manifest, each uploaded and downloaded once.
+        let records = node.history.recent(10).unwrap();
+        assert_eq!(records.len(), 6);
+        assert!(records.iter().all(|r| r.status == TransferStatus::Completed));
+
+        assert_eq!(std::fs::read(destination.path().join("top.txt")).unwrap(), b"top level");
+        assert_eq!(std::fs::read(destination.path().join("docs/nested/deep.bin")).unwrap(), vec![7u8; 3000]);
+    }
+
+    #[tokio::test]
+    async fn test_transfers_emit_telemetry_events() {
+        let source = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(source.path(), vec![3u8; 2500]).unwrap();
+        let storage = tempfile::tempdir().unwrap();
+        let node = NodeContext {
+            config: test_config(storage.path()),
+            dht: DHT::new(),
+            local_peer: Peer::new("127.0.0.1:8080"),
+            history: HistoryStore::open(storage.path()),
+            network_stats: SharedNetworkStats::default(),
+            latency: PeerLatency::default(),
+            peer_load: PeerLoad::default(),
+            replication_status: ReplicationStatusStore::open(storage.path()),
+            uploads: WatchedUploader::open(storage.path()),
+            reputation: ReputationStore::default(),
+            events: events::channel(),
+        };
+        let queue = Arc::new(ReplicationQueue::new(node.replication(), 1));
+        let mut received = node.events.subscribe();
+
+        let peers = vec![Peer::new("127.0.0.1:1"), Peer::new("127.0.0.1:2")];
+        let file_id = upload_file(&node, source.path(), &peers, 1024, &queue).await.unwrap();
+        let missing = Uuid::new_v4();
+        assert!(download_file(&node, &missing.to_string(), storage.path(), &[]).await.is_err());
+
+        let mut transfers = TransferProgress::default();
+        let mut rendered = Vec::new();
+        while let Ok(event) = received.try_recv() {
+            rendered.push(render_event(&event, &mut transfers));
+        }
+        assert_eq!(rendered.len(), 7, "{:?}", rendered);
+        assert_eq!(rendered[0], format!("Uploading {} as {} (3 chunks)", source.path().display(), file_id));
+        let mut progress = rendered[1..4].to_vec();
+        progress.sort();
+        assert_eq!(progress, (1..=3).map(|done| format!("Upload {}: {}/3 chunks", file_id, done)).collect::<Vec<_>>());
+        assert!(rendered[4].starts_with(&format!("Upload {} finished: ", file_id)), "{}", rendered[4]);
+        assert_eq!(rendered[5], format!("Downloading {}", missing));
+        assert_eq!(rendered[6], format!("Failed to download {}: File not found in DHT", missing));
+        assert!(transfers.uploads.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_streaming_upload_writes_chunks_and_manifest() {
+        let source = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..6500u32).map(|i| (i % 253) as u8).collect();
+        std::fs::write(source.path(), &content).unwrap();
+
+        let storage = tempfile::tempdir().unwrap();
+        let mut config = test_config(storage.path());
+        config.transfer.max_concurrent_uploads = 1;
+        let node = NodeContext {
+            config,
+            dht: DHT::new(),
+            local_peer: Peer::new("127.0.0.1:8080"),
+            history: HistoryStore::open(storage.path()),
+            network_stats: SharedNetworkStats::default(),
+            latency: PeerLatency::default(),
+            peer_load: PeerLoad::default(),
+            replication_status: ReplicationStatusStore::open(storage.path()),
+            uploads: WatchedUploader::open(storage.path()),
+            reputation: ReputationStore::default(),
+            events: events::channel(),
+        };
+        let queue = Arc::new(ReplicationQueue::new(node.replication(), 1));
+        let peers = vec![Peer::new("127.0.0.1:1"), Peer::new("127.0.0.1:2")];
+
+        let file_id = upload_file(&node, source.path(), &peers, 1024, &queue).await.unwrap();
+
+        let storage_dir = storage.path().join(file_id.to_string());
+        let (_, expected) = split_bytes_into_chunks(&content, 1024);
+        let manifest = load_manifest(&storage_dir).unwrap();
+        assert_eq!(manifest.total_chunks, 7);
+        assert_eq!(manifest.file_size, content.len() as u64);
+        assert_eq!(manifest.merkle_root, Some(merkle_root(HashAlgorithm::Blake3, &expected)));
+        assert_eq!(list_chunks(&storage_dir).unwrap(), (0..7).collect::<Vec<_>>());
+        assert_eq!(get_chunk(&storage_dir, 6).unwrap(), expected[6].1);
+        assert_eq!(node.dht.get_file_locations(&file_id).unwrap(), Some(vec![node.local_peer.clone()]));
+    }
+
+    #[tokio::test]
+    async fn test_resume_upload_skips_finished_chunks() {
+        let content: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let source = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(source.path(), &content).unwrap();
+
+        let storage = tempfile::tempdir().unwrap();
+        let node = NodeContext {
+            config: test_config(storage.path()),
+            dht: DHT::new(),
+            local_peer: Peer::new("127.0.0.1:8080"),
+            history: HistoryStore::open(storage.path()),
+            network_stats: SharedNetworkStats::default(),
+            latency: PeerLatency::default(),
+            peer_load: PeerLoad::default(),
+            replication_status: ReplicationStatusStore::open(storage.path()),
+            uploads: WatchedUploader::open(storage.path()),
+            reputation: ReputationStore::default(),
+            events: events::channel(),
+        };
+        let queue = Arc::new(ReplicationQueue::new(node.replication(), 1));
+
+        // A single peer is too few to replicate to, so the upload fails and stays pending.
+        assert!(upload_file(&node, source.path(), &[Peer::new("127.0.0.1:1")], 1024, &queue).await.is_err());
+        let mut pending = node.uploads.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].path, std::fs::canonicalize(source.path()).unwrap());
+
+        // As if chunk 0 had been replicated before the process died.
+        let mut upload = pending.remove(0);
+        upload.chunks_saved = [0].into();
+        upload.replicated_to = [(0, vec!["127.0.0.1:3".to_string()])].into();
+        node.uploads.start(upload.clone()).unwrap();
+
+        let peers = vec![Peer::new("127.0.0.1:1"), Peer::new("127.0.0.1:2")];
+        let file_id = resume_upload(&node, &upload, &peers, &queue).await.unwrap();
+        assert_eq!(file_id, upload.file_id);
+        assert!(node.uploads.pending().unwrap().is_empty());
+        // Only chunks 1 and 2 were offered, each to both peers.
+        let errors: u64 = node.network_stats.read().unwrap().values().map(|s| s.errors).sum();
+        assert_eq!(errors, 4);
+
+        let storage_dir = storage.path().join(file_id.to_string());
+        let manifest = load_manifest(&storage_dir).unwrap();
+        assert_eq!(manifest.total_chunks, 3);
+        assert_eq!(manifest.file_hash, Some(blake3(&content)));
+        assert_eq!(get_chunk(&storage_dir, 2).unwrap(), &content[2048..]);
+    }
+
+    #[tokio::test]
+    async fn test_resumed_download_refetches_unrecorded_chunks() {
+        let content: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let source = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(source.path(), &content).unwrap();
+
+        let storage = tempfile::tempdir().unwrap();
+        let node = NodeContext {
+            config: test_config(storage.path()),
+            dht: DHT::new(),
+            local_peer: Peer::new("127.0.0.1:8080"),
+            history: HistoryStore::open(storage.path()),
+            network_stats: SharedNetworkStats::default(),
+            latency: PeerLatency::default(),
+            peer_load: PeerLoad::default(),
+            replication_status: ReplicationStatusStore::open(storage.path()),
+            uploads: WatchedUploader::open(storage.path()),
+            reputation: ReputationStore::default(),
+            events: events::channel(),
+        };
+        let queue = Arc::new(ReplicationQueue::new(node.replication(), 1));
+        let peers = vec![Peer::new("127.0.0.1:1"), Peer::new("127.0.0.1:2")];
+        let file_id = upload_file(&node, source.path(), &peers, 1024, &queue).await.unwrap();
+        let storage_dir = storage.path().join(file_id.to_string());
+
+        // As if the process died while writing chunk 2.
+        let mut state = DownloadState::new(file_id, 3);
+        state.fetched = [0, 1].into();
+        WatchedDownload::new(storage.path(), state).save().unwrap();
+        std::fs::write(storage_dir.join("chunk_2.bin"), &content[2048..2100]).unwrap();
+
+        let mut received = node.events.subscribe();
+        let destination = tempfile::tempdir().unwrap();
+        let destination = destination.path().join("out.bin");
+        assert!(download_file(&node, &file_id.to_string(), &destination, &peers).await.is_err());
+        assert!(!chunk_exists(&storage_dir, 2));
+        let state = WatchedDownload::load(storage.path(), &file_id).unwrap().unwrap();
+        assert_eq!(state.fetched, [0, 1].into());
+        let mut progress = TransferProgress::default();
+        received.try_recv().unwrap();
+        let resumed = render_event(&received.try_recv().unwrap(), &mut progress);
+        assert_eq!(resumed, format!("Resuming download {}: 2/3 chunks already fetched", file_id));
+
+        save_chunk(&storage_dir, &ChunkMetadata::new(file_id, 2, 952, 3), &content[2048..]).unwrap();
+        WatchedDownload::new(storage.path(), DownloadState { fetched: [0, 1, 2].into(), ..state }).save().unwrap();
+        download_file(&node, &file_id.to_string(), &destination, &peers).await.unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), content);
+        assert_eq!(WatchedDownload::load(storage.path(), &file_id).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_sync_seed_directory_follows_changes() {
+        let source = tempfile::tempdir().unwrap();
+        std::fs::create_dir(source.path().join("nested")).unwrap();
+        std::fs::write(source.path().join("a.txt"), b"first version").unwrap();
+        std::fs::write(source.path().join("nested/b.bin"), vec![5u8; 3000]).unwrap();
+
+        let storage = tempfile::tempdir().unwrap();
+        let node = NodeContext::new(test_config(storage.path()), DHT::new(), Peer::new("127.0.0.1:8080"), SharedNetworkStats::default(), PeerLatency::default());
+        let queue = Arc::new(ReplicationQueue::new(node.replication(), 1));
+        // Unreachable peers: replication fails per chunk but the uploads succeed.
+        let peers = PeerRegistry::default();
+        peers.add(Peer::new("127.0.0.1:1"));
+        peers.add(Peer::new("127.0.0.1:2"));
+        let mut index = SeedIndex::open(storage.path()).unwrap();
+
+        let sync = sync_seed_directory(&node, source.path(), &mut index, &peers, &queue).await.unwrap();
+        assert_eq!(sync, SeedSync { uploaded: 2, removed: 0, failed: 0 });
+        assert_eq!(seed_totals(&index), (2, 13 + 3000));
+        let a_path = std::fs::canonicalize(source.path().join("a.txt")).unwrap();
+        let first_id = index.get(&a_path).unwrap().file_id;
+        assert_eq!(sync_seed_directory(&node, source.path(), &mut index, &peers, &queue).await.unwrap(), SeedSync::default());
+
+        std::fs::write(&a_path, b"second, longer version").unwrap();
+        std::fs::remove_file(source.path().join("nested/b.bin")).unwrap();
+        let sync = sync_seed_directory(&node, source.path(), &mut index, &peers, &queue).await.unwrap();
+        assert_eq!(sync, SeedSync { uploaded: 1, removed: 1, failed: 0 });
+        assert!(!storage.path().join(first_id.to_string()).exists());
+        assert_eq!(node.dht.get_file_locations(&first_id).unwrap().unwrap_or_default(), vec![]);
+
+        let index = SeedIndex::open(storage.path()).unwrap();
+        let seeded: Vec<&SeededFile> = index.files().collect();
+        assert_eq!(seeded.len(), 1);
+        assert_eq!((seeded[0].path.as_path(), seeded[0].size), (a_path.as_path(), 22));
+        assert!(storage.path().join(seeded[0].file_id.to_string()).exists());
+    }
+
+    #[tokio::test]
+    async fn test_search_result_describes_remote_file() {
+        let remote_root = tempfile::tempdir().unwrap();
+        let (manifest, _) = store_remote_file(remote_root.path(), b"search me");
+        let peer = spawn_remote_peer(remote_root.path()).await;
+
+        let storage = tempfile::tempdir().unwrap();
+        let node = NodeContext {
+            config: test_config(storage.path()),
+            dht: DHT::new(),
+            local_peer: Peer::new("127.0.0.1:8080"),
+            history: HistoryStore::open(storage.path()),
+            network_stats: SharedNetworkStats::default(),
+            latency: PeerLatency::default(),
+            peer_load: PeerLoad::default(),
+            replication_status: ReplicationStatusStore::open(storage.path()),
+            uploads: WatchedUploader::open(storage.path()),
+            reputation: ReputationStore::default(),
+            events: events::channel(),
+        };
+        // The first peer is unreachable, so the manifest comes from the second.
+        let addresses = vec!["127.0.0.1:1".to_string(), peer.address.clone()];
+        let result = describe_search_result(&node, manifest.file_id, addresses.clone()).await;
+        assert_eq!(result.file_name.as_deref(), Some("data.bin"));
+        assert_eq!(result.file_size, Some(9));
+        assert_eq!(result.seeders, 2);
+        assert_eq!(result.peers, addresses);
+        assert_eq!(
+            result.url.unwrap(),
+            format!("sharesphere://127.0.0.1:1/{}?name=data.bin&size=9", manifest.file_id)
+        );
+    }
+
+    /// Stores `content` under `storage_root` as a manifest plus chunks and returns the manifest and chunks.
+    fn store_remote_file(storage_root: &Path, content: &[u8]) -> (FileManifest, Vec<Chunk>) {
+        let (file_id, chunks) = split_bytes_into_chunks(content, 1024);
+        let mut manifest = FileManifest::new(file_id, "data.bin".to_string(), content.len() as u64, 1024, chunks.len());
+        manifest.merkle_root = Some(merkle_root(manifest.hash_algorithm, &chunks));
+        manifest.file_hash = Some(manifest.hash_algorithm.digest(content));
+        store_remote_file_as(storage_root, &manifest, &chunks);
+        (manifest, chunks)
+    }
+
+    fn store_remote_file_as(storage_root: &Path, manifest: &FileManifest, chunks: &[Chunk]) {
+        let storage_dir = initialize_storage(storage_root, manifest.file_id).unwrap();
+        for (metadata, data) in chunks {
+            save_chunk(&storage_dir, metadata, data).unwrap();
+        }
+        save_manifest(&storage_dir, manifest).unwrap();
+    }
+
+    /// Serves `storage_root` through `serve_incoming` on a local port.
+    async fn spawn_remote_peer(storage_root: &Path) -> Peer {
+        spawn_remote_peer_with(Config::with_storage_path(storage_root)).await
+    }
+
+    async fn spawn_remote_peer_with(config: Config) -> Peer {
+        use crate::peer::connection::serve_incoming;
+        use crate::peer::rate_limit::ConnectionLimiter;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = Peer::new(listener.local_addr().unwrap().to_string());
+        let limiter = ConnectionLimiter::new(100, 100);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve_incoming(
+                    stream,
+                    limiter.clone(),
+                    config.clone(),
+                    PeerRegistry::default(),
+                    DHT::new(),
+                    Peer::new("127.0.0.1:0"),
+                    SharedNetworkStats::default(),
+                ));
+            }
+        });
+        peer
+    }
+
+    #[tokio::test]
+    async fn test_fetch_chunk_verifies_merkle_proof() {
+        let remote_root = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let (manifest, chunks) = store_remote_file(remote_root.path(), &content);
+        let file_id = manifest.file_id;
+        let peer = spawn_remote_peer(remote_root.path()).await;
+
+        let local_root = tempfile::tempdir().unwrap();
+        let local_dir = initialize_storage(local_root.path(), file_id).unwrap();
+        save_manifest(&local_dir, &manifest).unwrap();
+
+        fetch_chunk_from_peer(&peer, &local_dir, file_id, 3, &test_config(local_root.path())).await.unwrap();
+        assert_eq!(get_chunk(&local_dir, 3).unwrap(), chunks[3].1);
+
+        // Corrupt the remote copy of chunk 2; its recorded hash no longer matches the data.
+        let remote_dir = remote_root.path().join(file_id.to_string());
+        std::fs::write(remote_dir.join("chunk_2.bin"), vec![0u8; 1024]).unwrap();
+        assert!(fetch_chunk_from_peer(&peer, &local_dir, file_id, 2, &test_config(local_root.path())).await.is_err());
+        assert!(!chunk_exists(&local_dir, 2));
+    }
+
+    #[tokio::test]
+    async fn test_scan_and_repair_refetches_damaged_chunks() {
+        let remote_root = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..4000u32).map(|i| (i % 241) as u8).collect();
+        let (manifest, chunks) = store_remote_file(remote_root.path(), &content);
+        let file_id = manifest.file_id;
+        let peer = spawn_remote_peer(remote_root.path()).await;
+
+        let local_root = tempfile::tempdir().unwrap();
+        store_remote_file_as(local_root.path(), &manifest, &chunks);
+        let local_dir = local_root.path().join(file_id.to_string());
+        std::fs::write(local_dir.join("chunk_1.bin"), vec![7u8; 1024]).unwrap();
+        std::fs::remove_file(local_dir.join("chunk_3.bin")).unwrap();
+
+        let config = test_config(local_root.path());
+        let dht = DHT::new();
+        let report = scan_and_repair(local_root.path(), file_id, &dht, &config).await.unwrap();
+        assert_eq!(report, RepairReport { chunks_checked: 4, corrupted_found: 2, repaired: 0, unrepaired: 2 });
+
+        dht.register_file_location(file_id, peer).unwrap();
+        let report = scan_and_repair(local_root.path(), file_id, &dht, &config).await.unwrap();
+        assert_eq!(report, RepairReport { chunks_checked: 4, corrupted_found: 2, repaired: 2, unrepaired: 0 });
+        assert_eq!(get_chunk(&local_dir, 1).unwrap(), chunks[1].1);
+        assert_eq!(get_chunk(&local_dir, 3).unwrap(), chunks[3].1);
+        assert_eq!(verify_file(local_root.path(), file_id).unwrap().corrupted_found, 0);
+    }
+
+    #[tokio::test]
+    async fn test_send_file_manifest_stores_manifest_on_peer() {
+        use crate::peer::connection::send_file_manifest;
+
+        let remote_root = tempfile::tempdir().unwrap();
+        let peer = spawn_remote_peer(remote_root.path()).await;
+        let mut manifest = FileManifest::new(Uuid::new_v4(), "notes.txt".to_string(), 3000, 1024, 3);
+        manifest.merkle_root = Some([7u8; 32]);
+
+        let config = test_config(remote_root.path());
+        send_file_manifest(&peer, &manifest, &config).await.unwrap();
+        let stored = load_manifest(remote_root.path().join(manifest.file_id.to_string())).unwrap();
+        assert_eq!(stored, manifest);
+        assert_eq!(get_remote_manifest(&peer, &manifest.file_id, &config).await.unwrap(), manifest);
+
+        // The same manifest again, or with only its name changed, is stored.
+        send_file_manifest(&peer, &manifest, &config).await.unwrap();
+        let renamed = FileManifest { file_name: "renamed.txt".to_string(), ..manifest.clone() };
+        send_file_manifest(&peer, &renamed, &config).await.unwrap();
+
+        // Another root or key would substitute the file or lose its key.
+        for forged in [
+            FileManifest { merkle_root: Some([8u8; 32]), ..renamed.clone() },
+            FileManifest { wrapped_key: Some("00:00".to_string()), ..renamed.clone() },
+        ] {
+            let err = send_file_manifest(&peer, &forged, &config).await.unwrap_err();
+            assert!(matches!(err, ConnectionError::ManifestRejected(id, _) if id == manifest.file_id), "{}", err);
+        }
+        assert_eq!(load_manifest(remote_root.path().join(manifest.file_id.to_string())).unwrap(), renamed);
+    }
+
+    #[tokio::test]
+    async fn test_send_chunk_to_peer_waits_for_acknowledgment() {
+        use crate::peer::connection::send_chunk_to_peer;
+
+        let local_root = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..2500u32).map(|i| (i % 199) as u8).collect();
+        let (manifest, chunks) = store_remote_file(local_root.path(), &content);
+        let file_id = manifest.file_id;
+        let local_root_str = local_root.path().to_str().unwrap();
+        let config = test_config(local_root.path());
+        let network_stats = SharedNetworkStats::default();
+
+        let remote_root = tempfile::tempdir().unwrap();
+        let peer = spawn_remote_peer(remote_root.path()).await;
+        // Without the manifest the peer has nothing to check the chunk against.
+        let err = send_chunk_to_peer(&peer, local_root_str, &file_id, 1, &network_stats, &config).await.unwrap_err();
+        assert!(err.to_string().contains("no manifest"), "{}", err);
+        crate::peer::connection::send_file_manifest(&peer, &manifest, &config).await.unwrap();
+        send_chunk_to_peer(&peer, local_root_str, &file_id, 1, &network_stats, &config).await.unwrap();
+        // Pushing the same chunk again is acknowledged.
+        send_chunk_to_peer(&peer, local_root_str, &file_id, 1, &network_stats, &config).await.unwrap();
+        let remote_dir = remote_root.path().join(file_id.to_string());
+        assert_eq!(get_chunk(&remote_dir, 1).unwrap(), chunks[1].1);
+        let recorded = crate::file_manager::storage::chunk_hash(&remote_dir, 1, HashAlgorithm::Blake3).unwrap();
+        assert_eq!(recorded, blake3(&chunks[1].1));
+        assert_eq!(network_stats.read().unwrap()[&peer.address].chunks_sent, 2);
+
+        // A peer whose storage root is a regular file cannot store the chunk and says why.
+        let blocked_root = tempfile::NamedTempFile::new().unwrap();
+        let blocked = spawn_remote_peer(blocked_root.path()).await;
+        let err = send_chunk_to_peer(&blocked, local_root_str, &file_id, 1, &network_stats, &config).await.unwrap_err();
+        assert!(err.to_string().contains("rejected chunk 1"), "{}", err);
+        assert_eq!(network_stats.read().unwrap()[&blocked.address].errors, 1);
+    }
+
+    /// Sends a `CHUNK_PUSH` with arbitrary bytes and proof, returning the reply.
+    async fn push_raw_chunk(peer: &Peer, config: &Config, file_id: Uuid, chunk_index: usize, data: &[u8], proof: &str) -> String {
+        let stream = transport::connect(peer, config).await.unwrap();
+        let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await.unwrap();
+        session.send(format!("CHUNK_PUSH:{}:{}:{}:{}\n", file_id, chunk_index, data.len(), proof).as_bytes()).await.unwrap();
+        session.send(data).await.unwrap();
+        session.stream.flush().await.unwrap();
+        while let Some(line) = session.read_line().await.unwrap() {
+            if line == "OK" || line.starts_with("ERROR:") {
+                return line;
+            }
+        }
+        panic!("connection closed before the push was answered");
+    }
+
+    #[tokio::test]
+    async fn test_chunk_push_cannot_replace_or_forge_chunks() {
+        let storage = tempfile::tempdir().unwrap();
+        let content = vec![9u8; 3000];
+        let (manifest, chunks) = store_remote_file(storage.path(), &content);
+        let storage_dir = storage.path().join(manifest.file_id.to_string());
+        std::fs::remove_file(storage_dir.join("chunk_2.bin")).unwrap();
+        let peer = spawn_remote_peer(storage.path()).await;
+        let config = test_config(storage.path());
+        let tree = MerkleTree::from_hashes(manifest.hash_algorithm, &chunks.iter().map(|(_, data)| blake3(data)).collect::<Vec<_>>());
+
+        // A stored chunk is not overwritten, even with a valid proof for other bytes.
+        let reply = push_raw_chunk(&peer, &config, manifest.file_id, 0, &[0u8; 1024], &tree.proof(0).to_hex()).await;
+        assert!(reply.contains("already stored"), "{}", reply);
+        assert_eq!(get_chunk(&storage_dir, 0).unwrap(), chunks[0].1);
+
+        // A missing chunk only comes back with bytes that match the Merkle root.
+        let reply = push_raw_chunk(&peer, &config, manifest.file_id, 2, &[0u8; 952], &tree.proof(2).to_hex()).await;
+        assert!(reply.contains("Merkle root"), "{}", reply);
+        assert!(!chunk_exists(&storage_dir, 2));
+        let reply = push_raw_chunk(&peer, &config, manifest.file_id, 2, &chunks[2].1, &tree.proof(2).to_hex()).await;
+        assert_eq!(reply, "OK");
+        assert_eq!(verify_file(storage.path(), manifest.file_id).unwrap().corrupted_found, 0);
+    }
+
+    #[tokio::test]
+    async fn test_list_remote_files_reads_peer_manifests() {
+        let remote_root = tempfile::tempdir().unwrap();
+        let (manifest, _) = store_remote_file(remote_root.path(), &[1u8; 3000]);
+        // Chunks without a manifest are not listed.
+        initialize_storage(remote_root.path(), Uuid::new_v4()).unwrap();
+        let peer = spawn_remote_peer(remote_root.path()).await;
+
+        let files = list_remote_files(&peer, &test_config(remote_root.path())).await.unwrap();
+        assert_eq!(
+            files,
+            vec![RemoteFileEntry { file_id: manifest.file_id, file_name: manifest.file_name, size_bytes: 3000 }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_fetches_file_from_exported_manifest() {
+        let remote_root = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..4500u32).map(|i| (i % 239) as u8).collect();
+        let (manifest, _) = store_remote_file(remote_root.path(), &content);
+        let peer = spawn_remote_peer(remote_root.path()).await;
+        let manifest_path = remote_root.path().join("exported.json");
+        std::fs::write(&manifest_path, json::to_string_pretty(&manifest).unwrap()).unwrap();
+
+        let storage = tempfile::tempdir().unwrap();
+        let node = NodeContext {
+            config: test_config(storage.path()),
+            dht: DHT::new(),
+            local_peer: Peer::new("127.0.0.1:8080"),
+            history: HistoryStore::open(storage.path()),
+            network_stats: SharedNetworkStats::default(),
+            latency: PeerLatency::default(),
+            peer_load: PeerLoad::default(),
+            replication_status: ReplicationStatusStore::open(storage.path()),
+            uploads: WatchedUploader::open(storage.path()),
+            reputation: ReputationStore::default(),
+            events: events::channel(),
+        };
+        let destination = storage.path().join("restored.bin");
+
+        // No peer holds the file yet: the import fails and withdraws the local registration.
+        assert!(import_file(&node, &manifest_path, &destination, &[]).await.is_err());
+        assert_eq!(node.dht.get_file_locations(&manifest.file_id).unwrap().unwrap_or_default(), vec![]);
+
+        node.dht.register_file_location(manifest.file_id, peer.clone()).unwrap();
+        let file_id = import_file(&node, &manifest_path, &destination, &[peer]).await.unwrap();
+        assert_eq!(file_id, manifest.file_id);
+        assert_eq!(std::fs::read(&destination).unwrap(), content);
+        let locations = node.dht.get_file_locations(&file_id).unwrap().unwrap();
+        assert!(locations.contains(&node.local_peer));
+    }
+
+    #[tokio::test]
+    async fn test_peers_add_and_remove_update_config_file() {
+        let storage = tempfile::tempdir().unwrap();
+        let remote = spawn_remote_peer(storage.path()).await;
+        let config_path = storage.path().join("config.yaml");
+        std::fs::write(&config_path, "peer_port: 8080\nbootstrap_peers: []\nencryption_key: \"00\"\n").unwrap();
+        let node = NodeContext {
+            config: test_config(storage.path()),
+            dht: DHT::new(),
+            local_peer: Peer::new("127.0.0.1:8080"),
+            history: HistoryStore::open(storage.path()),
+            network_stats: SharedNetworkStats::default(),
+            latency: PeerLatency::default(),
+            peer_load: PeerLoad::default(),
+            replication_status: ReplicationStatusStore::open(storage.path()),
+            uploads: WatchedUploader::open(storage.path()),
+            reputation: ReputationStore::default(),
+            events: events::channel(),
+        };
+        let peers = PeerRegistry::default();
+        let mut received = peers.subscribe();
+        // Listed by another config layer, so never written to this one.
+        let elsewhere: PeerUrl = "10.0.0.9:8080".parse().unwrap();
+        let mut merged = node.config.clone();
+        merged.discovery.bootstrap_peers.push(elsewhere.clone());
+        let manager = PeerManager {
+            config: Arc::new(RwLock::new(merged)),
+            config_path: Some(config_path.clone()),
+            peers,
+            connections: OutgoingConnections::default(),
+        };
+
+        let unreachable: PeerUrl = "127.0.0.1:1".parse().unwrap();
+        assert!(manager.add(&node, unreachable).await.is_err());
+        let url: PeerUrl = remote.address.parse().unwrap();
+        manager.add(&node, url.clone()).await.unwrap();
+        assert!(manager.add(&node, url.clone()).await.is_err());
+        assert!(matches!(received.recv().await.unwrap(), PeerEvent::Connected { .. }));
+        assert_eq!(
+            manager.statuses(),
+            vec![
+                PeerStatusEntry { address: elsewhere.address(), bootstrap: true, connected: false },
+                PeerStatusEntry { address: remote.address.clone(), bootstrap: true, connected: true },
+            ]
+        );
+        let saved = std::fs::read_to_string(&config_path).unwrap();
+        assert!(saved.contains(&format!("bootstrap_peers:\n  - \"{}\"\nencryption_key", url)), "{}", saved);
+
+        assert_eq!(manager.remove(&url).unwrap(), (true, true));
+        assert!(matches!(received.recv().await.unwrap(), PeerEvent::Disconnected { .. }));
+        assert_eq!(manager.statuses().len(), 1);
+        assert!(std::fs::read_to_string(&config_path).unwrap().contains("bootstrap_peers: []\n"));
+        assert_eq!(manager.remove(&url).unwrap(), (false, false));
+        // Only dropped from memory, as the file never listed it.
+        assert_eq!(manager.remove(&elsewhere).unwrap(), (true, false));
+        assert!(std::fs::read_to_string(&config_path).unwrap().contains("bootstrap_peers: []\n"));
+
+        // Without a config file, changes stay in memory.
+        let unsaved = PeerManager { config_path: None, ..manager };
+        unsaved.add(&node, url.clone()).await.unwrap();
+        assert_eq!(unsaved.config.read().unwrap().discovery.bootstrap_peers, vec![url]);
+    }
+
+    #[tokio::test]
+    async fn test_verify_replication_checks_each_recorded_peer() {
+        let storage = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..3000u32).map(|i| (i % 97) as u8).collect();
+        let (manifest, _) = store_remote_file(storage.path(), &content);
+        let file_id = manifest.file_id;
+        let peer = spawn_remote_peer(storage.path()).await;
+
+        let context = ReplicationContext {
+            network_stats: SharedNetworkStats::default(),
+            latency: PeerLatency::default(),
+            peer_load: PeerLoad::default(),
+            config: test_config(storage.path()),
+            status: ReplicationStatusStore::open(storage.path()),
+            reputation: ReputationStore::default(),
+        };
+        context.status.record_sent(file_id, 0, &peer.address).unwrap();
+        context.status.record_sent(file_id, 2, &peer.address).unwrap();
+        context.status.record_sent(file_id, 1, "127.0.0.1:1").unwrap();
+
+        let root = storage.path().to_str().unwrap();
+        assert_eq!(verify_replication(root, &file_id, &context).await.unwrap(), 2);
+        let verified: Vec<(usize, bool)> = context
+            .status
+            .get_replication_status(&file_id)
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.chunk_index, r.verified))
+            .collect();
+        assert_eq!(verified, vec![(0, true), (1, false), (2, true)]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_chunks_batch_over_one_connection() {
+        let remote_root = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..6000u32).map(|i| (i % 253) as u8).collect();
+        let (manifest, chunks) = store_remote_file(remote_root.path(), &content);
+        let file_id = manifest.file_id;
+        let peer = spawn_remote_peer(remote_root.path()).await;
+        let remote_dir = remote_root.path().join(file_id.to_string());
+        std::fs::write(remote_dir.join("chunk_4.bin"), vec![0u8; 1024]).unwrap();
+
+        let local_root = tempfile::tempdir().unwrap();
+        let local_dir = initialize_storage(local_root.path(), file_id).unwrap();
+        save_manifest(&local_dir, &manifest).unwrap();
+
+        let mut config = test_config(local_root.path());
+        config.transfer.max_pipeline_depth = 2;
+        let batch = fetch_chunks_batch(&peer, &file_id, &[0, 1, 2, 3, 4, 5, 9], &local_dir, &config).await.unwrap();
+        // Chunk 4 fails Merkle verification and chunk 9 does not exist.
+        assert_eq!(batch.fetched, vec![0, 1, 2, 3, 5]);
+        let failed: Vec<usize> = batch.failed.iter().map(|(i, _)| *i).collect();
+        assert_eq!(failed, vec![4, 9]);
+        for i in batch.fetched {
+            assert_eq!(get_chunk(&local_dir, i).unwrap(), chunks[i].1);
+        }
+        assert!(!chunk_exists(&local_dir, 4));
+    }
+
+    #[tokio::test]
+    async fn test_acl_restricts_downloads_to_shared_peers() {
+        let remote_root = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+        let (manifest, _) = store_remote_file(remote_root.path(), &content);
+        let file_id = manifest.file_id;
+        AclStore::new(remote_root.path()).share(&file_id, "00aa", "00bb").unwrap();
+        NodeKeyStore::new(remote_root.path()).insert("00bb", &[0xbb; 32]).unwrap();
+        let remote = Config {
+            node_id: Some("00aa".to_string()),
+            node_key: Some(hex::encode([0xaa; 32]).into()),
+            ..Config::with_storage_path(remote_root.path())
+        };
+        let peer = spawn_remote_peer_with(remote).await;
+
+        let local_root = tempfile::tempdir().unwrap();
+        let local_dir = initialize_storage(local_root.path(), file_id).unwrap();
+        save_manifest(&local_dir, &manifest).unwrap();
+
+        let anonymous = test_config(local_root.path());
+        let err = get_remote_manifest(&peer, &file_id, &anonymous).await.unwrap_err();
+        assert!(matches!(err, ConnectionError::Forbidden(id) if id == file_id), "{}", err);
+        let batch = fetch_chunks_batch(&peer, &file_id, &[0, 1], &local_dir, &anonymous).await.unwrap();
+        assert!(batch.fetched.is_empty());
+        assert_eq!(batch.failed, vec![(0, "forbidden".to_string()), (1, "forbidden".to_string())]);
+        let err = request_chunk(&peer, file_id, 2, &anonymous).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<ChunkFetchError>(), Some(ChunkFetchError::ChunkForbidden(_, 2))));
+
+        assert!(list_remote_files(&peer, &anonymous).await.unwrap().is_empty());
+
+        // Claiming a shared node ID is not enough without that node's key.
+        let impostor = Config {
+            node_id: Some("00bb".to_string()),
+            node_key: Some(hex::encode([0xcc; 32]).into()),
+            ..test_config(local_root.path())
+        };
+        let err = get_remote_manifest(&peer, &file_id, &impostor).await.unwrap_err();
+        assert!(matches!(err, ConnectionError::Forbidden(id) if id == file_id), "{}", err);
+        let unkeyed = Config { node_id: Some("00bb".to_string()), ..test_config(local_root.path()) };
+        let err = get_remote_manifest(&peer, &file_id, &unkeyed).await.unwrap_err();
+        assert!(matches!(err, ConnectionError::Forbidden(id) if id == file_id), "{}", err);
+
+        let shared = Config {
+            node_id: Some("00BB".to_string()),
+            node_key: Some(hex::encode([0xbb; 32]).into()),
+            ..test_config(local_root.path())
+        };
+        assert_eq!(get_remote_manifest(&peer, &file_id, &shared).await.unwrap(), manifest);
+        let batch = fetch_chunks_batch(&peer, &file_id, &[0, 1, 2], &local_dir, &shared).await.unwrap();
+        assert_eq!(batch.fetched, vec![0, 1, 2]);
+        let files = list_remote_files(&peer, &shared).await.unwrap();
+        assert_eq!(files.iter().map(|f| f.file_id).collect::<Vec<_>>(), vec![file_id]);
+
+        // The owner proves its ID with its own node_key.
+        let owner = Config {
+            node_id: Some("00aa".to_string()),
+            node_key: Some(hex::encode([0xaa; 32]).into()),
+            ..test_config(local_root.path())
+        };
+        assert_eq!(get_remote_manifest(&peer, &file_id, &owner).await.unwrap(), manifest);
+    }
+
+    #[tokio::test]
+    async fn test_traced_chunk_request_is_answered_on_its_own() {
+        let remote_root = tempfile::tempdir().unwrap();
+        let (manifest, _) = store_remote_file(remote_root.path(), &[5u8; 2500]);
+        let config = test_config(remote_root.path());
+        let peer = spawn_remote_peer_with(config.clone()).await;
+
+        let stream = transport::connect(&peer, &config).await.unwrap();
+        let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await.unwrap();
+        assert!(session.capabilities.distributed_tracing);
+        // Nothing follows the request, so a server waiting for another line would never answer.
+        session.send(format!("CHUNK_REQUEST:{}:1\n", manifest.file_id).as_bytes()).await.unwrap();
+        session.stream.flush().await.unwrap();
+        let answered = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(line) = session.read_line().await.unwrap() {
+                if line.starts_with("CHUNK_RESPONSE:") {
+                    return true;
+                }
+            }
+            false
+        });
+        assert!(answered.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_silent_connections_are_pinged_then_closed() {
+        let storage = tempfile::tempdir().unwrap();
+        let mut config = test_config(storage.path());
+        (config.discovery.heartbeat_interval_secs, config.discovery.heartbeat_timeout_secs) = (1, 2);
+        let peer = spawn_remote_peer_with(config.clone()).await;
+
+        let stream = transport::connect(&peer, &config).await.unwrap();
+        let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await.unwrap();
+        let pinged = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(line) = session.read_line().await.unwrap() {
+                if line == "PING" {
+                    return true;
+                }
+            }
+            false
+        });
+        assert!(pinged.await.unwrap());
+        session.send(b"PONG\n").await.unwrap();
+        session.stream.flush().await.unwrap();
+
+        // Without further replies the remote gives up on the connection.
+        let closed = tokio::time::timeout(Duration::from_secs(6), async {
+            loop {
+                match session.read_line().await {
+                    Ok(Some(_)) => continue,
+                    Ok(None) | Err(_) => return,
+                }
+            }
+        });
+        assert!(closed.await.is_ok());
+    }
+
+    /// Every command that awaits something runs on the caller's runtime; a
+    /// nested `Runtime::block_on` would panic the CLI task.
+    #[tokio::test]
+    async fn test_run_cli_awaits_commands() {
+        let storage = tempfile::tempdir().unwrap();
+        let content = vec![3u8; 2500];
+        let (manifest, _) = store_remote_file(storage.path(), &content);
+        let output = storage.path().join("exported.bin");
+        let config = Arc::new(RwLock::new(test_config(storage.path())));
+        let (tx, rx) = tokio::sync::mpsc::channel(8);
+        let cli = tokio::spawn(run_cli(
+            rx,
+            DHT::new(),
+            config,
+            None,
+            PeerRegistry::default(),
+            Peer::new("127.0.0.1:8080"),
+            SharedNetworkStats::default(),
+            PeerLatency::default(),
+            OutgoingConnections::default(),
+        ));
+        for command in [
+            format!("verify {} --repair", manifest.file_id),
+            format!("replication-status {} --verify", manifest.file_id),
+            format!("export {} {}", manifest.file_id, output.display()),
+            "peer-files 127.0.0.1:1".to_string(),
+            "exit".to_string(),
+        ] {
+            tx.send(command).await.unwrap();
+        }
+        tokio::time::timeout(Duration::from_secs(30), cli).await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_export_file_verifies_merkle_root() {
+        let storage = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..3500u32).map(|i| (i % 89) as u8).collect();
+        let (manifest, _) = store_remote_file(storage.path(), &content);
+        let output = storage.path().join("exported.bin");
+
+        let written = export_file(storage.path(), manifest.file_id, output.to_str().unwrap()).await.unwrap();
+        assert_eq!(written, content.len() as u64);
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+
+        std::fs::remove_file(&output).unwrap();
+        let storage_dir = storage.path().join(manifest.file_id.to_string());
+        std::fs::write(storage_dir.join("chunk_2.bin"), vec![1u8; 1024]).unwrap();
+        let err = export_file(storage.path(), manifest.file_id, output.to_str().unwrap()).await.unwrap_err();
+        assert!(err.to_string().contains("damaged chunks: [2]"), "{}", err);
+        assert!(!output.exists());
+    }
+
+    #[tokio::test]
+    async fn test_json_protocol_serves_manifest_and_chunks() {
+        use crate::peer::protocol::{Message, ProtocolVersion};
+
+        let remote_root = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..3000u32).map(|i| (i % 101) as u8).collect();
+        let (manifest, chunks) = store_remote_file(remote_root.path(), &content);
+        let file_id = manifest.file_id;
+        let peer = spawn_remote_peer(remote_root.path()).await;
+
+        let stream = transport::connect(&peer, &test_config(remote_root.path())).await.unwrap();
+        let mut session = PeerSession::handshake(stream, PeerCapabilities::local()).await.unwrap();
+        assert_eq!(session.negotiate_version(ProtocolVersion::V1).await.unwrap(), ProtocolVersion::V1);
+        assert_eq!(session.negotiate_version(ProtocolVersion::LATEST).await.unwrap(), ProtocolVersion::V2);
+        assert_eq!(session.protocol_version, ProtocolVersion::V2);
+
+        session.send_message(&Message::GetManifest { file_id }).await.unwrap();
+        assert_eq!(session.read_message().await.unwrap(), Some(Message::ManifestResponse { manifest: manifest.clone() }));
+
+        session.send_message(&Message::ChunkRequest { file_id, chunk_index: 1 }).await.unwrap();
+        match session.read_message().await.unwrap() {
+            Some(Message::ChunkResponse { chunk_index: 1, data, proof, .. }) => {
+                assert_eq!(data, chunks[1].1);
+                let proof = MerkleProof::from_hex(&proof).unwrap();
+                assert!(proof.verify(manifest.hash_algorithm, manifest.hash_algorithm.digest(&data), manifest.merkle_root.unwrap(), 1, manifest.total_chunks));
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        session.send_message(&Message::ChunkRequest { file_id, chunk_index: 9 }).await.unwrap();
+        assert_eq!(session.read_message().await.unwrap(), Some(Message::ChunkNotFound { file_id, chunk_index: 9 }));
+    }
+
+    #[tokio::test]
+    async fn test_download_fetches_manifest_from_peer() {
+        let remote_root = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..2500u32).map(|i| (i % 199) as u8).collect();
+        let (manifest, _) = store_remote_file(remote_root.path(), &content);
+        let peer = spawn_remote_peer(remote_root.path()).await;
+
+        let unknown = Uuid::new_v4();
+        let err = get_remote_manifest(&peer, &unknown, &test_config(remote_root.path())).await.unwrap_err();
+        assert!(matches!(err, transport::ConnectionError::ManifestNotFound(id) if id == unknown));
+
+        let storage = tempfile::tempdir().unwrap();
+        let node = NodeContext {
+            config: test_config(storage.path()),
+            dht: DHT::new(),
+            local_peer: Peer::new("127.0.0.1:8080"),
+            history: HistoryStore::open(storage.path()),
+            network_stats: SharedNetworkStats::default(),
+            latency: PeerLatency::default(),
+            peer_load: PeerLoad::default(),
+            replication_status: ReplicationStatusStore::open(storage.path()),
+            uploads: WatchedUploader::open(storage.path()),
+            reputation: ReputationStore::default(),
+            events: events::channel(),
+        };
+        // The first peer in DHT order is unreachable; the second one answers.
+        node.dht.register_file_location(manifest.file_id, Peer::new("127.0.0.1:1")).unwrap();
+        node.dht.register_file_location(manifest.file_id, peer.clone()).unwrap();
+
+        let destination = storage.path().join("downloaded.bin");
+        download_file(&node, &manifest.file_id.to_string(), &destination, &[peer]).await.unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), content);
+        assert_eq!(load_manifest(storage.path().join(manifest.file_id.to_string())).unwrap(), manifest);
+        assert!(node.network_stats.read().unwrap()["127.0.0.1:8080"].last_download_bytes_per_sec > 0);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_missing_chunk_reports_not_found() {
+        let remote_root = tempfile::tempdir().unwrap();
+        let (manifest, _) = store_remote_file(remote_root.path(), &[1u8; 3000]);
+        let file_id = manifest.file_id;
+        std::fs::remove_file(remote_root.path().join(file_id.to_string()).join("chunk_1.bin")).unwrap();
+        let peer = spawn_remote_peer(remote_root.path()).await;
+
+        let local_root = tempfile::tempdir().unwrap();
+        let local_dir = initialize_storage(local_root.path(), file_id).unwrap();
+        save_manifest(&local_dir, &manifest).unwrap();
+
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            fetch_chunk_from_peer(&peer, &local_dir, file_id, 1, &test_config(local_root.path())),
+        )
+        .await
+        .expect("fetch should fail immediately instead of waiting for data");
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ChunkFetchError>(),
+            Some(ChunkFetchError::ChunkNotFound(id, 1)) if *id == file_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_missing_chunks_fails_when_no_peer_has_chunk() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage_dir = initialize_storage(temp_dir.path(), Uuid::new_v4()).unwrap();
+
+        let attempts = Arc::new(Mutex::new(0));
+        let peers = vec![Peer::new("127.0.0.1:8081"), Peer::new("127.0.0.1:8082")];
+        let result = fetch_missing_chunks(&storage_dir, 1, &peers, |_peer, _chunk_index| {
+            let attempts = attempts.clone();
+            async move {
+                *attempts.lock().unwrap() += 1;
+                Err("unreachable".into())
+            }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(*attempts.lock().unwrap(), CHUNK_FETCH_RETRIES * peers.len());
+    }
+
+    #[test]
+    fn test_orphan_cleanup_keeps_pinned_files() {
+        let storage = tempfile::tempdir().unwrap();
+        let storage_root = storage.path().to_str().unwrap();
+        let (pinned, _) = store_remote_file(storage.path(), b"keep me");
+        let (orphan, _) = store_remote_file(storage.path(), b"delete me");
+        pin_file(storage_root, pinned.file_id).unwrap();
+
+        // Chunks left behind without a manifest, e.g. by an interrupted upload.
+        std::fs::remove_file(storage.path().join(orphan.file_id.to_string()).join(MANIFEST_FILENAME)).unwrap();
+        let (kept, _) = store_remote_file(storage.path(), b"keep my manifest");
+
+        // The DHT is empty, as right after a restart.
+        let dht = DHT::new();
+        let report = orphan_cleanup(storage_root, &dht, &Peer::new("127.0.0.1:8080"), false).unwrap();
+        assert_eq!(report.files_deleted, 1);
+        let mut remaining = list_all_files(storage_root).unwrap();
+        remaining.sort();
+        let mut expected = vec![pinned.file_id, kept.file_id];
+        expected.sort();
+        assert_eq!(remaining, expected);
+        assert!(!storage.path().join(orphan.file_id.to_string()).exists());
+
+        let files = list_files(storage_root).unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().any(|file| file.file_id == pinned.file_id && file.pinned));
+    }
+
+    #[test]
+    fn test_dht_dump_and_import() {
+        let storage = tempfile::tempdir().unwrap();
+        let (stored, _) = store_remote_file(storage.path(), b"dump me");
+        let unknown = Uuid::new_v4();
+        let node = NodeContext::new(test_config(storage.path()), DHT::new(), Peer::new("127.0.0.1:8080"), SharedNetworkStats::default(), PeerLatency::default());
+        node.dht.register_file_location(stored.file_id, node.local_peer.clone()).unwrap();
+        node.dht.register_file_location(unknown, Peer::new("10.0.0.2:8080")).unwrap();
+
+        let path = storage.path().join("dht.json");
+        assert_eq!(dump_dht(&node, Some(&path)).unwrap(), 2);
+        let dump: DhtDump = json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let metadata: HashMap<Uuid, Option<String>> =
+            dump.entries.into_iter().map(|entry| (entry.file_id, entry.metadata.map(|m| m.file_name))).collect();
+        assert_eq!(metadata, HashMap::from([(stored.file_id, Some("data.bin".to_string())), (unknown, None)]));
+
+        let dht = DHT::new();
+        dht.register_file_location(Uuid::new_v4(), Peer::new("10.0.0.3:8080")).unwrap();
+        assert_eq!(import_dht(&dht, &path).unwrap(), 2);
+        assert_eq!(dht.snapshot().unwrap(), node.dht.snapshot().unwrap());
+    }
+
+    #[test]
+    fn test_chunk_size_flag() {
+        let args = ["upload", "big.iso", "--chunk-size", "4194304"];
+        assert_eq!(parse_flag::<usize>(&args, "--chunk-size"), Ok(Some(4 * 1024 * 1024)));
+        assert_eq!(parse_flag::<usize>(&args[..2], "--chunk-size"), Ok(None));
+        assert!(parse_flag::<usize>(&args[..3], "--chunk-size").is_err());
+
+        assert_eq!(validate_chunk_size(MIN_EXPLICIT_CHUNK_SIZE), Ok(MIN_EXPLICIT_CHUNK_SIZE));
+        assert_eq!(validate_chunk_size(MAX_CHUNK_SIZE), Ok(MAX_CHUNK_SIZE));
+        assert!(validate_chunk_size(MIN_EXPLICIT_CHUNK_SIZE - 1).is_err());
+        assert!(validate_chunk_size(MAX_CHUNK_SIZE + 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_watch_peers_prints_events_as_json_lines() {
+        let (events, receiver) = broadcast::channel(2);
+        // The buffer holds two events, so the first of these three is dropped.
+        for (at, address) in [(1, "10.0.0.1:8080"), (2, "10.0.0.2:8080")] {
+            events.send(PeerEvent::Connected { address: address.to_string(), at }).unwrap();
+        }
+        events
+            .send(PeerEvent::Disconnected { address: "10.0.0.1:8080".to_string(), at: 3, reason: "reset".to_string() })
+            .unwrap();
+        drop(events);
+
+        let mut out = Vec::new();
+        let written = watch_peers(receiver, &mut out, std::future::pending()).await;
+
+        assert_eq!(written.unwrap(), 2);
+        let lines: Vec<PeerEvent> = String::from_utf8(out).unwrap().lines().map(|l| json::from_str(l).unwrap()).collect();
+        assert_eq!(lines[0], PeerEvent::Connected { address: "10.0.0.2:8080".to_string(), at: 2 });
+        assert!(matches!(&lines[1], PeerEvent::Disconnected { reason, .. } if reason == "reset"));
+    }
 }
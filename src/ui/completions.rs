@@ -0,0 +1,375 @@
+// src/ui/completions.rs
+
+//! Shell completion scripts generated from a clap command definition.
+//!
+//! Subcommands and `--long` options are completed from the definition.
+//! Option values are completed from possible values or value hints, and so
+//! are positional arguments with a path hint.
+//!
+//! The commands typed at a running node's prompt are read from stdin without
+//! line editing, so a shell cannot complete them. For those, `rlwrap` takes a
+//! word list instead: `rlwrap -f <(peerchunks completions rlwrap) peerchunks`.
+
+use clap::builder::PossibleValue;
+use clap::{Arg, ArgAction, Command, ValueEnum, ValueHint};
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    /// Subcommand names and their `--long` options, one per line, for `rlwrap -f`.
+    Rlwrap,
+}
+
+/// What to offer when completing a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ValueCompletion {
+    None,
+    Files,
+    Dirs,
+    Words(Vec<String>),
+}
+
+impl ValueCompletion {
+    fn of(arg: &Arg) -> ValueCompletion {
+        let values: Vec<String> = arg
+            .get_possible_values()
+            .iter()
+            .filter(|v| !v.is_hide_set())
+            .map(PossibleValue::get_name)
+            .map(str::to_string)
+            .collect();
+        if !values.is_empty() {
+            return ValueCompletion::Words(values);
+        }
+        match arg.get_value_hint() {
+            ValueHint::AnyPath | ValueHint::FilePath | ValueHint::ExecutablePath => ValueCompletion::Files,
+            ValueHint::DirPath => ValueCompletion::Dirs,
+            _ => ValueCompletion::None,
+        }
+    }
+}
+
+/// Writes a completion script for `cmd`, invoked as `bin_name`, to `out`.
+pub fn generate(shell: Shell, cmd: &mut Command, bin_name: &str, out: &mut dyn Write) -> io::Result<()> {
+    cmd.build();
+    let script = match shell {
+        Shell::Bash => bash(cmd, bin_name),
+        Shell::Zsh => zsh(cmd, bin_name),
+        Shell::Fish => fish(cmd, bin_name),
+        Shell::Rlwrap => words(cmd),
+    };
+    out.write_all(script.as_bytes())
+}
+
+fn subcommands(cmd: &Command) -> Vec<&Command> {
+    cmd.get_subcommands().filter(|c| !c.is_hide_set()).collect()
+}
+
+fn options(cmd: &Command) -> Vec<&Arg> {
+    cmd.get_arguments().filter(|a| !a.is_positional() && !a.is_hide_set()).collect()
+}
+
+fn positionals(cmd: &Command) -> Vec<&Arg> {
+    cmd.get_positionals().filter(|a| !a.is_hide_set()).collect()
+}
+
+/// Completion for positional values: the first positional that has one.
+fn positional_completion(cmd: &Command) -> ValueCompletion {
+    positionals(cmd)
+        .into_iter()
+        .map(ValueCompletion::of)
+        .find(|c| *c != ValueCompletion::None)
+        .unwrap_or(ValueCompletion::None)
+}
+
+fn takes_value(arg: &Arg) -> bool {
+    arg.get_action().takes_values()
+}
+
+fn about(cmd: &Command) -> String {
+    cmd.get_about().map(|a| a.to_string()).unwrap_or_default()
+}
+
+fn help(arg: &Arg) -> String {
+    arg.get_help()
+        .map(|h| h.to_string().lines().next().unwrap_or_default().to_string())
+        .unwrap_or_default()
+}
+
+fn switches(arg: &Arg) -> Vec<String> {
+    let mut switches = Vec::new();
+    if let Some(short) = arg.get_short() {
+        switches.push(format!("-{}", short));
+    }
+    if let Some(long) = arg.get_long() {
+        switches.push(format!("--{}", long));
+    }
+    switches
+}
+
+fn bash_function_name(bin_name: &str) -> String {
+    format!("_{}", bin_name.replace(|c: char| !c.is_ascii_alphanumeric(), "_"))
+}
+
+fn bash_compgen(completion: &ValueCompletion) -> String {
+    match completion {
+        ValueCompletion::None => "COMPREPLY=()".to_string(),
+        ValueCompletion::Files => "COMPREPLY=( $(compgen -f -- \"$cur\") )".to_string(),
+        ValueCompletion::Dirs => "COMPREPLY=( $(compgen -d -- \"$cur\") )".to_string(),
+        ValueCompletion::Words(words) => format!("COMPREPLY=( $(compgen -W \"{}\" -- \"$cur\") )", words.join(" ")),
+    }
+}
+
+/// The bash case arm body for one (sub)command: option values first, then
+/// words and positional values.
+fn bash_arm(cmd: &Command, extra_words: &[&str]) -> String {
+    let mut body = String::new();
+    let value_options: Vec<&Arg> = options(cmd).into_iter().filter(|a| takes_value(a)).collect();
+    if !value_options.is_empty() {
+        body.push_str("            case \"$prev\" in\n");
+        for arg in value_options {
+            body.push_str(&format!(
+                "                {})\n                    {}\n                    return 0\n                    ;;\n",
+                switches(arg).join("|"),
+                bash_compgen(&ValueCompletion::of(arg))
+            ));
+        }
+        body.push_str("            esac\n");
+    }
+
+    let mut words: Vec<String> = options(cmd).iter().flat_map(|a| switches(a)).collect();
+    words.extend(extra_words.iter().map(|w| w.to_string()));
+    body.push_str(&format!("            opts=\"{}\"\n", words.join(" ")));
+    body.push_str("            if [[ \"$cur\" == -* ]]; then\n");
+    body.push_str("                COMPREPLY=( $(compgen -W \"$opts\" -- \"$cur\") )\n");
+    body.push_str("                return 0\n            fi\n");
+    match positional_completion(cmd) {
+        ValueCompletion::Files => body.push_str("            COMPREPLY=( $(compgen -W \"$opts\" -f -- \"$cur\") )\n"),
+        ValueCompletion::Dirs => body.push_str("            COMPREPLY=( $(compgen -W \"$opts\" -d -- \"$cur\") )\n"),
+        ValueCompletion::Words(values) => body.push_str(&format!(
+            "            COMPREPLY=( $(compgen -W \"$opts {}\" -- \"$cur\") )\n",
+            values.join(" ")
+        )),
+        ValueCompletion::None => body.push_str("            COMPREPLY=( $(compgen -W \"$opts\" -- \"$cur\") )\n"),
+    }
+    body
+}
+
+fn bash(cmd: &Command, bin_name: &str) -> String {
+    let function = bash_function_name(bin_name);
+    let subs = subcommands(cmd);
+    let names: Vec<&str> = subs.iter().map(|c| c.get_name()).collect();
+
+    let mut script = format!(
+        "{function}() {{\n    local cur prev opts subcommand word\n    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"\n    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n    subcommand=\"\"\n"
+    );
+    if !names.is_empty() {
+        script.push_str(&format!(
+            "    for word in \"${{COMP_WORDS[@]:1:COMP_CWORD-1}}\"; do\n        case \"$word\" in\n            {})\n                subcommand=\"$word\"\n                break\n                ;;\n        esac\n    done\n",
+            names.join("|")
+        ));
+    }
+    script.push_str("\n    case \"$subcommand\" in\n        \"\")\n");
+    script.push_str(&bash_arm(cmd, &names));
+    script.push_str("            ;;\n");
+    for sub in subs {
+        script.push_str(&format!("        {})\n", sub.get_name()));
+        script.push_str(&bash_arm(sub, &[]));
+        script.push_str("            ;;\n");
+    }
+    script.push_str("    esac\n}\n\n");
+    script.push_str(&format!("complete -F {} -o bashdefault -o default {}\n", function, bin_name));
+    script
+}
+
+/// Escapes text for use inside a single-quoted zsh `_arguments` spec.
+fn zsh_escape(text: &str) -> String {
+    text.replace('\'', "'\\''").replace('[', "\\[").replace(']', "\\]").replace(':', "\\:")
+}
+
+fn zsh_action(completion: &ValueCompletion) -> String {
+    match completion {
+        ValueCompletion::None => " ".to_string(),
+        ValueCompletion::Files => "_files".to_string(),
+        ValueCompletion::Dirs => "_files -/".to_string(),
+        ValueCompletion::Words(words) => format!("({})", words.join(" ")),
+    }
+}
+
+fn zsh_specs(cmd: &Command) -> Vec<String> {
+    let mut specs = Vec::new();
+    for arg in options(cmd) {
+        let description = zsh_escape(&help(arg));
+        for switch in switches(arg) {
+            if takes_value(arg) {
+                // `-c+` takes `-cVALUE` or `-c VALUE`; `--config=` takes `--config=VALUE` or `--config VALUE`.
+                let separator = if switch.starts_with("--") { "=" } else { "+" };
+                let name = arg.get_id().as_str();
+                specs.push(format!(
+                    "'{}{}[{}]:{}:{}'",
+                    switch, separator, description, name, zsh_action(&ValueCompletion::of(arg))
+                ));
+            } else {
+                specs.push(format!("'{}[{}]'", switch, description));
+            }
+        }
+    }
+    for arg in positionals(cmd) {
+        specs.push(format!("':{}:{}'", arg.get_id().as_str(), zsh_action(&ValueCompletion::of(arg))));
+    }
+    specs
+}
+
+fn zsh(cmd: &Command, bin_name: &str) -> String {
+    let function = bash_function_name(bin_name);
+    let subs = subcommands(cmd);
+    let mut script = format!("#compdef {bin_name}\n\n{function}() {{\n    local line state\n\n    _arguments -C \\\n");
+    for spec in zsh_specs(cmd) {
+        script.push_str(&format!("        {} \\\n", spec));
+    }
+    if subs.is_empty() {
+        script.push_str("        && return 0\n}\n\n");
+        script.push_str(&format!("{} \"$@\"\n", function));
+        return script;
+    }
+    script.push_str("        '1: :->command' \\\n        '*::arg:->args'\n\n");
+    script.push_str("    case $state in\n        command)\n            _values 'command' \\\n");
+    for sub in &subs {
+        script.push_str(&format!("                '{}[{}]' \\\n", sub.get_name(), zsh_escape(&about(sub))));
+    }
+    script.push_str("            ;;\n        args)\n            case $line[1] in\n");
+    for sub in &subs {
+        script.push_str(&format!("                {})\n                    _arguments", sub.get_name()));
+        for spec in zsh_specs(sub) {
+            script.push_str(&format!(" \\\n                        {}", spec));
+        }
+        script.push_str("\n                    ;;\n");
+    }
+    script.push_str("            esac\n            ;;\n    esac\n}\n\n");
+    script.push_str(&format!("{} \"$@\"\n", function));
+    script
+}
+
+fn fish_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn fish_value_flags(completion: &ValueCompletion) -> String {
+    match completion {
+        ValueCompletion::None => " -r -f".to_string(),
+        ValueCompletion::Files => " -r -F".to_string(),
+        ValueCompletion::Dirs => " -r -f -a \"(__fish_complete_directories)\"".to_string(),
+        ValueCompletion::Words(words) => format!(" -r -f -a \"{}\"", words.join(" ")),
+    }
+}
+
+fn fish_args(bin_name: &str, condition: &str, cmd: &Command, script: &mut String) {
+    for arg in options(cmd) {
+        let mut line = format!("complete -c {} -n \"{}\"", bin_name, condition);
+        if let Some(short) = arg.get_short() {
+            line.push_str(&format!(" -s {}", short));
+        }
+        if let Some(long) = arg.get_long() {
+            line.push_str(&format!(" -l {}", long));
+        }
+        if takes_value(arg) {
+            line.push_str(&fish_value_flags(&ValueCompletion::of(arg)));
+        }
+        let description = help(arg);
+        if !description.is_empty() {
+            line.push_str(&format!(" -d '{}'", fish_escape(&description)));
+        }
+        script.push_str(&line);
+        script.push('\n');
+    }
+    match positional_completion(cmd) {
+        ValueCompletion::Files => script.push_str(&format!("complete -c {} -n \"{}\" -F\n", bin_name, condition)),
+        ValueCompletion::Dirs => script.push_str(&format!(
+            "complete -c {} -n \"{}\" -f -a \"(__fish_complete_directories)\"\n",
+            bin_name, condition
+        )),
+        ValueCompletion::Words(values) => script.push_str(&format!(
+            "complete -c {} -n \"{}\" -f -a \"{}\"\n",
+            bin_name, condition, values.join(" ")
+        )),
+        ValueCompletion::None => {}
+    }
+}
+
+fn fish(cmd: &Command, bin_name: &str) -> String {
+    let mut script = String::new();
+    let subs = subcommands(cmd);
+    let top_condition = if subs.is_empty() { "true" } else { "__fish_use_subcommand" };
+    fish_args(bin_name, top_condition, cmd, &mut script);
+    for sub in &subs {
+        let mut line = format!("complete -c {} -n \"__fish_use_subcommand\" -f -a \"{}\"", bin_name, sub.get_name());
+        let description = about(sub);
+        if !description.is_empty() {
+            line.push_str(&format!(" -d '{}'", fish_escape(&description)));
+        }
+        script.push_str(&line);
+        script.push('\n');
+    }
+    for sub in &subs {
+        fish_args(bin_name, &format!("__fish_seen_subcommand_from {}", sub.get_name()), sub, &mut script);
+    }
+    script
+}
+
+fn words(cmd: &Command) -> String {
+    let mut words = Vec::new();
+    for sub in subcommands(cmd) {
+        words.push(sub.get_name().to_string());
+        // clap's own `--help`, which the prompt does not handle.
+        let typed = options(sub).into_iter().filter(|arg| !matches!(arg.get_action(), ArgAction::Help | ArgAction::HelpShort | ArgAction::HelpLong));
+        words.extend(typed.filter_map(|arg| arg.get_long()).map(|long| format!("--{}", long)));
+    }
+    words.sort();
+    words.dedup();
+    words.into_iter().map(|word| word + "\n").collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ui::cli::Cli;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_generates_completions_for_every_shell() {
+        for shell in Shell::value_variants() {
+            let mut out = Vec::new();
+            generate(*shell, &mut Cli::command(), "sharesphere", &mut out).unwrap();
+            let script = String::from_utf8(out).unwrap();
+            assert!(!script.is_empty(), "{:?}", shell);
+            assert!(script.contains("upload"), "{:?}", shell);
+            assert!(script.contains("download"), "{:?}", shell);
+        }
+    }
+
+    #[test]
+    fn test_value_hints_drive_value_completion() {
+        let mut out = Vec::new();
+        generate(Shell::Bash, &mut Cli::command(), "sharesphere", &mut out).unwrap();
+        let script = String::from_utf8(out).unwrap();
+        // `upload <file_path>` completes files, `--format` its possible values.
+        assert!(script.contains("        upload)\n"));
+        assert!(script.contains("compgen -W \"$opts\" -f -- \"$cur\""));
+        assert!(script.contains("compgen -W \"table json csv\""));
+    }
+
+    #[test]
+    fn test_rlwrap_words_cover_the_prompt_commands() {
+        let mut out = Vec::new();
+        generate(Shell::Rlwrap, &mut Cli::command(), "sharesphere", &mut out).unwrap();
+        let words: Vec<String> = String::from_utf8(out).unwrap().lines().map(str::to_string).collect();
+        for word in ["list-files", "peers", "--add", "replication-status", "--verify", "exit"] {
+            assert!(words.iter().any(|w| w == word), "{}", word);
+        }
+        assert!(!words.iter().any(|w| w.contains("help")));
+        assert!(words.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
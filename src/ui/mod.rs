@@ -1 +1,3 @@
 pub mod cli;
+pub mod output;
+pub mod completions;
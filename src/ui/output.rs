@@ -0,0 +1,156 @@
+// src/ui/output.rs
+
+//! Rendering of command output as an aligned table, JSON, or CSV.
+
+use crate::json;
+use clap::ValueEnum;
+use log::error;
+use serde::Serialize;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <OutputFormat as ValueEnum>::from_str(s, true)
+            .map_err(|_| format!("Unknown output format {} (expected table, json or csv)", s))
+    }
+}
+
+/// Command output that can be printed in any [`OutputFormat`].
+pub trait Printable {
+    fn print(&self, format: OutputFormat);
+}
+
+/// A record shown as one line of a table or CSV file, or one object of a JSON array.
+pub trait Row: Serialize {
+    fn headers() -> &'static [&'static str];
+
+    fn cells(&self) -> Vec<String>;
+
+    /// Cells as shown in a table; defaults to the CSV cells.
+    fn table_cells(&self) -> Vec<String> {
+        self.cells()
+    }
+}
+
+impl<T: Row> Printable for Vec<T> {
+    fn print(&self, format: OutputFormat) {
+        let rendered = match format {
+            OutputFormat::Table => Some(render_table(T::headers(), &self.iter().map(Row::table_cells).collect::<Vec<_>>())),
+            OutputFormat::Csv => Some(render_csv(T::headers(), &self.iter().map(Row::cells).collect::<Vec<_>>())),
+            OutputFormat::Json => to_json(self),
+        };
+        if let Some(rendered) = rendered {
+            println!("{}", rendered);
+        }
+    }
+}
+
+/// Pretty-printed JSON, or `None` after logging the error.
+pub fn to_json<T: Serialize>(value: &T) -> Option<String> {
+    json::to_string_pretty(value)
+        .map_err(|e| error!("Failed to encode output as JSON: {}", e))
+        .ok()
+}
+
+/// Left-aligned columns separated by two spaces, sized to the widest cell.
+pub fn render_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let format_line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = *width))
+            .collect();
+        padded.join("  ").trim_end().to_string()
+    };
+
+    let mut lines = vec![format_line(headers.to_vec())];
+    lines.extend(rows.iter().map(|row| format_line(row.iter().map(String::as_str).collect())));
+    lines.join("\n")
+}
+
+/// RFC 4180 CSV with a header line. Fields are quoted only when needed.
+pub fn render_csv(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut lines = vec![headers.iter().map(|h| csv_field(h)).collect::<Vec<_>>().join(",")];
+    lines.extend(rows.iter().map(|row| row.iter().map(|c| csv_field(c)).collect::<Vec<_>>().join(",")));
+    lines.join("\n")
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// `bytes` in decimal units (B, KB, MB, GB, TB), e.g. `234 MB` or `19.0 MB`.
+/// Values of 100 or more of a unit are shown without decimals.
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 || value >= 100.0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_table_and_csv() {
+        let headers = ["NAME", "BYTES"];
+        let rows = vec![
+            vec!["a.txt".to_string(), "5".to_string()],
+            vec!["report, \"final\".pdf".to_string(), "12345".to_string()],
+        ];
+        assert_eq!(
+            render_table(&headers, &rows),
+            "NAME                 BYTES\na.txt                5\nreport, \"final\".pdf  12345"
+        );
+        assert_eq!(
+            render_csv(&headers, &rows),
+            "NAME,BYTES\na.txt,5\n\"report, \"\"final\"\".pdf\",12345"
+        );
+    }
+
+    #[test]
+    fn test_parse_output_format() {
+        assert_eq!("JSON".parse::<OutputFormat>(), Ok(OutputFormat::Json));
+        assert_eq!("csv".parse::<OutputFormat>(), Ok(OutputFormat::Csv));
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512.0), "512 B");
+        assert_eq!(format_bytes(19_000_000.0), "19.0 MB");
+        assert_eq!(format_bytes(234_400_000.0), "234 MB");
+        assert_eq!(format_bytes(1_250_000_000.0), "1.2 GB");
+        assert_eq!(format_bytes(5e15), "5000 TB");
+    }
+}
@@ -0,0 +1,247 @@
+// src/wasm.rs
+
+//! The parts of the node that are plain computation, with in-memory
+//! stand-ins for the functions that read or write files. Built with the
+//! `wasm` feature, which leaves out everything that needs tokio, the file
+//! system or sockets. [`ffi`] exports them to WebAssembly hosts.
+
+use crate::file_manager::chunker::{self, Chunk, ChunkMetadata};
+use crate::file_manager::hash::format_tagged;
+use crate::file_manager::merkle::MerkleTree;
+use uuid::Uuid;
+
+pub use crate::file_manager::chunker::merge_chunks;
+pub use crate::file_manager::hash::{blake3, sha256, HashAlgorithm};
+pub use crate::file_manager::manifest::FileManifest;
+pub use crate::peer::encryption::{decrypt, encrypt, EncryptionError, NonceTracker};
+
+/// [`chunker::split_bytes_into_chunks`]: the in-memory counterpart of the
+/// native `split_file_into_chunks`.
+pub fn split_file_into_chunks(data: &[u8], chunk_size: usize) -> (Uuid, Vec<Chunk>) {
+    chunker::split_bytes_into_chunks(data, chunk_size)
+}
+
+/// The bytes the native `save_chunk` would write to `chunk_<index>.bin`, for
+/// the caller to keep wherever it stores chunks. Their hash, kept next to the
+/// chunk on disk, is [`chunk_hash`].
+pub fn save_chunk(metadata: &ChunkMetadata, data: &[u8]) -> Vec<u8> {
+    debug_assert_eq!(metadata.actual_size, data.len());
+    data.to_vec()
+}
+
+/// Tagged BLAKE3 of a chunk (`blake3:<hex>`), as stored in its
+/// `chunk_<index>.hash` file.
+pub fn chunk_hash(data: &[u8]) -> String {
+    format_tagged(HashAlgorithm::Blake3, &blake3(data))
+}
+
+/// The manifest an upload of `data` as `file_name` records: the default hash
+/// algorithm, the Merkle root over the chunk hashes and the whole file's hash.
+pub fn build_manifest(file_name: &str, data: &[u8], chunk_size: usize) -> (FileManifest, Vec<Chunk>) {
+    let (file_id, chunks) = split_file_into_chunks(data, chunk_size);
+    let mut manifest = FileManifest::new(file_id, file_name.to_string(), data.len() as u64, chunk_size, chunks.len());
+    let algorithm = manifest.hash_algorithm;
+    let chunk_hashes: Vec<[u8; 32]> = chunks.iter().map(|(_, data)| algorithm.digest(data)).collect();
+    manifest.merkle_root = Some(MerkleTree::from_hashes(algorithm, &chunk_hashes).root());
+    manifest.file_hash = Some(algorithm.digest(data));
+    (manifest, chunks)
+}
+
+/// A C ABI over this module, for WebAssembly hosts that load the module
+/// directly; `wasm-bindgen` is not a dependency of this crate.
+///
+/// Inputs are passed as a pointer and a length, in memory from
+/// [`peerchunks_alloc`](ffi::peerchunks_alloc). Each result is returned as a
+/// pointer with its length written to `out_len`, and is released with
+/// [`peerchunks_free`](ffi::peerchunks_free). A null result means the input
+/// was invalid. JSON results use the same encoding as the native node.
+pub mod ffi {
+    use super::*;
+    use crate::json;
+    use std::ptr;
+    use std::sync::OnceLock;
+
+    /// Shared by every [`peerchunks_encrypt`] call, so no nonce is used twice
+    /// under any key.
+    static NONCES: OnceLock<NonceTracker> = OnceLock::new();
+
+    /// Allocates `len` zeroed bytes for an input.
+    #[no_mangle]
+    pub extern "C" fn peerchunks_alloc(len: usize) -> *mut u8 {
+        Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8
+    }
+
+    /// Frees an input or a result.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `len` must be a buffer from [`peerchunks_alloc`] or a result
+    /// of this module, not freed before.
+    #[no_mangle]
+    pub unsafe extern "C" fn peerchunks_free(ptr: *mut u8, len: usize) {
+        if !ptr.is_null() {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, len)));
+        }
+    }
+
+    unsafe fn input<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+        if len == 0 {
+            &[]
+        } else {
+            std::slice::from_raw_parts(ptr, len)
+        }
+    }
+
+    unsafe fn output(result: Option<Vec<u8>>, out_len: *mut usize) -> *mut u8 {
+        match result {
+            Some(bytes) => {
+                *out_len = bytes.len();
+                Box::into_raw(bytes.into_boxed_slice()) as *mut u8
+            }
+            None => {
+                *out_len = 0;
+                ptr::null_mut()
+            }
+        }
+    }
+
+    /// [`split_file_into_chunks`], returning the JSON array of the chunks'
+    /// metadata. Chunk `i` is the `chunk_size` bytes of `data` at `i * chunk_size`.
+    ///
+    /// # Safety
+    ///
+    /// `data` must point to `data_len` readable bytes and `out_len` must be writable.
+    #[no_mangle]
+    pub unsafe extern "C" fn peerchunks_split_file_into_chunks(
+        data: *const u8,
+        data_len: usize,
+        chunk_size: usize,
+        out_len: *mut usize,
+    ) -> *mut u8 {
+        let result = (chunk_size > 0).then(|| {
+            let (_, chunks) = split_file_into_chunks(input(data, data_len), chunk_size);
+            let metadata: Vec<&ChunkMetadata> = chunks.iter().map(|(metadata, _)| metadata).collect();
+            json::to_string(&metadata).ok().map(String::into_bytes)
+        });
+        output(result.flatten(), out_len)
+    }
+
+    /// [`build_manifest`], returning the manifest as JSON. `file_name` must be UTF-8.
+    ///
+    /// # Safety
+    ///
+    /// `file_name` and `data` must point to `file_name_len` and `data_len`
+    /// readable bytes, and `out_len` must be writable.
+    #[no_mangle]
+    pub unsafe extern "C" fn peerchunks_build_manifest(
+        file_name: *const u8,
+        file_name_len: usize,
+        data: *const u8,
+        data_len: usize,
+        chunk_size: usize,
+        out_len: *mut usize,
+    ) -> *mut u8 {
+        let result = std::str::from_utf8(input(file_name, file_name_len)).ok().filter(|_| chunk_size > 0).and_then(|name| {
+            let (manifest, _) = build_manifest(name, input(data, data_len), chunk_size);
+            json::to_string(&manifest).ok().map(String::into_bytes)
+        });
+        output(result, out_len)
+    }
+
+    /// [`chunk_hash`] of `data`, as `blake3:<hex>`.
+    ///
+    /// # Safety
+    ///
+    /// `data` must point to `data_len` readable bytes and `out_len` must be writable.
+    #[no_mangle]
+    pub unsafe extern "C" fn peerchunks_chunk_hash(data: *const u8, data_len: usize, out_len: *mut usize) -> *mut u8 {
+        output(Some(chunk_hash(input(data, data_len)).into_bytes()), out_len)
+    }
+
+    /// [`encrypt`] under the hex `key`, returning `<nonce hex>:<ciphertext hex>`.
+    ///
+    /// # Safety
+    ///
+    /// `data` and `key` must point to `data_len` and `key_len` readable
+    /// bytes, and `out_len` must be writable.
+    #[no_mangle]
+    pub unsafe extern "C" fn peerchunks_encrypt(
+        data: *const u8,
+        data_len: usize,
+        key: *const u8,
+        key_len: usize,
+        out_len: *mut usize,
+    ) -> *mut u8 {
+        let nonces = NONCES.get_or_init(NonceTracker::new_counter_based);
+        let result = std::str::from_utf8(input(key, key_len)).ok().and_then(|key| {
+            let (nonce, ciphertext) = encrypt(input(data, data_len), key, nonces).ok()?;
+            Some(format!("{}:{}", nonce, ciphertext).into_bytes())
+        });
+        output(result, out_len)
+    }
+
+    /// [`decrypt`] of a `<nonce hex>:<ciphertext hex>` from
+    /// [`peerchunks_encrypt`] under the hex `key`, returning the plaintext.
+    ///
+    /// # Safety
+    ///
+    /// `encrypted` and `key` must point to `encrypted_len` and `key_len`
+    /// readable bytes, and `out_len` must be writable.
+    #[no_mangle]
+    pub unsafe extern "C" fn peerchunks_decrypt(
+        encrypted: *const u8,
+        encrypted_len: usize,
+        key: *const u8,
+        key_len: usize,
+        out_len: *mut usize,
+    ) -> *mut u8 {
+        let encrypted = std::str::from_utf8(input(encrypted, encrypted_len)).ok();
+        let key = std::str::from_utf8(input(key, key_len)).ok();
+        let result = encrypted.zip(key).and_then(|(encrypted, key)| {
+            let (nonce, ciphertext) = encrypted.split_once(':')?;
+            decrypt(nonce, ciphertext, key).ok()
+        });
+        output(result, out_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ffi::*;
+    use super::*;
+
+    unsafe fn take(ptr: *mut u8, len: usize) -> Vec<u8> {
+        let bytes = std::slice::from_raw_parts(ptr, len).to_vec();
+        peerchunks_free(ptr, len);
+        bytes
+    }
+
+    #[test]
+    fn test_ffi_round_trips() {
+        let data: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+        let key = "ab".repeat(32);
+        let mut len = 0;
+        unsafe {
+            let chunks = take(peerchunks_split_file_into_chunks(data.as_ptr(), data.len(), 1024, &mut len), len);
+            let metadata: Vec<ChunkMetadata> = crate::json::from_str(std::str::from_utf8(&chunks).unwrap()).unwrap();
+            assert_eq!(metadata.iter().map(|m| m.actual_size).collect::<Vec<_>>(), vec![1024, 1024, 452]);
+            assert!(peerchunks_split_file_into_chunks(data.as_ptr(), data.len(), 0, &mut len).is_null());
+
+            let name = "a.bin";
+            let manifest = take(peerchunks_build_manifest(name.as_ptr(), name.len(), data.as_ptr(), data.len(), 1024, &mut len), len);
+            let manifest: FileManifest = crate::json::from_str(std::str::from_utf8(&manifest).unwrap()).unwrap();
+            assert_eq!((manifest.file_name.as_str(), manifest.file_size, manifest.total_chunks), (name, 2500, 3));
+            assert_eq!(manifest.file_hash, Some(blake3(&data)));
+
+            let hash = take(peerchunks_chunk_hash(data.as_ptr(), data.len(), &mut len), len);
+            assert_eq!(hash, chunk_hash(&data).into_bytes());
+
+            let encrypted = take(peerchunks_encrypt(data.as_ptr(), data.len(), key.as_ptr(), key.len(), &mut len), len);
+            let decrypted = take(peerchunks_decrypt(encrypted.as_ptr(), encrypted.len(), key.as_ptr(), key.len(), &mut len), len);
+            assert_eq!(decrypted, data);
+            let wrong_key = "cd".repeat(32);
+            assert!(peerchunks_decrypt(encrypted.as_ptr(), encrypted.len(), wrong_key.as_ptr(), wrong_key.len(), &mut len).is_null());
+            assert!(peerchunks_encrypt(data.as_ptr(), data.len(), b"00".as_ptr(), 2, &mut len).is_null());
+        }
+    }
+}